@@ -0,0 +1,94 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use serde_json::{Map, Value};
+
+/// Path of database/programs.json in a checkout of the community chip-8 database
+const DATABASE_VAR: &str = "RCHIP8_DATABASE";
+
+/// Fields of the ROM entries read by src/chip8/database.rs. The others are left out of the library.
+const ROM_FIELDS: [&str; 5] = ["platforms", "quirkyPlatforms", "tickrate", "colors", "keys"];
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    write_database();
+
+    #[cfg(feature = "ffi")]
+    write_ffi_header();
+}
+
+/// Writes the bundled ROM database to OUT_DIR/programs.json: the entries of the community database
+/// when RCHIP8_DATABASE points to it, then those of src/chip8/programs.json, which replace entries
+/// with the same hash.
+fn write_database() {
+    let dir = env::var("CARGO_MANIFEST_DIR").expect("Cargo sets the manifest directory");
+    let mut sources = Vec::new();
+    println!("cargo:rerun-if-env-changed={}", DATABASE_VAR);
+    if let Some(path) = env::var_os(DATABASE_VAR) {
+        sources.push(PathBuf::from(path));
+    }
+    sources.push(PathBuf::from(dir).join("src/chip8/programs.json"));
+
+    let mut programs = Vec::new();
+    for source in sources {
+        println!("cargo:rerun-if-changed={}", source.display());
+        let json = fs::read_to_string(&source)
+            .unwrap_or_else(|e| panic!("Could not read the ROM database {}: {}", source.display(), e));
+        let entries: Vec<Value> = serde_json::from_str(&json)
+            .unwrap_or_else(|e| panic!("Invalid ROM database {}: {}", source.display(), e));
+        programs.extend(entries.iter().map(strip_program));
+    }
+
+    let out = PathBuf::from(env::var("OUT_DIR").expect("Cargo sets the output directory")).join("programs.json");
+    fs::write(&out, Value::Array(programs).to_string()).expect("Could not write the ROM database");
+}
+
+/// Keeps the title of a program and the fields of its ROMs that rchip8 reads
+fn strip_program(program: &Value) -> Value {
+    let mut roms = Map::new();
+    if let Some(entries) = program["roms"].as_object() {
+        for (hash, rom) in entries {
+            let fields = ROM_FIELDS.iter()
+                .filter_map(|field| rom.get(*field).map(|value| (field.to_string(), value.clone())))
+                .collect();
+            roms.insert(hash.clone(), Value::Object(fields));
+        }
+    }
+
+    let mut stripped = Map::new();
+    stripped.insert(String::from("title"), program["title"].clone());
+    stripped.insert(String::from("roms"), Value::Object(roms));
+    Value::Object(stripped)
+}
+
+/// Writes OUT_DIR/rchip8.h, the header of the C API, from the declarations in ffi.rs.
+/// A test of ffi.rs checks that include/rchip8.h, the committed copy, matches it.
+#[cfg(feature = "ffi")]
+fn write_ffi_header() {
+    use cbindgen::{Builder, Config, EnumConfig, Language, RenameRule};
+
+    println!("cargo:rerun-if-changed=src/chip8/ffi.rs");
+    let dir = env::var("CARGO_MANIFEST_DIR").expect("Cargo sets the manifest directory");
+    let out = env::var("OUT_DIR").expect("Cargo sets the output directory");
+
+    let config = Config {
+        language: Language::C,
+        header: Some(String::from("/* Generated from src/chip8/ffi.rs by build.rs. Don't edit. */")),
+        include_guard: Some(String::from("RCHIP8_H")),
+        cpp_compat: true,
+        enumeration: EnumConfig {
+            rename_variants: RenameRule::QualifiedScreamingSnakeCase,
+            ..EnumConfig::default()
+        },
+        ..Config::default()
+    };
+
+    Builder::new()
+        .with_config(config)
+        .with_src(format!("{}/src/chip8/ffi.rs", dir))
+        .generate()
+        .expect("Could not generate the C header")
+        .write_to_file(format!("{}/rchip8.h", out));
+}
@@ -0,0 +1,66 @@
+#![no_main]
+
+//! Runs random ROMs on the headless machine and on `Reference`, an interpreter written from the
+//! specification, and checks after every instruction that both agree on registers, stack,
+//! memory and display, and that the machine faults where the specification is undefined. The
+//! reference only knows CHIP-8, so the ROMs run as CHIP-8 whatever platform the input picks.
+
+use libfuzzer_sys::fuzz_target;
+
+use rchip8::chip8::fault::{FaultPolicy, MachineFault};
+use rchip8::chip8::platform::Platform;
+use rchip8_fuzz::{FuzzInput, Reference, Undefined, MAX_CYCLES, MEMORY_SIZE};
+
+/// Whether the machine faulted at `pc` on what the specification leaves undefined
+fn faulted_on(fault: MachineFault, undefined: Undefined, pc: u16) -> bool {
+    match (fault, undefined) {
+        (MachineFault::IllegalOpcode { address, opcode }, Undefined::Opcode(word)) => address == pc && opcode == word,
+        (MachineFault::StackOverflow { address }, Undefined::StackOverflow) => address == pc,
+        (MachineFault::StackUnderflow { address }, Undefined::StackUnderflow) => address == pc,
+        (MachineFault::MemoryOutOfBounds { address, access }, Undefined::Address(_)) => address == pc && access >= MEMORY_SIZE,
+        _ => false,
+    }
+}
+
+fuzz_target!(|input: FuzzInput| {
+    let Some(mut chip8) = input.machine(Platform::Chip8, FaultPolicy::Halt) else {
+        return;
+    };
+    let mut reference = Reference::new(&chip8.ram, &input.rom, input.spec_quirks(), input.ipf());
+
+    for _ in 0..MAX_CYCLES {
+        let keys = input.keys_at(chip8.frame_count());
+        chip8.set_keys(keys);
+        reference.keys = keys;
+
+        let pc = reference.pc;
+        let opcode = chip8.trace_entry().opcode;
+        let result = chip8.step();
+        let halted = result.is_err();
+        match (result, reference.step()) {
+            (Ok(()), Ok(())) => {}
+            (Err(fault), Err(undefined)) => assert!(faulted_on(fault, undefined, pc), "{} where {:?}", fault, undefined),
+            (result, expected) => panic!("{:04X} at 0x{:03X}: {:?}, expected {:?}", opcode, pc, result, expected),
+        }
+
+        // Cxnn can't be predicted: check the interpreter kept to the mask, then follow it
+        if let Some((x, mask)) = reference.random {
+            let value = chip8.registers().v[x];
+            assert_eq!(value & ! mask, 0, "{:04X} at 0x{:03X} set bits outside of its mask", opcode, pc);
+            reference.v[x] = value;
+        }
+
+        let registers = chip8.registers();
+        assert_eq!(registers.v, reference.v, "V registers after {:04X} at 0x{:03X}", opcode, pc);
+        assert_eq!(registers.i, reference.i, "I after {:04X} at 0x{:03X}", opcode, pc);
+        assert_eq!(registers.pc, reference.pc, "pc after {:04X} at 0x{:03X}", opcode, pc);
+        assert_eq!((registers.dt, registers.st), (reference.dt, reference.st), "timers after {:04X} at 0x{:03X}", opcode, pc);
+        assert_eq!(chip8.stack(), &reference.stack[..], "stack after {:04X} at 0x{:03X}", opcode, pc);
+        assert!(chip8.ram == reference.ram, "memory after {:04X} at 0x{:03X}", opcode, pc);
+        assert!(chip8.framebuffer().pixels() == &reference.pixels[..], "display after {:04X} at 0x{:03X}", opcode, pc);
+
+        if halted {
+            break;
+        }
+    }
+});
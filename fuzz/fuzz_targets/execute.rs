@@ -0,0 +1,30 @@
+#![no_main]
+
+//! Runs random ROMs on the headless machine, with random platforms, quirks and keys, for a
+//! bounded number of instructions. Any panic is a bug: faulty programs must fault, not crash.
+
+use libfuzzer_sys::fuzz_target;
+
+use rchip8::chip8::fault::FaultPolicy;
+use rchip8_fuzz::{FuzzInput, MAX_CYCLES};
+
+fuzz_target!(|input: FuzzInput| {
+    let policy = if input.wrap { FaultPolicy::Wrap } else { FaultPolicy::Halt };
+    let Some(mut chip8) = input.machine(input.platform(), policy) else {
+        return;
+    };
+
+    while chip8.instruction_count() < MAX_CYCLES {
+        chip8.set_keys(input.keys_at(chip8.frame_count()));
+        if chip8.step().is_err() {
+            break;
+        }
+    }
+
+    // Save states restore the machine exactly, wherever it stopped
+    let state = chip8.save_state();
+    let mut restored = input.machine(input.platform(), policy).unwrap();
+    restored.load_state(&state).unwrap();
+    assert_eq!(restored.save_state(), state);
+    assert_eq!(restored.registers(), chip8.registers());
+});
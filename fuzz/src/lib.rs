@@ -0,0 +1,372 @@
+use arbitrary::Arbitrary;
+
+use rchip8::chip8::chip8::{CHIP8, FONT_ADDRESS};
+use rchip8::chip8::fault::FaultPolicy;
+use rchip8::chip8::platform::Platform;
+use rchip8::chip8::quirks::Quirks;
+
+/// Instructions a fuzz input runs at most
+pub const MAX_CYCLES: u64 = 20_000;
+
+const DISPLAY_COLS: usize = 64;
+const DISPLAY_ROWS: usize = 32;
+
+/// A ROM, the machine running it, and the keys pressed while it runs
+#[derive(Arbitrary, Debug)]
+pub struct FuzzInput {
+    platform: u8,
+    /// One bit per quirk, in the order of `Quirks`
+    quirks: u8,
+    ipf: u8,
+    /// Carry on after faults, as `--on-fault wrap`, instead of stopping
+    pub wrap: bool,
+    /// Key masks, each held for a number of frames, in order
+    keys: Vec<(u8, u16)>,
+    pub rom: Vec<u8>,
+}
+
+impl FuzzInput {
+    pub fn platform(&self) -> Platform {
+        match self.platform % 3 {
+            0 => Platform::Chip8,
+            1 => Platform::Schip,
+            _ => Platform::XoChip,
+        }
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        let bit = |index: u8| self.quirks & (1 << index) != 0;
+        Quirks {
+            shift: bit(0),
+            memory_increment_by_x: bit(1),
+            memory_leave_i_unchanged: bit(2),
+            wrap: bit(3),
+            jump: bit(4),
+            vblank: bit(5),
+            logic: bit(6),
+        }
+    }
+
+    /// Instructions per frame, kept low so timers and vblank run often
+    pub fn ipf(&self) -> u32 {
+        self.ipf as u32 % 32 + 1
+    }
+
+    /// Keys held during `frame`. The last mask stays held after the schedule ends.
+    pub fn keys_at(&self, frame: u64) -> [bool; 16] {
+        let mut start = 0;
+        let mut mask = 0;
+        for (frames, keys) in &self.keys {
+            if frame < start {
+                break;
+            }
+            mask = *keys;
+            start += *frames as u64 + 1;
+        }
+        std::array::from_fn(|key| mask & (1 << key) != 0)
+    }
+
+    /// The headless machine running the ROM on `platform`, or None when the ROM doesn't load
+    pub fn machine(&self, platform: Platform, fault_policy: FaultPolicy) -> Option<CHIP8> {
+        let mut chip8 = CHIP8::headless(platform);
+        chip8.load_program(&self.rom).ok()?;
+        chip8.set_quirks(self.quirks());
+        chip8.set_fault_policy(fault_policy);
+        chip8.set_instructions_per_frame(self.ipf());
+        Some(chip8)
+    }
+}
+
+/// Quirks as the quirks test of Timendus' CHIP-8 test suite tells them apart, with the memory
+/// quirk of SUPER-CHIP 1.0 listed by the community chip-8 database
+#[derive(Clone, Copy, Debug)]
+pub struct SpecQuirks {
+    /// 8xy1, 8xy2 and 8xy3 reset VF to 0
+    pub vf_reset: bool,
+    pub memory: MemoryQuirk,
+    /// Dxyn waits for the display interrupt, so at most one sprite is drawn per frame
+    pub display_wait: bool,
+    /// Sprites are clipped at the edges of the display. Their position always wraps around.
+    pub clipping: bool,
+    /// 8xy6 and 8xyE shift Vx instead of Vy
+    pub shifting: bool,
+    /// Bxnn jumps to xnn + Vx instead of Bnnn jumping to nnn + V0
+    pub jumping: bool,
+}
+
+/// What Fx55 and Fx65 leave in I
+#[derive(Clone, Copy, Debug)]
+pub enum MemoryQuirk {
+    /// I + X + 1, past the last register, as on the COSMAC VIP
+    Increment,
+    /// I + X, as on SUPER-CHIP 1.0
+    IncrementByX,
+    /// I, as on SUPER-CHIP 1.1
+    Unchanged,
+}
+
+impl FuzzInput {
+    /// The quirks of `quirks`, as the specification names them
+    pub fn spec_quirks(&self) -> SpecQuirks {
+        let quirks = self.quirks();
+        SpecQuirks {
+            vf_reset: quirks.logic,
+            memory: match (quirks.memory_leave_i_unchanged, quirks.memory_increment_by_x) {
+                (true, _) => MemoryQuirk::Unchanged,
+                (false, true) => MemoryQuirk::IncrementByX,
+                (false, false) => MemoryQuirk::Increment,
+            },
+            display_wait: quirks.vblank,
+            clipping: ! quirks.wrap,
+            shifting: quirks.shift,
+            jumping: quirks.jump,
+        }
+    }
+}
+
+/// Size of the memory of CHIP-8
+pub const MEMORY_SIZE: usize = 0x1000;
+
+/// Where programs are loaded, after the memory of the interpreter
+const PROGRAM_START: usize = 0x200;
+
+/// What the specification leaves undefined, where the reference stops
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Undefined {
+    /// A word that is not an instruction, including 0nnn machine code routines
+    Opcode(u16),
+    /// 2nnn with the 16 levels of the stack used
+    StackOverflow,
+    /// 00EE with an empty stack
+    StackUnderflow,
+    /// An instruction, or memory at I, reaching this address past memory
+    Address(usize),
+}
+
+/// A CHIP-8 interpreter written from Cowgod's technical reference, with the flags and quirks of
+/// Timendus' test suite, to compare the emulator with instruction by instruction.
+///
+/// The specification leaves some choices to interpreters: the reference takes the memory below
+/// 0x200, fonts included, from the emulator, and its font address. It ends frames after `ipf`
+/// instructions, then counts the timers down. Fx0A completes once a key is held, storing the
+/// lowest one. Instructions that are undefined leave the machine as it was.
+pub struct Reference {
+    pub ram: Vec<u8>,
+    pub v: [u8; 16],
+    /// 16 bits wide, although only 12 bits address memory
+    pub i: u16,
+    pub pc: u16,
+    pub stack: Vec<u16>,
+    pub dt: u8,
+    pub st: u8,
+    pub pixels: Vec<bool>,
+    pub keys: [bool; 16],
+    quirks: SpecQuirks,
+    ipf: u32,
+    /// Instructions executed in the frame
+    ticks: u32,
+    /// Set when a sprite waits for the display interrupt, ending the frame
+    interrupt: bool,
+    /// Register written by the last Cxnn, and its mask, since random values can't be predicted
+    pub random: Option<(usize, u8)>,
+}
+
+impl Reference {
+    /// Loads `rom` at 0x200, after `interpreter`, the memory of the interpreter
+    pub fn new(interpreter: &[u8], rom: &[u8], quirks: SpecQuirks, ipf: u32) -> Self {
+        let mut ram = vec![0; MEMORY_SIZE];
+        ram[..PROGRAM_START].copy_from_slice(&interpreter[..PROGRAM_START]);
+        ram[PROGRAM_START..PROGRAM_START + rom.len()].copy_from_slice(rom);
+
+        Reference {
+            ram: ram,
+            v: [0; 16],
+            i: 0,
+            pc: PROGRAM_START as u16,
+            stack: Vec::new(),
+            dt: 0,
+            st: 0,
+            pixels: vec![false; DISPLAY_COLS * DISPLAY_ROWS],
+            keys: [false; 16],
+            quirks: quirks,
+            ipf: ipf,
+            ticks: 0,
+            interrupt: false,
+            random: None,
+        }
+    }
+
+    /// Executes an instruction, then ends the frame if it is over
+    pub fn step(&mut self) -> Result<(), Undefined> {
+        self.random = None;
+        let opcode = self.fetch()?;
+        let next = self.pc + 2;
+        self.execute(opcode, next)?;
+
+        self.ticks += 1;
+        if self.ticks == self.ipf || self.interrupt {
+            self.ticks = 0;
+            self.interrupt = false;
+            self.dt = self.dt.saturating_sub(1);
+            self.st = self.st.saturating_sub(1);
+        }
+        Ok(())
+    }
+
+    fn fetch(&self) -> Result<u16, Undefined> {
+        let pc = self.pc as usize;
+        if pc + 1 >= MEMORY_SIZE {
+            return Err(Undefined::Address(pc + 1));
+        }
+        Ok(u16::from_be_bytes([self.ram[pc], self.ram[pc + 1]]))
+    }
+
+    /// Checks the `len` bytes at I are in memory
+    fn at_i(&self, len: usize) -> Result<usize, Undefined> {
+        let i = self.i as usize;
+        match i + len <= MEMORY_SIZE {
+            true => Ok(i),
+            false => Err(Undefined::Address(i + len - 1)),
+        }
+    }
+
+    /// Executes `opcode`, the instruction at pc, with `next` the address of the next one.
+    /// Changes nothing when the instruction is undefined.
+    fn execute(&mut self, opcode: u16, next: u16) -> Result<(), Undefined> {
+        let x = (opcode >> 8 & 0xF) as usize;
+        let y = (opcode >> 4 & 0xF) as usize;
+        let n = (opcode & 0xF) as usize;
+        let kk = (opcode & 0xFF) as u8;
+        let nnn = opcode & 0xFFF;
+        let mut pc = next;
+
+        match (opcode >> 12, x, y, n) {
+            (0x0, 0x0, 0xE, 0x0) => self.pixels.fill(false),
+            (0x0, 0x0, 0xE, 0xE) => pc = self.stack.pop().ok_or(Undefined::StackUnderflow)?,
+            (0x1, ..) => pc = nnn,
+            (0x2, ..) => {
+                if self.stack.len() == 16 {
+                    return Err(Undefined::StackOverflow);
+                }
+                self.stack.push(next);
+                pc = nnn;
+            }
+            (0x3, ..) if self.v[x] == kk => pc += 2,
+            (0x4, ..) if self.v[x] != kk => pc += 2,
+            (0x5, .., 0x0) if self.v[x] == self.v[y] => pc += 2,
+            (0x3 | 0x4, ..) | (0x5, .., 0x0) => {}
+            (0x6, ..) => self.v[x] = kk,
+            (0x7, ..) => self.v[x] = self.v[x].wrapping_add(kk),
+            (0x8, .., 0x0) => self.v[x] = self.v[y],
+            (0x8, .., 0x1..=0x3) => {
+                self.v[x] = match n {
+                    0x1 => self.v[x] | self.v[y],
+                    0x2 => self.v[x] & self.v[y],
+                    _ => self.v[x] ^ self.v[y],
+                };
+                if self.quirks.vf_reset {
+                    self.v[0xF] = 0;
+                }
+            }
+            // VF is set after the result, so the flag wins when Vx is VF
+            (0x8, .., 0x4) => {
+                let (sum, carry) = self.v[x].overflowing_add(self.v[y]);
+                self.set_with_flag(x, sum, carry);
+            }
+            (0x8, .., 0x5) => {
+                let (difference, borrow) = self.v[x].overflowing_sub(self.v[y]);
+                self.set_with_flag(x, difference, ! borrow);
+            }
+            (0x8, .., 0x7) => {
+                let (difference, borrow) = self.v[y].overflowing_sub(self.v[x]);
+                self.set_with_flag(x, difference, ! borrow);
+            }
+            (0x8, .., 0x6 | 0xE) => {
+                let value = if self.quirks.shifting { self.v[x] } else { self.v[y] };
+                match n {
+                    0x6 => self.set_with_flag(x, value >> 1, value & 0x01 != 0),
+                    _ => self.set_with_flag(x, value << 1, value & 0x80 != 0),
+                }
+            }
+            (0x9, .., 0x0) if self.v[x] != self.v[y] => pc += 2,
+            (0x9, .., 0x0) => {}
+            (0xA, ..) => self.i = nnn,
+            (0xB, ..) => pc = nnn + self.v[if self.quirks.jumping { x } else { 0 }] as u16,
+            (0xC, ..) => {
+                self.v[x] = 0;
+                self.random = Some((x, kk));
+            }
+            (0xD, ..) => {
+                let i = self.at_i(n)?;
+                let sprite = self.ram[i..i + n].to_vec();
+                self.v[0xF] = self.draw(self.v[x] as usize, self.v[y] as usize, &sprite) as u8;
+                self.interrupt = self.quirks.display_wait;
+            }
+            (0xE, _, 0x9, 0xE) if self.held(self.v[x]) => pc += 2,
+            (0xE, _, 0xA, 0x1) if ! self.held(self.v[x]) => pc += 2,
+            (0xE, _, 0x9, 0xE) | (0xE, _, 0xA, 0x1) => {}
+            (0xF, _, 0x0, 0x7) => self.v[x] = self.dt,
+            (0xF, _, 0x0, 0xA) => match self.keys.iter().position(|held| *held) {
+                Some(key) => self.v[x] = key as u8,
+                None => pc = self.pc,
+            },
+            (0xF, _, 0x1, 0x5) => self.dt = self.v[x],
+            (0xF, _, 0x1, 0x8) => self.st = self.v[x],
+            (0xF, _, 0x1, 0xE) => self.i = self.i.wrapping_add(self.v[x] as u16),
+            (0xF, _, 0x2, 0x9) => self.i = (FONT_ADDRESS + (self.v[x] & 0xF) as usize * 5) as u16,
+            (0xF, _, 0x3, 0x3) => {
+                let i = self.at_i(3)?;
+                let value = self.v[x];
+                self.ram[i..i + 3].copy_from_slice(&[value / 100, value / 10 % 10, value % 10]);
+            }
+            (0xF, _, 0x5 | 0x6, 0x5) => {
+                let i = self.at_i(x + 1)?;
+                match y {
+                    0x5 => self.ram[i..=i + x].copy_from_slice(&self.v[..=x]),
+                    _ => self.v[..=x].copy_from_slice(&self.ram[i..=i + x]),
+                }
+                self.i = match self.quirks.memory {
+                    MemoryQuirk::Increment => self.i.wrapping_add(x as u16 + 1),
+                    MemoryQuirk::IncrementByX => self.i.wrapping_add(x as u16),
+                    MemoryQuirk::Unchanged => self.i,
+                };
+            }
+            _ => return Err(Undefined::Opcode(opcode)),
+        }
+
+        self.pc = pc;
+        Ok(())
+    }
+
+    /// Keys past F don't exist, so are never held
+    fn held(&self, key: u8) -> bool {
+        self.keys.get(key as usize).is_some_and(|held| *held)
+    }
+
+    /// Sets Vx to `value`, then VF to `flag`
+    fn set_with_flag(&mut self, x: usize, value: u8, flag: bool) {
+        self.v[x] = value;
+        self.v[0xF] = flag as u8;
+    }
+
+    /// XORs `sprite` onto the display at (x, y), wrapped around the display. Returns true if a
+    /// pixel was erased.
+    fn draw(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
+        let (x, y) = (x % DISPLAY_COLS, y % DISPLAY_ROWS);
+        let mut erased = false;
+        for (row, bits) in sprite.iter().enumerate() {
+            for col in 0..8 {
+                let (px, py) = (x + col, y + row);
+                if self.quirks.clipping && (px >= DISPLAY_COLS || py >= DISPLAY_ROWS) {
+                    continue;
+                }
+                if bits & (0x80 >> col) != 0 {
+                    let index = py % DISPLAY_ROWS * DISPLAY_COLS + px % DISPLAY_COLS;
+                    erased |= self.pixels[index];
+                    self.pixels[index] = ! self.pixels[index];
+                }
+            }
+        }
+        erased
+    }
+}
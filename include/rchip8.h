@@ -0,0 +1,131 @@
+/* Generated from src/chip8/ffi.rs by build.rs. Don't edit. */
+
+#ifndef RCHIP8_H
+#define RCHIP8_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Platform a machine emulates
+ */
+typedef enum Rchip8Platform {
+  RCHIP8_PLATFORM_CHIP8 = 0,
+  RCHIP8_PLATFORM_SCHIP = 1,
+  RCHIP8_PLATFORM_XO_CHIP = 2,
+} Rchip8Platform;
+
+/**
+ * Result of the functions of the API. On errors, `rchip8_last_error` describes what failed.
+ */
+typedef enum Rchip8Status {
+  RCHIP8_STATUS_OK = 0,
+  RCHIP8_STATUS_NULL_POINTER = 1,
+  RCHIP8_STATUS_INVALID_ROM = 2,
+  /**
+   * The machine faulted. The program counter is left on the faulting instruction.
+   */
+  RCHIP8_STATUS_FAULT = 3,
+  RCHIP8_STATUS_INVALID_STATE = 4,
+  RCHIP8_STATUS_BUFFER_TOO_SMALL = 5,
+  RCHIP8_STATUS_INVALID_KEY = 6,
+} Rchip8Status;
+
+/**
+ * A machine, created by `rchip8_new` and freed by `rchip8_free`
+ */
+typedef struct Rchip8Machine Rchip8Machine;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Returns a machine emulating `platform`, without program, window nor sound
+ */
+struct Rchip8Machine *rchip8_new(enum Rchip8Platform platform);
+
+/**
+ * Frees a machine returned by `rchip8_new`. Does nothing when `machine` is null.
+ */
+void rchip8_free(struct Rchip8Machine *machine);
+
+/**
+ * Loads the `len` bytes of the ROM at `data` into memory at 0x200
+ */
+enum Rchip8Status rchip8_load_rom(struct Rchip8Machine *machine,
+                                  const uint8_t *data,
+                                  uintptr_t len);
+
+/**
+ * Sets the instructions executed per frame, between 1 and 1000
+ */
+enum Rchip8Status rchip8_set_instructions_per_frame(struct Rchip8Machine *machine, uint32_t ipf);
+
+/**
+ * Executes `cycles` instructions, ending frames and updating the timers every
+ * instructions-per-frame instructions. Stops on faults.
+ */
+enum Rchip8Status rchip8_step(struct Rchip8Machine *machine, uint64_t cycles);
+
+/**
+ * Executes instructions until the frame ends. Stops on faults.
+ */
+enum Rchip8Status rchip8_run_frame(struct Rchip8Machine *machine);
+
+/**
+ * Pixels of the display, row by row, one byte per pixel: 1 when lit, 0 otherwise.
+ * The pointer stays valid until the machine runs again, which may change the resolution.
+ */
+const uint8_t *rchip8_framebuffer(const struct Rchip8Machine *machine);
+
+/**
+ * Width of the display in pixels: 64, or 128 in hires
+ */
+uint32_t rchip8_framebuffer_width(const struct Rchip8Machine *machine);
+
+/**
+ * Height of the display in pixels: 32, or 64 in hires
+ */
+uint32_t rchip8_framebuffer_height(const struct Rchip8Machine *machine);
+
+/**
+ * Holds or releases a key of the keypad, from 0x0 to 0xF
+ */
+enum Rchip8Status rchip8_set_key(struct Rchip8Machine *machine, uint8_t key, bool pressed);
+
+/**
+ * True while the sound timer runs, and the machine beeps
+ */
+bool rchip8_sound_active(const struct Rchip8Machine *machine);
+
+/**
+ * Size of the buffer `rchip8_save_state` needs. It only depends on the platform.
+ */
+uintptr_t rchip8_state_size(const struct Rchip8Machine *machine);
+
+/**
+ * Saves the state of the machine into the `len` bytes at `buffer`
+ */
+enum Rchip8Status rchip8_save_state(struct Rchip8Machine *machine, uint8_t *buffer, uintptr_t len);
+
+/**
+ * Restores a state saved by `rchip8_save_state` on a machine of the same platform
+ */
+enum Rchip8Status rchip8_load_state(struct Rchip8Machine *machine,
+                                    const uint8_t *buffer,
+                                    uintptr_t len);
+
+/**
+ * Description of the last error, empty if none. The string stays valid until the next call
+ * on the machine.
+ */
+const char *rchip8_last_error(const struct Rchip8Machine *machine);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* RCHIP8_H */
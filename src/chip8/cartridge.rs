@@ -0,0 +1,217 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::chip8::database::{Palette, RomInfo};
+use crate::chip8::octo;
+use crate::chip8::platform::Platform;
+use crate::chip8::quirks::QuirkOverrides;
+use crate::chip8::rom::{self, RomError};
+
+/// An Octo cartridge: an assembled program and the options it was shared with
+pub struct Cartridge {
+    pub program: Vec<u8>,
+    pub info: RomInfo,
+}
+
+/// Options saved by Octo in a cartridge
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OctoOptions {
+    tickrate: Option<u32>,
+    fill_color: Option<String>,
+    fill_color2: Option<String>,
+    blend_color: Option<String>,
+    background_color: Option<String>,
+    shift_quirks: Option<bool>,
+    load_store_quirks: Option<bool>,
+    clip_quirks: Option<bool>,
+    jump_quirks: Option<bool>,
+    v_blank_quirks: Option<bool>,
+    logic_quirks: Option<bool>,
+    max_size: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    program: String,
+    #[serde(default)]
+    options: OctoOptions,
+}
+
+/// Returns true if `path` looks like an Octo cartridge
+pub fn is_cartridge(path: &str) -> bool {
+    path.to_lowercase().ends_with(".gif")
+}
+
+/// Loads an Octo cartridge and assembles the program it holds.
+///
+/// Cartridges are GIF images with the payload hidden in the palette indexes of the pixels:
+/// each pixel holds two bits in the low bits of its index, four pixels make a byte, most
+/// significant bits first, across all the frames. The payload is a 32 bit big endian length
+/// followed by that many bytes of UTF-8 JSON: `{"program": "<Octo source>", "options": {...}}`.
+pub fn load(path: &str) -> Result<Cartridge, RomError> {
+    let data = rom::read(path)?;
+    let payload = decode(&data).map_err(|reason| RomError::BadCartridge(path.to_string(), reason))?;
+
+    let payload: Payload = serde_json::from_slice(&payload)
+        .map_err(|e| RomError::BadCartridge(path.to_string(), format!("invalid payload: {}", e)))?;
+
+    let program = octo::assemble(&payload.program)
+        .map_err(|e| RomError::BadCartridge(path.to_string(), format!("could not assemble program: {}", e)))?;
+
+    let title = Path::new(path).file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    Ok(Cartridge {
+        program: program,
+        info: payload.options.into_info(title),
+    })
+}
+
+/// Extracts the payload hidden in the pixels of the GIF
+fn decode(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(data).map_err(|e| e.to_string())?;
+
+    let mut pixels = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().map_err(|e| e.to_string())? {
+        pixels.extend_from_slice(&frame.buffer);
+    }
+
+    let bytes: Vec<u8> = pixels.chunks_exact(4)
+        .map(|p| (p[0] & 3) << 6 | (p[1] & 3) << 4 | (p[2] & 3) << 2 | (p[3] & 3))
+        .collect();
+
+    if bytes.len() < 4 {
+        return Err(String::from("no payload"));
+    }
+    let size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    if size == 0 || size > bytes.len() - 4 {
+        return Err(String::from("no payload"));
+    }
+
+    Ok(bytes[4..4 + size].to_vec())
+}
+
+impl OctoOptions {
+    fn into_info(self, title: String) -> RomInfo {
+        // Octo picks the platform through the maximum ROM size
+        let platform = self.max_size.map(|max_size| match max_size {
+            size if size > 3584 => Platform::XoChip,
+            3583 => Platform::Schip,
+            _ => Platform::Chip8,
+        });
+
+        // Octo names the colors of plane 2 and of both planes fill2 and blend
+        let palette = match (self.background_color, self.fill_color) {
+            (Some(background), Some(fill)) => {
+                let colors: Vec<String> = [Some(background), Some(fill), self.fill_color2, self.blend_color]
+                    .into_iter().map_while(|color| color).collect();
+                Palette::from_hex_list(&colors)
+            }
+            _ => None,
+        };
+
+        RomInfo {
+            title: title,
+            platform: platform,
+            quirks: QuirkOverrides {
+                shift: self.shift_quirks,
+                memory_increment_by_x: None,
+                memory_leave_i_unchanged: self.load_store_quirks,
+                wrap: self.clip_quirks.map(|clip| ! clip),
+                jump: self.jump_quirks,
+                vblank: self.v_blank_quirks,
+                logic: self.logic_quirks,
+            },
+            tickrate: self.tickrate,
+            palette: palette,
+            keys: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes `payload` the way Octo does, in two frames of 8 colors so the high bits of the
+    /// indexes must be ignored
+    fn encode(payload: &str) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload.as_bytes());
+        let mut pixels: Vec<u8> = bytes.iter()
+            .flat_map(|byte| [byte >> 6, byte >> 4, byte >> 2, *byte].map(|bits| bits & 3))
+            .enumerate()
+            .map(|(i, bits)| bits | ((i % 2) as u8) << 2)
+            .collect();
+        let width = 16;
+        let height = pixels.len().div_ceil(2 * width) as u16;
+        pixels.resize(2 * width * height as usize, 0);
+
+        let palette: Vec<u8> = (0..8).flat_map(|i| [i * 32, i * 32, i * 32]).collect();
+        let mut gif = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif, width as u16, height, &palette).unwrap();
+            for frame in pixels.chunks(width * height as usize) {
+                let frame = gif::Frame::from_indexed_pixels(width as u16, height, frame, None);
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+        gif
+    }
+
+    fn load_payload(payload: &str) -> Result<Cartridge, RomError> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Cart.gif");
+        std::fs::write(&path, encode(payload)).unwrap();
+        load(path.to_str().unwrap())
+    }
+
+    fn options(json: &str) -> RomInfo {
+        serde_json::from_str::<OctoOptions>(json).unwrap().into_info(String::from("Cart"))
+    }
+
+    #[test]
+    fn programs_round_trip_through_gifs() {
+        let payload = r##"{"program": ": main\n  v0 := 0x2A\n  jump main", "options": {"tickrate": 20, "fillColor": "#FF0000", "backgroundColor": "#000000"}}"##;
+        let cartridge = load_payload(payload).unwrap();
+        assert_eq!(cartridge.program, vec![0x12, 0x02, 0x60, 0x2A, 0x12, 0x02]);
+        assert_eq!(cartridge.info.title, "Cart");
+        assert_eq!(cartridge.info.tickrate, Some(20));
+        assert_eq!(cartridge.info.palette.unwrap().foreground, [255, 0, 0]);
+
+        assert!(matches!(load_payload("{}"), Err(RomError::BadCartridge(_, _))));
+        assert!(matches!(load_payload(r#"{"program": ": main jump nowhere"}"#), Err(RomError::BadCartridge(_, _))));
+        assert!(decode(&encode("")).is_err());
+    }
+
+    #[test]
+    fn max_size_picks_the_platform() {
+        assert_eq!(options(r#"{"maxSize": 3215}"#).platform, Some(Platform::Chip8));
+        assert_eq!(options(r#"{"maxSize": 3583}"#).platform, Some(Platform::Schip));
+        assert_eq!(options(r#"{"maxSize": 3584}"#).platform, Some(Platform::Chip8));
+        assert_eq!(options(r#"{"maxSize": 65024}"#).platform, Some(Platform::XoChip));
+        assert_eq!(options("{}").platform, None);
+    }
+
+    #[test]
+    fn options_map_to_quirks_and_colors() {
+        let info = options(r##"{
+            "tickrate": 500, "shiftQuirks": true, "loadStoreQuirks": false, "clipQuirks": true,
+            "jumpQuirks": false, "vBlankQuirks": true, "logicQuirks": false,
+            "backgroundColor": "#000000", "fillColor": "#111111", "fillColor2": "#222222", "blendColor": "#333333"
+        }"##);
+        assert_eq!(info.tickrate, Some(500));
+        let quirks = info.quirks;
+        assert_eq!((quirks.shift, quirks.memory_leave_i_unchanged, quirks.wrap), (Some(true), Some(false), Some(false)));
+        assert_eq!((quirks.jump, quirks.vblank, quirks.logic), (Some(false), Some(true), Some(false)));
+        assert_eq!(quirks.memory_increment_by_x, None);
+        assert_eq!(info.palette.unwrap().colors(), [[0; 3], [0x11; 3], [0x22; 3], [0x33; 3]]);
+
+        assert!(options(r##"{"fillColor": "#111111"}"##).palette.is_none());
+    }
+}
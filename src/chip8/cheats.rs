@@ -0,0 +1,481 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::chip8::chip8::Registers;
+use crate::chip8::database;
+
+/// Candidates listed after a RAM search step
+const LISTED_CANDIDATES: usize = 20;
+
+/// What a cheat freezes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheatTarget {
+    Ram(u16),
+    V(usize),
+    I,
+    Dt,
+    St,
+}
+
+impl FromStr for CheatTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_uppercase();
+        match upper.as_str() {
+            "I" => Ok(CheatTarget::I),
+            "DT" => Ok(CheatTarget::Dt),
+            "ST" => Ok(CheatTarget::St),
+            _ if upper.len() == 2 && upper.starts_with('V') => usize::from_str_radix(&upper[1..], 16)
+                .map(CheatTarget::V)
+                .map_err(|_| format!("invalid register {}", s)),
+            _ => parse_hex(s).map(CheatTarget::Ram),
+        }
+    }
+}
+
+impl fmt::Display for CheatTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatTarget::Ram(address) => write!(f, "{:03X}", address),
+            CheatTarget::V(index) => write!(f, "V{:X}", index),
+            CheatTarget::I => write!(f, "I"),
+            CheatTarget::Dt => write!(f, "DT"),
+            CheatTarget::St => write!(f, "ST"),
+        }
+    }
+}
+
+/// Keeps a byte of memory or a register at a value, every frame.
+/// Written `TARGET = VALUE  # description`, in hex, e.g. `2A4 = 05  # infinite lives` or `V3 = 09`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cheat {
+    pub target: CheatTarget,
+    pub value: u16,
+    pub description: String,
+}
+
+impl FromStr for Cheat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (cheat, description) = s.split_once('#').unwrap_or((s, ""));
+        let (target, value) = cheat.split_once('=').ok_or(format!("expected TARGET = VALUE, got {}", s.trim()))?;
+        let target: CheatTarget = target.trim().parse()?;
+        let value = parse_hex(value.trim())?;
+
+        if target != CheatTarget::I && value > 0xFF {
+            return Err(format!("{} only holds a byte, got {:X}", target, value));
+        }
+        if let CheatTarget::V(index) = target {
+            if index > 0xF {
+                return Err(format!("invalid register V{:X}", index));
+            }
+        }
+
+        Ok(Cheat { target: target, value: value, description: description.trim().to_string() })
+    }
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = if self.target == CheatTarget::I { 4 } else { 2 };
+        write!(f, "{} = {:0width$X}", self.target, self.value, width = width)?;
+        if ! self.description.is_empty() {
+            write!(f, "  # {}", self.description)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_hex(value: &str) -> Result<u16, String> {
+    let digits = value.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid hex value {}", value))
+}
+
+/// How a RAM search step narrows down the candidates, comparing with the last step
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchCondition {
+    Equal(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl FromStr for SearchCondition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words.as_slice() {
+            ["=" | "eq", value] => parse_hex(value)
+                .and_then(|value| u8::try_from(value).map_err(|_| format!("{} is not a byte", value)))
+                .map(SearchCondition::Equal),
+            ["changed"] => Ok(SearchCondition::Changed),
+            ["unchanged"] => Ok(SearchCondition::Unchanged),
+            ["increased"] => Ok(SearchCondition::Increased),
+            ["decreased"] => Ok(SearchCondition::Decreased),
+            _ => Err(format!("unknown search: {}", s)),
+        }
+    }
+}
+
+/// Finds the addresses holding a value by narrowing down the whole memory step by step
+pub struct RamSearch {
+    candidates: Vec<u16>,
+    /// Memory at the last step
+    previous: Vec<u8>,
+}
+
+impl RamSearch {
+    pub fn new(ram: &[u8]) -> Self {
+        RamSearch {
+            candidates: (0..ram.len()).map(|address| address as u16).collect(),
+            previous: ram.to_vec(),
+        }
+    }
+
+    pub fn filter(&mut self, ram: &[u8], condition: SearchCondition) {
+        let previous = &self.previous;
+        self.candidates.retain(|address| {
+            let (old, new) = (previous[*address as usize], ram[*address as usize]);
+            match condition {
+                SearchCondition::Equal(value) => new == value,
+                SearchCondition::Changed => new != old,
+                SearchCondition::Unchanged => new == old,
+                SearchCondition::Increased => new > old,
+                SearchCondition::Decreased => new < old,
+            }
+        });
+        self.previous = ram.to_vec();
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+}
+
+/// Cheats of a ROM, the RAM search and memory watches, driven by console commands
+#[derive(Default)]
+pub struct CheatEngine {
+    cheats: Vec<Cheat>,
+    /// File the cheats are loaded from and saved to
+    path: Option<PathBuf>,
+    search: Option<RamSearch>,
+    /// Watched addresses and their value at the last frame
+    watches: Vec<(u16, u8)>,
+}
+
+impl CheatEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Default cheat file of a ROM, named after its SHA-1 hash in the config directory
+    pub fn default_path(program: &[u8]) -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("rchip8").join("cheats").join(format!("{}.cht", database::sha1_hex(program))))
+    }
+
+    /// Loads the cheats of `path`, one per line. Blank lines and lines starting with # are ignored.
+    /// A missing file is not an error: cheats are saved there.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        self.path = Some(path.to_path_buf());
+
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("Could not read cheats {}: {}", path.display(), e)),
+        };
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let cheat = line.parse().map_err(|e| format!("{}:{}: {}", path.display(), number + 1, e))?;
+            self.cheats.push(cheat);
+        }
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<PathBuf, String> {
+        let path = self.path.clone().ok_or("No cheat file")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("Could not create {}: {}", dir.display(), e))?;
+        }
+
+        let text: String = self.cheats.iter().map(|cheat| format!("{}\n", cheat)).collect();
+        fs::write(&path, text).map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+        Ok(path)
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    /// Freezes the targets of the cheats, once per frame
+    pub fn apply(&self, ram: &mut [u8], registers: &mut Registers) {
+        for cheat in &self.cheats {
+            match cheat.target {
+                CheatTarget::Ram(address) => ram[address as usize % ram.len()] = cheat.value as u8,
+                CheatTarget::V(index) => registers.v[index] = cheat.value as u8,
+                CheatTarget::I => registers.i = cheat.value,
+                CheatTarget::Dt => registers.dt = cheat.value as u8,
+                CheatTarget::St => registers.st = cheat.value as u8,
+            }
+        }
+    }
+
+    /// Reports the watched addresses whose value changed since the last frame
+    pub fn check_watches(&mut self, ram: &[u8]) {
+        for (address, value) in self.watches.iter_mut() {
+            let current = ram[*address as usize % ram.len()];
+            if current != *value {
+                println!("Watch 0x{:03X}: {:02X} -> {:02X}", address, value, current);
+                *value = current;
+            }
+        }
+    }
+
+    /// Runs a console command and returns what to print
+    pub fn command(&mut self, line: &str, ram: &[u8]) -> String {
+        let line = line.trim();
+        let (command, arguments) = line.split_once(' ').unwrap_or((line, ""));
+        let arguments = arguments.trim();
+
+        match command {
+            "" => String::new(),
+            "search" if arguments.is_empty() || arguments == "reset" => {
+                self.search = Some(RamSearch::new(ram));
+                format!("New search: {} candidates", ram.len())
+            }
+            "search" => match (arguments.parse::<SearchCondition>(), &mut self.search) {
+                (Err(e), _) => e,
+                (Ok(condition), Some(search)) => {
+                    search.filter(ram, condition);
+                    self.list_candidates(ram)
+                }
+                (Ok(condition), None) => {
+                    let mut search = RamSearch::new(ram);
+                    search.filter(ram, condition);
+                    self.search = Some(search);
+                    self.list_candidates(ram)
+                }
+            },
+            "list" => self.list_candidates(ram),
+            "freeze" => match freeze_arguments(arguments).parse::<Cheat>() {
+                Ok(cheat) => {
+                    self.cheats.retain(|c| c.target != cheat.target);
+                    let message = format!("Frozen {}", cheat);
+                    self.cheats.push(cheat);
+                    message
+                }
+                Err(e) => e,
+            },
+            "unfreeze" => match arguments.parse::<CheatTarget>() {
+                Ok(target) => {
+                    self.cheats.retain(|cheat| cheat.target != target);
+                    format!("Unfrozen {}", target)
+                }
+                Err(e) => e,
+            },
+            "cheats" if self.cheats.is_empty() => String::from("No cheats"),
+            "cheats" => self.cheats.iter().map(|cheat| cheat.to_string()).collect::<Vec<String>>().join("\n"),
+            "save" => match self.save() {
+                Ok(path) => format!("Cheats saved to {}", path.display()),
+                Err(e) => e,
+            },
+            "watch" => match parse_hex(arguments) {
+                Ok(address) => {
+                    let value = ram[address as usize % ram.len()];
+                    self.watches.push((address, value));
+                    format!("Watching 0x{:03X}: {:02X}", address, value)
+                }
+                Err(e) => e,
+            },
+            "unwatch" => match parse_hex(arguments) {
+                Ok(address) => {
+                    self.watches.retain(|(watched, _)| *watched != address);
+                    format!("Stopped watching 0x{:03X}", address)
+                }
+                Err(e) => e,
+            },
+            "help" => String::from(CONSOLE_HELP),
+            _ => format!("Unknown command {}. Type help for the list of commands.", command),
+        }
+    }
+
+    fn list_candidates(&self, ram: &[u8]) -> String {
+        let Some(search) = &self.search else {
+            return String::from("No search in progress");
+        };
+
+        let candidates = search.candidates();
+        let mut lines = vec![format!("{} candidates", candidates.len())];
+        for address in candidates.iter().take(LISTED_CANDIDATES) {
+            lines.push(format!("  0x{:03X}: {:02X}", address, ram[*address as usize]));
+        }
+        if candidates.len() > LISTED_CANDIDATES {
+            lines.push(String::from("  ..."));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Turns `TARGET VALUE [description]` into the syntax of cheat files
+fn freeze_arguments(arguments: &str) -> String {
+    let mut words = arguments.splitn(3, ' ');
+    let target = words.next().unwrap_or("");
+    let value = words.next().unwrap_or("");
+    match words.next() {
+        Some(description) => format!("{} = {} # {}", target, value, description),
+        None => format!("{} = {}", target, value),
+    }
+}
+
+const CONSOLE_HELP: &str = "\
+search                  start a new RAM search
+search = VALUE          keep the addresses holding VALUE
+search changed          keep the addresses changed since the last step
+search unchanged        ... unchanged since the last step
+search increased        ... increased since the last step
+search decreased        ... decreased since the last step
+list                    list the candidates
+freeze TARGET VALUE [DESCRIPTION]
+                        keep an address, V0-VF, I, DT or ST at VALUE every frame
+unfreeze TARGET         stop freezing TARGET
+cheats                  list the cheats
+save                    save the cheats to the cheat file of the ROM
+watch ADDRESS           print the value at ADDRESS when it changes
+unwatch ADDRESS         stop watching ADDRESS
+Addresses and values are hex.";
+
+/// Reads console commands from stdin on a thread of its own, so the emulation doesn't block
+pub fn console() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::chip8::CHIP8;
+    use crate::chip8::platform::Platform;
+
+    #[test]
+    fn cheat_files_are_parsed_line_by_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("game.cht");
+        fs::write(&path, "# lives and score\n\n2A4 = 05  # infinite lives\nv3 = 0x09\ni = 0300\nDT = 00\n").unwrap();
+
+        let mut engine = CheatEngine::new();
+        engine.load(&path).unwrap();
+        assert_eq!(engine.cheats(), [
+            Cheat { target: CheatTarget::Ram(0x2A4), value: 5, description: String::from("infinite lives") },
+            Cheat { target: CheatTarget::V(3), value: 9, description: String::new() },
+            Cheat { target: CheatTarget::I, value: 0x300, description: String::new() },
+            Cheat { target: CheatTarget::Dt, value: 0, description: String::new() },
+        ]);
+
+        // Saved cheats load back the same
+        engine.save().unwrap();
+        let mut reloaded = CheatEngine::new();
+        reloaded.load(&path).unwrap();
+        assert_eq!(reloaded.cheats(), engine.cheats());
+
+        // A missing file is an empty list of cheats
+        let mut engine = CheatEngine::new();
+        engine.load(&dir.path().join("missing.cht")).unwrap();
+        assert!(engine.cheats().is_empty());
+    }
+
+    #[test]
+    fn bad_cheats_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("game.cht");
+        fs::write(&path, "2A4 = 05\n2A5 05\n").unwrap();
+        let error = CheatEngine::new().load(&path).unwrap_err();
+        assert_eq!(error, format!("{}:2: expected TARGET = VALUE, got 2A5 05", path.display()));
+
+        assert_eq!("10000 = 05".parse::<Cheat>().unwrap_err(), "invalid hex value 10000");
+        assert_eq!("2A4 = 100".parse::<Cheat>().unwrap_err(), "2A4 only holds a byte, got 100");
+        assert_eq!("VG = 01".parse::<Cheat>().unwrap_err(), "invalid register VG");
+        assert_eq!("V10 = 01".parse::<Cheat>().unwrap_err(), "invalid hex value V10");
+        assert!("I = 10000".parse::<Cheat>().is_err());
+        assert!("I = FFFF".parse::<Cheat>().is_ok());
+    }
+
+    #[test]
+    fn ram_searches_narrow_down_the_candidates() {
+        let mut ram = vec![0; 8];
+        ram[1] = 3;
+        ram[2] = 3;
+        let mut search = RamSearch::new(&ram);
+
+        search.filter(&ram, SearchCondition::Equal(3));
+        assert_eq!(search.candidates(), [1, 2]);
+
+        ram[1] = 4;
+        ram[5] = 9;
+        search.filter(&ram, SearchCondition::Changed);
+        assert_eq!(search.candidates(), [1]);
+
+        let mut search = RamSearch::new(&ram);
+        ram[2] = 2;
+        ram[3] = 1;
+        search.filter(&ram, SearchCondition::Increased);
+        assert_eq!(search.candidates(), [3]);
+
+        let mut search = RamSearch::new(&ram);
+        ram[0] = 7;
+        search.filter(&ram, SearchCondition::Unchanged);
+        assert_eq!(search.candidates(), [1, 2, 3, 4, 5, 6, 7]);
+        ram[4] = 1;
+        search.filter(&ram, SearchCondition::Decreased);
+        assert!(search.candidates().is_empty());
+
+        assert_eq!("= 2A".parse::<SearchCondition>(), Ok(SearchCondition::Equal(0x2A)));
+        assert!("= 100".parse::<SearchCondition>().is_err());
+        assert!("bigger".parse::<SearchCondition>().is_err());
+    }
+
+    #[test]
+    fn frozen_targets_are_reset_every_frame() {
+        // Forever: V0 += 1, then store it at 0x300, one loop per frame
+        let mut chip8 = CHIP8::headless(Platform::Chip8);
+        chip8.load_program(&[0xA3, 0x00, 0x70, 0x01, 0xF0, 0x55, 0x12, 0x00]).unwrap();
+        chip8.set_instructions_per_frame(4);
+
+        let mut engine = CheatEngine::new();
+        assert_eq!(engine.command("freeze V0 05 lives", &chip8.ram), "Frozen V0 = 05  # lives");
+        assert_eq!(engine.command("freeze 300 42", &chip8.ram), "Frozen 300 = 42");
+        chip8.set_cheats(engine);
+
+        for _ in 0..3 {
+            chip8.step_frame().unwrap();
+            assert_eq!((chip8.registers().v[0], chip8.ram[0x300]), (5, 0x42));
+            // The program changed both during the frame
+            chip8.step().unwrap();
+            chip8.step().unwrap();
+            chip8.step().unwrap();
+            assert_eq!((chip8.registers().v[0], chip8.ram[0x300]), (6, 6));
+        }
+    }
+}
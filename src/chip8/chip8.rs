@@ -1,11 +1,26 @@
 use rand::Rng;
-use sdl2::pixels::Color;
-use std::time::Duration;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use std::time::{Duration, Instant};
 use std::{thread, time};
-use crate::chip8::screen::Screen;
-use crate::chip8::synth::Synth;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem;
+use std::sync::mpsc::Receiver;
+use crate::chip8::cheats::CheatEngine;
+use crate::chip8::coverage::Coverage;
+use crate::chip8::database::Palette;
+use crate::chip8::debugger::DebugView;
+use crate::chip8::fault::{FaultPolicy, MachineFault};
+use crate::chip8::framebuffer::{Framebuffer, HIRES_HEIGHT, HIRES_WIDTH, LORES_HEIGHT, LORES_WIDTH};
+use crate::chip8::frontend::{AudioSink, InputEvent, InputSource, NullAudio, VideoSink};
+use crate::chip8::gdb::GdbStub;
+use crate::chip8::trace::{TraceEntry, Tracer};
+use crate::chip8::pacer::FramePacer;
+use crate::chip8::platform::Platform;
+use crate::chip8::profiler::Profiler;
+use crate::chip8::quirks::Quirks;
+use crate::chip8::rom::{self, RomError};
+use crate::chip8::script::{HookEvent, ScriptHooks, ScriptMachine};
+use crate::chip8::state::{StateReader, StateWriter};
+use crate::chip8::synth::SamplePattern;
 use crate::chip8::chip8::thread::JoinHandle;
 
 pub const MAX_MEM: usize = 1024 * 4;
@@ -15,8 +30,12 @@ pub const PROGRAM_ADDRESS: usize = 0x0200;
 
 pub const FONT_ADDRESS: usize = 0x50;
 
-const WINDOW_TITLE: &str = "Dedraks' CHIP-8 Emulator";
-const WINDOW_TITLE_PAUSED: &str = "Dedraks' CHIP-8 Emulator - PAUSED";
+/// The 8x10 digits of SUPER-CHIP are stored after the 4x5 ones
+pub const BIG_FONT_ADDRESS: usize = FONT_ADDRESS + FONT.len();
+
+pub const WINDOW_TITLE: &str = "Dedraks' CHIP-8 Emulator";
+
+const MAX_INSTRUCTIONS_PER_FRAME: u32 = 1000;
 
 const FONT: [u8; 80] = 
         [0x60, 0xB0, 0xD0, 0x90, 0x60, // 0
@@ -36,14 +55,48 @@ const FONT: [u8; 80] =
          0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
          0xF0, 0x80, 0xF0, 0x80, 0x80];// F
 
+const BIG_FONT: [u8; 160] =
+        [0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+         0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+         0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+         0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+         0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+         0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+         0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+         0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+         0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+         0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+         0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+         0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+         0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+         0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+         0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+         0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0];// F
+
+
+/// Registers of the machine, as seen by debuggers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub sp: usize,
+    pub dt: u8,
+    pub st: u8,
+}
 
 /// chip-8 representations
 pub struct CHIP8 {
     /// Memory
     //pub mem: memory::Memory,
-    pub ram: [u8; MAX_MEM],
-    /// Display
-    display: Screen,
+    pub ram: Vec<u8>,
+    /// Display. None when running headless
+    video: Option<Box<dyn VideoSink>>,
+
+    /// Keyboard, taken by `run` while it runs
+    input: Option<Box<dyn InputSource>>,
+
+    framebuffer: Framebuffer,
 
     /// Program Counter register
     pc: u16,
@@ -64,28 +117,98 @@ pub struct CHIP8 {
     // Sound timer register
     st: u8,
 
+    /// Flag registers of SUPER-CHIP and XO-CHIP, written by Fx75 and read by Fx85.
+    /// Not cleared on reset, as they lived in the calculator.
+    flags: [u8; 16],
+
+    /// Sample pattern of XO-CHIP, loaded by F002, and its pitch, set by Fx3A. The beep is a tone
+    /// until a pattern is loaded.
+    audio_pattern: Option<[u8; 16]>,
+    pitch: u8,
+
     key_state: KeyState,
 
-    synth: Synth,
+    audio: Box<dyn AudioSink>,
 
     debug_level: u32,
 
-    debug_fx0a: i32,
-
     paused: bool,
+
+    /// Instructions executed per frame
+    ipf: u32,
+
+    /// Instructions executed since the emulator started
+    instruction_count: u64,
+
+    /// Frames ended since the emulator started
+    frame_count: u64,
+
+    /// Instructions executed by `step` in the current frame
+    frame_ticks: u32,
+
+    /// `run` returns after this many frames, paused or not
+    frame_limit: Option<u64>,
+
+    platform: Platform,
+
+    quirks: Quirks,
+
+    /// Set by Dxyn when the vblank quirk is on: the rest of the frame is skipped
+    vblank_wait: bool,
+
+    window_title: String,
+
+    fault_policy: FaultPolicy,
+
+    /// The fault that halted the machine
+    fault: Option<MachineFault>,
+
+    /// Address of the instruction being executed
+    instruction_address: u16,
+
+    tracer: Option<Tracer>,
+
+    /// Execution pauses before the instructions at these addresses
+    breakpoints: HashSet<u16>,
+
+    gdb: Option<GdbStub>,
+
+    profiler: Option<Profiler>,
+
+    coverage: Option<Coverage>,
+
+    cheats: Option<CheatEngine>,
+
+    /// Lines typed on the cheat console
+    cheat_console: Option<Receiver<String>>,
+
+    hooks: Option<ScriptHooks>,
 }
 
 
 impl CHIP8 {
-    /// Returns a chip-8 machine/interpreter
-    pub fn new(debug_level: u32, scale_factor: i32) -> Self {
+    /// Returns a chip-8 machine/interpreter showing its display on `video`, driven by `run`.
+    /// With `NullAudio`, the machine is silent.
+    pub fn new(video: Box<dyn VideoSink>, input: Box<dyn InputSource>, audio: Box<dyn AudioSink>, debug_level: u32, platform: Platform) -> Self {
+        Self::with_frontend(Some(video), Some(input), audio, debug_level, platform)
+    }
+
+    /// Returns a machine without window nor sound, driven by `step`
+    pub fn headless(platform: Platform) -> Self {
+        Self::with_frontend(None, None, Box::new(NullAudio), 0, platform)
+    }
+
+    fn with_frontend(video: Option<Box<dyn VideoSink>>, input: Option<Box<dyn InputSource>>, audio: Box<dyn AudioSink>, debug_level: u32, platform: Platform) -> Self {
         //let mut mem = memory::Memory::new();
-        let mut ram = [0; 4096];
+        let mut ram = vec![0; platform.memory_size()];
 
         ram[FONT_ADDRESS .. FONT.len() + FONT_ADDRESS].copy_from_slice(&FONT);
+        ram[BIG_FONT_ADDRESS .. BIG_FONT.len() + BIG_FONT_ADDRESS].copy_from_slice(&BIG_FONT);
 
         CHIP8 {
-            display: Screen::new(debug_level > 1, scale_factor, WINDOW_TITLE),
+            video: video,
+            input: input,
+            framebuffer: Framebuffer::new(),
             ram: ram,
             pc: PROGRAM_ADDRESS as u16,
             i: 0,
@@ -94,18 +217,44 @@ impl CHIP8 {
             v: [0; 16],
             dt: 0,
             st: 0,
+            flags: [0; 16],
+            audio_pattern: None,
+            pitch: 64,
             key_state: KeyState::new(),
-            synth: Synth::new(),
+            audio: audio,
             debug_level: debug_level,
-            debug_fx0a: 1,
 
             paused: false,
+            ipf: 1,
+            instruction_count: 0,
+            frame_count: 0,
+            frame_ticks: 0,
+            frame_limit: None,
+            platform: platform,
+            quirks: Quirks::for_platform(platform),
+            vblank_wait: false,
+            window_title: String::from(WINDOW_TITLE),
+            fault_policy: FaultPolicy::Halt,
+            fault: None,
+            instruction_address: PROGRAM_ADDRESS as u16,
+            tracer: (debug_level > 0).then(Tracer::stdout),
+            breakpoints: HashSet::new(),
+            gdb: None,
+            profiler: None,
+            coverage: None,
+            cheats: None,
+            cheat_console: None,
+            hooks: None,
         }
     }
     
 
     fn reset(&mut self) {
-        self.display.clear_screen();
+        self.framebuffer.set_hires(false);
+        self.framebuffer.select_planes(1);
+        self.audio_pattern = None;
+        self.pitch = 64;
+        self.update_sample_pattern();
         self.pc = PROGRAM_ADDRESS as u16;
         self.i = 0;
         self.stack = [0; 16];
@@ -113,27 +262,465 @@ impl CHIP8 {
         self.v = [0; 16];
         self.dt = 0;
         self.st = 0;
-        self.debug_fx0a += 1;
-
-        self.display.canvas.set_draw_color(Color::RGB(0, 0, 0));
-        self.display.canvas.clear();
-        self.display.canvas.present();
+        self.fault = None;
+        self.render();
     }
 
     pub fn pause(&mut self) {
         println!("Pausing execution...");
-        self.display.set_title(WINDOW_TITLE_PAUSED);
+        self.set_title(&format!("{} - PAUSED", self.window_title));
         self.paused = true;
     }
 
     pub fn resume(&mut self) {
         println!("Resuming execution...");
-        self.display.set_title(WINDOW_TITLE);
+        let title = self.window_title.clone();
+        self.set_title(&title);
         self.paused = false;
     }
 
+    pub fn set_instructions_per_frame(&mut self, ipf: u32) {
+        self.ipf = ipf.clamp(1, MAX_INSTRUCTIONS_PER_FRAME);
+    }
+
+    /// Shows the current speed, instructions per frame and measured instructions per second
+    /// in the window title.
+    fn update_title(&mut self, pacer: &FramePacer, ips: u64) {
+        let speed = match pacer.speed() {
+            Some(multiplier) => format!("{}x", multiplier),
+            None => String::from("uncapped"),
+        };
+        let title = format!("{} - {} - {} IPF - {} IPS", self.window_title, speed, self.ipf, ips);
+        self.set_title(&title);
+    }
+
+    fn set_title(&mut self, title: &str) {
+        if let Some(video) = &mut self.video {
+            video.set_title(title);
+        }
+    }
+
+    pub fn set_fault_policy(&mut self, fault_policy: FaultPolicy) {
+        self.fault_policy = fault_policy;
+    }
+
+    /// Stops execution and shows the fault
+    fn halt(&mut self, fault: MachineFault) {
+        eprintln!("{}", fault);
+        self.fault = Some(fault);
+        self.pause();
+        self.set_title(&format!("{} - {}", self.window_title, fault));
+    }
+
+    /// Applies the fault policy: returns the fault if it should halt the machine,
+    /// otherwise the caller carries on as the hardware would.
+    fn raise(&self, fault: MachineFault) -> Result<(), MachineFault> {
+        match self.fault_policy {
+            FaultPolicy::Halt => Err(fault),
+            FaultPolicy::Log => {
+                eprintln!("{}", fault);
+                Ok(())
+            }
+            FaultPolicy::Wrap => Ok(()),
+        }
+    }
+
+    /// Returns the index of `address` in memory, wrapping it around if it is out of bounds
+    /// and the fault policy allows it.
+    fn mem_index(&self, address: usize) -> Result<usize, MachineFault> {
+        if address < self.ram.len() {
+            return Ok(address);
+        }
+        self.raise(MachineFault::MemoryOutOfBounds { address: self.instruction_address, access: address })?;
+        Ok(address % self.ram.len())
+    }
+
+    fn illegal_opcode(&self, word: u16) -> Result<(), MachineFault> {
+        self.raise(MachineFault::IllegalOpcode { address: self.instruction_address, opcode: word })
+    }
+
+    /// The instructions of SUPER-CHIP are also part of XO-CHIP
+    fn is_superchip(&self) -> bool {
+        self.platform != Platform::Chip8
+    }
+
+    /// Skips the next instruction, all 4 bytes of F000 nnnn on XO-CHIP
+    fn skip(&mut self) {
+        let long = self.platform == Platform::XoChip && self.opcode_at(self.pc) == 0xF000;
+        self.pc = self.pc.wrapping_add(if long { 4 } else { 2 });
+    }
+
+    /// Writes every executed instruction to the trace
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Counts where cycles go, see `profiler`
+    pub fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Records which bytes of memory are executed, read and written, see `coverage`
+    pub fn set_coverage(&mut self, coverage: Coverage) {
+        self.coverage = Some(coverage);
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Freezes memory and registers every frame, see `cheats`
+    pub fn set_cheats(&mut self, cheats: CheatEngine) {
+        self.cheats = Some(cheats);
+    }
+
+    /// Runs the cheat commands received on `console` between frames
+    pub fn set_cheat_console(&mut self, console: Receiver<String>) {
+        self.cheat_console = Some(console);
+    }
+
+    /// Applies the cheats and reports the watched addresses that changed
+    fn apply_cheats(&mut self) {
+        let mut registers = self.registers();
+        if let Some(cheats) = &mut self.cheats {
+            cheats.apply(&mut self.ram, &mut registers);
+            cheats.check_watches(&self.ram);
+        }
+        self.v = registers.v;
+        self.i = registers.i;
+        self.dt = registers.dt;
+        self.st = registers.st;
+    }
+
+    /// Runs the commands typed on the cheat console since the last frame
+    fn run_cheat_commands(&mut self) {
+        let (Some(console), Some(cheats)) = (&self.cheat_console, &mut self.cheats) else {
+            return;
+        };
+        while let Ok(line) = console.try_recv() {
+            let output = cheats.command(&line, &self.ram);
+            if ! output.is_empty() {
+                println!("{}", output);
+            }
+        }
+    }
+
+    /// Attaches a script to the machine and runs its top level, which registers its hooks
+    pub fn set_script_hooks(&mut self, hooks: ScriptHooks) {
+        self.hooks = Some(hooks);
+        self.run_hooks(HookEvent::Start);
+    }
+
+    /// Runs the script hooks of `event`, lending them the machine. A failing script is detached.
+    fn run_hooks(&mut self, event: HookEvent) {
+        let Some(mut hooks) = self.hooks.take() else {
+            return;
+        };
+        let result = hooks.call(event, self);
+
+        match result {
+            Ok(()) => self.hooks = Some(hooks),
+            Err(e) => eprintln!("Script failed, hooks stopped: {}", e),
+        }
+    }
+
+    /// Moves the memory, registers, pixels and keys scripts change into `machine`, swapping the
+    /// memory and pixels rather than copying them. The machine must not run before they are
+    /// given back with `return_from_script`.
+    pub fn lend_to_script(&mut self, machine: &mut ScriptMachine) {
+        mem::swap(&mut self.ram, &mut machine.ram);
+        mem::swap(&mut self.framebuffer, &mut machine.framebuffer);
+        machine.registers = self.registers();
+        machine.keys = self.key_state.keys;
+        machine.frame = self.frame_count;
+        machine.cycle = self.instruction_count;
+    }
+
+    pub fn return_from_script(&mut self, machine: &mut ScriptMachine) {
+        mem::swap(&mut self.ram, &mut machine.ram);
+        mem::swap(&mut self.framebuffer, &mut machine.framebuffer);
+        self.v = machine.registers.v;
+        self.i = machine.registers.i;
+        self.dt = machine.registers.dt;
+        self.st = machine.registers.st;
+        self.key_state.keys = machine.keys;
+    }
+
+    /// Runs the key hooks for the keys that changed since `before`
+    fn key_events(&mut self, before: [bool; 16]) {
+        if ! self.hooks.as_ref().is_some_and(|hooks| hooks.watches_keys()) {
+            return;
+        }
+        let keys = self.key_state.keys;
+        for (key, (was, is)) in before.into_iter().zip(keys).enumerate() {
+            if was != is {
+                self.run_hooks(HookEvent::Key(key as u8, is));
+            }
+        }
+    }
+
+    /// Reads a byte of data, wrapping around the end of memory
+    fn load(&mut self, address: usize) -> u8 {
+        let index = address % self.ram.len();
+        if let Some(coverage) = &mut self.coverage {
+            coverage.read(index as u16, 1);
+        }
+        self.ram[index]
+    }
+
+    /// Writes a byte of data, wrapping around the end of memory
+    fn store(&mut self, address: usize, value: u8) {
+        let index = address % self.ram.len();
+        if let Some(coverage) = &mut self.coverage {
+            coverage.written(index as u16, 1);
+        }
+        self.ram[index] = value;
+        if self.hooks.as_ref().is_some_and(|hooks| hooks.watches_write(index as u16)) {
+            self.run_hooks(HookEvent::Write(index as u16, value));
+        }
+    }
+
+    /// Returns the instruction at `address`, without moving pc
+    fn opcode_at(&self, address: u16) -> u16 {
+        let len = self.ram.len();
+        let address = address as usize;
+        (self.ram[address % len] as u16) << 8 | self.ram[(address + 1) % len] as u16
+    }
+
+    /// Returns the state of the machine before the instruction at pc is executed
+    pub fn trace_entry(&self) -> TraceEntry {
+        TraceEntry {
+            cycle: self.instruction_count,
+            pc: self.pc,
+            opcode: self.opcode_at(self.pc),
+            v: self.v,
+            i: self.i,
+            sp: self.sp as u8,
+            dt: self.dt,
+            st: self.st,
+        }
+    }
+
+    /// Records the state of the machine before the instruction at pc is executed
+    fn trace(&mut self) {
+        let entry = self.trace_entry();
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(e) = tracer.record(&entry) {
+                eprintln!("Could not write the trace, tracing stopped: {}", e);
+                self.tracer = None;
+            }
+        }
+    }
+
+    /// Sets a breakpoint at `address`, or removes the one already there
+    pub fn toggle_breakpoint(&mut self, address: u16) {
+        if ! self.breakpoints.remove(&address) {
+            self.breakpoints.insert(address);
+        }
+    }
+
+    pub fn set_breakpoint(&mut self, address: u16, enabled: bool) {
+        if enabled {
+            self.breakpoints.insert(address);
+        } else {
+            self.breakpoints.remove(&address);
+        }
+    }
+
+    /// Lets gdb drive the machine while it runs
+    pub fn set_gdb_stub(&mut self, gdb: GdbStub) {
+        self.gdb = Some(gdb);
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            v: self.v,
+            i: self.i,
+            pc: self.pc,
+            sp: self.sp,
+            dt: self.dt,
+            st: self.st,
+        }
+    }
+
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.v = registers.v;
+        self.i = registers.i;
+        self.pc = registers.pc;
+        self.sp = registers.sp.min(self.stack.len());
+        self.dt = registers.dt;
+        self.st = registers.st;
+        self.render();
+    }
+
+    /// Return addresses on the stack, oldest first
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp]
+    }
+
+    /// State of the 16 keys of the keypad, true when held
+    pub fn keys(&self) -> [bool; 16] {
+        self.key_state.keys
+    }
+
+    /// Holds or releases keys of the keypad, as the keyboard does
+    pub fn set_keys(&mut self, keys: [bool; 16]) {
+        let before = self.key_state.keys;
+        self.key_state.keys = keys;
+        self.key_events(before);
+    }
+
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.framebuffer
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// The fault that halted the machine, if any
+    pub fn fault(&self) -> Option<MachineFault> {
+        self.fault
+    }
+
+    /// Saves the registers, stack, timers, keypad, display and memory. The size of the state
+    /// only depends on the platform.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.u16(self.pc);
+        state.u16(self.i);
+        state.bytes(&self.v);
+        state.u8(self.sp as u8);
+        for address in self.stack {
+            state.u16(address);
+        }
+        state.u8(self.dt);
+        state.u8(self.st);
+        state.bytes(&self.flags);
+        state.u8(self.audio_pattern.is_some() as u8);
+        state.bytes(&self.audio_pattern.unwrap_or_default());
+        state.u8(self.pitch);
+        state.u16(self.key_state.keys.iter().rev().fold(0, |bits, pressed| bits << 1 | *pressed as u16));
+        state.u32(self.ipf);
+        state.u64(self.instruction_count);
+        state.u64(self.frame_count);
+        state.u32(self.frame_ticks);
+        state.u8(self.vblank_wait as u8);
+        // Room for hires pixels in both resolutions, as libretro wants states of one size
+        state.u8(self.framebuffer.is_hires() as u8);
+        state.u8(self.framebuffer.plane_mask());
+        let mut planes = self.framebuffer.planes().to_vec();
+        planes.resize(HIRES_WIDTH * HIRES_HEIGHT, 0);
+        state.bytes(&planes);
+        state.bytes(&self.ram);
+        state.finish()
+    }
+
+    /// Restores a state saved by `save_state` on the same platform. Clears the fault.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let mut reader = StateReader::new(state)?;
+        let pc = reader.u16()?;
+        let i = reader.u16()?;
+        let v = reader.bytes(16)?;
+        let sp = reader.u8()? as usize;
+        let mut stack = [0; 16];
+        for address in stack.iter_mut() {
+            *address = reader.u16()?;
+        }
+        let dt = reader.u8()?;
+        let st = reader.u8()?;
+        let flags = reader.bytes(16)?;
+        let has_audio_pattern = reader.u8()? != 0;
+        let audio_pattern = reader.bytes(16)?;
+        let pitch = reader.u8()?;
+        let keys = reader.u16()?;
+        let ipf = reader.u32()?;
+        let instruction_count = reader.u64()?;
+        let frame_count = reader.u64()?;
+        let frame_ticks = reader.u32()?;
+        let vblank_wait = reader.u8()? != 0;
+        let (width, height) = match reader.u8()? {
+            0 => (LORES_WIDTH, LORES_HEIGHT),
+            _ => (HIRES_WIDTH, HIRES_HEIGHT),
+        };
+        let plane_mask = reader.u8()?;
+        let planes = &reader.bytes(HIRES_WIDTH * HIRES_HEIGHT)?[..width * height];
+        let ram = reader.bytes(self.ram.len())?;
+        reader.finish()?;
+
+        if sp > stack.len() {
+            return Err(format!("Invalid stack pointer {} in save state", sp));
+        }
+
+        self.pc = pc;
+        self.i = i;
+        self.v.copy_from_slice(v);
+        self.sp = sp;
+        self.stack = stack;
+        self.dt = dt;
+        self.st = st;
+        self.flags.copy_from_slice(flags);
+        self.audio_pattern = has_audio_pattern.then(|| audio_pattern.try_into().expect("16 bytes were read"));
+        self.pitch = pitch;
+        self.update_sample_pattern();
+        for (key, pressed) in self.key_state.keys.iter_mut().enumerate() {
+            *pressed = keys & (1 << key) != 0;
+        }
+        self.set_instructions_per_frame(ipf);
+        self.instruction_count = instruction_count;
+        self.frame_count = frame_count;
+        self.frame_ticks = frame_ticks;
+        self.vblank_wait = vblank_wait;
+        self.framebuffer.set_planes(planes)?;
+        self.framebuffer.select_planes(plane_mask);
+        self.ram.copy_from_slice(ram);
+        self.fault = None;
+        self.render();
+        Ok(())
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        if let Some(video) = &mut self.video {
+            video.set_palette(palette);
+        }
+    }
+
+    /// Shows `title`, usually the name of the game, in the window title
+    pub fn set_window_title(&mut self, title: &str) {
+        self.window_title = String::from(title);
+        self.set_title(title);
+    }
+
+    /// Binds the keys of the actions (up, down, left, right, a, b) to hex keys
+    pub fn bind_action_keys(&mut self, keys: &HashMap<String, u8>) {
+        for (action, key) in keys {
+            self.key_state.bind_action(action, *key);
+        }
+    }
+
+    /// Binds keyboard keys, by SDL key name, to hex keys
+    pub fn bind_keys(&mut self, keys: &BTreeMap<String, u8>) {
+        for (name, key) in keys {
+            self.key_state.keymap.insert(name.clone(), key & 0xF);
+        }
+    }
+
+    /// Debug levels above 0 log every executed instruction on stdout, unless a trace is written
     pub fn set_debug_level(&mut self, debug_level: u32) {
         self.debug_level = debug_level;
+        if debug_level == 0 && self.tracer.as_ref().is_some_and(Tracer::is_stdout) {
+            self.tracer = None;
+        } else if debug_level > 0 && self.tracer.is_none() {
+            self.tracer = Some(Tracer::stdout());
+        }
     }
 
     fn decode_x_index(&mut self, word: u16) -> usize {
@@ -162,21 +749,67 @@ impl CHIP8 {
     /// 00E0 - CLS
     /// Clear the display.
     fn op_00e0(&mut self) {
-        if self.debug_level > 0 {
-            println!("00E0: CLS");
+        self.framebuffer.clear();
+        if let Some(video) = &mut self.video {
+            video.clear();
         }
-        self.display.clear_screen();
     }
 
     /// 00EE - RET
     /// Return from a subroutine.
     /// The interpreter sets the program counter to the address at the top of the stack, then subtracts 1 from the stack pointer.
-    fn op_00ee(&mut self) {
-        if self.debug_level > 0 {
-            println!("00EE: RET"); 
+    fn op_00ee(&mut self) -> Result<(), MachineFault> {
+        if self.sp == 0 {
+            self.raise(MachineFault::StackUnderflow { address: self.instruction_address })?;
+            self.sp = self.stack.len();
         }
         self.sp -= 1;
         self.pc = self.stack[self.sp];
+        Ok(())
+    }
+
+    /// 00Cn - SCD n (SUPER-CHIP)
+    /// Scroll the display down by n pixels.
+    fn op_00cn(&mut self, word: u16) {
+        let n = self.decode_n(word);
+        self.framebuffer.scroll_down(n as usize);
+    }
+
+    /// 00Dn - SCU n (XO-CHIP)
+    /// Scroll the selected planes up by n pixels.
+    fn op_00dn(&mut self, word: u16) {
+        let n = self.decode_n(word);
+        self.framebuffer.scroll_up(n as usize);
+    }
+
+    /// 00FB - SCR (SUPER-CHIP)
+    /// Scroll the display right by 4 pixels.
+    fn op_00fb(&mut self) {
+        self.framebuffer.scroll_right(4);
+    }
+
+    /// 00FC - SCL (SUPER-CHIP)
+    /// Scroll the display left by 4 pixels.
+    fn op_00fc(&mut self) {
+        self.framebuffer.scroll_left(4);
+    }
+
+    /// 00FD - EXIT (SUPER-CHIP)
+    /// Exit the interpreter. The machine keeps executing this instruction, showing the last screen.
+    fn op_00fd(&mut self) {
+        self.pc = self.instruction_address;
+    }
+
+    /// 00FE - LOW (SUPER-CHIP)
+    /// Switch to the 64x32 resolution, clearing the display.
+    fn op_00fe(&mut self) {
+        self.framebuffer.set_hires(false);
+    }
+
+    /// 00FF - HIGH (SUPER-CHIP)
+    /// Switch to the 128x64 resolution, clearing the display.
+    fn op_00ff(&mut self) {
+        self.framebuffer.set_hires(true);
     }
 
     /// 1nnn - JP addr
@@ -185,23 +818,21 @@ impl CHIP8 {
     fn op_1nnn(&mut self, word: u16) {
         let addr = self.decode_nnn(word);
         self.pc = addr;
-        if self.debug_level > 0 {
-            println!("{:04X}: JP 0x{:03X}", word, addr);
-        }
     }
 
     /// 2nnn - CALL addr
     /// Call subroutine at nnn.
     /// The interpreter increments the stack pointer, then puts the current PC on the top of the stack. The PC is then set to nnn.
-    fn op_2nnn(&mut self, word: u16) {
+    fn op_2nnn(&mut self, word: u16) -> Result<(), MachineFault> {
         let addr = self.decode_nnn(word);
+        if self.sp == self.stack.len() {
+            self.raise(MachineFault::StackOverflow { address: self.instruction_address })?;
+            self.sp = 0;
+        }
         self.stack[self.sp] = self.pc;
         self.sp += 1;
         self.pc = addr;
-
-        if self.debug_level > 0 {
-            println!("{:04X}: CALL 0x{:03X}", word, addr);
-        }
+        Ok(())
     }
 
     // 3xnn - SE Vx, byte
@@ -210,12 +841,9 @@ impl CHIP8 {
     fn op_3xnn(&mut self, word: u16) {
         let x_index = self.decode_x_index(word);
         let value = self.decode_nn(word);
-        if self.debug_level > 0 {
-            println!("{:04X}: SE V{:01X}, {:02X}",word, x_index, value);
-        }
         if self.v[x_index] == value {
             //println!("V{:01X}: {:02X} - Increment PC by 2.", x_index, value);
-            self.pc += 2;
+            self.skip();
         } else {
             //println!("V{:01X}: {:02X} - Ignoring.", x_index, self.v[x_index]);
         }
@@ -227,12 +855,9 @@ impl CHIP8 {
     fn op_4xnn(&mut self, word: u16) {
         let x_index = self.decode_x_index(word);
         let value = self.decode_nn(word);
-        if self.debug_level > 0 {
-            println!("{:04X}: SNE V{:01X}, 0x{:02X}", word, x_index, value);
-        }
         if self.v[x_index] != value {
             //println!("Skipping...");
-            self.pc += 2;
+            self.skip();
         }
     }
 
@@ -242,11 +867,43 @@ impl CHIP8 {
     fn op_5xy0(&mut self, word: u16) {
         let x_index = self.decode_x_index(word);
         let y_index = self.decode_y_index(word);
-        if self.debug_level > 0 {
-            println!("{:04X}: SE V{:01X}, V{:01X}", word, x_index, y_index);
-        }
         if self.v[x_index] == self.v[y_index] {
-            self.pc += 2;
+            self.skip();
+        }
+    }
+
+    /// 5xy2 - LD [I], Vx-Vy (XO-CHIP)
+    /// Store registers Vx through Vy in memory starting at location I, in reverse order when x > y.
+    /// I is left unchanged.
+    fn op_5xy2(&mut self, word: u16) -> Result<(), MachineFault> {
+        let registers = self.register_range(word);
+        self.mem_index(self.i as usize + registers.len() - 1)?;
+        for (offset, register) in registers.into_iter().enumerate() {
+            self.store(self.i as usize + offset, self.v[register]);
+        }
+        Ok(())
+    }
+
+    /// 5xy3 - LD Vx-Vy, [I] (XO-CHIP)
+    /// Read registers Vx through Vy from memory starting at location I, in reverse order when x > y.
+    /// I is left unchanged.
+    fn op_5xy3(&mut self, word: u16) -> Result<(), MachineFault> {
+        let registers = self.register_range(word);
+        self.mem_index(self.i as usize + registers.len() - 1)?;
+        for (offset, register) in registers.into_iter().enumerate() {
+            self.v[register] = self.load(self.i as usize + offset);
+        }
+        Ok(())
+    }
+
+    /// Registers x to y of 5xy2 and 5xy3, counting down when x > y
+    fn register_range(&mut self, word: u16) -> Vec<usize> {
+        let x_index = self.decode_x_index(word);
+        let y_index = self.decode_y_index(word);
+        if x_index <= y_index {
+            (x_index..=y_index).collect()
+        } else {
+            (y_index..=x_index).rev().collect()
         }
     }
 
@@ -258,9 +915,6 @@ impl CHIP8 {
         let value = self.decode_nn(word);
         //println!("{}", i);
         self.v[x_index] = value as u8;
-        if self.debug_level > 0 {
-            println!("{:04X}: LD V{:01X}, 0x{:02X}", word, x_index, self.v[x_index]);
-        }
         //println!("V{:01X} => 0x{:04X}", x_index, self.v[x_index]);
     }
 
@@ -272,20 +926,9 @@ impl CHIP8 {
         let value = self.decode_nn(word);
         //println!("V{:01X} => 0x{:04X}", x_index, self.v[x_index]);
 
-        if self.debug_level > 0 {
-            println!("{:04X}: ADD V{:01X}, 0x{:01X}", word, x_index, value);
-        }
-
-
-        let result = self.v[x_index] as u16 + value as u16;
 
-        if result > 255 {
-            self.v[0xf] = 1;
-            self.v[x_index] = (result - 256) as u8;
-        } else {
-            self.v[0xf] = 0;
-            self.v[x_index] = result as u8;
-        }
+        // Unlike 8xy4, there is no carry
+        self.v[x_index] = self.v[x_index].wrapping_add(value);
         //println!("V{:01X} += 0x{:02X}", x_index, value);
         //println!("V{:01X} => 0x{:04X}", x_index, self.v[x_index]);
     }
@@ -297,10 +940,6 @@ impl CHIP8 {
         let x_index = self.decode_x_index(word);
         let y_index = self.decode_y_index(word);
         self.v[x_index] = self.v[y_index];
-
-        if self.debug_level > 0 {
-            println!("{:04X}: LD V{:01X}, V{:01X}", word, x_index, y_index);
-        }
     }
 
     /// 8xy1 - OR Vx, Vy
@@ -312,9 +951,8 @@ impl CHIP8 {
         let x_index = self.decode_x_index(word);
         let y_index = self.decode_y_index(word);
         self.v[x_index] |= self.v[y_index];
-
-        if self.debug_level > 0 {
-            println!("{:04X}: OR V{:01X}, V{:01X}", word, x_index, y_index);
+        if self.quirks.logic {
+            self.v[0xF] = 0;
         }
     }
 
@@ -327,9 +965,8 @@ impl CHIP8 {
         let x_index = self.decode_x_index(word);
         let y_index = self.decode_y_index(word);
         self.v[x_index] &= self.v[y_index];
-
-        if self.debug_level > 0 {
-            println!("{:04X}: AND V{:01X}, V{:01X}", word, x_index, y_index);
+        if self.quirks.logic {
+            self.v[0xF] = 0;
         }
     }
 
@@ -342,9 +979,8 @@ impl CHIP8 {
         let x_index = self.decode_x_index(word);
         let y_index = self.decode_y_index(word);
         self.v[x_index] ^= self.v[y_index];
-
-        if self.debug_level > 0 {
-            println!("{:04X}: XOR V{:01X}, V{:01X}", word, x_index, y_index);
+        if self.quirks.logic {
+            self.v[0xF] = 0;
         }
     }
 
@@ -357,15 +993,9 @@ impl CHIP8 {
         let x_index = self.decode_x_index(word);
         let y_index = self.decode_y_index(word);
         
-        let result = self.v[x_index] as u16 + self.v[y_index] as u16;
-        if result > 255 {
-            self.v[0xf] = 1;
-        }
-        self.v[x_index] = (result & 0x00FF) as u8;
-
-        if self.debug_level > 0 {
-            println!("{:04X}: ADD V{:01X}, V{:01X}", word, x_index, y_index);
-        }
+        let (result, carry) = self.v[x_index].overflowing_add(self.v[y_index]);
+        self.v[x_index] = result;
+        self.v[0xF] = carry as u8;
     }
 
     /// 8xy5 - SUB Vx, Vy
@@ -392,10 +1022,6 @@ impl CHIP8 {
         }
 
         //println!("X => 0x{:01X}", self.v[x_index]);
-
-        if self.debug_level > 0 {
-            println!("{:04X}: SUB V{:01X}, V{:01X}", word, x_index, y_index);
-        }
     }
 
     /// 8xy6 - SHR Vx {, Vy}
@@ -404,39 +1030,28 @@ impl CHIP8 {
     /// divided by 2.
     fn op_8xy6(&mut self, word: u16) {
         let x_index = self.decode_x_index(word);
-
-        if self.v[x_index] & 0b00000001 == 1 {
-            self.v[0xF] = 1; 
-        } else {
-            self.v[0xF] = 0; 
+        if ! self.quirks.shift {
+            let y_index = self.decode_y_index(word);
+            self.v[x_index] = self.v[y_index];
         }
-        
-        self.v[x_index] = self.v[x_index] >> 1;
 
-        if self.debug_level > 0 {
-            println!("{:04X}: SHR V{:01X}", word, self.v[x_index]) ;
-        }
+        // VF is set last, so it holds the flag when it is also Vx
+        let flag = self.v[x_index] & 0b00000001;
+        self.v[x_index] = self.v[x_index] >> 1;
+        self.v[0xF] = flag;
     }
 
     /// 8xy7 - SUBN Vx, Vy
     /// Set Vx = Vy - Vx, set VF = NOT borrow.
-    /// If Vy > Vx, then VF is set to 1, otherwise 0. Then Vx is subtracted from Vy, and the 
+    /// If Vy >= Vx, then VF is set to 1, otherwise 0. Then Vx is subtracted from Vy, and the 
     /// results stored in Vx.
     fn op_8xy7(&mut self, word: u16) {
         let x_index = self.decode_x_index(word);
         let y_index = self.decode_y_index(word);
 
-        if self.v[y_index] > self.v[x_index] {
-            self.v[0xF] = 1;
-        } else {
-            self.v[0xF] = 0;
-        }
-
-        self.v[x_index] = self.v[y_index] - self.v[x_index];
-
-        if self.debug_level > 0 {
-            println!("{:04X}: SUBN V{:01X}, V{:01X}", word, x_index, y_index);
-        }
+        let not_borrow = self.v[y_index] >= self.v[x_index];
+        self.v[x_index] = self.v[y_index].wrapping_sub(self.v[x_index]);
+        self.v[0xF] = not_borrow as u8;
     }
 
     /// 8xyE - SHL Vx {, Vy}
@@ -445,18 +1060,14 @@ impl CHIP8 {
     /// multiplied by 2.
     fn op_8xye(&mut self, word: u16) {
         let x_index = self.decode_x_index(word);
-        
-        if self.v[x_index] & 0b10000000 == 1 { 
-            self.v[0xF] =    1 ;
-        } else { 
-            self.v[0xF] =    0 ;
+        if ! self.quirks.shift {
+            let y_index = self.decode_y_index(word);
+            self.v[x_index] = self.v[y_index];
         }
         
+        let flag = self.v[x_index] >> 7;
         self.v[x_index] = self.v[x_index] << 1;
-
-        if self.debug_level > 0 {
-            println!("{:04X}: SHL V{:01X}", word, self.v[x_index]) ;
-        }
+        self.v[0xF] = flag;
     }
 
     /// 9xy0 - SNE Vx, Vy
@@ -467,11 +1078,7 @@ impl CHIP8 {
         let x_index = self.decode_x_index(word);
         let y_index = self.decode_y_index(word);
         if self.v[x_index] != self.v[y_index] {
-            self.pc += 2;
-        }
-
-        if self.debug_level > 0 {
-            println!("{:04X}: SNE V{:01X}, V{:01X}", word, x_index, y_index);
+            self.skip();
         }
     }
 
@@ -481,9 +1088,6 @@ impl CHIP8 {
     fn op_annn(&mut self, word: u16) {
         let value = self.decode_nnn(word);
         self.i = value;
-        if self.debug_level > 0 {
-            println!("{:04X}: LD I, 0x{:03X}", word, value);
-        }
     }
 
     /// Bnnn - JP V0, addr
@@ -491,11 +1095,9 @@ impl CHIP8 {
     /// The program counter is set to nnn plus the value of V0.
     fn op_bnnn(&mut self, word: u16) {
         let addr = self.decode_nnn(word);
-        self.pc = self.v[0] as u16 + addr;
-
-        if self.debug_level > 0 {
-            println!("{:04X}: JP V0, 0x{:03X}", word, addr);
-        }
+        // With the jump quirk, Bxnn jumps to xnn + Vx
+        let x_index = if self.quirks.jump { self.decode_x_index(word) } else { 0 };
+        self.pc = self.v[x_index] as u16 + addr;
     }
 
     /// Cxnn - RND Vx, byte
@@ -509,11 +1111,6 @@ impl CHIP8 {
         let r = rng.gen_range(0..0xff) as u8;
         let val = r & value;
         self.v[x_index as usize] = val;
-
-        if self.debug_level > 0 {
-            //println!("Set random number 0x{:02X} to V{:01X}", val, x_index);
-            println!("{:04X}: RND V{:01X}, 0x{:02X}", word, x_index, value);
-        }
     }
 
     /// Dxyn - DRW Vx, Vy, nibble
@@ -525,24 +1122,41 @@ impl CHIP8 {
     /// display, it wraps around to the opposite side of the screen. See instruction 8xy3 for more 
     /// information on XOR, and section 2.4, Display, for more information on the Chip-8 screen and
     /// sprites.
-    fn op_dxyn(&mut self, word: u16) {
+    fn op_dxyn(&mut self, word: u16) -> Result<(), MachineFault> {
                     
         //println!("DXYN: Draw");   
 
         let x_index = self.decode_x_index(word);
         let y_index = self.decode_y_index(word);
         let n = self.decode_n(word);
-        let x = self.v[x_index] & 63;
-        let y = self.v[y_index] & 31;
+        let x = self.v[x_index];
+        let y = self.v[y_index];
         //println!("({}, {})", x, y);
         //println!("n {}", n);
-
-        if self.debug_level > 0 {
-            println!("{:04X}: DRW 0x{:01X}, 0x{:01X}, 0x{:01X}", word, x_index, y_index, n);
-        }
         
         
-        self.display.buffer_graphics(&mut self.ram, y, x, n,  self.i,);
+        // Dxy0 draws a 16x16 sprite of 32 bytes on SUPER-CHIP and XO-CHIP, and XO-CHIP draws
+        // a sprite on each selected plane
+        let large = n == 0 && self.is_superchip();
+        let len = self.framebuffer.sprite_bytes(if large { 32 } else { n as usize });
+        if len > 0 {
+            self.mem_index(self.i as usize + len - 1)?;
+        }
+        
+        if let Some(coverage) = &mut self.coverage {
+            coverage.read(self.i, len);
+        }
+        let collision = if large {
+            self.framebuffer.draw_large_sprite(&self.ram, x, y, self.i, self.quirks.wrap)
+        } else {
+            self.framebuffer.draw_sprite(&self.ram, x, y, n, self.i, self.quirks.wrap)
+        };
+        self.v[0xF] = collision as u8;
+
+        if self.quirks.vblank {
+            self.vblank_wait = true;
+        }
+        Ok(())
     }
 
     // Ex9E - SKP Vx
@@ -550,13 +1164,9 @@ impl CHIP8 {
     // Checks the keyboard, and if the key corresponding to the value of Vx is currently in the 
     // down position, PC is increased by 2.
     fn op_ex9e(&mut self, word: u16) {
-        let x_index = self.decode_x_index(word) as u8;
-        if self.key_state.check_key(x_index) {
-            self.pc += 2;
-        }
-
-        if self.debug_level > 0 {
-            println!("{:04X}: SKP V{:01X}", word, x_index);
+        let x_index = self.decode_x_index(word);
+        if self.key_state.check_key(self.v[x_index]) {
+            self.skip();
         }
     }
 
@@ -567,11 +1177,7 @@ impl CHIP8 {
     fn op_exa1(&mut self, word: u16) {
         let x_index = self.decode_x_index(word);
         if ! self.key_state.check_key(self.v[x_index]) {
-            self.pc += 2;
-        }
-
-        if self.debug_level > 0 {
-            println!("{:04X}: SKNP V{:01X}", word, x_index);
+            self.skip();
         }
     }
 
@@ -581,10 +1187,6 @@ impl CHIP8 {
     fn op_fx07(&mut self, word: u16) {
         let x_index = usize::from((word & 0x0F00) >> 8);
         self.v[x_index] = self.dt;
-
-        if self.debug_level > 0 {
-            println!("{:04X}: LD V{:01X}, DT", word, x_index);
-        }
     }
 
     /// Fx0A - LD Vx, K
@@ -602,14 +1204,8 @@ impl CHIP8 {
         // Otherwise decrements the program counter by 2
         if key != 255 {
             self.v[x_index] = key;
-            self.debug_fx0a = 2;
         } else {
-            self.pc -= 2;
-        }
-
-        if self.debug_level > 0 && self.debug_fx0a > 0 {
-            println!("{:04X}: LD V{:01X}, K", word, x_index);
-            self.debug_fx0a -= 1;
+            self.pc = self.pc.wrapping_sub(2);
         }
     }
 
@@ -619,10 +1215,6 @@ impl CHIP8 {
     fn op_fx15(&mut self, word: u16) {
         let x_index = self.decode_x_index(word);
         self.dt = self.v[x_index];
-
-        if self.debug_level > 0 {
-            println!("{:04X}: LD DT, V{:01X}", word, x_index);
-        }
     }
 
     /// Fx18 - LD ST, Vx
@@ -631,10 +1223,6 @@ impl CHIP8 {
     fn op_fx18(&mut self, word: u16) {
         let x_index = self.decode_x_index(word);
         self.st = self.v[x_index];
-
-        if self.debug_level > 0 {
-            println!("{:04X}: LD ST, V{:01X}", word, x_index);
-        }
     }
 
     /// Fx1E - ADD I, Vx
@@ -642,17 +1230,8 @@ impl CHIP8 {
     /// The values of I and Vx are added, and the results are stored in I.
     fn op_fx1e(&mut self, word: u16) {
         let x_index = self.decode_x_index(word);
-        self.i += self.v[x_index] as u16;
-        
-        // AMIGA interpreter behavior sets VF to 1 if I overflows from 0x0FFF to above 0x1000.
-        if self.i > 0x0FFF {
-            self.v[0xF] = 1;
-        }
-        self.i = self.i % 0x1000;
-
-        if self.debug_level > 0 {
-            println!("{:04X}: ADD I, V{:01X}", word, x_index);
-        }
+        // I is a 16-bit register and VF is left alone. Addresses past memory fault when used.
+        self.i = self.i.wrapping_add(self.v[x_index] as u16);
     }
 
     /// Fx29 - LD F, Vx
@@ -665,87 +1244,186 @@ impl CHIP8 {
         //println!("F{:01X}29", x_index);
         //println!("V{:01X}: 0x{:02X}", x_index, self.v[x_index]);
         //println!("Font base addr: 0x{:04X}", FONT_ADDRESS);
-        let font_addr = (5 * self.v[x_index] as usize + FONT_ADDRESS) as u16;
+        // Only the low nibble selects a digit, as the font has 16 of them
+        let font_addr = (5 * (self.v[x_index] & 0xF) as usize + FONT_ADDRESS) as u16;
         //println!("Font addr: 0x{:04X}", font_addr);
         //panic!("");
         self.i = font_addr;
+        
+    }
 
-        if self.debug_level > 0 {
-            println!("{:04X}: LD F, V{:01X}", word, x_index);
+    /// F000 nnnn - LD I, nnnn (XO-CHIP)
+    /// Set I = the 16-bit address in the word following the instruction, which is skipped.
+    fn op_f000(&mut self) -> Result<(), MachineFault> {
+        self.i = self.fetch_word()?;
+        Ok(())
+    }
+
+    /// Fn01 - PLANE n (XO-CHIP)
+    /// Select the planes drawn, cleared and scrolled.
+    fn op_fn01(&mut self, word: u16) {
+        let n = self.decode_x_index(word);
+        self.framebuffer.select_planes(n as u8);
+    }
+
+    /// F002 - AUDIO (XO-CHIP)
+    /// Load the 16-byte sample pattern from memory starting at location I.
+    fn op_f002(&mut self) -> Result<(), MachineFault> {
+        self.mem_index(self.i as usize + 15)?;
+        let mut pattern = [0; 16];
+        for (offset, byte) in pattern.iter_mut().enumerate() {
+            *byte = self.load(self.i as usize + offset);
         }
-        
+        self.audio_pattern = Some(pattern);
+        self.update_sample_pattern();
+        Ok(())
+    }
+
+    /// Fx3A - PITCH Vx (XO-CHIP)
+    /// Set the playback rate of the sample pattern.
+    fn op_fx3a(&mut self, word: u16) {
+        let x_index = self.decode_x_index(word);
+        self.pitch = self.v[x_index];
+        self.update_sample_pattern();
+    }
+
+    /// The sample pattern the beep plays, if XO-CHIP loaded one
+    pub fn sample_pattern(&self) -> Option<SamplePattern> {
+        self.audio_pattern.map(|bits| SamplePattern { bits: bits, pitch: self.pitch })
+    }
+
+    fn update_sample_pattern(&mut self) {
+        let pattern = self.sample_pattern();
+        self.audio.set_pattern(pattern);
+    }
+
+    /// Fx30 - LD HF, Vx (SUPER-CHIP)
+    /// Set I = location of the 8x10 sprite for digit Vx.
+    fn op_fx30(&mut self, word: u16) {
+        let x_index = self.decode_x_index(word);
+        self.i = (10 * (self.v[x_index] & 0xF) as usize + BIG_FONT_ADDRESS) as u16;
     }
 
     /// Fx33 - LD B, Vx
     /// Store BCD representation of Vx in memory locations I, I+1, and I+2.
     /// The interpreter takes the decimal value of Vx, and places the hundreds digit in memory at
     /// location in I, the tens digit at location I+1, and the ones digit at location I+2.
-    fn op_fx33(&mut self, word: u16) {
+    fn op_fx33(&mut self, word: u16) -> Result<(), MachineFault> {
         let x_index = self.decode_x_index(word);
-        self.ram[self.i as usize] = self.v[x_index] / 100;
-        self.ram[self.i as usize + 1] = self.v[x_index] % 100 / 10;
-        self.ram[self.i as usize + 2] = self.v[x_index] % 10;
-
-        if self.debug_level > 0 {
-            println!("{:04X}: LD B, V{:01X}", word, x_index);
-        }
+        self.mem_index(self.i as usize + 2)?;
+        self.store(self.i as usize, self.v[x_index] / 100);
+        self.store(self.i as usize + 1, self.v[x_index] % 100 / 10);
+        self.store(self.i as usize + 2, self.v[x_index] % 10);
+        Ok(())
     }
 
     /// Fx55 - LD [I], Vx
     /// Store registers V0 through Vx in memory starting at location I.
     /// The interpreter copies the values of registers V0 through Vx into memory, starting at the
     /// address in I.
-    fn op_fx55(&mut self, word: u16) {
+    fn op_fx55(&mut self, word: u16) -> Result<(), MachineFault> {
         let max_x_index = self.decode_x_index(word);
+        self.mem_index(self.i as usize + max_x_index)?;
         for i in 0..=max_x_index {
-            self.ram[self.i as usize + i] = self.v[i];
-        }
-
-        if self.debug_level > 0 {
-            println!("{:04X}: LD [I], V{:01X}", word, max_x_index);
+            self.store(self.i as usize + i, self.v[i]);
         }
+        self.increment_i_after_memory_op(max_x_index);
+        Ok(())
     }
 
     /// Fx65 - LD Vx, [I]
     /// Read registers V0 through Vx from memory starting at location I.
     /// The interpreter reads values from memory starting at location I into registers V0 through Vx.
-    fn op_fx65(&mut self, word: u16) {
+    fn op_fx65(&mut self, word: u16) -> Result<(), MachineFault> {
         let max_x_index = self.decode_x_index(word);
+        self.mem_index(self.i as usize + max_x_index)?;
         for i in 0..=max_x_index {
-            self.v[i] = self.ram[self.i as usize + i];
+            self.v[i] = self.load(self.i as usize + i);
         }
+        self.increment_i_after_memory_op(max_x_index);
+        Ok(())
+    }
+
+    /// Fx75 - LD R, Vx (SUPER-CHIP)
+    /// Store registers V0 through Vx in the flag registers.
+    fn op_fx75(&mut self, word: u16) {
+        let max_x_index = self.decode_x_index(word);
+        self.flags[..=max_x_index].copy_from_slice(&self.v[..=max_x_index]);
+    }
+
+    /// Fx85 - LD Vx, R (SUPER-CHIP)
+    /// Read registers V0 through Vx from the flag registers.
+    fn op_fx85(&mut self, word: u16) {
+        let max_x_index = self.decode_x_index(word);
+        self.v[..=max_x_index].copy_from_slice(&self.flags[..=max_x_index]);
+    }
 
-        if self.debug_level > 0 {
-            println!("{:04X}: LD V{:01X}, [I]", word, max_x_index);
+    /// Fx55 and Fx65 leave I past the last register accessed, unless quirks say otherwise.
+    fn increment_i_after_memory_op(&mut self, max_x_index: usize) {
+        if self.quirks.memory_leave_i_unchanged {
+            return;
         }
+        let increment = if self.quirks.memory_increment_by_x { max_x_index } else { max_x_index + 1 };
+        self.i = self.i.wrapping_add(increment as u16);
     }
 
     /// Fetch the next byte from memory and increments pc by 1
-    fn fetch_byte(&mut self) -> u8{
-        let byte = self.ram[self.pc as usize];
-        self.pc += 1;
-        self.pc %= MAX_MEM as u16; // pc cannot got beyond max memory size
-        byte
+    fn fetch_byte(&mut self) -> Result<u8, MachineFault> {
+        let index = self.mem_index(self.pc as usize)?;
+        // pc can end up past memory, where fetching faults unless the fault policy wraps it around
+        self.pc = (index + 1) as u16;
+        let byte = self.ram[index];
+        Ok(byte)
     }
 
     /// Fetch the next byte from memory and increments pc by 2
-    fn fetch_word(&mut self) -> u16 {      
-        let mut word = (self.fetch_byte() as u16) << 8;
-        word |= self.fetch_byte() as u16;
-        word
+    fn fetch_word(&mut self) -> Result<u16, MachineFault> {
+        let mut word = (self.fetch_byte()? as u16) << 8;
+        word |= self.fetch_byte()? as u16;
+        Ok(word)
     }
 
-    /// Loads a program into memory at address START_VECTOR
-    pub fn load_program(&mut self, program: [u8;MAX_MEM - PROGRAM_ADDRESS], size: usize) {
-        for i in PROGRAM_ADDRESS..(size + PROGRAM_ADDRESS) {
-            self.ram[i] = program[i - PROGRAM_ADDRESS];
-        }
+    /// Loads a program into memory at address PROGRAM_ADDRESS
+    pub fn load_program(&mut self, program: &[u8]) -> Result<(), RomError> {
+        rom::validate(program, self.platform)?;
+        self.ram[PROGRAM_ADDRESS..PROGRAM_ADDRESS + program.len()].copy_from_slice(program);
+        Ok(())
     }
 
     /// Decode and execute one instruction.
-    fn tick(&mut self) {
+    /// Returns the fault that halts the machine, if any. The program counter is then left on the
+    /// faulting instruction.
+    fn tick(&mut self) -> Result<(), MachineFault> {
+        self.instruction_address = self.pc;
+        if self.tracer.is_some() {
+            self.trace();
+        }
+        if self.hooks.as_ref().is_some_and(|hooks| hooks.watches_address(self.pc)) {
+            self.run_hooks(HookEvent::Execute(self.pc));
+        }
+        let opcode = self.opcode_at(self.pc);
+
+        match self.execute() {
+            Ok(()) => {
+                self.instruction_count += 1;
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.record(self.instruction_address, opcode);
+                }
+                if let Some(coverage) = &mut self.coverage {
+                    coverage.executed(self.instruction_address);
+                }
+                Ok(())
+            }
+            Err(fault) => {
+                self.pc = self.instruction_address;
+                Err(fault)
+            }
+        }
+    }
+
+    fn execute(&mut self) -> Result<(), MachineFault> {
             //println!("PC: 0x{:04X}", self.pc);
-            let word = self.fetch_word();
+            let word = self.fetch_word()?;
             let ins_category = word & 0xF000;
             let nn = self.decode_nn(word);
             let n = self.decode_n(word);
@@ -753,13 +1431,25 @@ impl CHIP8 {
             // Match the instruction category
             match ins_category {
                 0x0 => {
-                    match nn {
+                    match word {
                         // 00E0 -> Clear the screen (set all pixels off)
                         0x00E0 => self.op_00e0(),
 
                         // 00EE -> Return from subroutine
-                        0x00EE => self.op_00ee(),
-                        _ => {}
+                        0x00EE => self.op_00ee()?,
+
+                        // SUPER-CHIP: 00Cn scroll down, 00FB/00FC scroll right/left, 00FD exit,
+                        // 00FE/00FF low/high resolution
+                        0x00C0..=0x00CF if self.is_superchip() => self.op_00cn(word),
+                        0x00FB if self.is_superchip() => self.op_00fb(),
+                        0x00FC if self.is_superchip() => self.op_00fc(),
+                        0x00FD if self.is_superchip() => self.op_00fd(),
+                        0x00FE if self.is_superchip() => self.op_00fe(),
+                        0x00FF if self.is_superchip() => self.op_00ff(),
+
+                        // XO-CHIP: 00Dn scroll up
+                        0x00D0..=0x00DF if self.platform == Platform::XoChip => self.op_00dn(word),
+                        _ => self.illegal_opcode(word)?
                     }
                     
                 }
@@ -767,7 +1457,7 @@ impl CHIP8 {
                 0x1000 => self.op_1nnn(word),
 
                 // 2NNN -> Call subroutine at address NNN
-                0x2000 => self.op_2nnn(word),
+                0x2000 => self.op_2nnn(word)?,
 
                 // 3XNN -> Skip next instruction if V[X] == KK
                 0x3000 => self.op_3xnn(word),
@@ -776,7 +1466,11 @@ impl CHIP8 {
                 0x4000 => self.op_4xnn(word),
 
                 // 5XY0 -> Skip next instruction if V[X] == V[Y]
-                0x5000 => self.op_5xy0(word),
+                0x5000 if n == 0 => self.op_5xy0(word),
+
+                // 5XY2, 5XY3 (XO-CHIP) -> Store Vx to Vy in memory at I, and read them back
+                0x5000 if n == 2 && self.platform == Platform::XoChip => self.op_5xy2(word)?,
+                0x5000 if n == 3 && self.platform == Platform::XoChip => self.op_5xy3(word)?,
 
                 // 6XNN -> Set value of register V[X] to NN
                 0x6000 => self.op_6xnn(word),
@@ -821,11 +1515,11 @@ impl CHIP8 {
                         // 8XYE -> Shift V[X] left
                         0xe => self.op_8xye(word),
 
-                        _ => {}
+                        _ => self.illegal_opcode(word)?
                     }
                 }
                 // 9XY0 -> Skip next instruction if V[X] != V[Y]
-                0x9000 => self.op_9xy0(word),
+                0x9000 if n == 0 => self.op_9xy0(word),
 
                 // ANNN -> Set the value of index register I to NNN
                 0xA000 => self.op_annn(word),
@@ -839,7 +1533,7 @@ impl CHIP8 {
 
                 // DXYN
                 // Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
-                0xD000 => self.op_dxyn(word),
+                0xD000 => self.op_dxyn(word)?,
 
                 // EXNN
                 0xE000 => {
@@ -850,9 +1544,11 @@ impl CHIP8 {
                         // EXA1 -> Skip next instruction if key with the value of Vx is not pressed.
                         0x00A1 => self.op_exa1(word),
                         
-                        _ => {}
+                        _ => self.illegal_opcode(word)?
                     }
                 }
+                // F000 NNNN (XO-CHIP) -> Set I to the 16-bit address NNNN
+                0xF000 if word == 0xF000 && self.platform == Platform::XoChip => self.op_f000()?,
                 0xF000 => {
                     match nn {
                         // 0xFX07 -> V[X] = DT - The value of DT is placed into Vx.
@@ -874,101 +1570,297 @@ impl CHIP8 {
                         // Set I = location of sprite for digit Vx.
                         0x0029 => self.op_fx29(word),
 
+                        // 0xFN01 (XO-CHIP)
+                        // Select the planes drawn, cleared and scrolled.
+                        0x0001 if self.platform == Platform::XoChip => self.op_fn01(word),
+
+                        // 0xF002 (XO-CHIP)
+                        // Load the sample pattern from memory at I.
+                        0x0002 if word == 0xF002 && self.platform == Platform::XoChip => self.op_f002()?,
+
+                        // 0xFX3A (XO-CHIP)
+                        // Set the pitch of the sample pattern to Vx.
+                        0x003A if self.platform == Platform::XoChip => self.op_fx3a(word),
+
+                        // 0xFX30 (SUPER-CHIP)
+                        // Set I = location of the large sprite for digit Vx.
+                        0x0030 if self.is_superchip() => self.op_fx30(word),
+
                         // 0xFX33
                         // Store BCD representation of Vx in memory locations I, I+1, and I+2.
-                        0x0033 => self.op_fx33(word),
+                        0x0033 => self.op_fx33(word)?,
 
                         // 0xFX55
                         // Store registers V0 through Vx in memory starting at location I.
-                        0x0055 => self.op_fx55(word),
+                        0x0055 => self.op_fx55(word)?,
 
                         // 0xFX65
                         // Read registers V0 through Vx from memory starting at location I.
-                        0x0065 => self.op_fx65(word),
-                        _ => {}
+                        0x0065 => self.op_fx65(word)?,
+
+                        // 0xFX75 and 0xFX85 (SUPER-CHIP)
+                        // Store V0 through Vx in the flag registers, and read them back.
+                        0x0075 if self.is_superchip() => self.op_fx75(word),
+                        0x0085 if self.is_superchip() => self.op_fx85(word),
+                        _ => self.illegal_opcode(word)?
                     }
                 }
-                _ => {}
+                _ => self.illegal_opcode(word)?
+            }
+
+            Ok(())
+    }
+
+    /// Executes one instruction. The frame ends, updating the timers, after `ipf` instructions
+    /// or on vblank, as in `frame`.
+    pub fn step(&mut self) -> Result<(), MachineFault> {
+        self.tick()?;
+        self.frame_ticks += 1;
+        if self.frame_ticks >= self.ipf || self.vblank_wait {
+            self.end_frame();
+        }
+        Ok(())
+    }
+
+    /// Executes instructions with `step` until the frame ends
+    pub fn step_frame(&mut self) -> Result<(), MachineFault> {
+        let frame = self.frame_count;
+        while self.frame_count == frame {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Pauses the emulation and executes one instruction with `step`, so the timers are only
+    /// updated once `ipf` instructions were stepped. The display is rendered after every step.
+    pub fn single_step(&mut self) {
+        if ! self.paused {
+            self.pause();
+        }
+        let frame = self.frame_count;
+        if let Err(fault) = self.step() {
+            self.halt(fault);
+        }
+        if self.frame_count == frame {
+            self.render();
+        }
+    }
+
+    /// Instructions executed since the emulator started
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    /// Frames ended since the emulator started
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Makes `run` return after `frames` frames, counting the frames spent paused
+    pub fn set_frame_limit(&mut self, frames: Option<u64>) {
+        self.frame_limit = frames;
+    }
+
+    fn frame_limit_reached(&self, frames: u64) -> bool {
+        self.frame_limit.is_some_and(|limit| frames >= limit)
+    }
+
+    /// Run one frame: execute `ipf` instructions, then update the sound timer and render.
+    fn frame(&mut self) {
+        for _ in 0..self.ipf {
+            if let Err(fault) = self.tick() {
+                self.halt(fault);
+                break;
+            }
+            if self.breakpoints.contains(&self.pc) {
+                println!("Breakpoint at 0x{:03X}", self.pc);
+                self.pause();
+                break;
+            }
+            if self.vblank_wait {
+                break;
+            }
+        }
+        self.end_frame();
+    }
+
+    /// Update the timers and render the display.
+    fn end_frame(&mut self) {
+            self.frame_ticks = 0;
+            self.vblank_wait = false;
+            self.frame_count += 1;
+
+            if self.dt > 0 {
+                self.dt -= 1;
             }
 
-           
             if self.st > 0 {
                 self.st -= 1;
-                if ! self.synth.is_playing {
-                    self.synth.play();
-                }
-            }
-            if self.st == 0 && self.synth.is_playing {
-                self.synth.pause();
             }
 
-            // Render
-            self.display.render();
+            self.apply_cheats();
+            self.run_hooks(HookEvent::Frame);
 
-            if self.debug_level > 0 {
-                self.display.render_debug(self.pc, self.v, self.dt, self.st, self.sp, self.i, self.stack);
+            self.audio.frame(self.st > 0);
+
+            self.render();
+    }
+
+    /// Render the display, and the debug panel when debugging.
+    fn render(&mut self) {
+            if let Some(video) = &mut self.video {
+                video.render(&self.framebuffer);
+
+                if self.debug_level > 0 {
+                    video.render_debug(&DebugView {
+                        cycle: self.instruction_count,
+                        pc: self.pc,
+                        i: self.i,
+                        v: self.v,
+                        sp: self.sp,
+                        stack: self.stack,
+                        dt: self.dt,
+                        st: self.st,
+                        ram: &self.ram,
+                        keys: self.key_state.keys,
+                        breakpoints: &self.breakpoints,
+                        fault: self.fault,
+                    });
+                }
             }
     }
 
     
     /// Run the emulation
-    pub fn run(&mut self, fps: u32)  -> Result<(), String> {
+    pub fn run(&mut self, fps: u32, fast_forward_factor: u32)  -> Result<(), String> {
+
+        let mut input = self.input.take().ok_or("No input to run with")?;
+        let vsync = self.video.as_ref().is_some_and(|video| video.vsync());
+        let mut pacer = FramePacer::new(fps, vsync, fast_forward_factor);
 
-        let mut event_pump = self.display.sdl_context.event_pump()?;
+        // Instructions per second measurement
+        let mut ips = 0;
+        let mut ips_start = Instant::now();
+        let mut ips_count = self.instruction_count;
 
+        // Frames run or spent paused, for the frame limit
+        let mut frames = 0;
 
         'running: loop {
 
+            if self.frame_limit_reached(frames) {
+                break 'running;
+            }
 
+            let mut title_changed = false;
 
             // Handle events
-            for event in event_pump.poll_iter() {
+            for event in input.poll() {
                 match event {
-                    Event::Quit {..} => break 'running,
-                    Event::KeyDown { keycode: Some(keycode), .. } => {
-                        match keycode {
-                            Keycode::Escape => break 'running,
-                            Keycode::F5 => {
-                                println!("Reset emulator.");
-                                self.reset();
-                                //self.resume();
-                            },
-                            Keycode::F6 => {
-                                if ! self.paused {
-                                    self.pause();
-                                }
-                                self.tick();
-                            },
-                            Keycode::Space => {
-                                if self.paused {
-                                    self.resume();
-                                } else {
-                                    self.pause();
-                                }
-                            }
-                            _ => self.key_state.set_key_state(keycode, true)
+                    InputEvent::Quit => break 'running,
+                    InputEvent::Reset => {
+                        println!("Reset emulator.");
+                        self.reset();
+                        //self.resume();
+                    },
+                    InputEvent::Step => self.single_step(),
+                    InputEvent::SlowDown => {
+                        pacer.slow_down();
+                        println!("Speed: {}x", pacer.multiplier());
+                        title_changed = true;
+                    },
+                    InputEvent::SpeedUp => {
+                        pacer.speed_up();
+                        println!("Speed: {}x", pacer.multiplier());
+                        title_changed = true;
+                    },
+                    InputEvent::NormalSpeed => {
+                        pacer.normal_speed();
+                        println!("Speed: {}x", pacer.multiplier());
+                        title_changed = true;
+                    },
+                    InputEvent::FastForward(enabled) => {
+                        pacer.set_fast_forward(enabled);
+                        title_changed = true;
+                    },
+                    InputEvent::MoreInstructions => {
+                        self.set_instructions_per_frame(self.ipf + 1);
+                        title_changed = true;
+                    },
+                    InputEvent::FewerInstructions => {
+                        self.set_instructions_per_frame(self.ipf - 1);
+                        title_changed = true;
+                    },
+                    InputEvent::TogglePause => {
+                        if self.paused {
+                            self.resume();
+                        } else {
+                            self.pause();
                         }
                     }
-                    Event::KeyUp { keycode: Some(keycode), .. } => {
-                        self.key_state.set_key_state(keycode, false);
+                    InputEvent::Key { name, pressed } => {
+                        let before = self.key_state.keys;
+                        self.key_state.set_key_state(&name, pressed);
+                        self.key_events(before);
+                    }
+                    InputEvent::DebugClick { window, x, y } => {
+                        let clicked = self.video.as_ref().and_then(|video| video.debug_click(window, x, y));
+                        if let Some(address) = clicked {
+                            self.toggle_breakpoint(address);
+                            self.render();
+                        }
+                    }
+                    InputEvent::DebugScroll { window, lines } => {
+                        if let Some(video) = &mut self.video {
+                            video.debug_scroll(window, lines);
+                        }
+                        self.render();
                     }
-                    _ => {}
                 }
             }
 
+            if let Some(mut gdb) = self.gdb.take() {
+                gdb.poll(self);
+                self.gdb = Some(gdb);
+            }
+
+            self.run_cheat_commands();
+
             if self.paused {
+                frames += 1;
                 ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / fps));
+                pacer.reset();
+                ips_start = Instant::now();
+                ips_count = self.instruction_count;
                 continue 'running;
             }
     
-            // Decode and execute one instruction
-            self.tick();
+            // Run as many frames as the elapsed time calls for
+            for _ in 0..pacer.frames_due() {
+                if self.frame_limit_reached(frames) {
+                    break;
+                }
+                self.frame();
+                frames += 1;
+            }
+
+            let elapsed = ips_start.elapsed();
+            if elapsed >= Duration::from_secs(1) {
+                ips = ((self.instruction_count - ips_count) as f64 / elapsed.as_secs_f64()) as u64;
+                ips_start = Instant::now();
+                ips_count = self.instruction_count;
+                title_changed = true;
+            }
+
+            if title_changed && ! self.paused {
+                self.update_title(&pacer, ips);
+            }
     
             // Time management!
-            ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / fps));
+            pacer.wait();
         }
 
-        
+        self.input = Some(input);
 
         Ok(())
     }
@@ -977,136 +1869,73 @@ impl CHIP8 {
 
 
 
+/// Keyboard keys bound to each hex key by default, by SDL key name
+///
+///   1 2 3 C       1 2 3 4
+///   4 5 6 D  <=   Q W E R
+///   7 8 9 E       A S D F
+///   A 0 B F       Z X C V
+const DEFAULT_KEYMAP: [(&str, u8); 16] = [
+    ("1", 0x1), ("2", 0x2), ("3", 0x3), ("4", 0xC),
+    ("Q", 0x4), ("W", 0x5), ("E", 0x6), ("R", 0xD),
+    ("A", 0x7), ("S", 0x8), ("D", 0x9), ("F", 0xE),
+    ("Z", 0xA), ("X", 0x0), ("C", 0xB), ("V", 0xF),
+];
+
+/// Keyboard keys of the actions ROM metadata can bind to hex keys
+const ACTION_KEYS: [(&str, &str); 6] = [
+    ("up", "Up"),
+    ("down", "Down"),
+    ("left", "Left"),
+    ("right", "Right"),
+    ("a", "Return"),
+    ("b", "Right Shift"),
+];
+
 struct KeyState {
-    pub key_0: bool,
-    pub key_1: bool,
-    pub key_2: bool,
-    pub key_3: bool,
-    pub key_4: bool,
-    pub key_5: bool,
-    pub key_6: bool,
-    pub key_7: bool,
-    pub key_8: bool,
-    pub key_9: bool,
-    pub key_a: bool,
-    pub key_b: bool,
-    pub key_c: bool,
-    pub key_d: bool,
-    pub key_e: bool,
-    pub key_f: bool,
+    keys: [bool; 16],
+
+    /// Hex key bound to each keyboard key, by SDL key name
+    keymap: HashMap<String, u8>,
 }
 
 impl KeyState {
     fn new() -> Self {
         Self {
-            key_0: false,
-            key_1: false,
-            key_2: false,
-            key_3: false,
-            key_4: false,
-            key_5: false,
-            key_6: false,
-            key_7: false,
-            key_8: false,
-            key_9: false,
-            key_a: false,
-            key_b: false,
-            key_c: false,
-            key_d: false,
-            key_e: false,
-            key_f: false,
+            keys: [false; 16],
+            keymap: DEFAULT_KEYMAP.iter().map(|(name, key)| (String::from(*name), *key)).collect(),
         }
     }
 
+    /// Binds the keyboard key of an action to a hex key
+    fn bind_action(&mut self, action: &str, key: u8) {
+        if let Some((_, name)) = ACTION_KEYS.iter().find(|(a, _)| *a == action) {
+            self.keymap.insert(String::from(*name), key & 0xF);
+        }
+    }
 
     /// Returns the value of the key currently pressed. If no key is pressed, return 0xFF
     fn get_pressed_key(&self) -> u8 {
-        if self.key_0 {
-            0x0
-        } else if self.key_1 {
-            0x1 
-        } else if self.key_2 {
-            0x2
-        } else if self.key_3 {
-            0x3
-        } else if self.key_4 {
-            0x4
-        } else if self.key_5 {
-            0x5
-        } else if self.key_6 {
-            0x6
-        } else if self.key_7 {
-            0x7
-        } else if self.key_8 {
-            0x8
-        } else if self.key_9 {
-            0x9
-        } else if self.key_a {
-            0xA
-        } else if self.key_b {
-            0xB
-        } else if self.key_c {
-            0xC
-        } else if self.key_d {
-            0xD
-        } else if self.key_e {
-            0xE
-        } else if self.key_f {
-            0xF
-        } else {
-            0xFF
+        match self.keys.iter().position(|pressed| *pressed) {
+            Some(key) => key as u8,
+            None => 0xFF,
         }
     }
 
     // Checks the state of key
     // Returns true if the key is currently pressed, false otherwise
     fn check_key(&self, key: u8) -> bool {
-        match key {
-            0   => self.key_0,
-            1   => self.key_1, 
-            2   => self.key_2,
-            3   => self.key_3,
-            4   => self.key_4,
-            5   => self.key_5,
-            6   => self.key_6,
-            7   => self.key_7,
-            8   => self.key_8,
-            9   => self.key_9,
-            0xA => self.key_a,
-            0xB => self.key_b,
-            0xC => self.key_c,
-            0xD => self.key_d,
-            0xE => self.key_e,
-            0xF => self.key_f,
-            _   => false
+        match self.keys.get(key as usize) {
+            Some(pressed) => *pressed,
+            None => false,
         }
     }
 
     // Sets the state of key
     // True, the key is currently pressed, false otherwise
-    fn set_key_state(&mut self, key_code: Keycode, state: bool) {
-        match key_code {
-            Keycode::Num1 => self.key_1 = state,
-            Keycode::Num2 => self.key_2 = state,
-            Keycode::Num3 => self.key_3 = state,
-            Keycode::Num4 => self.key_c = state,
-            Keycode::Q    => self.key_4 = state,
-            Keycode::W    => self.key_5 = state,
-            Keycode::E    => self.key_6 = state,
-            Keycode::R    => self.key_d = state,
-            Keycode::A    => self.key_7 = state,
-            Keycode::S    => self.key_8 = state,
-            Keycode::D    => self.key_9 = state,
-            Keycode::F    => self.key_e = state,
-            Keycode::Z    => self.key_a = state,
-            Keycode::X    => self.key_0 = state,
-            Keycode::C    => self.key_b = state,
-            Keycode::V    => self.key_f = state,
-            _ => {}
+    fn set_key_state(&mut self, name: &str, state: bool) {
+        if let Some(key) = self.keymap.get(name) {
+            self.keys[*key as usize] = state;
         }
     }
 }
-
-
-
-
@@ -0,0 +1,333 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::chip8::database::{self, Palette, RomInfo};
+use crate::chip8::fault::FaultPolicy;
+use crate::chip8::platform::Platform;
+use crate::chip8::quirks::{QuirkOverrides, Quirks};
+
+/// File in the config directory with the settings
+const CONFIG_FILE: &str = "config.toml";
+
+/// Largest display scale
+pub const MAX_SCALE: i32 = 5;
+
+/// Foreground and background colors, written as `#rrggbb`, and the colors of pixels lit in
+/// plane 2 and in both planes of XO-CHIP
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PaletteColors {
+    pub background: String,
+    pub foreground: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plane2: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub both: Option<String>,
+}
+
+impl PaletteColors {
+    fn to_palette(&self) -> Result<Palette, String> {
+        let mut colors = vec![self.background.clone(), self.foreground.clone()];
+        match (&self.plane2, &self.both) {
+            (Some(plane2), Some(both)) => colors.extend([plane2.clone(), both.clone()]),
+            (None, None) => {}
+            _ => return Err(String::from("Invalid palette, plane2 and both are set together")),
+        }
+        Palette::from_hex_list(&colors)
+            .ok_or(format!("Invalid palette {}, colors are written #rrggbb", colors.join(" ")))
+    }
+}
+
+impl From<Palette> for PaletteColors {
+    fn from(palette: Palette) -> Self {
+        let hex = |[r, g, b]: [u8; 3]| format!("#{:02x}{:02x}{:02x}", r, g, b);
+        PaletteColors {
+            background: hex(palette.background),
+            foreground: hex(palette.foreground),
+            plane2: Some(hex(palette.plane2)),
+            both: Some(hex(palette.both)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioLayer {
+    pub enabled: Option<bool>,
+    /// Frequency of the beep, in Hz
+    pub frequency: Option<f32>,
+    pub volume: Option<f32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DebugLayer {
+    pub level: Option<u32>,
+    pub paused: Option<bool>,
+}
+
+/// Settings set by one source of configuration. Unset settings are left to the sources below.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ConfigLayer {
+    pub fps: Option<u32>,
+    /// Instructions per frame
+    pub ipf: Option<u32>,
+    pub fast_forward: Option<u32>,
+    pub scale: Option<i32>,
+    pub vsync: Option<bool>,
+    pub platform: Option<Platform>,
+    pub on_fault: Option<FaultPolicy>,
+    pub quirks: QuirkOverrides,
+    pub palette: Option<PaletteColors>,
+    /// Hex key bound to keyboard keys, by SDL key name
+    pub keys: HashMap<String, u8>,
+    pub audio: AudioLayer,
+    pub debug: DebugLayer,
+}
+
+impl ConfigLayer {
+    /// What the cartridge or the ROM database knows about a ROM
+    pub fn from_rom_info(info: &RomInfo) -> Self {
+        ConfigLayer {
+            ipf: info.tickrate,
+            platform: info.platform,
+            quirks: info.quirks.clone(),
+            palette: info.palette.map(PaletteColors::from),
+            ..ConfigLayer::default()
+        }
+    }
+
+    /// Overrides the settings set in `other`
+    fn apply(&mut self, other: &ConfigLayer) {
+        self.fps = other.fps.or(self.fps);
+        self.ipf = other.ipf.or(self.ipf);
+        self.fast_forward = other.fast_forward.or(self.fast_forward);
+        self.scale = other.scale.or(self.scale);
+        self.vsync = other.vsync.or(self.vsync);
+        self.platform = other.platform.or(self.platform);
+        self.on_fault = other.on_fault.or(self.on_fault);
+        self.quirks.merge(&other.quirks);
+        self.palette = other.palette.clone().or(self.palette.take());
+        self.keys.extend(other.keys.iter().map(|(name, key)| (name.clone(), *key)));
+        self.audio.enabled = other.audio.enabled.or(self.audio.enabled);
+        self.audio.frequency = other.audio.frequency.or(self.audio.frequency);
+        self.audio.volume = other.audio.volume.or(self.audio.volume);
+        self.debug.level = other.debug.level.or(self.debug.level);
+        self.debug.paused = other.debug.paused.or(self.debug.paused);
+    }
+}
+
+/// The config file: global settings, and settings of single ROMs in `[roms."<name>"]` tables,
+/// where the name is the file name of the ROM or its SHA-1 hash.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    #[serde(flatten)]
+    pub global: ConfigLayer,
+    pub roms: HashMap<String, ConfigLayer>,
+}
+
+impl Config {
+    /// Path of the config file in the config directory
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("rchip8").join(CONFIG_FILE))
+    }
+
+    /// Loads the config file at `path`. A missing file is an empty config unless `required`.
+    pub fn load(path: &Path, required: bool) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && ! required => Ok(Config::default()),
+            Err(e) => Err(format!("Could not read config {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Resolves the settings of a ROM. From lowest to highest precedence: built-in defaults, the
+    /// global settings, `info` from the cartridge or the ROM database, the settings of the ROM by
+    /// file name, then by hash, and `cli`. Without a platform from any of them, the ROM runs as
+    /// CHIP-8 with the default quirks rather than those of the platform.
+    pub fn settings(&self, rom: Option<(&str, &[u8])>, info: &RomInfo, cli: &ConfigLayer) -> Result<Settings, String> {
+        let mut layer = self.global.clone();
+        layer.apply(&ConfigLayer::from_rom_info(info));
+
+        if let Some((path, program)) = rom {
+            let file_name = Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned());
+            let keys = [file_name, Some(database::sha1_hex(program))];
+            for rom_layer in keys.iter().flatten().filter_map(|key| self.roms.get(key)) {
+                layer.apply(rom_layer);
+            }
+        }
+        layer.apply(cli);
+
+        let platform = layer.platform.unwrap_or(Platform::Chip8);
+        let mut quirks = match layer.platform {
+            Some(platform) => Quirks::for_platform(platform),
+            None => Quirks::default(),
+        };
+        quirks.apply(&layer.quirks);
+
+        let palette = match &layer.palette {
+            Some(colors) => colors.to_palette()?,
+            None => Palette::default(),
+        };
+
+        let fps = layer.fps.unwrap_or(60);
+        if fps == 0 {
+            return Err(String::from("fps must be at least 1"));
+        }
+        let scale = layer.scale.unwrap_or(1);
+        if ! (1..=MAX_SCALE).contains(&scale) {
+            return Err(format!("scale must be between 1 and {}, got {}", MAX_SCALE, scale));
+        }
+
+        Ok(Settings {
+            fps: fps,
+            ipf: layer.ipf.unwrap_or(1),
+            fast_forward: layer.fast_forward.unwrap_or(0),
+            scale: scale,
+            vsync: layer.vsync.unwrap_or(false),
+            platform: platform,
+            on_fault: layer.on_fault.unwrap_or(FaultPolicy::Halt),
+            quirks: quirks,
+            palette: PaletteColors::from(palette),
+            keys: layer.keys.into_iter().collect(),
+            audio: AudioSettings {
+                enabled: layer.audio.enabled.unwrap_or(true),
+                frequency: layer.audio.frequency.unwrap_or(440.0),
+                volume: layer.audio.volume.unwrap_or(0.2),
+            },
+            debug: DebugSettings {
+                level: layer.debug.level.unwrap_or(0),
+                paused: layer.debug.paused.unwrap_or(false),
+            },
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AudioSettings {
+    pub enabled: bool,
+    pub frequency: f32,
+    pub volume: f32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DebugSettings {
+    pub level: u32,
+    pub paused: bool,
+}
+
+/// Resolved settings, written in the format of the config file by `--print-config`
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Settings {
+    pub fps: u32,
+    pub ipf: u32,
+    pub fast_forward: u32,
+    pub scale: i32,
+    pub vsync: bool,
+    pub platform: Platform,
+    pub on_fault: FaultPolicy,
+    pub quirks: Quirks,
+    pub palette: PaletteColors,
+    /// Keys bound in the config, on top of the default keymap
+    pub keys: BTreeMap<String, u8>,
+    pub audio: AudioSettings,
+    pub debug: DebugSettings,
+}
+
+impl Settings {
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("Settings are serializable")
+    }
+
+    pub fn palette(&self) -> Palette {
+        self.palette.to_palette().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &[u8] = &[0x12, 0x00];
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn settings(config: &Config, cli: &ConfigLayer) -> Result<Settings, String> {
+        let info = RomInfo { tickrate: Some(2), ..RomInfo::default() };
+        config.settings(Some(("roms/game.ch8", PROGRAM)), &info, cli)
+    }
+
+    #[test]
+    fn layers_override_the_layers_below() {
+        // Defaults < global < ROM info (ipf 2) < file name < hash < command line
+        let hash = database::sha1_hex(PROGRAM);
+        let mut config = config(&format!("
+            ipf = 3
+            fps = 30
+            scale = 2
+            vsync = true
+            fast-forward = 4
+            [roms.\"game.ch8\"]
+            fps = 40
+            scale = 3
+            fast-forward = 5
+            [roms.\"{}\"]
+            scale = 4
+            fast-forward = 6
+        ", hash));
+
+        let resolved = settings(&config, &ConfigLayer::default()).unwrap();
+        assert_eq!(resolved.ipf, 2);
+        assert_eq!((resolved.fps, resolved.scale, resolved.fast_forward), (40, 4, 6));
+        assert!(resolved.vsync);
+
+        let cli = ConfigLayer { vsync: Some(false), scale: Some(5), ..ConfigLayer::default() };
+        let resolved = settings(&config, &cli).unwrap();
+        assert_eq!((resolved.scale, resolved.vsync), (5, false));
+
+        // The settings of the ROM win over its info
+        config.roms.get_mut("game.ch8").unwrap().ipf = Some(7);
+        assert_eq!(settings(&config, &ConfigLayer::default()).unwrap().ipf, 7);
+
+        // Without ROM info, the global settings apply, over the defaults
+        let resolved = config.settings(Some(("other.ch8", &[0x00, 0xE0])), &RomInfo::default(), &ConfigLayer::default()).unwrap();
+        assert_eq!((resolved.ipf, resolved.fps, resolved.scale, resolved.fast_forward), (3, 30, 2, 4));
+        config.global.fps = None;
+        assert_eq!(config.settings(None, &RomInfo::default(), &ConfigLayer::default()).unwrap().fps, 60);
+    }
+
+    #[test]
+    fn fps_and_scale_are_checked() {
+        let config = Config::default();
+        let layer = |fps, scale| ConfigLayer { fps: fps, scale: scale, ..ConfigLayer::default() };
+        assert!(settings(&config, &layer(Some(0), None)).is_err());
+        assert!(settings(&config, &layer(None, Some(0))).is_err());
+        assert!(settings(&config, &layer(None, Some(6))).is_err());
+        assert!(settings(&config, &layer(Some(1), Some(5))).is_ok());
+
+        let config = self::config("fps = 0");
+        assert_eq!(settings(&config, &ConfigLayer::default()).unwrap_err(), "fps must be at least 1");
+    }
+
+    #[test]
+    fn roms_of_unknown_platform_keep_the_default_quirks() {
+        let config = Config::default();
+        let resolved = settings(&config, &ConfigLayer::default()).unwrap();
+        assert_eq!((resolved.platform, resolved.quirks), (Platform::Chip8, Quirks::default()));
+
+        let cli = ConfigLayer { platform: Some(Platform::Chip8), ..ConfigLayer::default() };
+        assert_eq!(settings(&config, &cli).unwrap().quirks, Quirks::for_platform(Platform::Chip8));
+
+        let config = self::config("[quirks]\nwrap = false");
+        let resolved = settings(&config, &ConfigLayer::default()).unwrap();
+        assert_eq!(resolved.quirks, Quirks { wrap: false, ..Quirks::default() });
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::chip8::disasm;
+
+const EXECUTED: u8 = 1;
+const READ: u8 = 2;
+const WRITTEN: u8 = 4;
+/// First byte of an executed instruction
+const INSTRUCTION: u8 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum CoverageFormat {
+    /// Disassembly of the program with the coverage of each line
+    Annotated,
+    /// LCOV tracefile, with memory addresses as line numbers
+    Lcov,
+}
+
+/// A line of the report: an instruction, or a byte of data
+struct Line {
+    address: usize,
+    size: usize,
+    flags: u8,
+    executions: u64,
+}
+
+/// Records which bytes of memory were executed as instructions, read as data by Dxyn and Fx65,
+/// or written by Fx33 and Fx55.
+pub struct Coverage {
+    flags: Vec<u8>,
+    executions: Vec<u64>,
+    /// Where the ROM was loaded
+    program: Range<usize>,
+}
+
+impl Coverage {
+    pub fn new(memory_size: usize, program: Range<usize>) -> Self {
+        Coverage {
+            flags: vec![0; memory_size],
+            executions: vec![0; memory_size],
+            program: program,
+        }
+    }
+
+    fn mark(&mut self, address: usize, len: usize, flag: u8) {
+        for offset in 0..len {
+            let index = (address + offset) % self.flags.len();
+            self.flags[index] |= flag;
+        }
+    }
+
+    pub fn executed(&mut self, address: u16) {
+        let address = address as usize % self.flags.len();
+        self.mark(address, 2, EXECUTED);
+        self.flags[address] |= INSTRUCTION;
+        self.executions[address] += 1;
+    }
+
+    pub fn read(&mut self, address: u16, len: usize) {
+        self.mark(address as usize, len, READ);
+    }
+
+    pub fn written(&mut self, address: u16, len: usize) {
+        self.mark(address as usize, len, WRITTEN);
+    }
+
+    /// Splits memory into lines: executed instructions, bytes touched as data, and the
+    /// untouched words of the program, shown as instructions that never ran.
+    /// Untouched memory outside the program is left out.
+    fn lines(&self) -> Vec<Line> {
+        let mut lines = Vec::new();
+        let mut address = 0;
+        while address < self.flags.len() {
+            let flags = self.flags[address];
+            let next_flags = self.flags.get(address + 1).copied().unwrap_or(INSTRUCTION);
+            let in_program = self.program.contains(&address);
+
+            let size = if flags & INSTRUCTION != 0 {
+                2
+            } else if flags == 0 && ! in_program {
+                address += 1;
+                continue;
+            } else if flags == 0 && next_flags == 0 && self.program.contains(&(address + 1)) {
+                2
+            } else {
+                1
+            };
+
+            let flags = if size == 2 { flags | (next_flags & ! INSTRUCTION) } else { flags };
+            lines.push(Line { address: address, size: size, flags: flags, executions: self.executions[address] });
+            address += size;
+        }
+        lines
+    }
+
+    fn summary(&self) -> String {
+        let count = |flag: u8| self.program.clone().filter(|address| self.flags[*address] & flag != 0).count();
+        let percent = |count: usize| count as f64 * 100.0 / self.program.len().max(1) as f64;
+        let (executed, read, written) = (count(EXECUTED), count(READ), count(WRITTEN));
+        format!("Program bytes: {}, executed: {} ({:.1}%), read: {} ({:.1}%), written: {} ({:.1}%)",
+            self.program.len(), executed, percent(executed), read, percent(read), written, percent(written))
+    }
+
+    /// Disassembly of the program, each line starting with its coverage: X executed,
+    /// R read, W written, then the number of executions.
+    pub fn annotated(&self, ram: &[u8]) -> String {
+        let mut report = String::new();
+        writeln!(report, "; {}", self.summary()).unwrap();
+        writeln!(report, "; X executed, R read as data, W written").unwrap();
+
+        for line in self.lines() {
+            let flag = |flag: u8, c: char| if line.flags & flag != 0 { c } else { '-' };
+            let coverage = format!("{}{}{}", flag(EXECUTED, 'X'), flag(READ, 'R'), flag(WRITTEN, 'W'));
+            let executions = if line.flags & INSTRUCTION != 0 { line.executions.to_string() } else { String::new() };
+
+            let (bytes, text) = if line.size == 2 {
+                let word = (ram[line.address] as u16) << 8 | ram[(line.address + 1) % ram.len()] as u16;
+                (format!("{:04X}", word), disasm::disassemble(word))
+            } else {
+                (format!("{:02X}", ram[line.address]), format!("DB 0x{:02X}", ram[line.address]))
+            };
+
+            writeln!(report, "{} {:>8}  {:04X}  {:<4}  {}", coverage, executions, line.address, bytes, text).unwrap();
+        }
+        report
+    }
+
+    /// LCOV tracefile. Lines are the addresses of the instructions, in decimal, and hits are how
+    /// many times they ran. Data bytes are left out.
+    pub fn lcov(&self, source: &str) -> String {
+        let instructions: Vec<Line> = self.lines().into_iter().filter(|line| line.size == 2).collect();
+        let hit = instructions.iter().filter(|line| line.executions > 0).count();
+
+        let mut report = String::new();
+        writeln!(report, "TN:").unwrap();
+        writeln!(report, "SF:{}", source).unwrap();
+        for line in &instructions {
+            writeln!(report, "DA:{},{}", line.address, line.executions).unwrap();
+        }
+        writeln!(report, "LH:{}", hit).unwrap();
+        writeln!(report, "LF:{}", instructions.len()).unwrap();
+        writeln!(report, "end_of_record").unwrap();
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::chip8::{CHIP8, PROGRAM_ADDRESS};
+    use crate::chip8::platform::Platform;
+
+    /// Draws the byte at 0x20C, writes the BCD of V0 at 0x20E and loops. The word at 0x20A never
+    /// runs.
+    const PROGRAM: [u8; 13] = [0xA2, 0x0C, 0xD0, 0x01, 0xA2, 0x0E, 0xF0, 0x33, 0x12, 0x08, 0x00, 0x00, 0x80];
+
+    /// Runs the program for 10 instructions
+    fn run() -> CHIP8 {
+        let mut chip8 = CHIP8::headless(Platform::Chip8);
+        chip8.load_program(&PROGRAM).unwrap();
+        let program = PROGRAM_ADDRESS..PROGRAM_ADDRESS + PROGRAM.len();
+        chip8.set_coverage(Coverage::new(chip8.ram.len(), program));
+        chip8.set_instructions_per_frame(1000);
+        for _ in 0..10 {
+            chip8.step().unwrap();
+        }
+        chip8
+    }
+
+    #[test]
+    fn bytes_are_marked_executed_read_and_written() {
+        let chip8 = run();
+        let coverage = chip8.coverage().unwrap();
+        let flags = |address: usize| coverage.flags[address];
+        assert_eq!(flags(0x200), EXECUTED | INSTRUCTION);
+        assert_eq!(flags(0x201), EXECUTED);
+        assert_eq!(flags(0x20A) | flags(0x20B), 0);
+        assert_eq!(flags(0x20C), READ);
+        assert_eq!((flags(0x20E), flags(0x20F), flags(0x210), flags(0x211)), (WRITTEN, WRITTEN, WRITTEN, 0));
+        assert_eq!((coverage.executions[0x200], coverage.executions[0x208], coverage.executions[0x201]), (1, 6, 0));
+    }
+
+    #[test]
+    fn annotated_reports_cover_the_program_and_touched_data() {
+        let chip8 = run();
+        let report = chip8.coverage().unwrap().annotated(&chip8.ram);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "; Program bytes: 13, executed: 10 (76.9%), read: 1 (7.7%), written: 0 (0.0%)");
+        assert_eq!(lines[2], "X--        1  0200  A20C  LD I, 0x20C");
+        assert_eq!(lines[6], "X--        6  0208  1208  JP 0x208");
+        // Untouched words of the program are instructions that never ran
+        assert!(lines[7].starts_with("---           020A  0000  "));
+        assert_eq!(lines[8], "-R-           020C  80    DB 0x80");
+        assert_eq!(lines[9], "--W           020E  00    DB 0x00");
+        assert_eq!(lines.len(), 12);
+    }
+
+    #[test]
+    fn lcov_reports_list_the_instructions() {
+        let chip8 = run();
+        let lcov = chip8.coverage().unwrap().lcov("game.ch8");
+        assert_eq!(lcov, "TN:\nSF:game.ch8\nDA:512,1\nDA:514,1\nDA:516,1\nDA:518,1\nDA:520,6\nDA:522,0\nLH:5\nLF:6\nend_of_record\n");
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use crate::chip8::platform::Platform;
+use crate::chip8::quirks::QuirkOverrides;
+
+/// Bundled ROM metadata, in the format of the community chip-8 database (programs.json).
+/// build.rs generates it from src/chip8/programs.json and the community database.
+const PROGRAMS: &str = include_str!(concat!(env!("OUT_DIR"), "/programs.json"));
+
+/// File in the config directory with local entries. They replace bundled entries with the same hash.
+const USER_PROGRAMS_FILE: &str = "programs.json";
+
+/// Colors of the pixels, as RGB. Pixels lit in plane 1 are drawn in the foreground color;
+/// XO-CHIP also lights pixels in plane 2 only, or in both planes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub background: [u8; 3],
+    pub foreground: [u8; 3],
+    pub plane2: [u8; 3],
+    pub both: [u8; 3],
+}
+
+impl Palette {
+    /// Builds a palette from colors written as `#rrggbb`, with the default plane 2 colors
+    pub fn from_hex(background: &str, foreground: &str) -> Option<Self> {
+        Some(Palette {
+            background: parse_hex_color(background)?,
+            foreground: parse_hex_color(foreground)?,
+            ..Palette::default()
+        })
+    }
+
+    /// Builds a palette from 2 or 4 colors written as `#rrggbb`, in the order of the planes
+    /// lighting them: none, plane 1, plane 2, both
+    pub fn from_hex_list(colors: &[String]) -> Option<Self> {
+        let mut palette = Palette::from_hex(colors.first()?, colors.get(1)?)?;
+        if let (Some(plane2), Some(both)) = (colors.get(2), colors.get(3)) {
+            palette.plane2 = parse_hex_color(plane2)?;
+            palette.both = parse_hex_color(both)?;
+        }
+        Some(palette)
+    }
+
+    /// Color of a pixel, from the bits of its planes
+    pub fn color(&self, planes: u8) -> [u8; 3] {
+        match planes & 0x3 {
+            0 => self.background,
+            1 => self.foreground,
+            2 => self.plane2,
+            _ => self.both,
+        }
+    }
+
+    /// The colors, in the order of `from_hex_list`
+    pub fn colors(&self) -> [[u8; 3]; 4] {
+        [self.background, self.foreground, self.plane2, self.both]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            background: [0, 0, 0],
+            foreground: [255, 255, 255],
+            plane2: [85, 85, 85],
+            both: [170, 170, 170],
+        }
+    }
+}
+
+fn parse_hex_color(color: &str) -> Option<[u8; 3]> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+/// What is known about a ROM: everything needed to configure the machine to run it
+#[derive(Clone, Debug, Default)]
+pub struct RomInfo {
+    pub title: String,
+    pub platform: Option<Platform>,
+    pub quirks: QuirkOverrides,
+    /// Instructions per frame
+    pub tickrate: Option<u32>,
+    pub palette: Option<Palette>,
+    /// Hex key bound to each action (up, down, left, right, a, b)
+    pub keys: HashMap<String, u8>,
+}
+
+#[derive(Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    roms: HashMap<String, RomEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RomEntry {
+    #[serde(default)]
+    platforms: Vec<String>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, QuirkOverrides>,
+    tickrate: Option<u32>,
+    colors: Option<Colors>,
+    #[serde(default)]
+    keys: HashMap<String, u8>,
+}
+
+#[derive(Deserialize)]
+struct Colors {
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+/// Maps platform ids of the community database to the platforms we emulate
+fn platform_from_id(id: &str) -> Option<Platform> {
+    match id {
+        "originalChip8" | "hybridVIP" | "modernChip8" | "chip8x" => Some(Platform::Chip8),
+        "chip48" | "superchip1" | "superchip" => Some(Platform::Schip),
+        "xochip" => Some(Platform::XoChip),
+        _ => None,
+    }
+}
+
+impl RomEntry {
+    fn into_info(self, title: &str) -> RomInfo {
+        // The first platform we can emulate wins
+        let platform_id = self.platforms.iter().find(|id| platform_from_id(id).is_some());
+
+        let quirks = platform_id
+            .and_then(|id| self.quirky_platforms.get(id))
+            .cloned()
+            .unwrap_or_default();
+
+        let palette = self.colors.and_then(|colors| Palette::from_hex_list(&colors.pixels));
+
+        RomInfo {
+            title: title.to_string(),
+            platform: platform_id.and_then(|id| platform_from_id(id)),
+            quirks: quirks,
+            tickrate: self.tickrate,
+            palette: palette,
+            keys: self.keys,
+        }
+    }
+}
+
+/// ROM metadata looked up by SHA-1 hash
+pub struct Database {
+    roms: HashMap<String, RomInfo>,
+}
+
+impl Database {
+    /// Loads the bundled database, then the local entries from the config directory.
+    pub fn load() -> Self {
+        Database::load_with(Database::user_programs_path().as_deref())
+    }
+
+    /// Loads the bundled database, then the local entries of `user_programs`, if the file exists
+    fn load_with(user_programs: Option<&Path>) -> Self {
+        let mut database = Database { roms: HashMap::new() };
+        database.add_programs(PROGRAMS).expect("Bundled ROM database is invalid");
+
+        if let Some(path) = user_programs {
+            if let Ok(programs) = fs::read_to_string(path) {
+                if let Err(e) = database.add_programs(&programs) {
+                    eprintln!("Ignoring {}: {}", path.display(), e);
+                }
+            }
+        }
+
+        database
+    }
+
+    /// Path of the file with local entries
+    pub fn user_programs_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("rchip8").join(USER_PROGRAMS_FILE))
+    }
+
+    fn add_programs(&mut self, json: &str) -> Result<(), serde_json::Error> {
+        let programs: Vec<Program> = serde_json::from_str(json)?;
+        for program in programs {
+            for (hash, rom) in program.roms {
+                self.roms.insert(hash.to_lowercase(), rom.into_info(&program.title));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn lookup(&self, program: &[u8]) -> Option<&RomInfo> {
+        self.roms.get(&sha1_hex(program))
+    }
+}
+
+/// SHA-1 hash of a ROM, as lowercase hex
+pub fn sha1_hex(program: &[u8]) -> String {
+    format!("{:x}", Sha1::digest(program))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IBM_LOGO: &[u8] = include_bytes!("../../roms/IBM Logo.ch8");
+
+    /// A local entry for the IBM logo, in the format of the community database
+    const USER_PROGRAMS: &str = r##"[
+        {
+            "title": "My IBM Logo",
+            "roms": {
+                "1BA58656810B67FD131EB9AF3E3987863BF26C90": {
+                    "platforms": ["megachip8", "superchip"],
+                    "quirkyPlatforms": { "superchip": { "shift": false, "jump": true } },
+                    "tickrate": 30,
+                    "colors": { "pixels": ["#000000", "#ff0000", "#00ff00", "#0000ff"] },
+                    "keys": { "a": 6 }
+                }
+            }
+        },
+        {
+            "title": "Elsewhere",
+            "roms": { "0000000000000000000000000000000000000000": { "platforms": ["xochip"] } }
+        }
+    ]"##;
+
+    #[test]
+    fn bundled_roms_are_found_by_hash() {
+        let database = Database::load_with(None);
+        let info = database.lookup(IBM_LOGO).unwrap();
+        assert_eq!(info.title, "IBM Logo");
+        assert_eq!(info.platform, Some(Platform::Chip8));
+        assert!(database.lookup(&IBM_LOGO[1..]).is_none());
+    }
+
+    #[test]
+    fn user_entries_replace_bundled_entries_with_the_same_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(USER_PROGRAMS_FILE);
+        fs::write(&path, USER_PROGRAMS).unwrap();
+
+        let database = Database::load_with(Some(&path));
+        let info = database.lookup(IBM_LOGO).unwrap();
+        assert_eq!(info.title, "My IBM Logo");
+        // megachip8 can't be emulated: the quirks are those of the next platform
+        assert_eq!(info.platform, Some(Platform::Schip));
+        assert_eq!((info.quirks.shift, info.quirks.jump, info.quirks.wrap), (Some(false), Some(true), None));
+        assert_eq!(info.tickrate, Some(30));
+        assert_eq!(info.palette.unwrap().colors(), [[0, 0, 0], [255, 0, 0], [0, 255, 0], [0, 0, 255]]);
+        assert_eq!(info.keys["a"], 6);
+
+        // The other bundled entries and the new ones are merged
+        let test_opcode = include_bytes!("../../roms/test_opcode.ch8");
+        assert_eq!(database.lookup(test_opcode).unwrap().tickrate, Some(15));
+        assert_eq!(database.roms["0000000000000000000000000000000000000000"].platform, Some(Platform::XoChip));
+    }
+
+    #[test]
+    fn invalid_user_entries_are_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(USER_PROGRAMS_FILE);
+        fs::write(&path, "{ not json").unwrap();
+        assert_eq!(Database::load_with(Some(&path)).lookup(IBM_LOGO).unwrap().title, "IBM Logo");
+
+        let missing = dir.path().join("missing.json");
+        assert_eq!(Database::load_with(Some(&missing)).lookup(IBM_LOGO).unwrap().title, "IBM Logo");
+    }
+
+    #[test]
+    fn palettes_take_two_or_four_colors() {
+        let colors = |list: &[&str]| list.iter().map(|color| color.to_string()).collect::<Vec<_>>();
+
+        let palette = Palette::from_hex_list(&colors(&["#102030", "#ffffff"])).unwrap();
+        assert_eq!(palette.background, [0x10, 0x20, 0x30]);
+        assert_eq!((palette.plane2, palette.both), (Palette::default().plane2, Palette::default().both));
+
+        let palette = Palette::from_hex_list(&colors(&["#000000", "#111111", "#222222", "#333333"])).unwrap();
+        assert_eq!(palette.color(2), [0x22, 0x22, 0x22]);
+        assert_eq!(palette.color(3), [0x33, 0x33, 0x33]);
+
+        assert!(Palette::from_hex_list(&colors(&["#000000"])).is_none());
+        assert!(Palette::from_hex_list(&colors(&["#000000", "fff"])).is_none());
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::HashSet;
+
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{Window, WindowContext};
+
+use crate::chip8::disasm;
+use crate::chip8::fault::MachineFault;
+
+const FONT_PATH: &str = "FiraCode-Regular.ttf";
+const FONT_SIZE: u16 = 14;
+
+/// Size of the panel, in characters
+const COLS: u32 = 48;
+const ROWS: u32 = 30;
+
+/// Instructions shown in the disassembly, and how many of them come before PC
+const DISASM_ROWS: usize = 16;
+const DISASM_ROWS_BEFORE_PC: usize = 5;
+const DISASM_TOP: i32 = 1;
+
+const MEMORY_TOP: i32 = 19;
+const MEMORY_ROWS: usize = 8;
+const MEMORY_ROW_BYTES: usize = 8;
+
+const REGISTERS_LEFT: i32 = 31;
+const KEYPAD_LEFT: i32 = 40;
+const FAULT_TOP: i32 = 28;
+
+/// Hex keys as laid out on the COSMAC VIP keypad
+const KEYPAD: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+const TEXT_COLOR: Color = Color::RGB(255, 0, 0);
+const LABEL_COLOR: Color = Color::RGB(160, 160, 160);
+const CHANGED_COLOR: Color = Color::RGB(255, 255, 0);
+const BREAKPOINT_COLOR: Color = Color::RGB(255, 128, 0);
+const HIGHLIGHT_COLOR: Color = Color::RGB(48, 48, 96);
+
+/// State of the machine shown by the debug panel
+pub struct DebugView<'a> {
+    /// Instructions executed so far, to tell when the machine stepped
+    pub cycle: u64,
+    pub pc: u16,
+    pub i: u16,
+    pub v: [u8; 16],
+    pub sp: usize,
+    pub stack: [u16; 16],
+    pub dt: u8,
+    pub st: u8,
+    pub ram: &'a [u8],
+    pub keys: [bool; 16],
+    pub breakpoints: &'a HashSet<u16>,
+    pub fault: Option<MachineFault>,
+}
+
+/// Registers compared between steps to highlight changes
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    v: [u8; 16],
+    i: u16,
+    sp: usize,
+    dt: u8,
+    st: u8,
+}
+
+/// Debug window: disassembly, registers, memory, call stack and keypad.
+/// Clicking an instruction of the disassembly toggles a breakpoint, the mouse wheel scrolls it.
+pub struct DebugPanel {
+    canvas: Canvas<Window>,
+
+    /// One texture per printable ASCII character, rendered once in white and tinted when drawn
+    glyphs: Vec<Texture<'static>>,
+    glyph_width: u32,
+    glyph_height: u32,
+
+    /// Addresses of the instructions shown in the disassembly, top to bottom
+    disasm_addresses: Vec<u16>,
+    /// Scroll of the disassembly, in instructions, relative to PC
+    scroll: i32,
+
+    last_cycle: u64,
+    previous: Option<Registers>,
+    current: Option<Registers>,
+}
+
+impl DebugPanel {
+    pub fn new(mut canvas: Canvas<Window>) -> Self {
+        // The font and textures live as long as the window, so they are created once for good
+        let ttf_context = Box::leak(Box::new(sdl2::ttf::init().expect("Could not initialise SDL2_ttf")));
+        let font = ttf_context.load_font(FONT_PATH, FONT_SIZE).expect("Could not load the debugger font");
+        let texture_creator: &'static TextureCreator<WindowContext> = Box::leak(Box::new(canvas.texture_creator()));
+
+        let mut glyphs = Vec::new();
+        let mut glyph_width = 0;
+        let mut glyph_height = 0;
+        for c in ' '..='~' {
+            let surface = font.render_char(c).blended(Color::RGB(255, 255, 255)).expect("Could not render the debugger font");
+            glyph_width = glyph_width.max(surface.width());
+            glyph_height = glyph_height.max(surface.height());
+            glyphs.push(texture_creator.create_texture_from_surface(&surface).expect("Could not create a texture"));
+        }
+
+        canvas.window_mut().set_size(COLS * glyph_width, ROWS * glyph_height).expect("Could not resize the debug window");
+
+        DebugPanel {
+            canvas: canvas,
+            glyphs: glyphs,
+            glyph_width: glyph_width,
+            glyph_height: glyph_height,
+            disasm_addresses: Vec::new(),
+            scroll: 0,
+            last_cycle: u64::MAX,
+            previous: None,
+            current: None,
+        }
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    /// Returns the address of the instruction under the mouse, if any
+    pub fn instruction_at(&self, x: i32, y: i32) -> Option<u16> {
+        let col = x / self.glyph_width as i32;
+        let row = y / self.glyph_height as i32 - DISASM_TOP;
+        if col >= REGISTERS_LEFT - 1 || row < 0 {
+            return None;
+        }
+        self.disasm_addresses.get(row as usize).copied()
+    }
+
+    pub fn scroll(&mut self, lines: i32) {
+        self.scroll += lines;
+    }
+
+    fn cell(&self, col: i32, row: i32, width: u32) -> Rect {
+        Rect::new(col * self.glyph_width as i32, row * self.glyph_height as i32, width * self.glyph_width, self.glyph_height)
+    }
+
+    fn draw_text(&mut self, col: i32, row: i32, text: &str, color: Color) {
+        for (offset, c) in text.chars().enumerate() {
+            let index = match c {
+                ' '..='~' => c as usize - ' ' as usize,
+                _ => '?' as usize - ' ' as usize,
+            };
+            let target = self.cell(col + offset as i32, row, 1);
+            let glyph = &mut self.glyphs[index];
+            glyph.set_color_mod(color.r, color.g, color.b);
+            let query = glyph.query();
+            let target = Rect::new(target.x(), target.y(), query.width, query.height);
+            self.canvas.copy(glyph, None, Some(target)).expect("Could not draw the debug panel");
+        }
+    }
+
+    fn highlight(&mut self, col: i32, row: i32, width: u32) {
+        self.canvas.set_draw_color(HIGHLIGHT_COLOR);
+        self.canvas.fill_rect(self.cell(col, row, width)).expect("Could not draw the debug panel");
+    }
+
+    pub fn render(&mut self, view: &DebugView, background: Color) {
+        let registers = Registers { v: view.v, i: view.i, sp: view.sp, dt: view.dt, st: view.st };
+        if view.cycle != self.last_cycle {
+            self.last_cycle = view.cycle;
+            self.previous = self.current;
+            self.current = Some(registers);
+            self.scroll = 0;
+        }
+        let previous = self.previous.unwrap_or(registers);
+        let changed = |is_changed: bool| if is_changed { CHANGED_COLOR } else { TEXT_COLOR };
+
+        self.canvas.set_draw_color(background);
+        self.canvas.clear();
+
+        self.render_disassembly(view);
+
+        // Registers
+        self.draw_text(REGISTERS_LEFT, 0, "REGISTERS", LABEL_COLOR);
+        for index in 0..16 {
+            let col = REGISTERS_LEFT + (index as i32 / 8) * 7;
+            let row = 1 + index as i32 % 8;
+            self.draw_text(col, row, &format!("V{:X} {:02X}", index, view.v[index]), changed(view.v[index] != previous.v[index]));
+        }
+        self.draw_text(REGISTERS_LEFT, 10, &format!("PC {:04X}", view.pc), TEXT_COLOR);
+        self.draw_text(REGISTERS_LEFT, 11, &format!("I  {:04X}", view.i), changed(view.i != previous.i));
+        self.draw_text(REGISTERS_LEFT, 12, &format!("SP {:X}", view.sp), changed(view.sp != previous.sp));
+        self.draw_text(REGISTERS_LEFT, 13, &format!("DT {:02X}", view.dt), changed(view.dt != previous.dt));
+        self.draw_text(REGISTERS_LEFT, 14, &format!("ST {:02X}", view.st), changed(view.st != previous.st));
+
+        self.render_memory(view);
+
+        // Call stack, innermost call first
+        self.draw_text(REGISTERS_LEFT, MEMORY_TOP - 1, "STACK", LABEL_COLOR);
+        for (row, level) in (0..view.sp.min(view.stack.len())).rev().enumerate() {
+            self.draw_text(REGISTERS_LEFT, MEMORY_TOP + row as i32, &format!("{:X} {:04X}", level, view.stack[level]), TEXT_COLOR);
+        }
+
+        // Keypad
+        self.draw_text(KEYPAD_LEFT, MEMORY_TOP - 1, "KEYPAD", LABEL_COLOR);
+        for (row, keys) in KEYPAD.iter().enumerate() {
+            for (col, key) in keys.iter().enumerate() {
+                let col = KEYPAD_LEFT + col as i32 * 2;
+                let row = MEMORY_TOP + row as i32;
+                let pressed = view.keys[*key as usize];
+                if pressed {
+                    self.highlight(col, row, 1);
+                }
+                self.draw_text(col, row, &format!("{:X}", key), if pressed { CHANGED_COLOR } else { TEXT_COLOR });
+            }
+        }
+
+        if let Some(fault) = view.fault {
+            self.draw_text(0, FAULT_TOP, &fault.to_string(), CHANGED_COLOR);
+        }
+
+        self.canvas.present();
+    }
+
+    /// Instructions around PC, with the current one highlighted and breakpoints marked
+    fn render_disassembly(&mut self, view: &DebugView) {
+        self.draw_text(0, DISASM_TOP - 1, "DISASSEMBLY", LABEL_COLOR);
+
+        let len = view.ram.len();
+        let first = view.pc as i64 + (self.scroll as i64 - DISASM_ROWS_BEFORE_PC as i64) * 2;
+        self.disasm_addresses = (0..DISASM_ROWS as i64)
+            .map(|row| (first + row * 2).rem_euclid(len as i64) as u16)
+            .collect();
+
+        for (row, address) in self.disasm_addresses.clone().into_iter().enumerate() {
+            let row = DISASM_TOP + row as i32;
+            let word = (view.ram[address as usize] as u16) << 8 | view.ram[(address as usize + 1) % len] as u16;
+            let current = address == view.pc;
+            if current {
+                self.highlight(0, row, (REGISTERS_LEFT - 1) as u32);
+            }
+
+            let breakpoint = view.breakpoints.contains(&address);
+            let marker = match (current, breakpoint) {
+                (true, true) => ">*",
+                (true, false) => "> ",
+                (false, true) => " *",
+                (false, false) => "  ",
+            };
+            let color = if breakpoint { BREAKPOINT_COLOR } else { TEXT_COLOR };
+            let text = format!("{}{:04X} {:04X} {}", marker, address, word, disasm::disassemble(word));
+            self.draw_text(0, row, &text, color);
+        }
+    }
+
+    /// Hex dump of the memory around I, with the byte at I highlighted
+    fn render_memory(&mut self, view: &DebugView) {
+        self.draw_text(0, MEMORY_TOP - 1, "MEMORY", LABEL_COLOR);
+
+        let len = view.ram.len();
+        let first = (view.i as usize & !(MEMORY_ROW_BYTES - 1)) as i64 - (MEMORY_ROWS / 2 * MEMORY_ROW_BYTES) as i64;
+        for row in 0..MEMORY_ROWS {
+            let row_address = (first + (row * MEMORY_ROW_BYTES) as i64).rem_euclid(len as i64) as usize;
+            let screen_row = MEMORY_TOP + row as i32;
+            self.draw_text(0, screen_row, &format!("{:04X}", row_address), LABEL_COLOR);
+
+            for offset in 0..MEMORY_ROW_BYTES {
+                let address = (row_address + offset) % len;
+                let col = 5 + offset as i32 * 3;
+                if address == view.i as usize {
+                    self.highlight(col, screen_row, 2);
+                }
+                self.draw_text(col, screen_row, &format!("{:02X}", view.ram[address]), TEXT_COLOR);
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+/// Groups of instructions, used to filter traces
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum OpcodeClass {
+    /// Jumps, calls and returns: 00EE, 00FD, 1nnn, 2nnn, Bnnn
+    Flow,
+    /// Conditional skips on registers: 3xkk, 4xkk, 5xy0, 9xy0
+    Skip,
+    /// Register loads and arithmetic: 6xkk, 7xkk, 8xyn, Cxkk
+    Alu,
+    /// I and memory: Annn, 5xy2, 5xy3, F000, Fx1E, Fx29, Fx30, Fx33, Fx55, Fx65, Fx75, Fx85
+    Memory,
+    /// 00E0, 00Cn, 00Dn, 00FB, 00FC, 00FE, 00FF, Dxyn and Fn01
+    Display,
+    /// Fx07, Fx15, Fx18, F002, Fx3A
+    Timer,
+    /// Ex9E, ExA1, Fx0A
+    Input,
+    /// Anything the interpreter doesn't know
+    Illegal,
+}
+
+/// Returns the group `word` belongs to. SUPER-CHIP and XO-CHIP instructions are known on every
+/// platform.
+pub fn classify(word: u16) -> OpcodeClass {
+    let n = word & 0x000F;
+    let nn = word & 0x00FF;
+
+    match word & 0xF000 {
+        0x0000 => match word {
+            0x00E0 => OpcodeClass::Display,
+            0x00EE | 0x00FD => OpcodeClass::Flow,
+            0x00C0..=0x00DF | 0x00FB | 0x00FC | 0x00FE | 0x00FF => OpcodeClass::Display,
+            _ => OpcodeClass::Illegal,
+        }
+        0x1000 | 0x2000 | 0xB000 => OpcodeClass::Flow,
+        0x3000 | 0x4000 => OpcodeClass::Skip,
+        0x5000 | 0x9000 if n == 0 => OpcodeClass::Skip,
+        0x5000 if n == 2 || n == 3 => OpcodeClass::Memory,
+        0x6000 | 0x7000 | 0xC000 => OpcodeClass::Alu,
+        0x8000 => match n {
+            0x0..=0x7 | 0xE => OpcodeClass::Alu,
+            _ => OpcodeClass::Illegal,
+        }
+        0xA000 => OpcodeClass::Memory,
+        0xD000 => OpcodeClass::Display,
+        0xE000 => match nn {
+            0x9E | 0xA1 => OpcodeClass::Input,
+            _ => OpcodeClass::Illegal,
+        }
+        0xF000 if word == 0xF000 => OpcodeClass::Memory,
+        0xF000 => match nn {
+            0x01 => OpcodeClass::Display,
+            0x02 if word == 0xF002 => OpcodeClass::Timer,
+            0x07 | 0x15 | 0x18 | 0x3A => OpcodeClass::Timer,
+            0x0A => OpcodeClass::Input,
+            0x1E | 0x29 | 0x30 | 0x33 | 0x55 | 0x65 | 0x75 | 0x85 => OpcodeClass::Memory,
+            _ => OpcodeClass::Illegal,
+        }
+        _ => OpcodeClass::Illegal,
+    }
+}
+
+/// Returns the pattern of the instruction `word` is, e.g. `Dxyn`
+pub fn pattern(word: u16) -> &'static str {
+    if classify(word) == OpcodeClass::Illegal {
+        return "illegal";
+    }
+
+    match word & 0xF000 {
+        0x0000 => match word {
+            0x00E0 => "00E0",
+            0x00EE => "00EE",
+            0x00C0..=0x00CF => "00Cn",
+            0x00D0..=0x00DF => "00Dn",
+            0x00FB => "00FB",
+            0x00FC => "00FC",
+            0x00FD => "00FD",
+            0x00FE => "00FE",
+            _ => "00FF",
+        }
+        0x1000 => "1nnn",
+        0x2000 => "2nnn",
+        0x3000 => "3xkk",
+        0x4000 => "4xkk",
+        0x5000 => match word & 0xF {
+            0x0 => "5xy0",
+            0x2 => "5xy2",
+            _ => "5xy3",
+        }
+        0x6000 => "6xkk",
+        0x7000 => "7xkk",
+        0x8000 => match word & 0xF {
+            0x0 => "8xy0",
+            0x1 => "8xy1",
+            0x2 => "8xy2",
+            0x3 => "8xy3",
+            0x4 => "8xy4",
+            0x5 => "8xy5",
+            0x6 => "8xy6",
+            0x7 => "8xy7",
+            _ => "8xyE",
+        }
+        0x9000 => "9xy0",
+        0xA000 => "Annn",
+        0xB000 => "Bnnn",
+        0xC000 => "Cxkk",
+        0xD000 => "Dxyn",
+        0xE000 => match word & 0xFF {
+            0x9E => "Ex9E",
+            _ => "ExA1",
+        }
+        _ => match word & 0xFF {
+            0x00 => "F000",
+            0x01 => "Fn01",
+            0x02 => "F002",
+            0x07 => "Fx07",
+            0x0A => "Fx0A",
+            0x15 => "Fx15",
+            0x18 => "Fx18",
+            0x1E => "Fx1E",
+            0x29 => "Fx29",
+            0x30 => "Fx30",
+            0x33 => "Fx33",
+            0x3A => "Fx3A",
+            0x55 => "Fx55",
+            0x65 => "Fx65",
+            0x75 => "Fx75",
+            _ => "Fx85",
+        }
+    }
+}
+
+/// Returns the mnemonic of `word`, in the syntax of Cowgod's technical reference.
+/// Words that are not instructions are shown as `DW 0xNNNN`.
+pub fn disassemble(word: u16) -> String {
+    let x = (word & 0x0F00) >> 8;
+    let y = (word & 0x00F0) >> 4;
+    let n = word & 0x000F;
+    let nn = word & 0x00FF;
+    let nnn = word & 0x0FFF;
+
+    if classify(word) == OpcodeClass::Illegal {
+        return format!("DW 0x{:04X}", word);
+    }
+
+    match word & 0xF000 {
+        0x0000 => match word {
+            0x00E0 => String::from("CLS"),
+            0x00EE => String::from("RET"),
+            0x00C0..=0x00CF => format!("SCD {}", n),
+            0x00D0..=0x00DF => format!("SCU {}", n),
+            0x00FB => String::from("SCR"),
+            0x00FC => String::from("SCL"),
+            0x00FD => String::from("EXIT"),
+            0x00FE => String::from("LOW"),
+            _ => String::from("HIGH"),
+        }
+        0x1000 => format!("JP 0x{:03X}", nnn),
+        0x2000 => format!("CALL 0x{:03X}", nnn),
+        0x3000 => format!("SE V{:X}, 0x{:02X}", x, nn),
+        0x4000 => format!("SNE V{:X}, 0x{:02X}", x, nn),
+        0x5000 => match n {
+            0x0 => format!("SE V{:X}, V{:X}", x, y),
+            0x2 => format!("LD [I], V{:X}-V{:X}", x, y),
+            _ => format!("LD V{:X}-V{:X}, [I]", x, y),
+        }
+        0x6000 => format!("LD V{:X}, 0x{:02X}", x, nn),
+        0x7000 => format!("ADD V{:X}, 0x{:02X}", x, nn),
+        0x8000 => match n {
+            0x0 => format!("LD V{:X}, V{:X}", x, y),
+            0x1 => format!("OR V{:X}, V{:X}", x, y),
+            0x2 => format!("AND V{:X}, V{:X}", x, y),
+            0x3 => format!("XOR V{:X}, V{:X}", x, y),
+            0x4 => format!("ADD V{:X}, V{:X}", x, y),
+            0x5 => format!("SUB V{:X}, V{:X}", x, y),
+            0x6 => format!("SHR V{:X}, V{:X}", x, y),
+            0x7 => format!("SUBN V{:X}, V{:X}", x, y),
+            _ => format!("SHL V{:X}, V{:X}", x, y),
+        }
+        0x9000 => format!("SNE V{:X}, V{:X}", x, y),
+        0xA000 => format!("LD I, 0x{:03X}", nnn),
+        0xB000 => format!("JP V0, 0x{:03X}", nnn),
+        0xC000 => format!("RND V{:X}, 0x{:02X}", x, nn),
+        0xD000 => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        0xE000 => match nn {
+            0x9E => format!("SKP V{:X}", x),
+            _ => format!("SKNP V{:X}", x),
+        }
+        _ => match nn {
+            0x00 => String::from("LD I, long"),
+            0x01 => format!("PLANE {}", x),
+            0x02 => String::from("AUDIO"),
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x30 => format!("LD HF, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x3A => format!("PITCH V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            0x75 => format!("LD R, V{:X}", x),
+            _ => format!("LD V{:X}, R", x),
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Errors the machine runs into while executing a program.
+/// Addresses are the ones of the faulting instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MachineFault {
+    /// 2nnn called with the 16 levels of the stack already used
+    StackOverflow { address: u16 },
+    /// 00EE with an empty stack
+    StackUnderflow { address: u16 },
+    /// Memory read or written past the end of memory
+    MemoryOutOfBounds { address: u16, access: usize },
+    /// The instruction is not a valid CHIP-8 instruction
+    IllegalOpcode { address: u16, opcode: u16 },
+}
+
+impl fmt::Display for MachineFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MachineFault::StackOverflow { address } => write!(f, "Stack overflow at 0x{:03X}", address),
+            MachineFault::StackUnderflow { address } => write!(f, "Stack underflow at 0x{:03X}", address),
+            MachineFault::MemoryOutOfBounds { address, access } => write!(f,
+                "Memory access out of bounds at 0x{:03X}: 0x{:04X}", address, access),
+            MachineFault::IllegalOpcode { address, opcode } => write!(f,
+                "Illegal opcode {:04X} at 0x{:03X}", opcode, address),
+        }
+    }
+}
+
+impl std::error::Error for MachineFault {}
+
+/// What to do when the machine faults
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FaultPolicy {
+    /// Stop execution and show the fault
+    Halt,
+    /// Report the fault and carry on as with `wrap`
+    Log,
+    /// Carry on as the hardware would: the stack and memory addresses wrap around,
+    /// illegal opcodes do nothing
+    Wrap,
+}
@@ -0,0 +1,295 @@
+use std::ffi::{c_char, CString};
+use std::ptr;
+use std::slice;
+
+use crate::chip8::chip8::CHIP8;
+use crate::chip8::platform::Platform;
+
+/// Platform a machine emulates
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub enum Rchip8Platform {
+    Chip8 = 0,
+    Schip = 1,
+    XoChip = 2,
+}
+
+/// Result of the functions of the API. On errors, `rchip8_last_error` describes what failed.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rchip8Status {
+    Ok = 0,
+    NullPointer = 1,
+    InvalidRom = 2,
+    /// The machine faulted. The program counter is left on the faulting instruction.
+    Fault = 3,
+    InvalidState = 4,
+    BufferTooSmall = 5,
+    InvalidKey = 6,
+}
+
+/// A machine, created by `rchip8_new` and freed by `rchip8_free`
+pub struct Rchip8Machine {
+    chip8: CHIP8,
+    /// Description of the last error
+    error: CString,
+}
+
+impl Rchip8Machine {
+    fn fail(&mut self, status: Rchip8Status, message: impl ToString) -> Rchip8Status {
+        let message = message.to_string().replace('\0', "");
+        self.error = CString::new(message).unwrap_or_default();
+        status
+    }
+}
+
+/// Returns a machine emulating `platform`, without program, window nor sound
+#[no_mangle]
+pub extern "C" fn rchip8_new(platform: Rchip8Platform) -> *mut Rchip8Machine {
+    let platform = match platform {
+        Rchip8Platform::Chip8 => Platform::Chip8,
+        Rchip8Platform::Schip => Platform::Schip,
+        Rchip8Platform::XoChip => Platform::XoChip,
+    };
+    let machine = Rchip8Machine {
+        chip8: CHIP8::headless(platform),
+        error: CString::default(),
+    };
+    Box::into_raw(Box::new(machine))
+}
+
+/// Frees a machine returned by `rchip8_new`. Does nothing when `machine` is null.
+#[no_mangle]
+pub unsafe extern "C" fn rchip8_free(machine: *mut Rchip8Machine) {
+    if ! machine.is_null() {
+        drop(Box::from_raw(machine));
+    }
+}
+
+/// Loads the `len` bytes of the ROM at `data` into memory at 0x200
+#[no_mangle]
+pub unsafe extern "C" fn rchip8_load_rom(machine: *mut Rchip8Machine, data: *const u8, len: usize) -> Rchip8Status {
+    let Some(machine) = machine.as_mut() else {
+        return Rchip8Status::NullPointer;
+    };
+    if data.is_null() {
+        return machine.fail(Rchip8Status::NullPointer, "No ROM");
+    }
+
+    match machine.chip8.load_program(slice::from_raw_parts(data, len)) {
+        Ok(()) => Rchip8Status::Ok,
+        Err(e) => machine.fail(Rchip8Status::InvalidRom, e),
+    }
+}
+
+/// Sets the instructions executed per frame, between 1 and 1000
+#[no_mangle]
+pub unsafe extern "C" fn rchip8_set_instructions_per_frame(machine: *mut Rchip8Machine, ipf: u32) -> Rchip8Status {
+    let Some(machine) = machine.as_mut() else {
+        return Rchip8Status::NullPointer;
+    };
+    machine.chip8.set_instructions_per_frame(ipf);
+    Rchip8Status::Ok
+}
+
+/// Executes `cycles` instructions, ending frames and updating the timers every
+/// instructions-per-frame instructions. Stops on faults.
+#[no_mangle]
+pub unsafe extern "C" fn rchip8_step(machine: *mut Rchip8Machine, cycles: u64) -> Rchip8Status {
+    let Some(machine) = machine.as_mut() else {
+        return Rchip8Status::NullPointer;
+    };
+    for _ in 0..cycles {
+        if let Err(fault) = machine.chip8.step() {
+            return machine.fail(Rchip8Status::Fault, fault);
+        }
+    }
+    Rchip8Status::Ok
+}
+
+/// Executes instructions until the frame ends. Stops on faults.
+#[no_mangle]
+pub unsafe extern "C" fn rchip8_run_frame(machine: *mut Rchip8Machine) -> Rchip8Status {
+    let Some(machine) = machine.as_mut() else {
+        return Rchip8Status::NullPointer;
+    };
+    match machine.chip8.step_frame() {
+        Ok(()) => Rchip8Status::Ok,
+        Err(fault) => machine.fail(Rchip8Status::Fault, fault),
+    }
+}
+
+/// Pixels of the display, row by row, one byte per pixel: 1 when lit, 0 otherwise.
+/// The pointer stays valid until the machine runs again, which may change the resolution.
+#[no_mangle]
+pub unsafe extern "C" fn rchip8_framebuffer(machine: *const Rchip8Machine) -> *const u8 {
+    match machine.as_ref() {
+        Some(machine) => machine.chip8.framebuffer().pixels().as_ptr() as *const u8,
+        None => ptr::null(),
+    }
+}
+
+/// Width of the display in pixels: 64, or 128 in hires
+#[no_mangle]
+pub unsafe extern "C" fn rchip8_framebuffer_width(machine: *const Rchip8Machine) -> u32 {
+    machine.as_ref().map_or(0, |machine| machine.chip8.framebuffer().width() as u32)
+}
+
+/// Height of the display in pixels: 32, or 64 in hires
+#[no_mangle]
+pub unsafe extern "C" fn rchip8_framebuffer_height(machine: *const Rchip8Machine) -> u32 {
+    machine.as_ref().map_or(0, |machine| machine.chip8.framebuffer().height() as u32)
+}
+
+/// Holds or releases a key of the keypad, from 0x0 to 0xF
+#[no_mangle]
+pub unsafe extern "C" fn rchip8_set_key(machine: *mut Rchip8Machine, key: u8, pressed: bool) -> Rchip8Status {
+    let Some(machine) = machine.as_mut() else {
+        return Rchip8Status::NullPointer;
+    };
+    if key > 0xF {
+        return machine.fail(Rchip8Status::InvalidKey, format!("Invalid key {}, keys are 0 to F", key));
+    }
+
+    let mut keys = machine.chip8.keys();
+    keys[key as usize] = pressed;
+    machine.chip8.set_keys(keys);
+    Rchip8Status::Ok
+}
+
+/// True while the sound timer runs, and the machine beeps
+#[no_mangle]
+pub unsafe extern "C" fn rchip8_sound_active(machine: *const Rchip8Machine) -> bool {
+    machine.as_ref().is_some_and(|machine| machine.chip8.registers().st > 0)
+}
+
+/// Size of the buffer `rchip8_save_state` needs. It only depends on the platform.
+#[no_mangle]
+pub unsafe extern "C" fn rchip8_state_size(machine: *const Rchip8Machine) -> usize {
+    machine.as_ref().map_or(0, |machine| machine.chip8.save_state().len())
+}
+
+/// Saves the state of the machine into the `len` bytes at `buffer`
+#[no_mangle]
+pub unsafe extern "C" fn rchip8_save_state(machine: *mut Rchip8Machine, buffer: *mut u8, len: usize) -> Rchip8Status {
+    let Some(machine) = machine.as_mut() else {
+        return Rchip8Status::NullPointer;
+    };
+    if buffer.is_null() {
+        return machine.fail(Rchip8Status::NullPointer, "No buffer");
+    }
+
+    let state = machine.chip8.save_state();
+    if len < state.len() {
+        return machine.fail(Rchip8Status::BufferTooSmall, format!("Save states need {} bytes", state.len()));
+    }
+    ptr::copy_nonoverlapping(state.as_ptr(), buffer, state.len());
+    Rchip8Status::Ok
+}
+
+/// Restores a state saved by `rchip8_save_state` on a machine of the same platform
+#[no_mangle]
+pub unsafe extern "C" fn rchip8_load_state(machine: *mut Rchip8Machine, buffer: *const u8, len: usize) -> Rchip8Status {
+    let Some(machine) = machine.as_mut() else {
+        return Rchip8Status::NullPointer;
+    };
+    if buffer.is_null() {
+        return machine.fail(Rchip8Status::NullPointer, "No buffer");
+    }
+
+    match machine.chip8.load_state(slice::from_raw_parts(buffer, len)) {
+        Ok(()) => Rchip8Status::Ok,
+        Err(e) => machine.fail(Rchip8Status::InvalidState, e),
+    }
+}
+
+/// Description of the last error, empty if none. The string stays valid until the next call
+/// on the machine.
+#[no_mangle]
+pub unsafe extern "C" fn rchip8_last_error(machine: *const Rchip8Machine) -> *const c_char {
+    match machine.as_ref() {
+        Some(machine) => machine.error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    /// The header generated by build.rs
+    const HEADER: &str = include_str!(concat!(env!("OUT_DIR"), "/rchip8.h"));
+
+    /// 6007: V0 = 7, then 1202: jump to itself
+    const PROGRAM: [u8; 4] = [0x60, 0x07, 0x12, 0x02];
+
+    fn last_error(machine: *const Rchip8Machine) -> String {
+        unsafe { CStr::from_ptr(rchip8_last_error(machine)) }.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn committed_header_is_up_to_date() {
+        let committed = include_str!("../../include/rchip8.h");
+        assert!(committed == HEADER, "include/rchip8.h is out of date, copy {}/rchip8.h over it", env!("OUT_DIR"));
+    }
+
+    #[test]
+    fn machines_run_and_restore_states() {
+        let machine = rchip8_new(Rchip8Platform::Chip8);
+        unsafe {
+            assert_eq!(rchip8_load_rom(machine, PROGRAM.as_ptr(), PROGRAM.len()), Rchip8Status::Ok);
+            assert_eq!(rchip8_step(machine, 1), Rchip8Status::Ok);
+
+            let size = rchip8_state_size(machine);
+            let mut state = vec![0; size];
+            assert_eq!(rchip8_save_state(machine, state.as_mut_ptr(), size), Rchip8Status::Ok);
+            assert_eq!((*machine).chip8.registers().v[0], 7);
+
+            assert_eq!(rchip8_step(machine, 10), Rchip8Status::Ok);
+            assert_eq!((*machine).chip8.registers().pc, 0x202);
+
+            // A fresh machine picks up where the state left off
+            let other = rchip8_new(Rchip8Platform::Chip8);
+            assert_eq!(rchip8_load_state(other, state.as_ptr(), size), Rchip8Status::Ok);
+            assert_eq!(((*other).chip8.registers().pc, (*other).chip8.registers().v[0]), (0x202, 7));
+            rchip8_free(other);
+            rchip8_free(machine);
+        }
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        let machine = rchip8_new(Rchip8Platform::Chip8);
+        unsafe {
+            assert_eq!(rchip8_load_rom(ptr::null_mut(), PROGRAM.as_ptr(), PROGRAM.len()), Rchip8Status::NullPointer);
+            assert_eq!(rchip8_load_rom(machine, ptr::null(), 0), Rchip8Status::NullPointer);
+            assert_eq!(last_error(machine), "No ROM");
+            assert_eq!(rchip8_load_rom(machine, [0; 4000].as_ptr(), 4000), Rchip8Status::InvalidRom);
+            assert_eq!(rchip8_step(ptr::null_mut(), 1), Rchip8Status::NullPointer);
+
+            let size = rchip8_state_size(machine);
+            let mut state = vec![0; size];
+            assert_eq!(rchip8_save_state(ptr::null_mut(), state.as_mut_ptr(), size), Rchip8Status::NullPointer);
+            assert_eq!(rchip8_save_state(machine, ptr::null_mut(), size), Rchip8Status::NullPointer);
+            assert_eq!(rchip8_save_state(machine, state.as_mut_ptr(), size - 1), Rchip8Status::BufferTooSmall);
+            assert_eq!(last_error(machine), format!("Save states need {} bytes", size));
+
+            assert_eq!(rchip8_load_state(ptr::null_mut(), state.as_ptr(), size), Rchip8Status::NullPointer);
+            assert_eq!(rchip8_load_state(machine, ptr::null(), size), Rchip8Status::NullPointer);
+            assert_eq!(rchip8_load_state(machine, state.as_ptr(), 16), Rchip8Status::InvalidState);
+
+            // F0FF is not an instruction
+            let halted = rchip8_new(Rchip8Platform::Chip8);
+            assert_eq!(rchip8_load_rom(halted, [0xF0, 0xFF].as_ptr(), 2), Rchip8Status::Ok);
+            assert_eq!(rchip8_step(halted, 1), Rchip8Status::Fault);
+            assert!(! last_error(halted).is_empty());
+            rchip8_free(halted);
+
+            assert_eq!(rchip8_state_size(ptr::null()), 0);
+            assert!(rchip8_last_error(ptr::null()).is_null());
+            rchip8_free(ptr::null_mut());
+            rchip8_free(machine);
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use crate::chip8::database;
+
+/// Size of the display in the low resolution of CHIP-8, and in the high resolution of
+/// SUPER-CHIP and XO-CHIP
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+
+/// The pixels of the display. Kept apart from the window so the machine can run headless.
+///
+/// XO-CHIP draws on two bit planes. A pixel is lit when it is lit in either plane, and front ends
+/// color it by the planes it is lit in.
+#[derive(Clone)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<bool>,
+
+    /// Bit 0 is the pixel in plane 1, bit 1 in plane 2
+    planes: Vec<u8>,
+
+    /// Planes drawn, cleared and scrolled, selected by Fn01 of XO-CHIP
+    plane_mask: u8,
+}
+
+impl Framebuffer {
+    /// A cleared display in low resolution
+    pub fn new() -> Self {
+        Framebuffer {
+            width: LORES_WIDTH,
+            height: LORES_HEIGHT,
+            pixels: vec![false; LORES_WIDTH * LORES_HEIGHT],
+            planes: vec![0; LORES_WIDTH * LORES_HEIGHT],
+            plane_mask: 1,
+        }
+    }
+
+    /// Size of the display in pixels
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.width == HIRES_WIDTH
+    }
+
+    /// Switches between 64x32 and 128x64 pixels, clearing both planes
+    pub fn set_hires(&mut self, hires: bool) {
+        (self.width, self.height) = if hires { (HIRES_WIDTH, HIRES_HEIGHT) } else { (LORES_WIDTH, LORES_HEIGHT) };
+        self.pixels = vec![false; self.width * self.height];
+        self.planes = vec![0; self.width * self.height];
+    }
+
+    pub fn plane_mask(&self) -> u8 {
+        self.plane_mask
+    }
+
+    /// Selects the planes drawn, cleared and scrolled: 1, 2, both (3) or none (0)
+    pub fn select_planes(&mut self, mask: u8) {
+        self.plane_mask = mask & 0x3;
+    }
+
+    /// Pixels row by row, true when lit
+    pub fn pixels(&self) -> &[bool] {
+        &self.pixels
+    }
+
+    /// Pixels row by row, as the bits of their planes
+    pub fn planes(&self) -> &[u8] {
+        &self.planes
+    }
+
+    /// Index of the pixel at (x, y) in `pixels`, if it is on the display
+    pub fn index(&self, x: usize, y: usize) -> Option<usize> {
+        (x < self.width && y < self.height).then_some(y * self.width + x)
+    }
+
+    /// SHA-1 hash of the pixels, one byte per pixel, to compare screens
+    pub fn sha1_hex(&self) -> String {
+        let bytes: Vec<u8> = self.pixels.iter().map(|pixel| *pixel as u8).collect();
+        database::sha1_hex(&bytes)
+    }
+
+    /// The screen as text, one line per row, `#` for lit pixels and `.` for dark ones
+    pub fn to_ascii(&self) -> String {
+        let mut text = String::with_capacity((self.width + 1) * self.height);
+        for row in self.pixels.chunks(self.width) {
+            text.extend(row.iter().map(|pixel| if *pixel { '#' } else { '.' }));
+            text.push('\n');
+        }
+        text
+    }
+
+    /// True when the pixel at (x, y) is lit in either plane
+    pub fn get_pixel(&self, x: usize, y: usize) -> bool {
+        self.index(x, y).is_some_and(|index| self.pixels[index])
+    }
+
+    /// Lights or darkens the pixel at (x, y) in the selected planes
+    pub fn set_pixel(&mut self, x: usize, y: usize, lit: bool) {
+        if let Some(index) = self.index(x, y) {
+            let bits = if lit { self.planes[index] | self.plane_mask } else { self.planes[index] & ! self.plane_mask };
+            self.set_bits(index, bits);
+        }
+    }
+
+    fn set_bits(&mut self, index: usize, bits: u8) {
+        self.planes[index] = bits;
+        self.pixels[index] = bits != 0;
+    }
+
+    /// Darkens the selected planes
+    pub fn clear(&mut self) {
+        for index in 0..self.planes.len() {
+            self.set_bits(index, self.planes[index] & ! self.plane_mask);
+        }
+    }
+
+    /// Replaces the planes of the pixels, row by row, e.g. from a save state. Their number sets
+    /// the resolution.
+    pub fn set_planes(&mut self, planes: &[u8]) -> Result<(), String> {
+        match planes.len() {
+            len if len == LORES_WIDTH * LORES_HEIGHT => self.set_hires(false),
+            len if len == HIRES_WIDTH * HIRES_HEIGHT => self.set_hires(true),
+            len => return Err(format!("{} pixels are neither 64x32 nor 128x64", len)),
+        }
+        for (index, bits) in planes.iter().enumerate() {
+            self.set_bits(index, bits & 0x3);
+        }
+        Ok(())
+    }
+
+    /// Number of bytes sprites of `len` bytes take in memory: one sprite per selected plane
+    pub fn sprite_bytes(&self, len: usize) -> usize {
+        len * self.plane_mask.count_ones() as usize
+    }
+
+    /// Draws an n-byte sprite read from memory at I, with its top left corner at (x, y), on each
+    /// selected plane, the sprite of plane 2 following the one of plane 1.
+    /// Pixels past the edges wrap around or are clipped. Returns true if any pixel was erased.
+    pub fn draw_sprite(&mut self, mem: &[u8], x: u8, y: u8, n: u8, i: u16, wrap: bool) -> bool {
+        let byte = |offset: usize| mem[(i as usize + offset) % mem.len()] as u16;
+        let n = usize::from(n);
+        self.draw_planes(n, |sprite, row| byte(sprite * n + row) << 8, x, y, 8, wrap)
+    }
+
+    /// Draws the 16x16 sprite of SUPER-CHIP, two bytes per row, read from memory at I
+    pub fn draw_large_sprite(&mut self, mem: &[u8], x: u8, y: u8, i: u16, wrap: bool) -> bool {
+        let byte = |offset: usize| mem[(i as usize + offset) % mem.len()] as u16;
+        self.draw_planes(16, |sprite, row| byte(32 * sprite + 2 * row) << 8 | byte(32 * sprite + 2 * row + 1), x, y, 16, wrap)
+    }
+
+    /// Draws `rows` rows, given by `row(sprite, row)`, on each selected plane
+    fn draw_planes(&mut self, rows: usize, row: impl Fn(usize, usize) -> u16, x: u8, y: u8, cols: usize, wrap: bool) -> bool {
+        let mut collision = false;
+        let mut sprite = 0;
+        for plane in [1, 2] {
+            if self.plane_mask & plane == 0 {
+                continue;
+            }
+            let sprite_rows: Vec<u16> = (0..rows).map(|r| row(sprite, r)).collect();
+            collision |= self.draw_rows(plane, &sprite_rows, x, y, cols, wrap);
+            sprite += 1;
+        }
+        collision
+    }
+
+    /// XORs rows of up to 16 pixels, most significant bit on the left, onto a plane
+    fn draw_rows(&mut self, plane: u8, rows: &[u16], x: u8, y: u8, cols: usize, wrap: bool) -> bool {
+        let (x, y) = (x as usize % self.width, y as usize % self.height);
+        let mut collision = false;
+
+        for (row, sprite_row) in rows.iter().enumerate() {
+            let mut py = y + row;
+            if py >= self.height {
+                if ! wrap {
+                    break;
+                }
+                py %= self.height;
+            }
+
+            for col in 0..cols {
+                if sprite_row & (0x8000 >> col) == 0 {
+                    continue;
+                }
+
+                let mut px = x + col;
+                if px >= self.width {
+                    if ! wrap {
+                        break;
+                    }
+                    px %= self.width;
+                }
+
+                let index = py * self.width + px;
+                collision |= self.planes[index] & plane != 0;
+                self.set_bits(index, self.planes[index] ^ plane);
+            }
+        }
+
+        collision
+    }
+
+    /// Moves the selected planes down by n pixels, darkening the rows scrolled in
+    pub fn scroll_down(&mut self, n: usize) {
+        self.scroll(|x, y| y.checked_sub(n).map(|y| (x, y)));
+    }
+
+    /// Moves the selected planes up by n pixels
+    pub fn scroll_up(&mut self, n: usize) {
+        self.scroll(|x, y| Some((x, y + n)));
+    }
+
+    /// Moves the selected planes right by n pixels
+    pub fn scroll_right(&mut self, n: usize) {
+        self.scroll(|x, y| x.checked_sub(n).map(|x| (x, y)));
+    }
+
+    /// Moves the selected planes left by n pixels
+    pub fn scroll_left(&mut self, n: usize) {
+        self.scroll(|x, y| Some((x + n, y)));
+    }
+
+    /// Moves the selected planes, each pixel taking the bits of the pixel at `from(x, y)`, or
+    /// darkening when that pixel is off the display
+    fn scroll(&mut self, from: impl Fn(usize, usize) -> Option<(usize, usize)>) {
+        let planes = self.planes.clone();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let source = from(x, y).and_then(|(x, y)| self.index(x, y)).map_or(0, |index| planes[index]);
+                let index = y * self.width + x;
+                self.set_bits(index, planes[index] & ! self.plane_mask | source & self.plane_mask);
+            }
+        }
+    }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolution_changes_clear_the_display() {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.set_pixel(63, 31, true);
+        assert_eq!((framebuffer.width(), framebuffer.height(), framebuffer.pixels().len()), (64, 32, 2048));
+
+        framebuffer.set_hires(true);
+        assert!(framebuffer.is_hires());
+        assert_eq!((framebuffer.width(), framebuffer.height(), framebuffer.pixels().len()), (128, 64, 8192));
+        assert!(! framebuffer.pixels().contains(&true));
+    }
+
+    #[test]
+    fn sprites_wrap_at_the_edges_of_the_resolution() {
+        let sprite = [0xC0];
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.draw_sprite(&sprite, 127, 0, 1, 0, true);
+        assert!(framebuffer.get_pixel(63, 0) && framebuffer.get_pixel(0, 0));
+
+        framebuffer.set_hires(true);
+        framebuffer.draw_sprite(&sprite, 127, 0, 1, 0, true);
+        assert!(framebuffer.get_pixel(127, 0) && framebuffer.get_pixel(0, 0));
+        assert!(! framebuffer.get_pixel(63, 0));
+
+        framebuffer.clear();
+        framebuffer.draw_sprite(&sprite, 127, 63, 1, 0, false);
+        assert_eq!(framebuffer.pixels().iter().filter(|pixel| **pixel).count(), 1);
+    }
+
+    #[test]
+    fn pixels_set_the_resolution() {
+        let mut framebuffer = Framebuffer::new();
+        assert!(framebuffer.set_planes(&[1; 128 * 64]).is_ok());
+        assert!(framebuffer.is_hires() && framebuffer.get_pixel(127, 63));
+        assert!(framebuffer.set_planes(&[0; 64 * 32]).is_ok());
+        assert!(! framebuffer.is_hires());
+        assert!(framebuffer.set_planes(&[0; 10]).is_err());
+    }
+
+    #[test]
+    fn large_sprites_are_16_pixels_square() {
+        let sprite = [0xFF; 32];
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.set_hires(true);
+        assert!(! framebuffer.draw_large_sprite(&sprite, 0, 0, 0, true));
+        assert_eq!(framebuffer.pixels().iter().filter(|pixel| **pixel).count(), 256);
+        assert!(framebuffer.get_pixel(15, 15) && ! framebuffer.get_pixel(16, 0));
+        assert!(framebuffer.draw_large_sprite(&sprite, 8, 8, 0, true));
+    }
+
+    #[test]
+    fn scrolling_darkens_the_pixels_scrolled_in() {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.set_pixel(0, 0, true);
+        framebuffer.scroll_down(4);
+        assert!(framebuffer.get_pixel(0, 4) && ! framebuffer.get_pixel(0, 0));
+        framebuffer.scroll_right(4);
+        assert!(framebuffer.get_pixel(4, 4) && ! framebuffer.get_pixel(0, 4));
+        framebuffer.scroll_left(4);
+        framebuffer.scroll_up(4);
+        assert!(framebuffer.get_pixel(0, 0));
+        assert_eq!(framebuffer.pixels().iter().filter(|pixel| **pixel).count(), 1);
+
+        framebuffer.scroll_left(1);
+        assert!(! framebuffer.get_pixel(63, 0));
+        assert!(! framebuffer.pixels().contains(&true));
+    }
+
+    #[test]
+    fn planes_are_drawn_cleared_and_scrolled_when_selected() {
+        let sprite = [0x80, 0x40];
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.select_planes(3);
+        assert_eq!(framebuffer.sprite_bytes(1), 2);
+        framebuffer.draw_sprite(&sprite, 0, 0, 1, 0, true);
+        assert_eq!((framebuffer.planes()[0], framebuffer.planes()[1]), (1, 2));
+
+        framebuffer.select_planes(2);
+        assert!(framebuffer.draw_sprite(&sprite, 1, 0, 1, 0, true));
+        assert_eq!(framebuffer.planes()[1], 0);
+        framebuffer.draw_sprite(&sprite, 0, 0, 1, 0, true);
+        framebuffer.scroll_down(1);
+        assert_eq!((framebuffer.planes()[0], framebuffer.planes()[64]), (1, 2));
+
+        framebuffer.clear();
+        assert!(framebuffer.get_pixel(0, 0) && ! framebuffer.get_pixel(0, 1));
+
+        framebuffer.select_planes(0);
+        assert!(! framebuffer.draw_sprite(&sprite, 0, 0, 1, 0, true));
+        assert!(framebuffer.get_pixel(0, 0));
+    }
+}
@@ -0,0 +1,110 @@
+use crate::chip8::database::Palette;
+use crate::chip8::debugger::DebugView;
+use crate::chip8::framebuffer::Framebuffer;
+use crate::chip8::synth::SamplePattern;
+
+/// Where the machine is shown and the keypad read from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// SDL window, with a debug window from debug level 2
+    Sdl,
+    /// The terminal, with the registers alongside the display from debug level 1
+    Terminal,
+    /// Nothing shown and no input, e.g. to debug with gdb
+    Null,
+    /// Frames written to an animated GIF and the beep to a WAV file, without input
+    Recording,
+}
+
+/// What the user asks for from the keyboard or the mouse
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InputEvent {
+    Quit,
+    /// A keyboard key bound to the keypad, by SDL key name, e.g. `Q` or `Up`
+    Key { name: String, pressed: bool },
+    Reset,
+    Step,
+    TogglePause,
+    SlowDown,
+    SpeedUp,
+    NormalSpeed,
+    FastForward(bool),
+    MoreInstructions,
+    FewerInstructions,
+    /// A click in a debug window, at window coordinates
+    DebugClick { window: u32, x: i32, y: i32 },
+    /// Lines to scroll the disassembly of a debug window by
+    DebugScroll { window: u32, lines: i32 },
+}
+
+/// Where the machine shows its display and, when debugging, its state
+pub trait VideoSink {
+    fn render(&mut self, framebuffer: &Framebuffer);
+
+    fn render_debug(&mut self, view: &DebugView);
+
+    fn clear(&mut self);
+
+    fn set_title(&mut self, title: &str);
+
+    fn set_palette(&mut self, palette: Palette);
+
+    /// True when presenting a frame waits for the display refresh, pacing the emulation
+    fn vsync(&self) -> bool {
+        false
+    }
+
+    /// Returns the address of the instruction clicked in a debug window, if any
+    fn debug_click(&self, _window: u32, _x: i32, _y: i32) -> Option<u16> {
+        None
+    }
+
+    fn debug_scroll(&mut self, _window: u32, _lines: i32) {}
+}
+
+/// Where the machine plays its beep
+pub trait AudioSink {
+    /// Called at the end of every frame, beeping while the sound timer runs
+    fn frame(&mut self, beeping: bool);
+
+    /// Called when XO-CHIP loads a sample pattern or changes its pitch. Without a pattern, the
+    /// beep is a tone.
+    fn set_pattern(&mut self, _pattern: Option<SamplePattern>) {}
+}
+
+/// Where the machine reads the keypad and the emulator hotkeys from
+pub trait InputSource {
+    /// Returns the events since the last call, without blocking
+    fn poll(&mut self) -> Vec<InputEvent>;
+}
+
+/// Shows nothing
+pub struct NullVideo;
+
+impl VideoSink for NullVideo {
+    fn render(&mut self, _framebuffer: &Framebuffer) {}
+
+    fn render_debug(&mut self, _view: &DebugView) {}
+
+    fn clear(&mut self) {}
+
+    fn set_title(&mut self, _title: &str) {}
+
+    fn set_palette(&mut self, _palette: Palette) {}
+}
+
+/// Plays nothing
+pub struct NullAudio;
+
+impl AudioSink for NullAudio {
+    fn frame(&mut self, _beeping: bool) {}
+}
+
+/// Never reports any event
+pub struct NullInput;
+
+impl InputSource for NullInput {
+    fn poll(&mut self) -> Vec<InputEvent> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,440 @@
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::chip8::chip8::{Registers, CHIP8};
+use crate::chip8::fault::MachineFault;
+
+/// Registers in the order of the `g` packet: V0 to VF, I, PC, SP, DT, ST.
+/// Values are sent little endian, 16 bit registers first byte low.
+const REGISTER_COUNT: usize = 21;
+const REGISTER_I: usize = 16;
+const REGISTER_PC: usize = 17;
+const REGISTER_SP: usize = 18;
+const REGISTER_DT: usize = 19;
+const REGISTER_ST: usize = 20;
+
+/// Signals reported to gdb when the machine stops
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rchip8.chip8">
+    <reg name="v0" bitsize="8" regnum="0"/>
+    <reg name="v1" bitsize="8"/>
+    <reg name="v2" bitsize="8"/>
+    <reg name="v3" bitsize="8"/>
+    <reg name="v4" bitsize="8"/>
+    <reg name="v5" bitsize="8"/>
+    <reg name="v6" bitsize="8"/>
+    <reg name="v7" bitsize="8"/>
+    <reg name="v8" bitsize="8"/>
+    <reg name="v9" bitsize="8"/>
+    <reg name="va" bitsize="8"/>
+    <reg name="vb" bitsize="8"/>
+    <reg name="vc" bitsize="8"/>
+    <reg name="vd" bitsize="8"/>
+    <reg name="ve" bitsize="8"/>
+    <reg name="vf" bitsize="8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="dt" bitsize="8"/>
+    <reg name="st" bitsize="8"/>
+  </feature>
+</target>
+"#;
+
+/// GDB remote serial protocol server. Polled from the run loop, it never blocks the emulation.
+pub struct GdbStub {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    buffer: Vec<u8>,
+
+    /// Set when gdb asked to continue, until the machine stops
+    running: bool,
+}
+
+impl GdbStub {
+    /// Listens for gdb on localhost
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        println!("Waiting for gdb on port {}...", port);
+
+        Ok(GdbStub {
+            listener: listener,
+            client: None,
+            buffer: Vec::new(),
+            running: false,
+        })
+    }
+
+    /// Accepts a connection, handles the packets received since the last call and reports
+    /// the machine stopping after a continue.
+    pub fn poll(&mut self, chip8: &mut CHIP8) {
+        if self.client.is_none() {
+            self.accept(chip8);
+            return;
+        }
+
+        if let Err(e) = self.receive(chip8) {
+            println!("gdb disconnected: {}", e);
+            self.disconnect(chip8);
+            return;
+        }
+
+        if self.running && chip8.is_paused() {
+            self.running = false;
+            let signal = stop_signal(chip8);
+            self.send(&format!("S{:02x}", signal));
+        }
+    }
+
+    fn accept(&mut self, chip8: &mut CHIP8) {
+        match self.listener.accept() {
+            Ok((stream, address)) => {
+                if stream.set_nonblocking(true).is_err() {
+                    return;
+                }
+                stream.set_nodelay(true).ok();
+                println!("gdb connected from {}", address);
+                self.client = Some(stream);
+                self.buffer.clear();
+                self.running = false;
+                // gdb expects the target to be stopped when it attaches
+                if ! chip8.is_paused() {
+                    chip8.pause();
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => eprintln!("gdb: {}", e),
+        }
+    }
+
+    fn disconnect(&mut self, chip8: &mut CHIP8) {
+        self.client = None;
+        self.running = false;
+        if chip8.is_paused() {
+            chip8.resume();
+        }
+    }
+
+    /// Reads what is available on the socket and handles every complete packet
+    fn receive(&mut self, chip8: &mut CHIP8) -> io::Result<()> {
+        let mut data = [0; 4096];
+        loop {
+            let Some(client) = &mut self.client else {
+                return Ok(());
+            };
+            match client.read(&mut data) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed")),
+                Ok(n) => self.buffer.extend_from_slice(&data[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        while let Some(packet) = self.next_packet(chip8) {
+            if let Some(reply) = self.handle(&packet, chip8) {
+                self.send(&reply);
+            }
+            if self.client.is_none() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Extracts the next `$data#checksum` packet from the buffer, acknowledging it.
+    /// Handles the interrupt byte sent by Ctrl-C on the way.
+    fn next_packet(&mut self, chip8: &mut CHIP8) -> Option<String> {
+        loop {
+            let start = self.buffer.iter().position(|b| *b == b'$' || *b == 0x03)?;
+            if self.buffer[start] == 0x03 {
+                self.buffer.drain(..=start);
+                if ! chip8.is_paused() {
+                    chip8.pause();
+                }
+                self.running = false;
+                self.send(&format!("S{:02x}", SIGINT));
+                continue;
+            }
+
+            let end = start + self.buffer[start..].iter().position(|b| *b == b'#')?;
+            if self.buffer.len() < end + 3 {
+                return None;
+            }
+
+            let data = self.buffer[start + 1..end].to_vec();
+            let checksum = std::str::from_utf8(&self.buffer[end + 1..end + 3]).ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            self.buffer.drain(..end + 3);
+
+            if checksum != Some(data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))) {
+                self.write(b"-");
+                continue;
+            }
+
+            self.write(b"+");
+            return Some(String::from_utf8_lossy(&data).into_owned());
+        }
+    }
+
+    /// Returns the reply to a packet. Unsupported packets get an empty reply.
+    fn handle(&mut self, packet: &str, chip8: &mut CHIP8) -> Option<String> {
+        let (command, arguments) = packet.split_at(packet.len().min(1));
+
+        let reply = match command {
+            "?" => format!("S{:02x}", stop_signal(chip8)),
+            "g" => encode_registers(&chip8.registers()),
+            "G" => match decode_registers(arguments, chip8.registers()) {
+                Some(registers) => {
+                    chip8.set_registers(&registers);
+                    String::from("OK")
+                }
+                None => String::from("E01"),
+            },
+            "p" => match usize::from_str_radix(arguments, 16).ok().and_then(|n| read_register(&chip8.registers(), n)) {
+                Some(value) => value,
+                None => String::from("E01"),
+            },
+            "P" => {
+                let mut registers = chip8.registers();
+                let written = arguments.split_once('=')
+                    .and_then(|(n, value)| Some((usize::from_str_radix(n, 16).ok()?, decode_hex(value)?)))
+                    .and_then(|(n, bytes)| write_register(&mut registers, n, &bytes));
+                match written {
+                    Some(()) => {
+                        chip8.set_registers(&registers);
+                        String::from("OK")
+                    }
+                    None => String::from("E01"),
+                }
+            }
+            "m" => match parse_range(arguments).and_then(|(address, length)| read_memory(&chip8.ram, address, length)) {
+                Some(bytes) => encode_hex(&bytes),
+                None => String::from("E01"),
+            },
+            "M" => {
+                let written = arguments.split_once(':')
+                    .and_then(|(range, data)| Some((parse_range(range)?, decode_hex(data)?)))
+                    .filter(|((address, length), data)| data.len() == *length && address.checked_add(*length).is_some_and(|end| end <= chip8.ram.len()));
+                match written {
+                    Some(((address, _), data)) => {
+                        chip8.ram[address..address + data.len()].copy_from_slice(&data);
+                        String::from("OK")
+                    }
+                    None => String::from("E01"),
+                }
+            }
+            "Z" | "z" => match parse_breakpoint(arguments) {
+                Some(address) => {
+                    chip8.set_breakpoint(address, command == "Z");
+                    String::from("OK")
+                }
+                None => String::new(),
+            },
+            "s" => {
+                chip8.single_step();
+                format!("S{:02x}", stop_signal(chip8))
+            }
+            "c" => {
+                // Stops are reported by `poll` once the machine pauses again
+                self.running = true;
+                chip8.resume();
+                return None;
+            }
+            "D" => {
+                self.send("OK");
+                println!("gdb detached");
+                self.disconnect(chip8);
+                return None;
+            }
+            "k" => {
+                println!("gdb killed the session");
+                self.disconnect(chip8);
+                return None;
+            }
+            "H" => String::from("OK"),
+            "q" => query(arguments),
+            _ => String::new(),
+        };
+
+        Some(reply)
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        if let Some(client) = &mut self.client {
+            if let Err(e) = client.write_all(data) {
+                eprintln!("gdb: {}", e);
+            }
+        }
+    }
+
+    fn send(&mut self, reply: &str) {
+        let checksum = reply.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        self.write(format!("${}#{:02x}", reply, checksum).as_bytes());
+    }
+}
+
+/// Replies to `q` packets
+fn query(query: &str) -> String {
+    if query.starts_with("Supported") {
+        return String::from("PacketSize=1000;qXfer:features:read+");
+    }
+
+    if let Some(annex) = query.strip_prefix("Xfer:features:read:target.xml:") {
+        let Some((offset, length)) = parse_range(annex) else {
+            return String::from("E01");
+        };
+        let xml = TARGET_XML.as_bytes();
+        if offset >= xml.len() {
+            return String::from("l");
+        }
+        let end = (offset + length).min(xml.len());
+        let marker = if end == xml.len() { "l" } else { "m" };
+        return format!("{}{}", marker, String::from_utf8_lossy(&xml[offset..end]));
+    }
+
+    match query {
+        "Attached" => String::from("1"),
+        "C" => String::from("QC1"),
+        "fThreadInfo" => String::from("m1"),
+        "sThreadInfo" => String::from("l"),
+        _ => String::new(),
+    }
+}
+
+/// Signal telling gdb why the machine stopped
+fn stop_signal(chip8: &CHIP8) -> u8 {
+    match chip8.fault() {
+        Some(MachineFault::IllegalOpcode { .. }) => SIGILL,
+        Some(_) => SIGSEGV,
+        None => SIGTRAP,
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if ! hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Parses `address,length`
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let (address, length) = range.split_once(',')?;
+    Some((usize::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+/// Parses the `0,address,kind` of a software breakpoint packet
+fn parse_breakpoint(arguments: &str) -> Option<u16> {
+    let mut fields = arguments.split(',');
+    if fields.next()? != "0" {
+        return None;
+    }
+    u16::from_str_radix(fields.next()?, 16).ok()
+}
+
+fn read_memory(ram: &[u8], address: usize, length: usize) -> Option<Vec<u8>> {
+    if address >= ram.len() {
+        return None;
+    }
+    let end = address.saturating_add(length).min(ram.len());
+    Some(ram[address..end].to_vec())
+}
+
+fn register_bytes(registers: &Registers, n: usize) -> Option<Vec<u8>> {
+    match n {
+        0..=15 => Some(vec![registers.v[n]]),
+        REGISTER_I => Some(registers.i.to_le_bytes().to_vec()),
+        REGISTER_PC => Some(registers.pc.to_le_bytes().to_vec()),
+        REGISTER_SP => Some(vec![registers.sp as u8]),
+        REGISTER_DT => Some(vec![registers.dt]),
+        REGISTER_ST => Some(vec![registers.st]),
+        _ => None,
+    }
+}
+
+fn read_register(registers: &Registers, n: usize) -> Option<String> {
+    register_bytes(registers, n).map(|bytes| encode_hex(&bytes))
+}
+
+fn write_register(registers: &mut Registers, n: usize, bytes: &[u8]) -> Option<()> {
+    if bytes.len() != register_bytes(registers, n)?.len() {
+        return None;
+    }
+    match n {
+        0..=15 => registers.v[n] = bytes[0],
+        REGISTER_I => registers.i = u16::from_le_bytes([bytes[0], bytes[1]]),
+        REGISTER_PC => registers.pc = u16::from_le_bytes([bytes[0], bytes[1]]),
+        REGISTER_SP => registers.sp = bytes[0] as usize,
+        REGISTER_DT => registers.dt = bytes[0],
+        _ => registers.st = bytes[0],
+    }
+    Some(())
+}
+
+fn encode_registers(registers: &Registers) -> String {
+    (0..REGISTER_COUNT).filter_map(|n| read_register(registers, n)).collect()
+}
+
+fn decode_registers(hex: &str, mut registers: Registers) -> Option<Registers> {
+    let bytes = decode_hex(hex)?;
+    let mut offset = 0;
+    for n in 0..REGISTER_COUNT {
+        let size = register_bytes(&registers, n)?.len();
+        write_register(&mut registers, n, bytes.get(offset..offset + size)?)?;
+        offset += size;
+    }
+    Some(registers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::platform::Platform;
+
+    fn machine(program: &[u8]) -> CHIP8 {
+        let mut chip8 = CHIP8::headless(Platform::Chip8);
+        chip8.load_program(program).unwrap();
+        chip8
+    }
+
+    #[test]
+    fn memory_writes_must_fit_in_memory() {
+        let mut gdb = GdbStub::listen(0).unwrap();
+        let mut chip8 = machine(&[0x00, 0xE0]);
+        assert_eq!(gdb.handle("M200,2:abcd", &mut chip8).as_deref(), Some("OK"));
+        assert_eq!(&chip8.ram[0x200..0x202], &[0xAB, 0xCD]);
+        assert_eq!(gdb.handle("Mfff,2:abcd", &mut chip8).as_deref(), Some("E01"));
+        assert_eq!(gdb.handle("Mffffffffffffffff,1:00", &mut chip8).as_deref(), Some("E01"));
+    }
+
+    #[test]
+    fn steps_end_the_frame_after_ipf_instructions() {
+        let mut gdb = GdbStub::listen(0).unwrap();
+        // V0 := 10, DT := V0, then V1 := 1 until the frame ends
+        let mut chip8 = machine(&[0x60, 0x0A, 0xF0, 0x15, 0x61, 0x01, 0x61, 0x01]);
+        chip8.set_instructions_per_frame(3);
+
+        for _ in 0..2 {
+            assert_eq!(gdb.handle("s", &mut chip8).as_deref(), Some("S05"));
+        }
+        assert_eq!((chip8.frame_count(), chip8.registers().dt), (0, 10));
+
+        gdb.handle("s", &mut chip8);
+        assert_eq!((chip8.frame_count(), chip8.registers().dt), (1, 9));
+        assert_eq!(chip8.registers().pc, 0x206);
+    }
+}
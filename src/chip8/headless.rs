@@ -0,0 +1,231 @@
+use std::fs;
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::chip8::chip8::CHIP8;
+use crate::chip8::database::Palette;
+use crate::chip8::fault::MachineFault;
+use crate::chip8::framebuffer::Framebuffer;
+
+/// How the final screen of a headless run is written
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ScreenFormat {
+    /// One line per row, # for lit pixels
+    Ascii,
+    /// SHA-1 hash of the pixels, one byte per pixel
+    Hash,
+    /// Grayscale PNG image, one image pixel per screen pixel
+    Png,
+    /// Don't write the screen
+    None,
+}
+
+/// Keys held on the keypad from given frames on, for headless runs.
+///
+/// One line per change: the frame, counted from 0, then the hex keys held from that frame until
+/// the next line. A frame alone releases every key. Blank lines and lines starting with # are
+/// ignored.
+///
+/// ```text
+/// 0
+/// 60 5      # hold 5 at frame 60
+/// 65 4 6    # release 5, hold 4 and 6
+/// 90        # release everything
+/// ```
+pub struct KeyScript {
+    changes: Vec<(u64, [bool; 16])>,
+}
+
+impl KeyScript {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Could not read key script {}: {}", path, e))?;
+        KeyScript::parse(&text).map_err(|e| format!("{}:{}", path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut changes = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let frame = words.next().unwrap_or("");
+            let frame: u64 = frame.parse().map_err(|_| format!("{}: invalid frame {}", number + 1, frame))?;
+
+            let mut keys = [false; 16];
+            for word in words {
+                match u8::from_str_radix(word, 16) {
+                    Ok(key) if key < 16 => keys[key as usize] = true,
+                    _ => return Err(format!("{}: invalid key {}, keys are 0 to F", number + 1, word)),
+                }
+            }
+            changes.push((frame, keys));
+        }
+
+        Ok(KeyScript { changes: changes })
+    }
+
+    /// Keys to hold from `frame` on, if they change at that frame
+    pub fn keys_at(&self, frame: u64) -> Option<[bool; 16]> {
+        self.changes.iter().rev().find(|(at, _)| *at == frame).map(|(_, keys)| *keys)
+    }
+}
+
+/// Writes the screen to `output`, or to stdout
+pub fn write_screen(framebuffer: &Framebuffer, format: ScreenFormat, output: Option<&str>) -> Result<(), String> {
+    let bytes = match format {
+        ScreenFormat::None => return Ok(()),
+        ScreenFormat::Ascii => framebuffer.to_ascii().into_bytes(),
+        ScreenFormat::Hash => format!("{}\n", framebuffer.sha1_hex()).into_bytes(),
+        ScreenFormat::Png if output.is_none() => return Err(String::from("PNG screens need --screen-file")),
+        ScreenFormat::Png => encode_png(framebuffer).map_err(|e| format!("Could not encode the screen: {}", e))?,
+    };
+
+    match output {
+        Some(path) => fs::write(path, bytes).map_err(|e| format!("Could not write {}: {}", path, e)),
+        None => std::io::stdout().write_all(&bytes).map_err(|e| e.to_string()),
+    }
+}
+
+fn encode_png(framebuffer: &Framebuffer) -> Result<Vec<u8>, png::EncodingError> {
+    let mut image = Vec::new();
+    let mut encoder = png::Encoder::new(&mut image, framebuffer.width() as u32, framebuffer.height() as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    // White for plane 1, as with the default palette, and grays for plane 2 and both planes
+    let pixels: Vec<u8> = framebuffer.planes().iter().map(|planes| Palette::default().color(*planes)[0]).collect();
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+    Ok(image)
+}
+
+/// State of the machine at the end of a headless run, printed as JSON
+#[derive(Serialize)]
+pub struct MachineState {
+    pub cycles: u64,
+    pub frames: u64,
+    pub pc: u16,
+    pub i: u16,
+    pub v: [u8; 16],
+    pub stack: Vec<u16>,
+    pub dt: u8,
+    pub st: u8,
+    /// Keys held on the keypad
+    pub keys: Vec<u8>,
+    pub screen_hash: String,
+    pub fault: Option<String>,
+}
+
+impl MachineState {
+    pub fn of(chip8: &CHIP8, fault: Option<&MachineFault>) -> Self {
+        let registers = chip8.registers();
+        MachineState {
+            cycles: chip8.instruction_count(),
+            frames: chip8.frame_count(),
+            pc: registers.pc,
+            i: registers.i,
+            v: registers.v,
+            stack: chip8.stack().to_vec(),
+            dt: registers.dt,
+            st: registers.st,
+            keys: (0..16).filter(|key| chip8.keys()[*key as usize]).collect(),
+            screen_hash: chip8.framebuffer().sha1_hex(),
+            fault: fault.map(|fault| fault.to_string()),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Machine state is serializable")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_scripts_hold_keys_from_their_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.txt");
+        fs::write(&path, "# Start\n0\n\n60 5      # hold 5\n65 4 a\n90\n").unwrap();
+        let script = KeyScript::load(path.to_str().unwrap()).unwrap();
+
+        assert_eq!(script.keys_at(0), Some([false; 16]));
+        assert_eq!(script.keys_at(1), None);
+        let keys = script.keys_at(60).unwrap();
+        assert!(keys[5] && keys.iter().filter(|held| **held).count() == 1);
+        let keys = script.keys_at(65).unwrap();
+        assert!(keys[4] && keys[0xA] && ! keys[5]);
+        assert_eq!(script.keys_at(90), Some([false; 16]));
+        assert_eq!(script.keys_at(91), None);
+    }
+
+    #[test]
+    fn key_script_errors_give_their_line() {
+        assert_eq!(KeyScript::parse("0\nx 5").err().unwrap(), "2: invalid frame x");
+        assert_eq!(KeyScript::parse("# keys\n10 10").err().unwrap(), "2: invalid key 10, keys are 0 to F");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.txt");
+        fs::write(&path, "1 G").unwrap();
+        let path = path.to_str().unwrap();
+        assert_eq!(KeyScript::load(path).err().unwrap(), format!("{}:1: invalid key G, keys are 0 to F", path));
+        assert!(KeyScript::load(dir.path().join("missing").to_str().unwrap()).is_err());
+    }
+
+    fn screen() -> Framebuffer {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.set_pixel(1, 0, true);
+        framebuffer.set_pixel(63, 31, true);
+        framebuffer
+    }
+
+    #[test]
+    fn screens_are_written_as_text_and_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("screen");
+        let path = path.to_str().unwrap();
+        let framebuffer = screen();
+
+        write_screen(&framebuffer, ScreenFormat::Ascii, Some(path)).unwrap();
+        let text = fs::read_to_string(path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 32);
+        assert_eq!(lines[0], format!(".#{}", ".".repeat(62)));
+        assert_eq!(lines[31], format!("{}#", ".".repeat(63)));
+
+        write_screen(&framebuffer, ScreenFormat::Hash, Some(path)).unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), format!("{}\n", framebuffer.sha1_hex()));
+        assert_ne!(framebuffer.sha1_hex(), Framebuffer::new().sha1_hex());
+
+        fs::remove_file(path).unwrap();
+        write_screen(&framebuffer, ScreenFormat::None, Some(path)).unwrap();
+        assert!(fs::metadata(path).is_err());
+    }
+
+    #[test]
+    fn screens_are_written_as_grayscale_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("screen.png");
+        let path = path.to_str().unwrap();
+        assert!(write_screen(&screen(), ScreenFormat::Png, None).is_err());
+        write_screen(&screen(), ScreenFormat::Png, Some(path)).unwrap();
+
+        let decoder = png::Decoder::new(std::io::BufReader::new(fs::File::open(path).unwrap()));
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height, info.color_type), (64, 32, png::ColorType::Grayscale));
+
+        let (dark, lit) = (Palette::default().color(0)[0], Palette::default().color(1)[0]);
+        assert_eq!(pixels[..3], [dark, lit, dark]);
+        assert_eq!(pixels[64 * 32 - 1], lit);
+        assert_eq!(pixels.iter().filter(|pixel| **pixel == lit).count(), 2);
+    }
+}
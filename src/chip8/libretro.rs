@@ -0,0 +1,593 @@
+use std::ffi::{c_char, c_uint, c_void, CStr};
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+
+use clap::ValueEnum;
+
+use crate::chip8::chip8::CHIP8;
+use crate::chip8::config::{Config, ConfigLayer};
+use crate::chip8::database::{Database, Palette, RomInfo};
+use crate::chip8::fault::MachineFault;
+use crate::chip8::framebuffer::{HIRES_HEIGHT, HIRES_WIDTH, LORES_HEIGHT, LORES_WIDTH};
+use crate::chip8::platform::Platform;
+use crate::chip8::quirks::QuirkOverrides;
+use crate::chip8::rom;
+use crate::chip8::synth::Tone;
+
+const RETRO_API_VERSION: c_uint = 1;
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_REGION_NTSC: c_uint = 0;
+const RETRO_MEMORY_SYSTEM_RAM: c_uint = 2;
+const RETRO_PIXEL_FORMAT_XRGB8888: c_uint = 1;
+
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_ENVIRONMENT_GET_VARIABLE: c_uint = 15;
+const RETRO_ENVIRONMENT_SET_VARIABLES: c_uint = 16;
+const RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE: c_uint = 17;
+const RETRO_ENVIRONMENT_SET_SUPPORT_NO_GAME: c_uint = 18;
+
+const FPS: u32 = 60;
+const SAMPLE_RATE: u32 = 44100;
+
+/// Hex key of each joypad button, by button id: B, Y, Select, Start, Up, Down, Left, Right,
+/// A, X, L, R, L2, R2, L3 and R3
+const DEFAULT_BUTTONS: [u8; 16] = [0x0, 0x7, 0xA, 0xB, 0x2, 0x8, 0x4, 0x6, 0x5, 0x9, 0x1, 0x3, 0xC, 0xD, 0xE, 0xF];
+
+/// Joypad button id of the actions the ROM database binds keys to
+const ACTION_BUTTONS: [(&str, usize); 6] = [
+    ("up", 4),
+    ("down", 5),
+    ("left", 6),
+    ("right", 7),
+    ("a", 8),
+    ("b", 0),
+];
+
+/// Core options: key, then description and values, the first value being the default
+const OPTIONS: [(&str, &str); 9] = [
+    ("rchip8_platform\0", "Platform; auto|chip8|schip|xo-chip\0"),
+    ("rchip8_ipf\0", "Instructions per frame; auto|1|2|5|8|10|15|20|30|50|100|200|500|1000\0"),
+    ("rchip8_quirk_shift\0", "Shift quirk, 8xy6 and 8xyE shift Vx; auto|on|off\0"),
+    ("rchip8_quirk_memory_increment_by_x\0", "Memory quirk, Fx55 and Fx65 increment I by X; auto|on|off\0"),
+    ("rchip8_quirk_memory_leave_i_unchanged\0", "Memory quirk, Fx55 and Fx65 leave I unchanged; auto|on|off\0"),
+    ("rchip8_quirk_wrap\0", "Wrap quirk, sprites wrap around the screen; auto|on|off\0"),
+    ("rchip8_quirk_jump\0", "Jump quirk, Bnnn jumps to nnn + Vx; auto|on|off\0"),
+    ("rchip8_quirk_vblank\0", "Vblank quirk, Dxyn waits for the next frame; auto|on|off\0"),
+    ("rchip8_quirk_logic\0", "Logic quirk, 8xy1, 8xy2 and 8xy3 reset VF; auto|on|off\0"),
+];
+
+type EnvironmentFn = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn = unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+type AudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = unsafe extern "C" fn();
+type InputStateFn = unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+#[repr(C)]
+pub struct SystemInfo {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool,
+}
+
+#[repr(C)]
+pub struct GameGeometry {
+    base_width: c_uint,
+    base_height: c_uint,
+    max_width: c_uint,
+    max_height: c_uint,
+    aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct SystemTiming {
+    fps: f64,
+    sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct SystemAvInfo {
+    geometry: GameGeometry,
+    timing: SystemTiming,
+}
+
+#[repr(C)]
+pub struct GameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+#[repr(C)]
+struct Variable {
+    key: *const c_char,
+    value: *const c_char,
+}
+
+/// Callbacks set by the front end
+#[derive(Clone, Copy, Default)]
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+// Front ends may call the core from any thread, one call at a time. Callbacks are called with
+// the core locked, so they must not call back into the core.
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+fn callbacks() -> Callbacks {
+    *CALLBACKS.lock().unwrap()
+}
+
+fn set_callbacks(set: impl FnOnce(&mut Callbacks)) {
+    set(&mut CALLBACKS.lock().unwrap());
+}
+
+/// Runs `f` on the loaded game, if any
+fn with_core<R>(f: impl FnOnce(&mut Core) -> R) -> Option<R> {
+    CORE.lock().unwrap().as_mut().map(f)
+}
+
+/// Replaces the loaded game
+fn set_core(core: Option<Core>) {
+    *CORE.lock().unwrap() = core;
+}
+
+fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    match callbacks().environment {
+        Some(environment) => unsafe { environment(cmd, data) },
+        None => false,
+    }
+}
+
+/// Value of a core option, `key` ending with a null byte
+fn option(key: &str) -> Option<String> {
+    let mut variable = Variable { key: key.as_ptr() as *const c_char, value: ptr::null() };
+    if ! environment(RETRO_ENVIRONMENT_GET_VARIABLE, &mut variable as *mut Variable as *mut c_void) || variable.value.is_null() {
+        return None;
+    }
+    let value = unsafe { CStr::from_ptr(variable.value) };
+    Some(value.to_string_lossy().into_owned())
+}
+
+/// Settings set by the core options, on top of the ROM database
+fn options_layer() -> ConfigLayer {
+    let quirk = |key: &str| match option(key).as_deref() {
+        Some("on") => Some(true),
+        Some("off") => Some(false),
+        _ => None,
+    };
+
+    ConfigLayer {
+        ipf: option("rchip8_ipf\0").and_then(|ipf| ipf.parse().ok()),
+        platform: option("rchip8_platform\0").and_then(|platform| Platform::from_str(&platform, true).ok()),
+        quirks: QuirkOverrides {
+            shift: quirk("rchip8_quirk_shift\0"),
+            memory_increment_by_x: quirk("rchip8_quirk_memory_increment_by_x\0"),
+            memory_leave_i_unchanged: quirk("rchip8_quirk_memory_leave_i_unchanged\0"),
+            wrap: quirk("rchip8_quirk_wrap\0"),
+            jump: quirk("rchip8_quirk_jump\0"),
+            vblank: quirk("rchip8_quirk_vblank\0"),
+            logic: quirk("rchip8_quirk_logic\0"),
+        },
+        ..ConfigLayer::default()
+    }
+}
+
+/// The loaded game
+struct Core {
+    chip8: CHIP8,
+    program: Vec<u8>,
+    info: RomInfo,
+    platform: Platform,
+    palette: Palette,
+    /// Hex key of each joypad button
+    buttons: [u8; 16],
+    /// The fault that halted the machine, until reset
+    fault: Option<MachineFault>,
+    tone: Tone,
+    /// The display as XRGB8888 pixels
+    video: Vec<u32>,
+    /// Stereo samples of the current frame
+    audio: Vec<i16>,
+}
+
+// SAFETY: the machine of the core is headless. Its only front end is the null audio sink and it
+// never holds scripts, so nothing in it is tied to the thread that created it.
+unsafe impl Send for Core {}
+
+impl Core {
+    fn new(program: Vec<u8>, info: RomInfo) -> Result<Self, String> {
+        let settings = Config::default().settings(None, &info, &options_layer())?;
+
+        let mut chip8 = CHIP8::headless(settings.platform);
+        chip8.load_program(&program).map_err(|e| e.to_string())?;
+        chip8.set_quirks(settings.quirks);
+        chip8.set_fault_policy(settings.on_fault);
+        chip8.set_instructions_per_frame(settings.ipf);
+
+        let mut buttons = DEFAULT_BUTTONS;
+        for (action, button) in ACTION_BUTTONS {
+            if let Some(key) = info.keys.get(action) {
+                buttons[button] = key & 0xF;
+            }
+        }
+
+        Ok(Core {
+            chip8: chip8,
+            program: program,
+            info: info,
+            platform: settings.platform,
+            palette: settings.palette(),
+            buttons: buttons,
+            fault: None,
+            tone: Tone::new(settings.audio.frequency, settings.audio.volume, SAMPLE_RATE),
+            video: Vec::with_capacity(HIRES_WIDTH * HIRES_HEIGHT),
+            audio: Vec::with_capacity((SAMPLE_RATE / FPS * 2) as usize),
+        })
+    }
+
+    /// Applies changed core options. A new platform restarts the game.
+    fn update_options(&mut self) -> Result<(), String> {
+        let settings = Config::default().settings(None, &self.info, &options_layer())?;
+        if settings.platform != self.platform {
+            *self = Core::new(self.program.clone(), self.info.clone())?;
+            return Ok(());
+        }
+
+        self.chip8.set_quirks(settings.quirks);
+        self.chip8.set_instructions_per_frame(settings.ipf);
+        Ok(())
+    }
+
+    fn read_joypad(&mut self, input_state: InputStateFn) {
+        let mut keys = [false; 16];
+        for (id, key) in self.buttons.iter().enumerate() {
+            if unsafe { input_state(0, RETRO_DEVICE_JOYPAD, 0, id as c_uint) } != 0 {
+                keys[*key as usize] = true;
+            }
+        }
+        self.chip8.set_keys(keys);
+    }
+
+    /// Runs the machine until the frame ends, unless it faulted
+    fn run_frame(&mut self) {
+        if self.fault.is_some() {
+            return;
+        }
+
+        if let Err(fault) = self.chip8.step_frame() {
+            eprintln!("{}", fault);
+            self.fault = Some(fault);
+        }
+    }
+
+    /// Fills `video` with the display, in its current resolution
+    fn render(&mut self) {
+        let xrgb = |[r, g, b]: [u8; 3]| (r as u32) << 16 | (g as u32) << 8 | b as u32;
+        let colors = self.palette.colors().map(xrgb);
+        self.video.clear();
+        self.video.extend(self.chip8.framebuffer().planes().iter().map(|planes| colors[*planes as usize]));
+    }
+
+    fn beep(&mut self) {
+        let beeping = self.fault.is_none() && self.chip8.registers().st > 0;
+        self.tone.set_pattern(self.chip8.sample_pattern());
+        self.audio.clear();
+        for _ in 0..SAMPLE_RATE / FPS {
+            let sample = self.tone.sample(beeping);
+            self.audio.extend_from_slice(&[sample, sample]);
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: EnvironmentFn) {
+    set_callbacks(|callbacks| callbacks.environment = Some(callback));
+
+    let mut variables: Vec<Variable> = OPTIONS.iter()
+        .map(|(key, value)| Variable { key: key.as_ptr() as *const c_char, value: value.as_ptr() as *const c_char })
+        .collect();
+    variables.push(Variable { key: ptr::null(), value: ptr::null() });
+    environment(RETRO_ENVIRONMENT_SET_VARIABLES, variables.as_mut_ptr() as *mut c_void);
+
+    let mut no_game = false;
+    environment(RETRO_ENVIRONMENT_SET_SUPPORT_NO_GAME, &mut no_game as *mut bool as *mut c_void);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: VideoRefreshFn) {
+    set_callbacks(|callbacks| callbacks.video_refresh = Some(callback));
+}
+
+/// Samples are sent a frame at a time with the batch callback
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: AudioSampleBatchFn) {
+    set_callbacks(|callbacks| callbacks.audio_batch = Some(callback));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: InputPollFn) {
+    set_callbacks(|callbacks| callbacks.input_poll = Some(callback));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: InputStateFn) {
+    set_callbacks(|callbacks| callbacks.input_state = Some(callback));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    set_core(None);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    *info = SystemInfo {
+        library_name: b"rchip8\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: b"ch8|c8|sc8|xo8\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    *info = SystemAvInfo {
+        geometry: GameGeometry {
+            base_width: LORES_WIDTH as c_uint,
+            base_height: LORES_HEIGHT as c_uint,
+            max_width: HIRES_WIDTH as c_uint,
+            max_height: HIRES_HEIGHT as c_uint,
+            aspect_ratio: LORES_WIDTH as f32 / LORES_HEIGHT as f32,
+        },
+        timing: SystemTiming {
+            fps: FPS as f64,
+            sample_rate: SAMPLE_RATE as f64,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+/// Restarts the game with a fresh copy of the ROM
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    with_core(|core| match Core::new(core.program.clone(), core.info.clone()) {
+        Ok(restarted) => *core = restarted,
+        Err(e) => eprintln!("{}", e),
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let callbacks = callbacks();
+
+    let mut updated = false;
+    if environment(RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE, &mut updated as *mut bool as *mut c_void) && updated {
+        if let Some(Err(e)) = with_core(|core| core.update_options()) {
+            eprintln!("{}", e);
+        }
+    }
+
+    if let Some(input_poll) = callbacks.input_poll {
+        unsafe { input_poll() };
+    }
+
+    with_core(|core| {
+        if let Some(input_state) = callbacks.input_state {
+            core.read_joypad(input_state);
+        }
+        core.run_frame();
+        core.render();
+        core.beep();
+
+        if let Some(video_refresh) = callbacks.video_refresh {
+            let (width, height) = (core.chip8.framebuffer().width(), core.chip8.framebuffer().height());
+            let pitch = width * std::mem::size_of::<u32>();
+            unsafe { video_refresh(core.video.as_ptr() as *const c_void, width as c_uint, height as c_uint, pitch) };
+        }
+        if let Some(audio_batch) = callbacks.audio_batch {
+            unsafe { audio_batch(core.audio.as_ptr(), core.audio.len() / 2) };
+        }
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    with_core(|core| core.chip8.save_state().len()).unwrap_or(0)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    with_core(|core| {
+        let state = core.chip8.save_state();
+        if data.is_null() || size < state.len() {
+            return false;
+        }
+        ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+        true
+    }).unwrap_or(false)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    let state = slice::from_raw_parts(data as *const u8, size);
+    with_core(|core| match core.chip8.load_state(state) {
+        Ok(()) => {
+            core.fault = None;
+            true
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            false
+        }
+    }).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    if game.is_null() || (*game).data.is_null() {
+        return false;
+    }
+
+    let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+    if ! environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) {
+        eprintln!("The front end doesn't support XRGB8888");
+        return false;
+    }
+
+    let program = slice::from_raw_parts((*game).data as *const u8, (*game).size).to_vec();
+    let mut info = Database::load().lookup(&program).cloned().unwrap_or_default();
+    info.platform = info.platform.or(rom::guess_platform(&program));
+
+    match Core::new(program, info) {
+        Ok(core) => {
+            set_core(Some(core));
+            true
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            false
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: c_uint, _info: *const GameInfo, _num_info: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    set_core(None);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    match id {
+        RETRO_MEMORY_SYSTEM_RAM => with_core(|core| core.chip8.ram.as_mut_ptr() as *mut c_void).unwrap_or(ptr::null_mut()),
+        _ => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    match id {
+        RETRO_MEMORY_SYSTEM_RAM => with_core(|core| core.chip8.ram.len()).unwrap_or(0),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const IBM_LOGO: &[u8] = include_bytes!("../../roms/IBM Logo.ch8");
+
+    static LIT_PIXELS: AtomicUsize = AtomicUsize::new(0);
+    static VIDEO_WIDTH: AtomicUsize = AtomicUsize::new(0);
+    static AUDIO_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+    /// Accepts the pixel format and leaves every option to its default
+    unsafe extern "C" fn environment_stub(cmd: c_uint, _data: *mut c_void) -> bool {
+        cmd == RETRO_ENVIRONMENT_SET_PIXEL_FORMAT
+    }
+
+    unsafe extern "C" fn video_refresh_stub(data: *const c_void, width: c_uint, height: c_uint, _pitch: usize) {
+        let pixels = slice::from_raw_parts(data as *const u32, (width * height) as usize);
+        LIT_PIXELS.store(pixels.iter().filter(|pixel| **pixel != 0).count(), Ordering::SeqCst);
+        VIDEO_WIDTH.store(width as usize, Ordering::SeqCst);
+    }
+
+    unsafe extern "C" fn audio_batch_stub(_data: *const i16, frames: usize) -> usize {
+        AUDIO_FRAMES.store(frames, Ordering::SeqCst);
+        frames
+    }
+
+    unsafe extern "C" fn input_poll_stub() {}
+
+    unsafe extern "C" fn input_state_stub(_port: c_uint, _device: c_uint, _index: c_uint, _id: c_uint) -> i16 {
+        0
+    }
+
+    #[test]
+    fn front_ends_run_and_serialize_games() {
+        retro_set_environment(environment_stub);
+        retro_set_video_refresh(video_refresh_stub);
+        retro_set_audio_sample_batch(audio_batch_stub);
+        retro_set_input_poll(input_poll_stub);
+        retro_set_input_state(input_state_stub);
+        retro_init();
+
+        let game = GameInfo { path: ptr::null(), data: IBM_LOGO.as_ptr() as *const c_void, size: IBM_LOGO.len(), meta: ptr::null() };
+        assert!(unsafe { retro_load_game(&game) });
+        for _ in 0..60 {
+            retro_run();
+        }
+        assert_eq!(VIDEO_WIDTH.load(Ordering::SeqCst), LORES_WIDTH);
+        let lit = LIT_PIXELS.load(Ordering::SeqCst);
+        assert!(lit > 0);
+        assert_eq!(AUDIO_FRAMES.load(Ordering::SeqCst), (SAMPLE_RATE / FPS) as usize);
+
+        let size = retro_serialize_size();
+        let mut state = vec![0u8; size];
+        assert!(unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, size) });
+        assert!(! unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, size - 1) });
+        assert!(! unsafe { retro_serialize(ptr::null_mut(), size) });
+
+        // Restarting clears the display, the state brings the logo back
+        retro_reset();
+        assert!(unsafe { retro_unserialize(state.as_ptr() as *const c_void, size) });
+        retro_run();
+        assert_eq!(LIT_PIXELS.load(Ordering::SeqCst), lit);
+        assert!(! unsafe { retro_unserialize(state.as_ptr() as *const c_void, 4) });
+
+        retro_unload_game();
+        assert_eq!(retro_serialize_size(), 0);
+        assert!(retro_get_memory_data(RETRO_MEMORY_SYSTEM_RAM).is_null());
+        retro_deinit();
+    }
+}
@@ -1,3 +1,34 @@
+pub mod cartridge;
+pub mod cheats;
 pub mod chip8;
+pub mod config;
+pub mod coverage;
+pub mod database;
+pub mod debugger;
+pub mod disasm;
+pub mod fault;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod framebuffer;
+pub mod frontend;
+pub mod gdb;
+pub mod headless;
+#[cfg(feature = "libretro")]
+pub mod libretro;
+pub mod octo;
+pub mod pacer;
+pub mod platform;
+pub mod profiler;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod quirks;
+pub mod recording;
+pub mod rom;
 pub mod screen;
+pub mod script;
+pub mod script_runner;
+pub mod state;
 pub mod synth;
+pub mod terminal;
+pub mod trace;
+pub mod trace_diff;
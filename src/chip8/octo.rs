@@ -0,0 +1,983 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::chip8::chip8::PROGRAM_ADDRESS;
+
+/// Octo programs can address up to 64KB (XO-CHIP)
+const MEMORY_SIZE: usize = 1024 * 64;
+
+/// Macros expanding macros past this depth are taken to recurse forever
+const MAX_MACRO_DEPTH: usize = 64;
+
+/// Error found while assembling Octo source
+#[derive(Debug)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Assembles Octo source into a ROM loaded at PROGRAM_ADDRESS.
+/// As in Octo, the ROM starts with a jump to the `main` label.
+///
+/// Supports the Octo instruction set (CHIP-8, SUPER-CHIP and XO-CHIP statements), labels,
+/// `:next`, `:const`, `:alias`, `:unpack`, `:org`, `:byte`, `:pointer`, `:call`, `:macro`,
+/// `:calc` and the `if`/`else`/`end`, `loop`/`while`/`again` control flow.
+/// `:stringmode` is not supported. Debugger directives (`:breakpoint`, `:monitor`, `:assert`)
+/// are ignored.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler::new(tokenize(source));
+    assembler.run()?;
+    Ok(assembler.rom())
+}
+
+#[derive(Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    /// Macro expansions the token comes from
+    depth: usize,
+}
+
+/// Splits the source into whitespace separated tokens, dropping comments
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let code = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        for text in code.split_whitespace() {
+            tokens.push(Token { text: String::from(text), line: i + 1, depth: 0 });
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(bin) = digits.strip_prefix("0b").or(digits.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse::<f64>().ok()?
+    } else {
+        return None;
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let text = text.to_lowercase();
+    let digit = text.strip_prefix('v')?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+/// How a forward reference is patched once its label is defined
+#[derive(Clone, Copy)]
+enum FixupKind {
+    /// Low 12 bits of the instruction at the address
+    Nnn,
+    /// 16 bit word at the address
+    Word,
+    /// Byte at the address
+    Byte,
+    /// `:unpack`: nibble in the high 4 bits, high 4 bits of the address in the low 4 bits
+    Unpack(u8),
+}
+
+struct Fixup {
+    address: usize,
+    kind: FixupKind,
+    name: String,
+    line: usize,
+}
+
+/// An address, or a label not defined yet
+enum Address {
+    Known(u16),
+    Forward(String),
+}
+
+enum Flow {
+    /// `loop`: address of the loop start and the `while` jumps out of it
+    Loop { start: u16, breaks: Vec<usize> },
+    /// `if ... begin`: address of the jump to the else branch
+    If { jump: usize },
+    /// `else`: address of the jump to the end
+    Else { jump: usize },
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// Instructions that skip the next one when a condition is false or when it is true
+struct Condition {
+    skip_unless: u16,
+    skip_when: u16,
+}
+
+struct Assembler {
+    /// Tokens left to assemble, in reverse order so macros can push their expansion
+    tokens: Vec<Token>,
+    memory: Vec<u8>,
+    here: usize,
+    end: usize,
+    line: usize,
+    /// Macro expansions the current token comes from
+    depth: usize,
+    symbols: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    flow: Vec<Flow>,
+}
+
+impl Assembler {
+    fn new(mut tokens: Vec<Token>) -> Self {
+        tokens.reverse();
+        Assembler {
+            tokens: tokens,
+            memory: vec![0; MEMORY_SIZE],
+            // The program starts with a jump to main
+            here: PROGRAM_ADDRESS + 2,
+            end: PROGRAM_ADDRESS + 2,
+            line: 1,
+            depth: 0,
+            symbols: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            flow: Vec::new(),
+        }
+    }
+
+    fn rom(&self) -> Vec<u8> {
+        self.memory[PROGRAM_ADDRESS..self.end].to_vec()
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, AsmError> {
+        Err(AsmError { line: self.line, message: message })
+    }
+
+    fn next(&mut self) -> Result<String, AsmError> {
+        match self.tokens.pop() {
+            Some(token) => {
+                self.line = token.line;
+                self.depth = token.depth;
+                Ok(token.text)
+            }
+            None => self.error(String::from("unexpected end of file")),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.last().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), AsmError> {
+        let token = self.next()?;
+        if token != expected {
+            return self.error(format!("expected '{}', found '{}'", expected, token));
+        }
+        Ok(())
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), AsmError> {
+        if self.here >= MEMORY_SIZE {
+            return self.error(String::from("program is larger than 64KB"));
+        }
+        self.memory[self.here] = byte;
+        self.here += 1;
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    fn emit(&mut self, word: u16) -> Result<(), AsmError> {
+        self.emit_byte((word >> 8) as u8)?;
+        self.emit_byte(word as u8)
+    }
+
+    fn patch(&mut self, address: usize, kind: FixupKind, value: u16) -> Result<(), AsmError> {
+        match kind {
+            FixupKind::Nnn => {
+                check_nnn(value).or_else(|message| self.error(message))?;
+                self.memory[address] = (self.memory[address] & 0xF0) | ((value >> 8) & 0x0F) as u8;
+                self.memory[address + 1] = value as u8;
+            }
+            FixupKind::Word => {
+                self.memory[address] = (value >> 8) as u8;
+                self.memory[address + 1] = value as u8;
+            }
+            FixupKind::Byte => self.memory[address] = value as u8,
+            FixupKind::Unpack(nibble) => self.memory[address] = (nibble << 4) | ((value >> 8) & 0x0F) as u8,
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: String, value: f64) -> Result<(), AsmError> {
+        if parse_register(&name).is_some() || parse_number(&name).is_some() {
+            return self.error(format!("'{}' is not a valid name", name));
+        }
+        self.symbols.insert(name, value);
+        Ok(())
+    }
+
+    fn register(&mut self) -> Result<u8, AsmError> {
+        let token = self.next()?;
+        self.register_from(&token)
+    }
+
+    fn register_from(&self, token: &str) -> Result<u8, AsmError> {
+        match parse_register(token).or(self.aliases.get(token).copied()) {
+            Some(register) => Ok(register),
+            None => self.error(format!("expected a register, found '{}'", token)),
+        }
+    }
+
+    fn is_register(&self, token: &str) -> bool {
+        parse_register(token).is_some() || self.aliases.contains_key(token)
+    }
+
+    /// A number or a defined name
+    fn value(&mut self) -> Result<f64, AsmError> {
+        let token = self.next()?;
+        if let Some(value) = parse_number(&token).or(self.symbols.get(&token).copied()) {
+            return Ok(value);
+        }
+        self.error(format!("undefined name '{}'", token))
+    }
+
+    fn byte_value(&mut self) -> Result<u8, AsmError> {
+        let value = self.value()? as i64;
+        if !(-128..=255).contains(&value) {
+            return self.error(format!("{} does not fit in a byte", value));
+        }
+        Ok(value as u8)
+    }
+
+    fn nibble_value(&mut self) -> Result<u8, AsmError> {
+        let value = self.value()? as i64;
+        if !(0..=15).contains(&value) {
+            return self.error(format!("{} does not fit in a nibble", value));
+        }
+        Ok(value as u8)
+    }
+
+    /// A number, a defined name, or a label to be defined later
+    fn address(&mut self) -> Result<Address, AsmError> {
+        let token = self.next()?;
+        if let Some(value) = parse_number(&token).or(self.symbols.get(&token).copied()) {
+            return Ok(Address::Known(value as i64 as u16));
+        }
+        if self.is_register(&token) || token.starts_with(':') {
+            return self.error(format!("expected an address, found '{}'", token));
+        }
+        Ok(Address::Forward(token))
+    }
+
+    /// Emits an instruction with an address in its low 12 bits
+    fn emit_nnn(&mut self, opcode: u16, address: Address) -> Result<(), AsmError> {
+        match address {
+            Address::Known(address) => {
+                check_nnn(address).or_else(|message| self.error(message))?;
+                self.emit(opcode | address)
+            }
+            Address::Forward(name) => {
+                self.fixups.push(Fixup { address: self.here, kind: FixupKind::Nnn, name: name, line: self.line });
+                self.emit(opcode)
+            }
+        }
+    }
+
+    fn emit_word(&mut self, address: Address) -> Result<(), AsmError> {
+        match address {
+            Address::Known(address) => self.emit(address),
+            Address::Forward(name) => {
+                self.fixups.push(Fixup { address: self.here, kind: FixupKind::Word, name: name, line: self.line });
+                self.emit(0)
+            }
+        }
+    }
+
+    fn run(&mut self) -> Result<(), AsmError> {
+        while let Some(token) = self.tokens.pop() {
+            self.line = token.line;
+            self.depth = token.depth;
+            self.statement(token.text)?;
+        }
+
+        if !self.flow.is_empty() {
+            return self.error(String::from("missing 'end' or 'again' at the end of the program"));
+        }
+
+        self.fixups.push(Fixup { address: PROGRAM_ADDRESS, kind: FixupKind::Nnn, name: String::from("main"), line: 1 });
+        self.memory[PROGRAM_ADDRESS] = 0x10;
+
+        for i in 0..self.fixups.len() {
+            let (address, kind) = (self.fixups[i].address, self.fixups[i].kind);
+            self.line = self.fixups[i].line;
+            let value = match self.symbols.get(&self.fixups[i].name) {
+                Some(value) => *value as i64 as u16,
+                None => return self.error(format!("undefined name '{}'", self.fixups[i].name)),
+            };
+            self.patch(address, kind, value)?;
+        }
+
+        Ok(())
+    }
+
+    fn statement(&mut self, token: String) -> Result<(), AsmError> {
+        match token.as_str() {
+            ":" => {
+                let name = self.next()?;
+                self.define(name, self.here as f64)?;
+            }
+            ":next" => {
+                let name = self.next()?;
+                self.define(name, (self.here + 1) as f64)?;
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.value()?;
+                self.define(name, value)?;
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+            }
+            ":unpack" => {
+                let nibble = self.nibble_value()?;
+                match self.address()? {
+                    Address::Known(address) => {
+                        self.emit(0x6000 | ((nibble as u16) << 4) | (address >> 8) & 0x0F)?;
+                        self.emit(0x6100 | (address & 0xFF))?;
+                    }
+                    Address::Forward(name) => {
+                        self.fixups.push(Fixup { address: self.here + 1, kind: FixupKind::Unpack(nibble), name: name.clone(), line: self.line });
+                        self.emit(0x6000)?;
+                        self.fixups.push(Fixup { address: self.here + 1, kind: FixupKind::Byte, name: name, line: self.line });
+                        self.emit(0x6100)?;
+                    }
+                }
+            }
+            ":org" => {
+                let address = self.value()? as usize;
+                if address >= MEMORY_SIZE {
+                    return self.error(format!("address 0x{:X} is out of memory", address));
+                }
+                self.here = address;
+            }
+            ":byte" => {
+                if self.peek() == Some("{") {
+                    let value = self.calc_block()? as i64;
+                    self.emit_byte(value as u8)?;
+                } else {
+                    match self.address()? {
+                        Address::Known(value) => self.emit_byte(value as u8)?,
+                        Address::Forward(name) => {
+                            self.fixups.push(Fixup { address: self.here, kind: FixupKind::Byte, name: name, line: self.line });
+                            self.emit_byte(0)?;
+                        }
+                    }
+                }
+            }
+            ":pointer" => {
+                let address = self.address()?;
+                self.emit_word(address)?;
+            }
+            ":call" => {
+                let address = self.address()?;
+                self.emit_nnn(0x2000, address)?;
+            }
+            ":macro" => self.define_macro()?,
+            ":calc" => {
+                let name = self.next()?;
+                let value = self.calc_block()?;
+                self.define(name, value)?;
+            }
+            ":proto" | ":breakpoint" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            ":assert" => {
+                while self.next()? != "}" {}
+            }
+            ":stringmode" => return self.error(String::from(":stringmode is not supported")),
+
+            "clear" => self.emit(0x00E0)?,
+            "return" | ";" => self.emit(0x00EE)?,
+            "exit" => self.emit(0x00FD)?,
+            "lores" => self.emit(0x00FE)?,
+            "hires" => self.emit(0x00FF)?,
+            "scroll-right" => self.emit(0x00FB)?,
+            "scroll-left" => self.emit(0x00FC)?,
+            "scroll-down" => {
+                let n = self.nibble_value()?;
+                self.emit(0x00C0 | n as u16)?;
+            }
+            "scroll-up" => {
+                let n = self.nibble_value()?;
+                self.emit(0x00D0 | n as u16)?;
+            }
+            "audio" => self.emit(0xF002)?,
+            "plane" => {
+                let n = self.nibble_value()?;
+                self.emit(0xF001 | (n as u16) << 8)?;
+            }
+            "jump" => {
+                let address = self.address()?;
+                self.emit_nnn(0x1000, address)?;
+            }
+            "jump0" => {
+                let address = self.address()?;
+                self.emit_nnn(0xB000, address)?;
+            }
+            "sprite" => {
+                let x = self.register()? as u16;
+                let y = self.register()? as u16;
+                let n = self.nibble_value()? as u16;
+                self.emit(0xD000 | x << 8 | y << 4 | n)?;
+            }
+            "bcd" => {
+                let x = self.register()? as u16;
+                self.emit(0xF033 | x << 8)?;
+            }
+            "save" | "load" => {
+                let x = self.register()? as u16;
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()? as u16;
+                    let opcode = if token == "save" { 0x5002 } else { 0x5003 };
+                    self.emit(opcode | x << 8 | y << 4)?;
+                } else {
+                    let opcode = if token == "save" { 0xF055 } else { 0xF065 };
+                    self.emit(opcode | x << 8)?;
+                }
+            }
+            "saveflags" => {
+                let x = self.register()? as u16;
+                self.emit(0xF075 | x << 8)?;
+            }
+            "loadflags" => {
+                let x = self.register()? as u16;
+                self.emit(0xF085 | x << 8)?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()? as u16;
+                let opcode = match token.as_str() {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                };
+                self.emit(opcode | x << 8)?;
+            }
+            "i" | "I" => self.i_statement()?,
+            "if" => self.if_statement()?,
+            "else" => {
+                let jump = match self.flow.pop() {
+                    Some(Flow::If { jump }) => jump,
+                    _ => return self.error(String::from("'else' without 'if ... begin'")),
+                };
+                let end_jump = self.here;
+                self.emit(0x1000)?;
+                let here = self.here as u16;
+                self.patch(jump, FixupKind::Nnn, here)?;
+                self.flow.push(Flow::Else { jump: end_jump });
+            }
+            "end" => {
+                let jump = match self.flow.pop() {
+                    Some(Flow::If { jump }) | Some(Flow::Else { jump }) => jump,
+                    _ => return self.error(String::from("'end' without 'if ... begin'")),
+                };
+                let here = self.here as u16;
+                self.patch(jump, FixupKind::Nnn, here)?;
+            }
+            "loop" => self.flow.push(Flow::Loop { start: self.here as u16, breaks: Vec::new() }),
+            "while" => {
+                let condition = self.condition()?;
+                self.emit(condition.skip_when)?;
+                let jump = self.here;
+                self.emit(0x1000)?;
+                match self.flow.iter_mut().rev().find(|flow| matches!(flow, Flow::Loop { .. })) {
+                    Some(Flow::Loop { breaks, .. }) => breaks.push(jump),
+                    _ => return self.error(String::from("'while' outside of a loop")),
+                }
+            }
+            "again" => {
+                let (start, breaks) = match self.flow.pop() {
+                    Some(Flow::Loop { start, breaks }) => (start, breaks),
+                    _ => return self.error(String::from("'again' without 'loop'")),
+                };
+                self.emit_nnn(0x1000, Address::Known(start))?;
+                let here = self.here as u16;
+                for jump in breaks {
+                    self.patch(jump, FixupKind::Nnn, here)?;
+                }
+            }
+            _ => {
+                if self.macros.contains_key(&token) {
+                    self.expand_macro(&token)?;
+                } else if self.is_register(&token) {
+                    let x = self.register_from(&token)?;
+                    self.register_statement(x)?;
+                } else if let Some(value) = parse_number(&token) {
+                    self.emit_byte(value as i64 as u8)?;
+                } else if token.starts_with(':') {
+                    return self.error(format!("unknown directive '{}'", token));
+                } else {
+                    // A bare name calls the subroutine with that label
+                    self.tokens.push(Token { text: token, line: self.line, depth: self.depth });
+                    let address = self.address()?;
+                    self.emit_nnn(0x2000, address)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn i_statement(&mut self) -> Result<(), AsmError> {
+        let operator = self.next()?;
+        match operator.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    let x = self.register()? as u16;
+                    self.emit(0xF029 | x << 8)
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    let x = self.register()? as u16;
+                    self.emit(0xF030 | x << 8)
+                }
+                Some("long") => {
+                    self.next()?;
+                    self.emit(0xF000)?;
+                    let address = self.address()?;
+                    self.emit_word(address)
+                }
+                _ => {
+                    let address = self.address()?;
+                    self.emit_nnn(0xA000, address)
+                }
+            },
+            "+=" => {
+                let x = self.register()? as u16;
+                self.emit(0xF01E | x << 8)
+            }
+            _ => self.error(format!("unknown operator 'i {}'", operator)),
+        }
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), AsmError> {
+        let x = x as u16;
+        let operator = self.next()?;
+        let operand = self.next()?;
+
+        if let Some(y) = parse_register(&operand).or(self.aliases.get(&operand).copied()) {
+            let y = y as u16;
+            let n = match operator.as_str() {
+                ":=" => 0x0,
+                "|=" => 0x1,
+                "&=" => 0x2,
+                "^=" => 0x3,
+                "+=" => 0x4,
+                "-=" => 0x5,
+                ">>=" => 0x6,
+                "=-" => 0x7,
+                "<<=" => 0xE,
+                _ => return self.error(format!("unknown operator '{}'", operator)),
+            };
+            return self.emit(0x8000 | x << 8 | y << 4 | n);
+        }
+
+        match (operator.as_str(), operand.as_str()) {
+            (":=", "key") => self.emit(0xF00A | x << 8),
+            (":=", "delay") => self.emit(0xF007 | x << 8),
+            (":=", "random") => {
+                let mask = self.byte_value()? as u16;
+                self.emit(0xC000 | x << 8 | mask)
+            }
+            (":=", _) | ("+=", _) | ("-=", _) => {
+                self.tokens.push(Token { text: operand, line: self.line, depth: self.depth });
+                let value = self.byte_value()?;
+                match operator.as_str() {
+                    ":=" => self.emit(0x6000 | x << 8 | value as u16),
+                    "+=" => self.emit(0x7000 | x << 8 | value as u16),
+                    _ => self.emit(0x7000 | x << 8 | value.wrapping_neg() as u16),
+                }
+            }
+            _ => self.error(format!("unknown operator '{}' for '{}'", operator, operand)),
+        }
+    }
+
+    fn if_statement(&mut self) -> Result<(), AsmError> {
+        let condition = self.condition()?;
+        let keyword = self.next()?;
+        match keyword.as_str() {
+            "then" => self.emit(condition.skip_unless),
+            "begin" => {
+                self.emit(condition.skip_when)?;
+                self.flow.push(Flow::If { jump: self.here });
+                self.emit(0x1000)
+            }
+            _ => self.error(format!("expected 'then' or 'begin', found '{}'", keyword)),
+        }
+    }
+
+    /// Parses a condition, emitting the instructions that compute it if any
+    fn condition(&mut self) -> Result<Condition, AsmError> {
+        let x = self.register()? as u16;
+        let operator = self.next()?;
+
+        match operator.as_str() {
+            "key" => return Ok(Condition { skip_unless: 0xE0A1 | x << 8, skip_when: 0xE09E | x << 8 }),
+            "-key" => return Ok(Condition { skip_unless: 0xE09E | x << 8, skip_when: 0xE0A1 | x << 8 }),
+            _ => {}
+        }
+
+        let operand = self.next()?;
+        let y = parse_register(&operand).or(self.aliases.get(&operand).copied()).map(|y| y as u16);
+        let value = match y {
+            Some(_) => 0,
+            None => {
+                self.tokens.push(Token { text: operand, line: self.line, depth: self.depth });
+                self.byte_value()? as u16
+            }
+        };
+
+        match (operator.as_str(), y) {
+            ("==", Some(y)) => Ok(Condition { skip_unless: 0x9000 | x << 8 | y << 4, skip_when: 0x5000 | x << 8 | y << 4 }),
+            ("!=", Some(y)) => Ok(Condition { skip_unless: 0x5000 | x << 8 | y << 4, skip_when: 0x9000 | x << 8 | y << 4 }),
+            ("==", None) => Ok(Condition { skip_unless: 0x4000 | x << 8 | value, skip_when: 0x3000 | x << 8 | value }),
+            ("!=", None) => Ok(Condition { skip_unless: 0x3000 | x << 8 | value, skip_when: 0x4000 | x << 8 | value }),
+            ("<", _) | (">=", _) => {
+                // VF = Vx - operand, VF flag = Vx >= operand
+                match y {
+                    Some(y) => {
+                        self.emit(0x8F00 | x << 4)?;
+                        self.emit(0x8F05 | y << 4)?;
+                    }
+                    None => {
+                        self.emit(0x6F00 | value)?;
+                        self.emit(0x8F07 | x << 4)?;
+                    }
+                }
+                Ok(self.flag_condition(operator == ">="))
+            }
+            (">", _) | ("<=", _) => {
+                // VF = operand - Vx, VF flag = operand >= Vx
+                match y {
+                    Some(y) => self.emit(0x8F00 | y << 4)?,
+                    None => self.emit(0x6F00 | value)?,
+                }
+                self.emit(0x8F05 | x << 4)?;
+                Ok(self.flag_condition(operator == "<="))
+            }
+            _ => self.error(format!("unknown comparison '{}'", operator)),
+        }
+    }
+
+    /// Condition on the flag left in VF by a subtraction
+    fn flag_condition(&self, flag: bool) -> Condition {
+        let flag = flag as u16;
+        Condition { skip_unless: 0x4F00 | flag, skip_when: 0x3F00 | flag }
+    }
+
+    fn define_macro(&mut self) -> Result<(), AsmError> {
+        let name = self.next()?;
+        let mut args = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token == "{" {
+                break;
+            }
+            args.push(token);
+        }
+
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.next()?;
+            match token.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            body.push(Token { text: token, line: self.line, depth: 0 });
+        }
+
+        self.macros.insert(name, Macro { args: args, body: body });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), AsmError> {
+        let depth = self.depth + 1;
+        if depth > MAX_MACRO_DEPTH {
+            return self.error(format!("macro '{}' is expanded more than {} levels deep", name, MAX_MACRO_DEPTH));
+        }
+
+        let arg_count = self.macros[name].args.len();
+        let mut values = Vec::new();
+        for _ in 0..arg_count {
+            values.push(self.next()?);
+        }
+
+        let line = self.line;
+        let definition = &self.macros[name];
+        let expansion: Vec<Token> = definition.body.iter().map(|token| {
+            let text = match definition.args.iter().position(|arg| *arg == token.text) {
+                Some(i) => values[i].clone(),
+                None => token.text.clone(),
+            };
+            Token { text: text, line: line, depth: depth }
+        }).collect();
+
+        self.tokens.extend(expansion.into_iter().rev());
+        Ok(())
+    }
+
+    /// Evaluates `{ expression }`.
+    /// As in Octo, operators have no precedence and are evaluated right to left.
+    fn calc_block(&mut self) -> Result<f64, AsmError> {
+        self.expect("{")?;
+        let value = self.calc_expression()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    fn calc_expression(&mut self) -> Result<f64, AsmError> {
+        let left = self.calc_term()?;
+        let operator = match self.peek() {
+            Some(operator) if is_binary_operator(operator) => String::from(operator),
+            _ => return Ok(left),
+        };
+        self.next()?;
+        let right = self.calc_expression()?;
+
+        Ok(match operator.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => ((left as i64) & (right as i64)) as f64,
+            "|" => ((left as i64) | (right as i64)) as f64,
+            "^" => ((left as i64) ^ (right as i64)) as f64,
+            "<<" => ((left as i64) << (right as i64)) as f64,
+            ">>" => ((left as i64) >> (right as i64)) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as i64 as f64,
+            "<=" => (left <= right) as i64 as f64,
+            ">" => (left > right) as i64 as f64,
+            ">=" => (left >= right) as i64 as f64,
+            "==" => (left == right) as i64 as f64,
+            _ => (left != right) as i64 as f64,
+        })
+    }
+
+    fn calc_term(&mut self) -> Result<f64, AsmError> {
+        let token = self.next()?;
+        if let Some(value) = parse_number(&token) {
+            return Ok(value);
+        }
+
+        match token.as_str() {
+            "(" => {
+                let value = self.calc_expression()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            "-" => Ok(-self.calc_term()?),
+            "~" => Ok(!(self.calc_term()? as i64) as f64),
+            "!" => Ok((self.calc_term()? == 0.0) as i64 as f64),
+            "sin" => Ok(self.calc_term()?.sin()),
+            "cos" => Ok(self.calc_term()?.cos()),
+            "tan" => Ok(self.calc_term()?.tan()),
+            "exp" => Ok(self.calc_term()?.exp()),
+            "log" => Ok(self.calc_term()?.ln()),
+            "abs" => Ok(self.calc_term()?.abs()),
+            "sqrt" => Ok(self.calc_term()?.sqrt()),
+            "sign" => Ok(self.calc_term()?.signum()),
+            "ceil" => Ok(self.calc_term()?.ceil()),
+            "floor" => Ok(self.calc_term()?.floor()),
+            "@" => {
+                let address = self.calc_term()? as usize;
+                Ok(self.memory.get(address).copied().unwrap_or(0) as f64)
+            }
+            _ => match self.symbols.get(&token) {
+                Some(value) => Ok(*value),
+                None => self.error(format!("undefined name '{}'", token)),
+            },
+        }
+    }
+}
+
+/// Jumps, calls and `i :=` only hold 12 bit addresses
+fn check_nnn(address: u16) -> Result<(), String> {
+    match address {
+        0..=0xFFF => Ok(()),
+        _ => Err(format!("address 0x{:X} does not fit in 12 bits", address)),
+    }
+}
+
+fn is_binary_operator(token: &str) -> bool {
+    matches!(token, "+" | "-" | "*" | "/" | "%" | "&" | "|" | "^" | "<<" | ">>" | "pow" | "min" | "max"
+        | "<" | "<=" | ">" | ">=" | "==" | "!=")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> (usize, String) {
+        let error = assemble(source).unwrap_err();
+        (error.line, error.message)
+    }
+
+    #[test]
+    fn assembles_chip8_programs() {
+        let source = "
+            : main
+              v0 := 5
+              v1 += 2
+              i := sprite
+              sprite v0 v1 4
+              loop
+                v0 += 1
+                if v0 == 10 then jump done
+              again
+            : done
+              jump done
+            : sprite
+              0xF0 0x90 0x90 0xF0
+        ";
+        assert_eq!(assemble(source).unwrap(), vec![
+            0x12, 0x02, 0x60, 0x05, 0x71, 0x02, 0xA2, 0x14, 0xD0, 0x14, 0x70, 0x01, 0x40, 0x0A,
+            0x12, 0x12, 0x12, 0x0A, 0x12, 0x12, 0xF0, 0x90, 0x90, 0xF0,
+        ]);
+    }
+
+    #[test]
+    fn assembles_directives_and_xochip_statements() {
+        let source = "
+            :const SPEED 3
+            :macro add-speed reg { reg += SPEED }
+            : main
+              add-speed v2
+              :calc LIMIT { SPEED * 4 + 1 }
+              v3 := LIMIT
+              :unpack 0xA data
+              i := long data
+              plane 3
+              save v1 - v4
+              if v2 > v3 begin
+                clear
+              else
+                return
+              end
+            : data
+              :byte 0xAB
+              :pointer data
+        ";
+        // :calc has no precedence and evaluates right to left, so LIMIT is 3 * (4 + 1)
+        assert_eq!(assemble(source).unwrap(), vec![
+            0x12, 0x02, 0x72, 0x03, 0x63, 0x0F, 0x60, 0xA2, 0x61, 0x20, 0xF0, 0x00, 0x02, 0x20,
+            0xF3, 0x01, 0x51, 0x42, 0x8F, 0x30, 0x8F, 0x25, 0x3F, 0x00, 0x12, 0x1E, 0x00, 0xE0,
+            0x12, 0x20, 0x00, 0xEE, 0xAB, 0x02, 0x20,
+        ]);
+    }
+
+    #[test]
+    fn addresses_must_fit_in_12_bits() {
+        assert_eq!(error(": main\njump 0x1000"), (2, String::from("address 0x1000 does not fit in 12 bits")));
+        assert_eq!(error(": main\njump far\n:org 0x1000\n: far clear"),
+                   (2, String::from("address 0x1000 does not fit in 12 bits")));
+        assert_eq!(error(":org 0x1000\n: main clear"), (1, String::from("address 0x1000 does not fit in 12 bits")));
+        assert_eq!(error(":org 0x1000\n: main\nloop\nclear\nagain"),
+                   (5, String::from("address 0x1000 does not fit in 12 bits")));
+        // Long addresses are 16 bits
+        assert!(assemble(": main\ni := long far\n:org 0x1000\n: far clear").is_ok());
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        assert_eq!(error(": main\n\njump nowhere"), (3, String::from("undefined name 'nowhere'")));
+        assert_eq!(error("clear"), (1, String::from("undefined name 'main'")));
+        assert_eq!(error(": main\nv0 := 256"), (2, String::from("256 does not fit in a byte")));
+        assert_eq!(error(": main\nloop\nclear"), (3, String::from("missing 'end' or 'again' at the end of the program")));
+        assert_eq!(error(": main\nelse"), (2, String::from("'else' without 'if ... begin'")));
+        assert_eq!(error(": main\nend"), (2, String::from("'end' without 'if ... begin'")));
+        assert_eq!(error(": main\nagain"), (2, String::from("'again' without 'loop'")));
+        assert_eq!(error(": main\n:foo"), (2, String::from("unknown directive ':foo'")));
+        assert_eq!(error(": main\n:stringmode"), (2, String::from(":stringmode is not supported")));
+    }
+
+    #[test]
+    fn negative_constants_wrap_around() {
+        let source = "
+            :const DOWN -1
+            : main
+              :byte DOWN
+              :byte -2
+              :byte { 0 - 3 }
+              :pointer DOWN
+              :byte back
+            :const back -4
+        ";
+        assert_eq!(assemble(source).unwrap(), vec![0x12, 0x02, 0xFF, 0xFE, 0xFD, 0xFF, 0xFF, 0xFC]);
+        assert_eq!(error(": main
+jump -1"), (2, String::from("address 0xFFFF does not fit in 12 bits")));
+    }
+
+    #[test]
+    fn recursive_macros_are_errors() {
+        assert_eq!(error(":macro forever { forever }
+: main
+forever"),
+                   (3, String::from("macro 'forever' is expanded more than 64 levels deep")));
+        assert_eq!(error(":macro twice x { x x }
+:macro grow { twice grow }
+: main
+grow"),
+                   (4, String::from("macro 'grow' is expanded more than 64 levels deep")));
+        // Nesting below the limit is fine
+        assert_eq!(assemble(":macro one { 1 }
+:macro two { one one }
+: main
+two").unwrap(), vec![0x12, 0x02, 0x01, 0x01]);
+    }
+}
@@ -0,0 +1,182 @@
+use std::time::{Duration, Instant};
+
+/// Maximum number of frames run back to back when the emulation falls behind.
+/// Anything beyond that is dropped so a long stall doesn't turn into a burst.
+const MAX_CATCH_UP_FRAMES: u32 = 5;
+
+const MIN_MULTIPLIER: f64 = 0.125;
+const MAX_MULTIPLIER: f64 = 8.0;
+
+/// Keeps the emulation running at a fixed frame rate.
+/// Elapsed time is measured with a monotonic clock and accumulated, so the time spent
+/// executing and rendering a frame is accounted for instead of added to the frame period.
+pub struct FramePacer {
+    frame_duration: Duration,
+    accumulator: Duration,
+    last: Instant,
+    multiplier: f64,
+    vsync: bool,
+    /// The last call to `frames_due` returned 0, so nothing was presented since
+    idle: bool,
+
+    /// Speed factor applied while fast-forwarding. 0 runs uncapped.
+    fast_forward_factor: u32,
+    fast_forwarding: bool,
+}
+
+impl FramePacer {
+    pub fn new(fps: u32, vsync: bool, fast_forward_factor: u32) -> Self {
+        FramePacer {
+            frame_duration: Duration::from_secs(1) / fps.max(1),
+            accumulator: Duration::ZERO,
+            last: Instant::now(),
+            multiplier: 1.0,
+            vsync: vsync,
+            idle: false,
+            fast_forward_factor: fast_forward_factor,
+            fast_forwarding: false,
+        }
+    }
+
+    /// Returns how many frames are due since the last call.
+    pub fn frames_due(&mut self) -> u32 {
+        if self.is_uncapped() {
+            self.reset();
+            return 1;
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        self.last = now;
+        self.accumulate(elapsed)
+    }
+
+    /// Adds `elapsed` wall clock time, scaled by the speed, and takes the frames it completes
+    fn accumulate(&mut self, elapsed: Duration) -> u32 {
+        self.accumulator += elapsed.mul_f64(self.effective_multiplier());
+
+        let mut frames = 0;
+        while self.accumulator >= self.frame_duration && frames < MAX_CATCH_UP_FRAMES {
+            self.accumulator -= self.frame_duration;
+            frames += 1;
+        }
+
+        if frames == MAX_CATCH_UP_FRAMES {
+            self.accumulator = Duration::ZERO;
+        }
+
+        self.idle = frames == 0;
+        frames
+    }
+
+    /// Sleeps until the next frame is due.
+    /// With vsync, presenting a frame already blocked on the display refresh, so it only sleeps
+    /// when no frame was due, e.g. in slow motion or below the refresh rate.
+    pub fn wait(&self) {
+        if self.is_uncapped() || (self.vsync && ! self.idle) {
+            return;
+        }
+
+        let remaining = self.frame_duration.saturating_sub(self.accumulator).div_f64(self.effective_multiplier());
+        let elapsed = self.last.elapsed();
+        if remaining > elapsed {
+            ::std::thread::sleep(remaining - elapsed);
+        }
+    }
+
+    /// Forgets the time elapsed so far, e.g. after the emulation was paused.
+    pub fn reset(&mut self) {
+        self.accumulator = Duration::ZERO;
+        self.last = Instant::now();
+    }
+
+    /// Current speed multiplier, fast-forward included. Returns None when running uncapped.
+    pub fn speed(&self) -> Option<f64> {
+        if self.is_uncapped() {
+            None
+        } else {
+            Some(self.effective_multiplier())
+        }
+    }
+
+    pub fn multiplier(&self) -> f64 {
+        self.multiplier
+    }
+
+    pub fn set_fast_forward(&mut self, fast_forwarding: bool) {
+        self.fast_forwarding = fast_forwarding;
+    }
+
+    fn is_uncapped(&self) -> bool {
+        self.fast_forwarding && self.fast_forward_factor == 0
+    }
+
+    fn effective_multiplier(&self) -> f64 {
+        if self.fast_forwarding {
+            self.multiplier * self.fast_forward_factor as f64
+        } else {
+            self.multiplier
+        }
+    }
+
+    /// Turbo: doubles the emulation speed, up to 8x.
+    pub fn speed_up(&mut self) {
+        self.multiplier = (self.multiplier * 2.0).min(MAX_MULTIPLIER);
+    }
+
+    /// Slow motion: halves the emulation speed, down to 1/8x.
+    pub fn slow_down(&mut self) {
+        self.multiplier = (self.multiplier / 2.0).max(MIN_MULTIPLIER);
+    }
+
+    pub fn normal_speed(&mut self) {
+        self.multiplier = 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+    #[test]
+    fn frames_are_due_as_time_accumulates() {
+        let mut pacer = FramePacer::new(60, false, 0);
+        assert_eq!(pacer.accumulate(FRAME / 2), 0);
+        assert_eq!(pacer.accumulate(FRAME / 2), 1);
+        assert_eq!(pacer.accumulate(FRAME * 2 + FRAME / 2), 2);
+        assert_eq!(pacer.accumulate(FRAME / 2), 1);
+        assert_eq!(pacer.accumulator, Duration::ZERO);
+    }
+
+    #[test]
+    fn catching_up_is_capped() {
+        let mut pacer = FramePacer::new(60, false, 0);
+        assert_eq!(pacer.accumulate(FRAME * 100), MAX_CATCH_UP_FRAMES);
+        // The frames beyond the cap are dropped, not run later
+        assert_eq!(pacer.accumulator, Duration::ZERO);
+        assert_eq!(pacer.accumulate(FRAME / 2), 0);
+    }
+
+    #[test]
+    fn speed_scales_the_elapsed_time() {
+        let mut pacer = FramePacer::new(60, false, 0);
+        pacer.slow_down();
+        assert_eq!(pacer.accumulate(FRAME), 0);
+        assert_eq!(pacer.accumulate(FRAME), 1);
+
+        pacer.normal_speed();
+        pacer.speed_up();
+        assert_eq!(pacer.accumulate(FRAME), 2);
+    }
+
+    #[test]
+    fn vsync_waits_only_when_idle() {
+        let mut pacer = FramePacer::new(60, true, 0);
+        pacer.accumulate(FRAME / 2);
+        assert!(pacer.idle);
+        pacer.accumulate(FRAME);
+        assert!(! pacer.idle);
+    }
+}
@@ -0,0 +1,45 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::chip8::chip8::{MAX_MEM, PROGRAM_ADDRESS};
+
+/// XO-CHIP extends the address space to 64KB.
+const XO_CHIP_MAX_MEM: usize = 1024 * 64;
+
+/// The flavour of CHIP-8 a ROM is written for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Platform {
+    /// Original COSMAC VIP CHIP-8
+    Chip8,
+    /// SUPER-CHIP
+    Schip,
+    /// XO-CHIP
+    XoChip,
+}
+
+impl Platform {
+    /// Size of the addressable memory
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::Schip => MAX_MEM,
+            Platform::XoChip => XO_CHIP_MAX_MEM,
+        }
+    }
+
+    /// Largest ROM that fits in memory after the program address
+    pub fn max_rom_size(&self) -> usize {
+        self.memory_size() - PROGRAM_ADDRESS
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Platform::Chip8 => write!(f, "CHIP-8"),
+            Platform::Schip => write!(f, "SUPER-CHIP"),
+            Platform::XoChip => write!(f, "XO-CHIP"),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::chip8::disasm;
+
+/// Rows shown in each table of the text report
+const REPORT_ROWS: usize = 20;
+
+/// A subroutine call in progress
+struct Frame {
+    address: u16,
+    /// Cycle the subroutine was entered at
+    start: u64,
+    /// Cycles spent in the subroutines it called
+    children: u64,
+}
+
+#[derive(Clone, Default)]
+struct SubroutineStats {
+    calls: u64,
+    inclusive: u64,
+    exclusive: u64,
+}
+
+/// Counts where cycles go: executions per address and per instruction, and the cycles spent in
+/// each subroutine, from 2nnn to the matching 00EE.
+#[derive(Default)]
+pub struct Profiler {
+    cycles: u64,
+    addresses: HashMap<u16, u64>,
+    opcodes: HashMap<&'static str, u64>,
+    subroutines: HashMap<u16, SubroutineStats>,
+    stack: Vec<Frame>,
+    /// Addresses of the subroutines in `stack`: the current chain of calls
+    calls: Vec<u16>,
+
+    /// Instructions executed under each chain of calls, for flame graphs
+    folded: HashMap<Vec<u16>, u64>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the instruction `opcode`, executed at `address`
+    pub fn record(&mut self, address: u16, opcode: u16) {
+        *self.addresses.entry(address).or_insert(0) += 1;
+        *self.opcodes.entry(disasm::pattern(opcode)).or_insert(0) += 1;
+
+        // The chain of calls is only copied the first time it is seen
+        match self.folded.get_mut(self.calls.as_slice()) {
+            Some(count) => *count += 1,
+            None => {
+                self.folded.insert(self.calls.clone(), 1);
+            }
+        }
+
+        self.cycles += 1;
+
+        match opcode & 0xF000 {
+            0x2000 => {
+                self.stack.push(Frame { address: opcode & 0x0FFF, start: self.cycles, children: 0 });
+                self.calls.push(opcode & 0x0FFF);
+            }
+            // A return without a call, e.g. when the program manipulates the stack, is ignored
+            0x0000 if opcode == 0x00EE => if let Some(frame) = self.stack.pop() {
+                self.calls.pop();
+                let inclusive = self.cycles - frame.start;
+                let stats = self.subroutines.entry(frame.address).or_default();
+                stats.calls += 1;
+                stats.inclusive += inclusive;
+                stats.exclusive += inclusive - frame.children;
+
+                if let Some(parent) = self.stack.last_mut() {
+                    parent.children += inclusive;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Subroutine statistics, counting the calls still in progress as if they returned now
+    fn subroutine_stats(&self) -> HashMap<u16, SubroutineStats> {
+        let mut subroutines = self.subroutines.clone();
+        let mut children = 0;
+        for frame in self.stack.iter().rev() {
+            let inclusive = self.cycles - frame.start;
+            let stats = subroutines.entry(frame.address).or_default();
+            stats.calls += 1;
+            stats.inclusive += inclusive;
+            stats.exclusive += inclusive - (frame.children + children);
+            children = inclusive;
+        }
+        subroutines
+    }
+
+    /// Text report of the hot spots. `ram` is used to disassemble the hottest addresses.
+    pub fn report(&self, ram: &[u8]) -> String {
+        let percent = |count: u64| count as f64 * 100.0 / self.cycles.max(1) as f64;
+        let mut report = String::new();
+
+        writeln!(report, "Instructions executed: {}", self.cycles).unwrap();
+
+        writeln!(report, "\nHot spots\n  Address      Count       %  Instruction").unwrap();
+        let mut addresses: Vec<(&u16, &u64)> = self.addresses.iter().collect();
+        addresses.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (address, count) in addresses.into_iter().take(REPORT_ROWS) {
+            let index = *address as usize % ram.len();
+            let word = (ram[index] as u16) << 8 | ram[(index + 1) % ram.len()] as u16;
+            writeln!(report, "  0x{:04X} {:>10} {:>6.2}%  {}", address, count, percent(*count), disasm::disassemble(word)).unwrap();
+        }
+
+        writeln!(report, "\nInstructions\n  Opcode       Count       %").unwrap();
+        let mut opcodes: Vec<(&&str, &u64)> = self.opcodes.iter().collect();
+        opcodes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (opcode, count) in opcodes {
+            writeln!(report, "  {:<7} {:>10} {:>6.2}%", opcode, count, percent(*count)).unwrap();
+        }
+
+        writeln!(report, "\nSubroutines\n  Address   Calls  Inclusive       %  Exclusive       %").unwrap();
+        let subroutines = self.subroutine_stats();
+        let mut subroutines: Vec<(&u16, &SubroutineStats)> = subroutines.iter().collect();
+        subroutines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+        for (address, stats) in subroutines.into_iter().take(REPORT_ROWS) {
+            writeln!(report, "  0x{:04X} {:>7} {:>10} {:>6.2}% {:>10} {:>6.2}%", address, stats.calls,
+                stats.inclusive, percent(stats.inclusive), stats.exclusive, percent(stats.exclusive)).unwrap();
+        }
+
+        report
+    }
+
+    /// Folded stacks, one line per chain of calls followed by its instruction count, as read by
+    /// flamegraph.pl and inferno. The program itself is the `main` frame.
+    pub fn folded_stacks(&self) -> String {
+        let mut lines: Vec<String> = self.folded.iter()
+            .map(|(calls, count)| {
+                let frames: Vec<String> = std::iter::once(String::from("main"))
+                    .chain(calls.iter().map(|address| format!("sub_{:03X}", address)))
+                    .collect();
+                format!("{} {}", frames.join(";"), count)
+            })
+            .collect();
+        lines.sort();
+
+        let mut folded = lines.join("\n");
+        folded.push('\n');
+        folded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// main calls 0x300, which calls 0x400, then loops on itself
+    const TRACE: [(u16, u16); 7] = [
+        (0x200, 0x2300),
+        (0x300, 0x6001),
+        (0x302, 0x2400),
+        (0x400, 0x6002),
+        (0x402, 0x00EE),
+        (0x304, 0x00EE),
+        (0x202, 0x1202),
+    ];
+
+    fn profile(trace: &[(u16, u16)]) -> Profiler {
+        let mut profiler = Profiler::new();
+        for (address, opcode) in trace {
+            profiler.record(*address, *opcode);
+        }
+        profiler
+    }
+
+    #[test]
+    fn subroutines_count_their_callees_inclusively_only() {
+        let stats = profile(&TRACE).subroutine_stats();
+        let (outer, inner) = (&stats[&0x300], &stats[&0x400]);
+        // 0x300 runs 5 instructions from the one after its call to its 00EE, 2 of them in 0x400
+        assert_eq!((outer.calls, outer.inclusive, outer.exclusive), (1, 5, 3));
+        assert_eq!((inner.calls, inner.inclusive, inner.exclusive), (1, 2, 2));
+
+        // Calls in progress count up to now
+        let stats = profile(&TRACE[..4]).subroutine_stats();
+        assert_eq!((stats[&0x300].inclusive, stats[&0x300].exclusive), (3, 2));
+        assert_eq!((stats[&0x400].inclusive, stats[&0x400].exclusive), (1, 1));
+
+        // Returns without calls are ignored
+        let stats = profile(&[(0x200, 0x00EE), (0x202, 0x00EE)]).subroutine_stats();
+        assert!(stats.is_empty());
+    }
+
+    #[test]
+    fn folded_stacks_count_instructions_per_chain_of_calls() {
+        assert_eq!(profile(&TRACE).folded_stacks(), "main 2\nmain;sub_300 3\nmain;sub_300;sub_400 2\n");
+    }
+
+    #[test]
+    fn reports_list_hot_spots_and_subroutines() {
+        let mut profiler = profile(&TRACE);
+        profiler.record(0x202, 0x1202);
+        let mut ram = vec![0; 0x1000];
+        ram[0x202..0x204].copy_from_slice(&[0x12, 0x02]);
+
+        let report = profiler.report(&ram);
+        assert!(report.starts_with("Instructions executed: 8\n"));
+        assert!(report.contains("\n  0x0202          2  25.00%  JP 0x202\n"));
+        assert!(report.contains("\n  2nnn             2  25.00%\n"));
+        assert!(report.contains("\n  0x0300       1          5  62.50%          3  37.50%\n"));
+    }
+}
@@ -0,0 +1,415 @@
+use clap::ValueEnum;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+use crate::chip8::cartridge;
+use crate::chip8::chip8::CHIP8;
+use crate::chip8::config::{Config, ConfigLayer};
+use crate::chip8::database::{Database, RomInfo};
+use crate::chip8::fault::MachineFault;
+use crate::chip8::platform::Platform;
+use crate::chip8::rom;
+
+use exceptions::Fault;
+
+// The macro checks a feature of pyo3 this crate doesn't have
+#[allow(unexpected_cfgs)]
+mod exceptions {
+    use pyo3::create_exception;
+    use pyo3::exceptions::PyRuntimeError;
+
+    create_exception!(rchip8, Fault, PyRuntimeError, "The machine faulted: stack overflow, out of bounds memory access or illegal opcode.");
+}
+
+/// Actions of `Env`: holding one of the 16 keys, or none
+const ACTION_COUNT: usize = 17;
+
+/// A machine without window nor sound, running a ROM.
+///
+/// The ROM is looked up in the ROM database, like `rchip8 run` does, to set the platform, the
+/// quirks and the instructions per frame. `platform` and `ipf` override it.
+#[pyclass(unsendable, module = "rchip8")]
+pub struct Machine {
+    chip8: CHIP8,
+    /// The fault that halted the machine, until restored
+    fault: Option<MachineFault>,
+}
+
+impl Machine {
+    fn load(program: &[u8], cartridge_info: Option<RomInfo>, platform: Option<&str>, ipf: Option<u32>) -> PyResult<Self> {
+        let platform = match platform {
+            Some(name) => Some(Platform::from_str(name, true)
+                .map_err(|_| PyValueError::new_err(format!("Invalid platform {}, platforms are chip8, schip and xo-chip", name)))?),
+            None => None,
+        };
+
+        let mut info = cartridge_info.unwrap_or_else(|| Database::load().lookup(program).cloned().unwrap_or_default());
+        info.platform = info.platform.or(rom::guess_platform(program));
+        let layer = ConfigLayer {
+            ipf: ipf,
+            platform: platform,
+            ..ConfigLayer::default()
+        };
+        let settings = Config::default().settings(None, &info, &layer).map_err(PyValueError::new_err)?;
+
+        let mut chip8 = CHIP8::headless(settings.platform);
+        chip8.load_program(program).map_err(|e| PyValueError::new_err(e.to_string()))?;
+        chip8.set_quirks(settings.quirks);
+        chip8.set_fault_policy(settings.on_fault);
+        chip8.set_instructions_per_frame(settings.ipf);
+
+        Ok(Machine {
+            chip8: chip8,
+            fault: None,
+        })
+    }
+
+    fn check(&mut self, result: Result<(), MachineFault>) -> PyResult<()> {
+        result.map_err(|fault| {
+            self.fault = Some(fault);
+            Fault::new_err(fault.to_string())
+        })
+    }
+
+    fn screen(&self) -> Vec<u8> {
+        self.chip8.framebuffer().pixels().iter().map(|pixel| *pixel as u8).collect()
+    }
+}
+
+#[pymethods]
+impl Machine {
+    #[new]
+    #[pyo3(signature = (rom, platform = None, ipf = None))]
+    fn new(rom: &[u8], platform: Option<&str>, ipf: Option<u32>) -> PyResult<Self> {
+        Machine::load(rom, None, platform, ipf)
+    }
+
+    /// Loads a ROM file or an Octo cartridge
+    #[staticmethod]
+    #[pyo3(signature = (path, platform = None, ipf = None))]
+    fn open(path: &str, platform: Option<&str>, ipf: Option<u32>) -> PyResult<Self> {
+        let (program, info) = match cartridge::is_cartridge(path) {
+            true => cartridge::load(path).map(|cartridge| (cartridge.program, Some(cartridge.info))),
+            false => rom::read(path).map(|program| (program, None)),
+        }.map_err(|e| PyValueError::new_err(e.to_string()))?;
+        Machine::load(&program, info, platform, ipf)
+    }
+
+    /// Executes `cycles` instructions. Raises `Fault` when the machine faults.
+    #[pyo3(signature = (cycles = 1))]
+    fn step(&mut self, cycles: u64) -> PyResult<()> {
+        for _ in 0..cycles {
+            let result = self.chip8.step();
+            self.check(result)?;
+        }
+        Ok(())
+    }
+
+    /// Runs `frames` frames. Raises `Fault` when the machine faults.
+    #[pyo3(signature = (frames = 1))]
+    fn run_frame(&mut self, frames: u32) -> PyResult<()> {
+        for _ in 0..frames {
+            let result = self.chip8.step_frame();
+            self.check(result)?;
+        }
+        Ok(())
+    }
+
+    /// Pixels of the display row by row, one byte per pixel, 1 when lit:
+    /// `numpy.frombuffer(machine.framebuffer(), numpy.uint8).reshape(machine.height, machine.width)`
+    fn framebuffer<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.screen())
+    }
+
+    #[getter]
+    fn width(&self) -> usize {
+        self.chip8.framebuffer().width()
+    }
+
+    #[getter]
+    fn height(&self) -> usize {
+        self.chip8.framebuffer().height()
+    }
+
+    /// Holds or releases a key of the keypad, from 0x0 to 0xF
+    fn set_key(&mut self, key: usize, pressed: bool) -> PyResult<()> {
+        if key > 0xF {
+            return Err(PyValueError::new_err(format!("Invalid key {}, keys are 0 to 15", key)));
+        }
+        let mut keys = self.chip8.keys();
+        keys[key] = pressed;
+        self.chip8.set_keys(keys);
+        Ok(())
+    }
+
+    /// State of the 16 keys of the keypad, True when held
+    #[getter]
+    fn keys(&self) -> [bool; 16] {
+        self.chip8.keys()
+    }
+
+    #[getter]
+    fn pc(&self) -> u16 {
+        self.chip8.registers().pc
+    }
+
+    #[getter]
+    fn i(&self) -> u16 {
+        self.chip8.registers().i
+    }
+
+    /// Registers V0 to VF
+    #[getter]
+    fn v(&self) -> [u8; 16] {
+        self.chip8.registers().v
+    }
+
+    #[getter]
+    fn dt(&self) -> u8 {
+        self.chip8.registers().dt
+    }
+
+    #[getter]
+    fn st(&self) -> u8 {
+        self.chip8.registers().st
+    }
+
+    /// Return addresses on the stack, oldest first
+    #[getter]
+    fn stack(&self) -> Vec<u16> {
+        self.chip8.stack().to_vec()
+    }
+
+    fn ram<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.chip8.ram)
+    }
+
+    /// True while the sound timer runs
+    #[getter]
+    fn sound_active(&self) -> bool {
+        self.chip8.registers().st > 0
+    }
+
+    #[getter]
+    fn frame_count(&self) -> u64 {
+        self.chip8.frame_count()
+    }
+
+    #[getter]
+    fn instruction_count(&self) -> u64 {
+        self.chip8.instruction_count()
+    }
+
+    /// Description of the fault that halted the machine, if any
+    #[getter]
+    fn fault(&self) -> Option<String> {
+        self.fault.map(|fault| fault.to_string())
+    }
+
+    /// Saves the whole machine, to `restore` it later
+    fn snapshot<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.chip8.save_state())
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> PyResult<()> {
+        self.chip8.load_state(snapshot).map_err(PyValueError::new_err)?;
+        self.fault = None;
+        Ok(())
+    }
+}
+
+/// A game as a reinforcement learning environment, in the style of Gymnasium.
+///
+/// Actions 0 to 15 hold the key of the same value for a step, 16 holds no key. Observations are
+/// the pixels of the display, as `Machine.framebuffer` returns them. The game's score is only
+/// known to the caller, from `machine.ram()`, so rewards are always 0. An episode terminates
+/// when the machine faults, and is truncated after `max_frames` frames.
+#[pyclass(unsendable, module = "rchip8")]
+pub struct Env {
+    machine: Py<Machine>,
+    episode: Episode,
+}
+
+/// How `Env` plays episodes, apart from the machine
+struct Episode {
+    /// Snapshot the episodes start from
+    initial: Vec<u8>,
+    frames_per_step: u32,
+    max_frames: Option<u64>,
+}
+
+impl Episode {
+    fn reset(&self, machine: &mut Machine) -> PyResult<()> {
+        machine.restore(&self.initial)
+    }
+
+    /// Holds the key of `action` for `frames_per_step` frames. Returns whether the episode
+    /// terminated and whether it was truncated.
+    fn step(&self, machine: &mut Machine, action: usize) -> PyResult<(bool, bool)> {
+        if action >= ACTION_COUNT {
+            return Err(PyValueError::new_err(format!("Invalid action {}, actions are 0 to {}", action, ACTION_COUNT - 1)));
+        }
+
+        let mut keys = [false; 16];
+        if action < 16 {
+            keys[action] = true;
+        }
+        machine.chip8.set_keys(keys);
+
+        for _ in 0..self.frames_per_step {
+            if machine.fault.is_some() {
+                break;
+            }
+            let result = machine.chip8.step_frame();
+            let _ = machine.check(result);
+        }
+
+        let terminated = machine.fault.is_some();
+        let truncated = self.max_frames.is_some_and(|max| machine.chip8.frame_count() >= max);
+        Ok((terminated, truncated))
+    }
+}
+
+#[pymethods]
+impl Env {
+    #[new]
+    #[pyo3(signature = (rom, platform = None, ipf = None, frames_per_step = 4, max_frames = None))]
+    fn new(py: Python<'_>, rom: &[u8], platform: Option<&str>, ipf: Option<u32>, frames_per_step: u32, max_frames: Option<u64>) -> PyResult<Self> {
+        let machine = Machine::load(rom, None, platform, ipf)?;
+        let episode = Episode {
+            initial: machine.chip8.save_state(),
+            frames_per_step: frames_per_step.max(1),
+            max_frames: max_frames,
+        };
+        Ok(Env {
+            machine: Py::new(py, machine)?,
+            episode: episode,
+        })
+    }
+
+    /// The machine, to read registers and memory
+    #[getter]
+    fn machine(&self, py: Python<'_>) -> Py<Machine> {
+        self.machine.clone_ref(py)
+    }
+
+    #[getter]
+    fn action_count(&self) -> usize {
+        ACTION_COUNT
+    }
+
+    /// Shape of the observations: rows, columns
+    #[getter]
+    fn observation_shape(&self, py: Python<'_>) -> (usize, usize) {
+        let machine = self.machine.borrow(py);
+        (machine.height(), machine.width())
+    }
+
+    /// Starts an episode. Returns the observation and the info dict.
+    fn reset<'py>(&mut self, py: Python<'py>) -> PyResult<(Bound<'py, PyBytes>, Bound<'py, PyDict>)> {
+        let mut machine = self.machine.borrow_mut(py);
+        self.episode.reset(&mut machine)?;
+        Ok((machine.framebuffer(py), info(py, &machine)?))
+    }
+
+    /// Holds the key of `action` for `frames_per_step` frames. Returns the observation, the reward,
+    /// whether the episode terminated, whether it was truncated, and the info dict.
+    #[allow(clippy::type_complexity)]
+    fn step<'py>(&mut self, py: Python<'py>, action: usize) -> PyResult<(Bound<'py, PyBytes>, f64, bool, bool, Bound<'py, PyDict>)> {
+        let mut machine = self.machine.borrow_mut(py);
+        let (terminated, truncated) = self.episode.step(&mut machine, action)?;
+        Ok((machine.framebuffer(py), 0.0, terminated, truncated, info(py, &machine)?))
+    }
+}
+
+fn info<'py>(py: Python<'py>, machine: &Machine) -> PyResult<Bound<'py, PyDict>> {
+    let info = PyDict::new_bound(py);
+    info.set_item("frame", machine.chip8.frame_count())?;
+    info.set_item("fault", machine.fault())?;
+    Ok(info)
+}
+
+#[pymodule]
+fn rchip8(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<Machine>()?;
+    module.add_class::<Env>()?;
+    module.add("Fault", module.py().get_type_bound::<Fault>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(words: &[u16]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn machines_are_set_up_like_rchip8_run() {
+        // Hires is SUPER-CHIP only, the platform guessed when none is given
+        let hires = program(&[0x00FF, 0x1202]);
+        let mut machine = Machine::load(&hires, None, None, None).unwrap();
+        machine.step(2).unwrap();
+        assert!(machine.chip8.framebuffer().is_hires());
+
+        let mut machine = Machine::load(&hires, None, Some("chip8"), None).unwrap();
+        assert!(machine.step(1).is_err());
+        assert!(machine.fault().is_some());
+
+        let info = RomInfo { platform: Some(Platform::Chip8), tickrate: Some(5), ..RomInfo::default() };
+        let mut machine = Machine::load(&program(&[0x1200]), Some(info.clone()), None, None).unwrap();
+        machine.run_frame(1).unwrap();
+        assert_eq!(machine.instruction_count(), 5);
+        let mut machine = Machine::load(&program(&[0x1200]), Some(info), None, Some(3)).unwrap();
+        machine.run_frame(2).unwrap();
+        assert_eq!(machine.instruction_count(), 6);
+
+        assert!(Machine::load(&hires, None, Some("nes"), None).is_err());
+    }
+
+    fn start(words: &[u16], max_frames: Option<u64>) -> (Machine, Episode) {
+        let machine = Machine::load(&program(words), None, Some("chip8"), Some(10)).unwrap();
+        let episode = Episode {
+            initial: machine.chip8.save_state(),
+            frames_per_step: 2,
+            max_frames: max_frames,
+        };
+        (machine, episode)
+    }
+
+    #[test]
+    fn episodes_are_truncated_after_their_frames() {
+        let (mut machine, episode) = start(&[0x7001, 0x1200], Some(5));
+        assert_eq!(episode.step(&mut machine, 3).unwrap(), (false, false));
+        assert_eq!(machine.keys(), std::array::from_fn(|key| key == 3));
+        assert_eq!(episode.step(&mut machine, 16).unwrap(), (false, false));
+        assert_eq!(machine.keys(), [false; 16]);
+        assert_eq!(episode.step(&mut machine, 0).unwrap(), (false, true));
+        assert_eq!(machine.frame_count(), 6);
+        assert!(episode.step(&mut machine, ACTION_COUNT).is_err());
+
+        episode.reset(&mut machine).unwrap();
+        assert_eq!((machine.frame_count(), machine.v()[0]), (0, 0));
+        assert_eq!(episode.step(&mut machine, 0).unwrap(), (false, false));
+
+        let (mut machine, episode) = start(&[0x7001, 0x1200], None);
+        for _ in 0..100 {
+            assert_eq!(episode.step(&mut machine, 0).unwrap(), (false, false));
+        }
+    }
+
+    #[test]
+    fn episodes_terminate_on_faults_until_reset() {
+        // Returns with an empty stack
+        let (mut machine, episode) = start(&[0x00EE], Some(5));
+        assert_eq!(episode.step(&mut machine, 0).unwrap(), (true, false));
+        assert_eq!(episode.step(&mut machine, 0).unwrap(), (true, false));
+        assert_eq!(machine.instruction_count(), 0);
+
+        episode.reset(&mut machine).unwrap();
+        assert!(machine.fault().is_none());
+        assert_eq!(episode.step(&mut machine, 0).unwrap(), (true, false));
+    }
+}
@@ -0,0 +1,112 @@
+use serde::{Deserialize, Serialize};
+
+use crate::chip8::platform::Platform;
+
+/// Behaviours that differ between CHIP-8 interpreters.
+/// Names follow the community chip-8 database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Quirks {
+    /// 8xy6 and 8xyE shift Vx in place instead of shifting Vy into Vx
+    pub shift: bool,
+    /// Fx55 and Fx65 increment I by X instead of X + 1
+    pub memory_increment_by_x: bool,
+    /// Fx55 and Fx65 leave I unchanged
+    pub memory_leave_i_unchanged: bool,
+    /// Sprites wrap around the edges of the screen instead of being clipped
+    pub wrap: bool,
+    /// Bnnn jumps to nnn + Vx instead of nnn + V0
+    pub jump: bool,
+    /// Dxyn waits for the next frame before drawing
+    pub vblank: bool,
+    /// 8xy1, 8xy2 and 8xy3 reset VF to 0
+    pub logic: bool,
+}
+
+/// The quirks rchip8 always had, for ROMs of unknown platform: shifts in place, I left unchanged
+/// by Fx55 and Fx65, and sprites wrapping around
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            shift: true,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: true,
+            wrap: true,
+            jump: false,
+            vblank: false,
+            logic: false,
+        }
+    }
+}
+
+impl Quirks {
+    /// Quirks of the reference interpreter of each platform
+    pub fn for_platform(platform: Platform) -> Self {
+        match platform {
+            Platform::Chip8 => Quirks {
+                shift: false,
+                memory_increment_by_x: false,
+                memory_leave_i_unchanged: false,
+                wrap: false,
+                jump: false,
+                vblank: true,
+                logic: true,
+            },
+            Platform::Schip => Quirks {
+                shift: true,
+                memory_increment_by_x: false,
+                memory_leave_i_unchanged: true,
+                wrap: false,
+                jump: true,
+                vblank: false,
+                logic: false,
+            },
+            Platform::XoChip => Quirks {
+                shift: false,
+                memory_increment_by_x: false,
+                memory_leave_i_unchanged: false,
+                wrap: true,
+                jump: false,
+                vblank: false,
+                logic: false,
+            },
+        }
+    }
+
+    /// Overrides the quirks set in `overrides`
+    pub fn apply(&mut self, overrides: &QuirkOverrides) {
+        self.shift = overrides.shift.unwrap_or(self.shift);
+        self.memory_increment_by_x = overrides.memory_increment_by_x.unwrap_or(self.memory_increment_by_x);
+        self.memory_leave_i_unchanged = overrides.memory_leave_i_unchanged.unwrap_or(self.memory_leave_i_unchanged);
+        self.wrap = overrides.wrap.unwrap_or(self.wrap);
+        self.jump = overrides.jump.unwrap_or(self.jump);
+        self.vblank = overrides.vblank.unwrap_or(self.vblank);
+        self.logic = overrides.logic.unwrap_or(self.logic);
+    }
+}
+
+/// A partial set of quirks, as found in ROM metadata
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuirkOverrides {
+    pub shift: Option<bool>,
+    pub memory_increment_by_x: Option<bool>,
+    pub memory_leave_i_unchanged: Option<bool>,
+    pub wrap: Option<bool>,
+    pub jump: Option<bool>,
+    pub vblank: Option<bool>,
+    pub logic: Option<bool>,
+}
+
+impl QuirkOverrides {
+    /// Overrides the quirks set in `other`
+    pub fn merge(&mut self, other: &QuirkOverrides) {
+        self.shift = other.shift.or(self.shift);
+        self.memory_increment_by_x = other.memory_increment_by_x.or(self.memory_increment_by_x);
+        self.memory_leave_i_unchanged = other.memory_leave_i_unchanged.or(self.memory_leave_i_unchanged);
+        self.wrap = other.wrap.or(self.wrap);
+        self.jump = other.jump.or(self.jump);
+        self.vblank = other.vblank.or(self.vblank);
+        self.logic = other.logic.or(self.logic);
+    }
+}
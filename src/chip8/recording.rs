@@ -0,0 +1,273 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+use crate::chip8::database::Palette;
+use crate::chip8::debugger::DebugView;
+use crate::chip8::framebuffer::{Framebuffer, HIRES_HEIGHT, HIRES_WIDTH};
+use crate::chip8::frontend::{AudioSink, VideoSink};
+use crate::chip8::synth::{SamplePattern, Tone};
+
+/// Sample rate of the recorded beep, in Hz
+const SAMPLE_RATE: u32 = 44100;
+
+/// Shortest delay of a GIF frame, in hundredths of a second. Most viewers show faster frames
+/// at 1/10 s, so display changes coming faster are merged into the next frame.
+const MIN_FRAME_DELAY: u64 = 2;
+
+/// Writes the frames to an animated GIF, timed by the frame rate of the emulation rather than
+/// by the clock. Frames are only written when the display changes.
+pub struct RecordingVideo {
+    encoder: gif::Encoder<BufWriter<File>>,
+    fps: u64,
+    /// Image pixels per display pixel
+    scale: usize,
+    palette: Palette,
+
+    /// Frames rendered so far
+    frames: u64,
+    /// Pixels not written yet, and the frame they were first shown at
+    pending: Option<(Vec<u8>, u64)>,
+}
+
+impl RecordingVideo {
+    pub fn create(path: &str, fps: u32, scale: usize) -> Result<Self, String> {
+        let scale = scale.max(1);
+        let file = File::create(path).map_err(|e| format!("Could not create {}: {}", path, e))?;
+        let palette = Palette::default();
+
+        // Large enough for hires, lores pixels are twice as large
+        let (width, height) = ((HIRES_WIDTH * scale) as u16, (HIRES_HEIGHT * scale) as u16);
+        let mut encoder = gif::Encoder::new(BufWriter::new(file), width, height, &colors(palette))
+            .map_err(|e| format!("Could not write {}: {}", path, e))?;
+        encoder.set_repeat(gif::Repeat::Infinite).map_err(|e| format!("Could not write {}: {}", path, e))?;
+
+        Ok(RecordingVideo {
+            encoder: encoder,
+            fps: fps.max(1) as u64,
+            scale: scale,
+            palette: palette,
+            frames: 0,
+            pending: None,
+        })
+    }
+
+    /// Time of a frame, in hundredths of a second
+    fn time(&self, frame: u64) -> u64 {
+        frame * 100 / self.fps
+    }
+
+    /// Writes the pending pixels, shown until `frame`
+    fn write_pending(&mut self, frame: u64) {
+        if let Some((pixels, start)) = self.pending.take() {
+            let delay = (self.time(frame) - self.time(start)).max(MIN_FRAME_DELAY);
+            let width = HIRES_WIDTH * self.scale;
+            let image = gif::Frame {
+                delay: delay.min(u16::MAX as u64) as u16,
+                width: width as u16,
+                height: (pixels.len() / width) as u16,
+                palette: Some(colors(self.palette)),
+                buffer: pixels.into(),
+                ..gif::Frame::default()
+            };
+            if let Err(e) = self.encoder.write_frame(&image) {
+                eprintln!("Could not write the recording: {}", e);
+            }
+        }
+    }
+}
+
+/// A GIF palette: background, foreground, then the colors of plane 2 and of both planes
+fn colors(palette: Palette) -> Vec<u8> {
+    palette.colors().concat()
+}
+
+/// Pixels of the framebuffer as palette indices, the bits of their planes, scaled up
+fn scale_pixels(framebuffer: &Framebuffer, scale: usize) -> Vec<u8> {
+    let width = framebuffer.width();
+    let mut pixels = Vec::with_capacity(framebuffer.planes().len() * scale * scale);
+    for row in framebuffer.planes().chunks(width) {
+        let line: Vec<u8> = row.iter()
+            .flat_map(|planes| std::iter::repeat_n(*planes, scale))
+            .collect();
+        for _ in 0..scale {
+            pixels.extend_from_slice(&line);
+        }
+    }
+    pixels
+}
+
+impl VideoSink for RecordingVideo {
+    fn render(&mut self, framebuffer: &Framebuffer) {
+        let pixels = scale_pixels(framebuffer, self.scale * HIRES_WIDTH / framebuffer.width());
+        let frame = self.frames;
+        self.frames += 1;
+
+        match self.pending.as_ref().map(|(pending, start)| (*pending == pixels, *start)) {
+            Some((true, _)) => {}
+            Some((false, start)) if self.time(frame) - self.time(start) < MIN_FRAME_DELAY => {
+                self.pending = Some((pixels, start));
+            }
+            _ => {
+                self.write_pending(frame);
+                self.pending = Some((pixels, frame));
+            }
+        }
+    }
+
+    fn render_debug(&mut self, _view: &DebugView) {}
+
+    fn clear(&mut self) {}
+
+    fn set_title(&mut self, _title: &str) {}
+
+    fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+}
+
+impl Drop for RecordingVideo {
+    fn drop(&mut self) {
+        self.write_pending(self.frames);
+    }
+}
+
+/// Writes the beep to a 16-bit mono WAV file, one frame of samples per frame
+pub struct RecordingAudio {
+    path: String,
+    writer: BufWriter<File>,
+    samples_per_frame: u32,
+    samples: u32,
+    tone: Tone,
+}
+
+impl RecordingAudio {
+    pub fn create(path: &str, fps: u32, frequency: f32, volume: f32) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Could not create {}: {}", path, e))?;
+        let mut writer = BufWriter::new(file);
+        write_wav_header(&mut writer, 0).map_err(|e| format!("Could not write {}: {}", path, e))?;
+
+        Ok(RecordingAudio {
+            path: String::from(path),
+            writer: writer,
+            samples_per_frame: SAMPLE_RATE / fps.max(1),
+            samples: 0,
+            tone: Tone::new(frequency, volume, SAMPLE_RATE),
+        })
+    }
+
+    fn write_frame(&mut self, beeping: bool) -> io::Result<()> {
+        for _ in 0..self.samples_per_frame {
+            self.writer.write_all(&self.tone.sample(beeping).to_le_bytes())?;
+        }
+        self.samples += self.samples_per_frame;
+        Ok(())
+    }
+
+    /// Writes the sizes of the data into the header
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_wav_header(&mut self.writer, self.samples)?;
+        self.writer.flush()
+    }
+}
+
+/// RIFF header of a WAV file holding `samples` 16-bit mono samples
+fn write_wav_header(writer: &mut impl Write, samples: u32) -> io::Result<()> {
+    let data_size = samples * 2;
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVEfmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    // PCM, mono
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&SAMPLE_RATE.to_le_bytes())?;
+    writer.write_all(&(SAMPLE_RATE * 2).to_le_bytes())?;
+    // Bytes per sample, bits per sample
+    writer.write_all(&2u16.to_le_bytes())?;
+    writer.write_all(&16u16.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())
+}
+
+impl AudioSink for RecordingAudio {
+    fn frame(&mut self, beeping: bool) {
+        if let Err(e) = self.write_frame(beeping) {
+            eprintln!("Could not write {}: {}", self.path, e);
+        }
+    }
+
+    fn set_pattern(&mut self, pattern: Option<SamplePattern>) {
+        self.tone.set_pattern(pattern);
+    }
+}
+
+impl Drop for RecordingAudio {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("Could not write {}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn display_changes_are_recorded_as_timed_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.gif");
+        let path = path.to_str().unwrap();
+
+        let blank = Framebuffer::new();
+        let mut lit = Framebuffer::new();
+        lit.set_pixel(0, 0, true);
+        {
+            let mut video: Box<dyn VideoSink> = Box::new(RecordingVideo::create(path, 60, 1).unwrap());
+            // At 60 fps, frames are shown at 0, 1, 3, 5, 6 and 8 hundredths of a second: the blank
+            // display of frame 0 is replaced by frame 1, too early to be written
+            for framebuffer in [&blank, &lit, &blank, &lit, &lit, &lit] {
+                video.render(framebuffer);
+            }
+        }
+
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(File::open(path).unwrap()).unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (128, 64));
+
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push((frame.delay, frame.buffer.to_vec()));
+        }
+        let delays: Vec<u16> = frames.iter().map(|(delay, _)| *delay).collect();
+        assert_eq!(delays, [3, 2, 5]);
+        // Lores pixels are 2x2 in the image
+        let pixels = &frames[0].1;
+        assert_eq!((pixels[0], pixels[1], pixels[128], pixels[129], pixels[2]), (1, 1, 1, 1, 0));
+        assert!(frames[1].1.iter().all(|pixel| *pixel == 0));
+        assert_eq!(frames[2].1, frames[0].1);
+    }
+
+    #[test]
+    fn beeps_are_recorded_a_frame_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.wav");
+        let path = path.to_str().unwrap();
+        {
+            let mut audio: Box<dyn AudioSink> = Box::new(RecordingAudio::create(path, 60, 440.0, 1.0).unwrap());
+            audio.frame(false);
+            audio.frame(true);
+        }
+
+        let wav = fs::read(path).unwrap();
+        let samples: Vec<i16> = wav[44..].chunks(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]])).collect();
+        assert_eq!(samples.len(), 2 * 735);
+        assert_eq!(&wav[40..44], (2 * 735 * 2u32).to_le_bytes());
+        assert_eq!(&wav[4..8], (36 + 2 * 735 * 2u32).to_le_bytes());
+        assert!(samples[..735].iter().all(|sample| *sample == 0));
+        assert!(samples[735..].iter().any(|sample| *sample > i16::MAX / 2));
+    }
+}
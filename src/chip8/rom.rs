@@ -0,0 +1,290 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+
+use zip::ZipArchive;
+
+use crate::chip8::platform::Platform;
+
+/// Errors that can happen while loading a ROM
+#[derive(Debug)]
+pub enum RomError {
+    /// The file doesn't exist
+    NotFound(String),
+    /// The ROM doesn't fit in memory
+    TooLarge { size: usize, max: usize, platform: Platform },
+    /// The ROM has no data at all
+    Empty,
+    /// The file exists but couldn't be read
+    Unreadable(String, io::Error),
+    /// The file is not a valid ZIP archive
+    BadArchive(String, zip::result::ZipError),
+    /// The archive has no .ch8, .sc8 or .xo8 file
+    NoRomInArchive(String),
+    /// The archive has more than one ROM and none was picked
+    AmbiguousArchive(String, Vec<String>),
+    /// The requested entry doesn't exist in the archive
+    EntryNotFound(String, String),
+    /// The Octo cartridge has no valid program
+    BadCartridge(String, String),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::NotFound(path) => write!(f, "ROM file not found: {}", path),
+            RomError::TooLarge { size, max, platform } => write!(f,
+                "ROM is too large for {}: {} bytes, maximum is {} bytes", platform, size, max),
+            RomError::Empty => write!(f, "ROM is empty"),
+            RomError::Unreadable(path, e) => write!(f, "Could not read ROM file {}: {}", path, e),
+            RomError::BadArchive(path, e) => write!(f, "Could not read ZIP archive {}: {}", path, e),
+            RomError::NoRomInArchive(path) => write!(f, "No .ch8, .sc8 or .xo8 file in {}", path),
+            RomError::AmbiguousArchive(path, entries) => write!(f,
+                "{} has more than one ROM, pick one with {}:<entry>. Available: {}", path, path, entries.join(", ")),
+            RomError::EntryNotFound(path, entry) => write!(f, "{} not found in {}", entry, path),
+            RomError::BadCartridge(path, reason) => write!(f, "Could not load Octo cartridge {}: {}", path, reason),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+/// Path that reads the ROM from stdin
+pub const STDIN_PATH: &str = "-";
+
+/// Extensions recognised as ROMs inside ZIP archives
+const ROM_EXTENSIONS: [&str; 3] = [".ch8", ".sc8", ".xo8"];
+
+/// Reads a ROM. `validate` checks it fits the memory of a platform.
+/// `path` can be a file, `-` for stdin, a ZIP archive holding a single ROM or
+/// `archive.zip:entry` to pick a ROM from an archive.
+pub fn read(path: &str) -> Result<Vec<u8>, RomError> {
+    if path == STDIN_PATH {
+        read_stdin()
+    } else if let Some((archive, entry)) = split_archive_path(path) {
+        read_zip(archive, entry)
+    } else {
+        read_file(path)
+    }
+}
+
+/// Splits `archive.zip:entry` into archive and entry.
+/// A plain `archive.zip` returns no entry.
+fn split_archive_path(path: &str) -> Option<(&str, Option<&str>)> {
+    // Compared on the bytes of `path`: lowercasing can change the length of other characters
+    let is_zip = |end: usize| end >= 4 && path.as_bytes()[end - 4..end].eq_ignore_ascii_case(b".zip");
+    if is_zip(path.len()) {
+        return Some((path, None));
+    }
+
+    path.match_indices(':')
+        .map(|(i, _)| i)
+        .find(|i| is_zip(*i))
+        .map(|i| (&path[..i], Some(&path[i + 1..])))
+}
+
+fn open(path: &str) -> Result<File, RomError> {
+    File::open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => RomError::NotFound(path.to_string()),
+        _ => RomError::Unreadable(path.to_string(), e),
+    })
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, RomError> {
+    let mut program = Vec::new();
+    open(path)?.read_to_end(&mut program).map_err(|e| RomError::Unreadable(path.to_string(), e))?;
+    Ok(program)
+}
+
+fn read_stdin() -> Result<Vec<u8>, RomError> {
+    let mut program = Vec::new();
+    io::stdin().lock().read_to_end(&mut program).map_err(|e| RomError::Unreadable(String::from("stdin"), e))?;
+    Ok(program)
+}
+
+/// Reads `entry` from a ZIP archive. Without an entry, the archive must hold exactly one ROM.
+fn read_zip(path: &str, entry: Option<&str>) -> Result<Vec<u8>, RomError> {
+    let mut archive = ZipArchive::new(open(path)?).map_err(|e| RomError::BadArchive(path.to_string(), e))?;
+
+    let name = match entry {
+        Some(entry) => entry.to_string(),
+        None => {
+            // Names come in no particular order
+            let mut roms: Vec<String> = archive.file_names()
+                .filter(|name| ROM_EXTENSIONS.iter().any(|ext| name.to_lowercase().ends_with(ext)))
+                .map(String::from)
+                .collect();
+            roms.sort();
+
+            match roms.len() {
+                0 => return Err(RomError::NoRomInArchive(path.to_string())),
+                1 => roms[0].clone(),
+                _ => return Err(RomError::AmbiguousArchive(path.to_string(), roms)),
+            }
+        }
+    };
+
+    let mut file = archive.by_name(&name).map_err(|e| match e {
+        zip::result::ZipError::FileNotFound => RomError::EntryNotFound(path.to_string(), name.clone()),
+        _ => RomError::BadArchive(path.to_string(), e),
+    })?;
+
+    let mut program = Vec::new();
+    file.read_to_end(&mut program).map_err(|e| RomError::Unreadable(format!("{}:{}", path, name), e))?;
+    Ok(program)
+}
+
+/// Checks a ROM is not empty and fits the memory of the selected platform.
+pub fn validate(program: &[u8], platform: Platform) -> Result<(), RomError> {
+    if program.is_empty() {
+        return Err(RomError::Empty);
+    }
+
+    if program.len() > platform.max_rom_size() {
+        return Err(RomError::TooLarge {
+            size: program.len(),
+            max: platform.max_rom_size(),
+            platform: platform,
+        });
+    }
+
+    Ok(())
+}
+
+/// Guesses the platform a ROM is written for from the instructions it uses, at even offsets.
+/// Data can look like instructions, so this is only a hint. ROMs using only CHIP-8 instructions
+/// could be written for any platform: there is no guess.
+pub fn guess_platform(program: &[u8]) -> Option<Platform> {
+    if program.len() > Platform::Schip.max_rom_size() {
+        return Some(Platform::XoChip);
+    }
+
+    let words: Vec<u16> = program.chunks_exact(2).map(|word| (word[0] as u16) << 8 | word[1] as u16).collect();
+    let xo_chip = words.iter().any(|word| match word & 0xF000 {
+        0x0000 => word & 0xFFF0 == 0x00D0,
+        0x5000 => word & 0xF == 0x2 || word & 0xF == 0x3,
+        0xF000 => *word == 0xF000 || matches!(word & 0xFF, 0x01 | 0x02 | 0x3A),
+        _ => false,
+    });
+    let schip = words.iter().any(|word| match word & 0xF000 {
+        0x0000 => matches!(word, 0x00FB..=0x00FF) || word & 0xFFF0 == 0x00C0,
+        0xF000 => matches!(word & 0xFF, 0x30 | 0x75 | 0x85),
+        _ => false,
+    });
+
+    match (xo_chip, schip) {
+        (true, _) => Some(Platform::XoChip),
+        (false, true) => Some(Platform::Schip),
+        (false, false) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn empty_roms_are_rejected() {
+        assert!(matches!(validate(&[], Platform::Chip8), Err(RomError::Empty)));
+    }
+
+    #[test]
+    fn roms_must_fit_the_memory_of_the_platform() {
+        for platform in [Platform::Chip8, Platform::Schip, Platform::XoChip] {
+            let max = platform.max_rom_size();
+            assert!(validate(&vec![0; max], platform).is_ok());
+            match validate(&vec![0; max + 1], platform) {
+                Err(RomError::TooLarge { size, max: limit, .. }) => assert_eq!((size, limit), (max + 1, max)),
+                other => panic!("expected TooLarge for {}, got {:?}", platform, other),
+            }
+        }
+        assert!(validate(&vec![0; Platform::Chip8.max_rom_size() + 1], Platform::XoChip).is_ok());
+    }
+
+    #[test]
+    fn missing_files_are_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.ch8");
+        assert!(matches!(read(path.to_str().unwrap()), Err(RomError::NotFound(_))));
+    }
+
+    /// Writes a ZIP archive holding `entries`, each with two bytes of data
+    fn write_zip(path: &std::path::Path, entries: &[&str]) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for (index, entry) in entries.iter().enumerate() {
+            zip.start_file(*entry, zip::write::FileOptions::default()).unwrap();
+            io::Write::write_all(&mut zip, &[0x12, index as u8]).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn archive_paths_split_on_the_zip_extension() {
+        assert_eq!(split_archive_path("roms.zip"), Some(("roms.zip", None)));
+        assert_eq!(split_archive_path("ROMS.Zip"), Some(("ROMS.Zip", None)));
+        assert_eq!(split_archive_path("roms.zip:games/pong.ch8"), Some(("roms.zip", Some("games/pong.ch8"))));
+        assert_eq!(split_archive_path("C:/roms.ZIP:pong.ch8"), Some(("C:/roms.ZIP", Some("pong.ch8"))));
+        assert_eq!(split_archive_path("pong.ch8"), None);
+        assert_eq!(split_archive_path("zip:pong.ch8"), None);
+        assert_eq!(split_archive_path(STDIN_PATH), None);
+    }
+
+    #[test]
+    fn archive_paths_keep_non_ascii_names() {
+        // 'İ' is 2 bytes long, and 3 once lowercased
+        assert_eq!(split_archive_path("İİİ.zip:pong.ch8"), Some(("İİİ.zip", Some("pong.ch8"))));
+        assert_eq!(split_archive_path("İİİ.zip:İ.ch8"), Some(("İİİ.zip", Some("İ.ch8"))));
+        assert_eq!(split_archive_path("İ.ZIP"), Some(("İ.ZIP", None)));
+        assert_eq!(split_archive_path("İİİİ:pong.ch8"), None);
+    }
+
+    #[test]
+    fn archives_with_a_single_rom_are_read_without_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("roms.zip");
+        write_zip(&path, &["readme.txt", "pong.ch8"]);
+        assert_eq!(read(path.to_str().unwrap()).unwrap(), vec![0x12, 1]);
+    }
+
+    #[test]
+    fn archives_without_rom_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("roms.zip");
+        write_zip(&path, &["readme.txt"]);
+        assert!(matches!(read(path.to_str().unwrap()), Err(RomError::NoRomInArchive(_))));
+    }
+
+    #[test]
+    fn archives_with_several_roms_need_an_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("roms.zip");
+        write_zip(&path, &["pong.ch8", "tetris.ch8"]);
+        let path = path.to_str().unwrap();
+
+        match read(path) {
+            Err(RomError::AmbiguousArchive(_, entries)) => assert_eq!(entries, vec!["pong.ch8", "tetris.ch8"]),
+            other => panic!("expected AmbiguousArchive, got {:?}", other),
+        }
+        assert_eq!(read(&format!("{}:tetris.ch8", path)).unwrap(), vec![0x12, 1]);
+        assert!(matches!(read(&format!("{}:missing.ch8", path)), Err(RomError::EntryNotFound(..))));
+    }
+
+    #[test]
+    fn files_are_read_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("game.ch8");
+        fs::write(&path, [0x12, 0x00]).unwrap();
+        assert_eq!(read(path.to_str().unwrap()).unwrap(), vec![0x12, 0x00]);
+    }
+
+    #[test]
+    fn platforms_are_guessed_from_their_instructions() {
+        assert_eq!(guess_platform(&[0x00, 0xE0, 0x12, 0x00]), None);
+        assert_eq!(guess_platform(&[0x00, 0xFF, 0x12, 0x00]), Some(Platform::Schip));
+        assert_eq!(guess_platform(&[0x00, 0xFF, 0xF0, 0x00, 0x12, 0x34]), Some(Platform::XoChip));
+        assert_eq!(guess_platform(&[0; 4000]), Some(Platform::XoChip));
+    }
+}
@@ -1,58 +1,37 @@
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
 use sdl2::pixels::Color;
 use sdl2::rect;
 use sdl2::rect::Point;
-use sdl2::rect::Rect;
 use sdl2::render::Canvas;
 use sdl2::video::WindowPos;
 use sdl2_sys::SDL_RenderSetLogicalSize;
 
+use crate::chip8::database::Palette;
+use crate::chip8::debugger::{DebugPanel, DebugView};
+use crate::chip8::framebuffer::{Framebuffer, LORES_HEIGHT, LORES_WIDTH};
+use crate::chip8::frontend::{InputEvent, InputSource, VideoSink};
 
-// handle the annoying Rect i32
-macro_rules! rect(
-    ($x:expr, $y:expr, $w:expr, $h:expr) => (
-        Rect::new($x as i32, $y as i32, $w as u32, $h as u32)
-    )
-);
 
-#[inline]
-pub fn normalize_coordinates(x: u8, y: u8) -> (usize, usize) {
-    (x as usize % DISPLAY_COLS, y as usize % DISPLAY_ROWS)   
-}
-
-#[inline]
-pub fn idx(x: usize, y: usize) -> usize {
-    y * DISPLAY_COLS + x
-}
-
-#[inline]
-pub fn from_idx(i: usize) -> (usize, usize) {
-    let x = i % DISPLAY_COLS;
-    //println!("x: {}", x);
-    (x , (i / DISPLAY_COLS) )
-}
-
-const SPRITE_MAX_ROWS: usize = 15;
-
-
-const DISPLAY_SIZE: usize = 64 * 32;
 const DISPLAY_SCALE: usize = 20;
-const DISPLAY_COLS: usize = 0x40;
-const DISPLAY_ROWS: usize = 0x20;
 pub struct Screen {
-    //data: Memory,
-    data: [bool; DISPLAY_SIZE],
-    pub sdl_context: sdl2::Sdl,
+    sdl_context: sdl2::Sdl,
     video_subsystem: sdl2::VideoSubsystem,
     //window: sdl2::video::Window,
-    pub canvas: sdl2::render::Canvas<sdl2::video::Window>,
-    debug_canvas: sdl2::render::Canvas<sdl2::video::Window>,
+    canvas: sdl2::render::Canvas<sdl2::video::Window>,
+    debug_panel: DebugPanel,
     background_color: Color,
-    draw_color: Color,
+    /// Colors of the pixels lit in plane 1, plane 2 and both planes
+    draw_colors: [Color; 3],
+    vsync: bool,
+    /// Resolution of the display the canvas is scaled to
+    logical_size: (usize, usize),
 }
 
 impl Screen {
 
-    pub fn new(debug: bool, scale_factor: i32, window_title: &str) -> Self {
+    pub fn new(debug: bool, scale_factor: i32, vsync: bool, window_title: &str) -> Self {
 
         
         let sdl_context = sdl2::init().unwrap();
@@ -63,8 +42,8 @@ impl Screen {
 
         let mut window = video_subsystem.window(window_title, 
             //(DISPLAY_COLS * 10 + 200) as u32, 
-            (DISPLAY_COLS * scale_factor  as usize * 10) as u32, 
-            (DISPLAY_ROWS * scale_factor as usize * 10) as u32
+            (LORES_WIDTH * scale_factor  as usize * 10) as u32, 
+            (LORES_HEIGHT * scale_factor as usize * 10) as u32
         )
             .position_centered()
             .build()
@@ -72,10 +51,14 @@ impl Screen {
 
         window.set_position(WindowPos::from(window.position().0 - 180 )  , WindowPos::from(window.position().1));
             
-        let canvas = window.into_canvas().build().expect("Não foi possível criar um canvas. :(");
+        let mut canvas_builder = window.into_canvas();
+        if vsync {
+            canvas_builder = canvas_builder.present_vsync();
+        }
+        let canvas = canvas_builder.build().expect("Não foi possível criar um canvas. :(");
         unsafe {
             //SDL_RenderSetLogicalSize(canvas.raw(), (DISPLAY_COLS + 20) as i32, DISPLAY_ROWS as i32);
-            SDL_RenderSetLogicalSize(canvas.raw(), (DISPLAY_COLS) as i32, DISPLAY_ROWS as i32);
+            SDL_RenderSetLogicalSize(canvas.raw(), LORES_WIDTH as i32, LORES_HEIGHT as i32);
         }
 
         let mut debug_window = video_subsystem.window(format!("{} - DEBUG", window_title).as_str(), 
@@ -99,172 +82,89 @@ impl Screen {
 
         Screen {
             //data: Memory::new(),
-            data: [false; DISPLAY_SIZE],
             sdl_context: sdl_context,
             video_subsystem: video_subsystem,
             //window: window,
             canvas: canvas,
-            debug_canvas: canvas2,
+            debug_panel: DebugPanel::new(canvas2),
             background_color: Color::RGB(0, 0, 0),
-            draw_color: Color::RGB(255, 255, 255)
+            draw_colors: [Color::RGB(255, 255, 255), Color::RGB(85, 85, 85), Color::RGB(170, 170, 170)],
+            vsync: vsync,
+            logical_size: (LORES_WIDTH, LORES_HEIGHT),
         }
     }
 
-    pub fn set_title(&mut self, title: &str) {
-        self.canvas.window_mut().set_title(title);
-    }
-
-    fn get_pixel(&self, row: usize, col: usize) -> bool {
-        let index = (row * DISPLAY_COLS + col) % DISPLAY_SIZE;
-        self.data[index]
+    /// Returns the keyboard and mouse of the windows. Can only be called once.
+    pub fn input(&self) -> Result<SdlInput, String> {
+        Ok(SdlInput { event_pump: self.sdl_context.event_pump()? })
     }
+}
 
-    fn set_pixel(&mut self, row: usize, col: usize, value: bool) {
-        //println!("set_pixel({}, {}, {})", row, col, value);
-        let index = (row * DISPLAY_COLS + col) % DISPLAY_SIZE;
-        self.data[index] = value;
-        //println!("display.data[{}] = {}", index, value);
+impl VideoSink for Screen {
+    fn set_title(&mut self, title: &str) {
+        self.canvas.window_mut().set_title(title);
     }
 
-    pub fn clear_screen(&mut self) {
-        
-        self.data = [false; DISPLAY_SIZE];
+    fn clear(&mut self) {
         self.canvas.set_draw_color(self.background_color);
         self.canvas.clear();
         //self.canvas.present();
     }
 
-    pub fn buffer_graphics(&mut self, mem: &mut [u8; 4096], vx: u8, vy: u8, n: u8, i: u16) {
-        let mut sprite = [0u8; SPRITE_MAX_ROWS];
-        //let sprite = &mem.data[usize::from(i)..usize::from(i + (n * 2) as u16)];
-
-        for s in i..(i + n as u16) {
-            sprite[usize::from(s - i)] = mem[usize::from(s)];
+    fn set_palette(&mut self, palette: Palette) {
+        let [r, g, b] = palette.background;
+        self.background_color = Color::RGB(r, g, b);
+        for (planes, color) in self.draw_colors.iter_mut().enumerate() {
+            let [r, g, b] = palette.color(planes as u8 + 1);
+            *color = Color::RGB(r, g, b);
         }
+    }
 
-        //println!("Sprite at {}, {}", vx, vy);
-        for row in 0..usize::from(n) {
-            //println!("Sprite row: 0x{:02X} {:08b}", sprite[row], sprite[row]);
+    fn vsync(&self) -> bool {
+        self.vsync
+    }
 
-            let rev = [7, 6, 5, 4, 3, 2, 1, 0];
+    fn render_debug(&mut self, view: &DebugView) {
+        self.debug_panel.render(view, self.background_color);
+    }
 
-            for col in 0..8 {
-                let old_pixel = self.get_pixel( row + vx as usize, rev[col] + vy as usize);
-                let pixel = sprite[row] & (1 << col) != 0;
-                let new_pixel = pixel ^ old_pixel;
-                //println!("old: {}, curr: {}, new: {}", old_pixel, pixel, new_pixel);
-                //println!("old: {}, current: {}, new: {}", old_pixel, pixel, new_pixel);
-                self.set_pixel(vx as usize + row, vy as usize + rev[col], new_pixel);                
-            }
+    fn debug_click(&self, window: u32, x: i32, y: i32) -> Option<u16> {
+        if window != self.debug_panel.window_id() {
+            return None;
         }
+        self.debug_panel.instruction_at(x, y)
     }
 
-    
-    pub fn render_debug(&mut self, pc: u16, v: [u8; 16], dt: u8, st: u8, sp: usize, i: u16, stack: [u16; 16]) {
-        
-        self.debug_canvas.set_draw_color(self.background_color);
-        self.debug_canvas.clear();
-
-        let ttf_context = sdl2::ttf::init().map_err(|e| e.to_string()).expect("msg");
-        let mut font = ttf_context.load_font("FiraCode-Regular.ttf", 128).expect("msg");
-        font.set_style(sdl2::ttf::FontStyle::NORMAL);
-        let texture_creator = self.debug_canvas.texture_creator();
-
-        let pc_text = format!("PC: 0x{:02X}", pc);
-        let pc_surface = font
-            .render(&pc_text)
-            .blended(Color::RGBA(255, 0, 0, 255))
-            .map_err(|e| e.to_string()).expect("msg");
-        let pc_texture = texture_creator
-            .create_texture_from_surface(&pc_surface)
-            .map_err(|e| e.to_string()).expect("msg");
-        let pc_target = rect!(0, 0, 100, 25);
-        self.debug_canvas.copy(&pc_texture, None, Some(pc_target)).expect("msg");
-
-        let dt_text = format!("DT: 0x{:02X}", dt);
-        let dt_surface = font
-            .render(&dt_text)
-            .blended(Color::RGBA(255, 0, 0, 255))
-            .map_err(|e| e.to_string()).expect("msg");
-        let dt_texture = texture_creator
-            .create_texture_from_surface(&dt_surface)
-            .map_err(|e| e.to_string()).expect("msg");
-        let dt_target = rect!(0, 475, 100, 25);
-        self.debug_canvas.copy(&dt_texture, None, Some(dt_target)).expect("msg");
-
-        let st_text = format!("ST: 0x{:02X}", st);
-        let st_surface = font
-            .render(&st_text)
-            .blended(Color::RGBA(255, 0, 0, 255))
-            .map_err(|e| e.to_string()).expect("msg");
-        let st_texture = texture_creator
-            .create_texture_from_surface(&st_surface)
-            .map_err(|e| e.to_string()).expect("msg");
-        let st_target = rect!(0, 501, 100, 25);
-        self.debug_canvas.copy(&st_texture, None, Some(st_target)).expect("msg");
-
-
-        for i in 0..16 {
-            let v_text = format!("V{:01X}: 0x{:02X}", i, v[i]);
-
-            let v_surface = font
-            .render(&v_text)
-            .blended(Color::RGBA(255, 0, 0, 255))
-            .map_err(|e| e.to_string()).expect("msg");
-            let v_texture = texture_creator
-                .create_texture_from_surface(&v_surface)
-                .map_err(|e| e.to_string()).expect("msg");    
-            let v_target = rect!(0, i * 25 + 51, 100, 25);
-            self.debug_canvas.copy(&v_texture, None, Some(v_target)).expect("msg");
+    /// Scrolls the disassembly when the mouse wheel turns over the debug window
+    fn debug_scroll(&mut self, window: u32, lines: i32) {
+        if window == self.debug_panel.window_id() {
+            self.debug_panel.scroll(lines);
         }
+    }
 
-
-        let sp_text = format!("SP: 0x{:02X}", sp);
-        let sp_surface = font
-            .render(&sp_text)
-            .blended(Color::RGBA(255, 0, 0, 255))
-            .map_err(|e| e.to_string()).expect("msg");
-        let sp_texture = texture_creator
-            .create_texture_from_surface(&sp_surface)
-            .map_err(|e| e.to_string()).expect("msg");
-        let sp_target = rect!(0, 526, 100, 25);
-        self.debug_canvas.copy(&sp_texture, None, Some(sp_target)).expect("msg");
-
-        let i_text = format!("I: 0x{:04X}", i);
-        let i_surface = font
-            .render(&i_text)
-            .blended(Color::RGBA(255, 0, 0, 255))
-            .map_err(|e| e.to_string()).expect("msg");
-        let i_texture = texture_creator
-            .create_texture_from_surface(&i_surface)
-            .map_err(|e| e.to_string()).expect("msg");
-        let i_target = rect!(0, 551, 100, 25);
-        self.debug_canvas.copy(&i_texture, None, Some(i_target)).expect("msg");
-
-
-
-        for i in 0..sp {
-
+    fn render(&mut self, framebuffer: &Framebuffer) {
+        // Hires displays fill the same window
+        let size = (framebuffer.width(), framebuffer.height());
+        if size != self.logical_size {
+            unsafe {
+                SDL_RenderSetLogicalSize(self.canvas.raw(), size.0 as i32, size.1 as i32);
+            }
+            self.logical_size = size;
         }
 
-        self.debug_canvas.present();
-    }
-
-    pub fn render(&mut self) {
         //let mut rng = rand::thread_rng();
         self.canvas.set_draw_color(self.background_color);
         self.canvas.clear();
         let mut present = false;
 
-        self.canvas.set_draw_color(self.draw_color);
-        for i in 0..(DISPLAY_COLS * DISPLAY_ROWS) {
-            //print!("{} ", self.data[i]);
-            if self.data[i] {
-                //println!("print pixel {}", self.data[i]);
-                let d = from_idx(i);
-                //println!("i {}, d ({} {})", i, d.0, d.1);
-                self.canvas.draw_point(Point::new( d.0 as i32 , d.1 as i32)).unwrap();        
-                present = true;
+        for (planes, color) in self.draw_colors.iter().enumerate() {
+            self.canvas.set_draw_color(*color);
+            for i in 0..framebuffer.planes().len() {
+                if framebuffer.planes()[i] == planes as u8 + 1 {
+                    let d = (i % size.0, i / size.0);
+                    self.canvas.draw_point(Point::new( d.0 as i32 , d.1 as i32)).unwrap();        
+                    present = true;
+                }
             }
         }
         //println!("");
@@ -274,8 +174,42 @@ impl Screen {
         let rect =Rect::new((DISPLAY_COLS + 1) as i32, 0, 20, 32) ;
         self.canvas.fill_rect(rect);*/
 
-        if present {
+        // With vsync every frame is presented, so presentation paces the emulation.
+        if present || self.vsync {
             self.canvas.present();
         }
     }
-}
\ No newline at end of file
+}
+
+/// Keyboard and mouse events of the SDL windows
+pub struct SdlInput {
+    event_pump: sdl2::EventPump,
+}
+
+impl InputSource for SdlInput {
+    fn poll(&mut self) -> Vec<InputEvent> {
+        self.event_pump.poll_iter().filter_map(|event| match event {
+            Event::Quit {..} => Some(InputEvent::Quit),
+            Event::KeyDown { keycode: Some(keycode), .. } => Some(match keycode {
+                Keycode::Escape => InputEvent::Quit,
+                Keycode::F5 => InputEvent::Reset,
+                Keycode::F6 => InputEvent::Step,
+                Keycode::F7 => InputEvent::SlowDown,
+                Keycode::F8 => InputEvent::SpeedUp,
+                Keycode::F9 => InputEvent::NormalSpeed,
+                Keycode::Tab => InputEvent::FastForward(true),
+                Keycode::Equals | Keycode::KpPlus => InputEvent::MoreInstructions,
+                Keycode::Minus | Keycode::KpMinus => InputEvent::FewerInstructions,
+                Keycode::Space => InputEvent::TogglePause,
+                _ => InputEvent::Key { name: keycode.name(), pressed: true },
+            }),
+            Event::KeyUp { keycode: Some(Keycode::Tab), .. } => Some(InputEvent::FastForward(false)),
+            Event::KeyUp { keycode: Some(keycode), .. } => Some(InputEvent::Key { name: keycode.name(), pressed: false }),
+            Event::MouseButtonDown { window_id, mouse_btn: MouseButton::Left, x, y, .. } => {
+                Some(InputEvent::DebugClick { window: window_id, x: x, y: y })
+            }
+            Event::MouseWheel { window_id, y, .. } => Some(InputEvent::DebugScroll { window: window_id, lines: -y * 2 }),
+            _ => None,
+        }).collect()
+    }
+}
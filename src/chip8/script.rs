@@ -0,0 +1,382 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::rc::Rc;
+
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, FuncArgs, AST, INT};
+
+use crate::chip8::chip8::{Registers, CHIP8};
+use crate::chip8::framebuffer::Framebuffer;
+
+/// Operations a hook may run before it is taken to loop forever
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// What makes the interpreter call the hooks of a script
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookEvent {
+    /// The script was attached to the machine: its top level runs, registering hooks
+    Start,
+    /// A frame ended, after the timers were updated and before the display is rendered
+    Frame,
+    /// The instruction at this address is about to be executed
+    Execute(u16),
+    /// The instruction being executed wrote this byte at this address
+    Write(u16, u8),
+    /// A key of the keypad was pressed or released
+    Key(u8, bool),
+}
+
+/// The parts of the machine scripts read and change. The interpreter lends them to the script
+/// while its hooks run.
+#[derive(Default)]
+pub struct ScriptMachine {
+    pub registers: Registers,
+    pub ram: Vec<u8>,
+    pub framebuffer: Framebuffer,
+    pub keys: [bool; 16],
+    pub frame: u64,
+    pub cycle: u64,
+}
+
+/// Functions a script registered
+#[derive(Default)]
+struct Hooks {
+    frame: Vec<FnPtr>,
+    execute: HashMap<u16, Vec<FnPtr>>,
+    /// Memory write hooks, on one address or on all of them
+    write: Vec<(Option<u16>, FnPtr)>,
+    key: Vec<FnPtr>,
+}
+
+/// A Rhai script attached to a running ROM. Its top level registers functions called on frame
+/// ends, on executing an address, on memory writes and on key events:
+///
+/// ```rhai
+/// on_frame(|| if peek(0x2F0) < 3 { poke(0x2F0, 3) });
+/// on_exec(0x23A, || print(`collision, V0 = ${v(0)}`));
+/// on_write(0x2F0, |address, value| print(`lives: ${value}`));
+/// on_key(|key, pressed| if pressed { print(`key ${key}`) });
+/// ```
+pub struct ScriptHooks {
+    engine: Engine,
+    ast: AST,
+    hooks: Rc<RefCell<Hooks>>,
+    machine: Rc<RefCell<ScriptMachine>>,
+}
+
+impl ScriptHooks {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        ScriptHooks::compile(&source).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn compile(source: &str) -> Result<Self, String> {
+        let hooks = Rc::new(RefCell::new(Hooks::default()));
+        let machine = Rc::new(RefCell::new(ScriptMachine::default()));
+
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        register_hook_api(&mut engine, &hooks);
+        register_machine_api(&mut engine, &machine);
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+
+        Ok(ScriptHooks {
+            engine: engine,
+            ast: ast,
+            hooks: hooks,
+            machine: machine,
+        })
+    }
+
+    /// True when a hook runs before the instruction at `address`
+    pub fn watches_address(&self, address: u16) -> bool {
+        self.hooks.borrow().execute.contains_key(&address)
+    }
+
+    /// True when a hook runs on writes to `address`
+    pub fn watches_write(&self, address: u16) -> bool {
+        self.hooks.borrow().write.iter().any(|(watched, _)| watched.is_none_or(|watched| watched == address))
+    }
+
+    /// True when a hook runs on key events
+    pub fn watches_keys(&self) -> bool {
+        ! self.hooks.borrow().key.is_empty()
+    }
+
+    /// Runs the hooks of `event`, lent the parts of `chip8` they change
+    pub fn call(&mut self, event: HookEvent, chip8: &mut CHIP8) -> Result<(), String> {
+        chip8.lend_to_script(&mut self.machine.borrow_mut());
+        let result = self.dispatch(event);
+        chip8.return_from_script(&mut self.machine.borrow_mut());
+        result.map_err(|e| e.to_string())
+    }
+
+    fn dispatch(&self, event: HookEvent) -> Result<(), Box<EvalAltResult>> {
+        let callbacks = self.callbacks(event);
+        match event {
+            HookEvent::Start => self.engine.run_ast(&self.ast),
+            HookEvent::Frame | HookEvent::Execute(_) => self.run_all(&callbacks, ()),
+            HookEvent::Write(address, value) => self.run_all(&callbacks, (address as INT, value as INT)),
+            HookEvent::Key(key, pressed) => self.run_all(&callbacks, (key as INT, pressed)),
+        }
+    }
+
+    /// The hooks of `event`, cloned since hooks may register other hooks
+    fn callbacks(&self, event: HookEvent) -> Vec<FnPtr> {
+        let hooks = self.hooks.borrow();
+        match event {
+            HookEvent::Start => Vec::new(),
+            HookEvent::Frame => hooks.frame.clone(),
+            HookEvent::Execute(address) => hooks.execute.get(&address).cloned().unwrap_or_default(),
+            HookEvent::Write(address, _) => hooks.write.iter()
+                .filter(|(watched, _)| watched.is_none_or(|watched| watched == address))
+                .map(|(_, callback)| callback.clone())
+                .collect(),
+            HookEvent::Key(_, _) => hooks.key.clone(),
+        }
+    }
+
+    /// Calls `callbacks` in the order they were registered, ignoring what they return
+    fn run_all(&self, callbacks: &[FnPtr], args: impl FuncArgs + Clone) -> Result<(), Box<EvalAltResult>> {
+        for callback in callbacks {
+            let _: Dynamic = callback.call(&self.engine, &self.ast, args.clone())?;
+        }
+        Ok(())
+    }
+}
+
+/// Functions registering hooks: `on_frame(f)`, `on_exec(address, f)`, `on_write(f)`,
+/// `on_write(address, f)` and `on_key(f)`
+fn register_hook_api(engine: &mut Engine, hooks: &Rc<RefCell<Hooks>>) {
+    let registry = hooks.clone();
+    engine.register_fn("on_frame", move |callback: FnPtr| {
+        registry.borrow_mut().frame.push(callback);
+    });
+
+    let registry = hooks.clone();
+    engine.register_fn("on_exec", move |address: INT, callback: FnPtr| -> Result<(), Box<EvalAltResult>> {
+        let address = u16::try_from(address).map_err(|_| format!("Invalid address 0x{:X}", address))?;
+        registry.borrow_mut().execute.entry(address).or_default().push(callback);
+        Ok(())
+    });
+
+    let registry = hooks.clone();
+    engine.register_fn("on_write", move |callback: FnPtr| {
+        registry.borrow_mut().write.push((None, callback));
+    });
+
+    let registry = hooks.clone();
+    engine.register_fn("on_write", move |address: INT, callback: FnPtr| -> Result<(), Box<EvalAltResult>> {
+        let address = u16::try_from(address).map_err(|_| format!("Invalid address 0x{:X}", address))?;
+        registry.borrow_mut().write.push((Some(address), callback));
+        Ok(())
+    });
+
+    let registry = hooks.clone();
+    engine.register_fn("on_key", move |callback: FnPtr| {
+        registry.borrow_mut().key.push(callback);
+    });
+}
+
+/// Functions reading and changing the machine: registers, memory, pixels and keys
+pub fn register_machine_api(engine: &mut Engine, machine: &Rc<RefCell<ScriptMachine>>) {
+    let m = machine.clone();
+    engine.register_fn("pc", move || m.borrow().registers.pc as INT);
+    let m = machine.clone();
+    engine.register_fn("i", move || m.borrow().registers.i as INT);
+    let m = machine.clone();
+    engine.register_fn("set_i", move |value: INT| -> Result<(), Box<EvalAltResult>> {
+        m.borrow_mut().registers.i = u16::try_from(value).map_err(|_| format!("Invalid value {} for I", value))?;
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("v", move |x: INT| -> Result<INT, Box<EvalAltResult>> {
+        Ok(m.borrow().registers.v[register(x)?] as INT)
+    });
+    let m = machine.clone();
+    engine.register_fn("set_v", move |x: INT, value: INT| -> Result<(), Box<EvalAltResult>> {
+        m.borrow_mut().registers.v[register(x)?] = byte(value)?;
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("dt", move || m.borrow().registers.dt as INT);
+    let m = machine.clone();
+    engine.register_fn("set_dt", move |value: INT| -> Result<(), Box<EvalAltResult>> {
+        m.borrow_mut().registers.dt = byte(value)?;
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("st", move || m.borrow().registers.st as INT);
+    let m = machine.clone();
+    engine.register_fn("set_st", move |value: INT| -> Result<(), Box<EvalAltResult>> {
+        m.borrow_mut().registers.st = byte(value)?;
+        Ok(())
+    });
+
+    let m = machine.clone();
+    engine.register_fn("peek", move |address: INT| -> Result<INT, Box<EvalAltResult>> {
+        let machine = m.borrow();
+        Ok(machine.ram[memory_index(&machine, address)?] as INT)
+    });
+    let m = machine.clone();
+    engine.register_fn("poke", move |address: INT, value: INT| -> Result<(), Box<EvalAltResult>> {
+        let mut machine = m.borrow_mut();
+        let index = memory_index(&machine, address)?;
+        machine.ram[index] = byte(value)?;
+        Ok(())
+    });
+
+    let m = machine.clone();
+    engine.register_fn("width", move || m.borrow().framebuffer.width() as INT);
+    let m = machine.clone();
+    engine.register_fn("height", move || m.borrow().framebuffer.height() as INT);
+    let m = machine.clone();
+    engine.register_fn("pixel", move |x: INT, y: INT| -> Result<bool, Box<EvalAltResult>> {
+        let machine = m.borrow();
+        let (x, y) = pixel(&machine.framebuffer, x, y)?;
+        Ok(machine.framebuffer.get_pixel(x, y))
+    });
+    let m = machine.clone();
+    engine.register_fn("set_pixel", move |x: INT, y: INT, lit: bool| -> Result<(), Box<EvalAltResult>> {
+        let mut machine = m.borrow_mut();
+        let (x, y) = pixel(&machine.framebuffer, x, y)?;
+        machine.framebuffer.set_pixel(x, y, lit);
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("screen", move || m.borrow().framebuffer.to_ascii());
+    let m = machine.clone();
+    engine.register_fn("screen_hash", move || m.borrow().framebuffer.sha1_hex());
+
+    let m = machine.clone();
+    engine.register_fn("key", move |key: INT| -> Result<bool, Box<EvalAltResult>> {
+        Ok(m.borrow().keys[key_index(key)?])
+    });
+    let m = machine.clone();
+    engine.register_fn("press", move |key: INT| -> Result<(), Box<EvalAltResult>> {
+        m.borrow_mut().keys[key_index(key)?] = true;
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("release", move |key: INT| -> Result<(), Box<EvalAltResult>> {
+        m.borrow_mut().keys[key_index(key)?] = false;
+        Ok(())
+    });
+
+    let m = machine.clone();
+    engine.register_fn("frame", move || m.borrow().frame as INT);
+    let m = machine.clone();
+    engine.register_fn("cycle", move || m.borrow().cycle as INT);
+}
+
+fn register(x: INT) -> Result<usize, Box<EvalAltResult>> {
+    match x {
+        0..=15 => Ok(x as usize),
+        _ => Err(format!("Invalid register V{}, registers are V0 to V15", x).into()),
+    }
+}
+
+fn byte(value: INT) -> Result<u8, Box<EvalAltResult>> {
+    u8::try_from(value).map_err(|_| format!("Invalid byte {}", value).into())
+}
+
+fn key_index(key: INT) -> Result<usize, Box<EvalAltResult>> {
+    match key {
+        0..=15 => Ok(key as usize),
+        _ => Err(format!("Invalid key {}, keys are 0 to 15", key).into()),
+    }
+}
+
+fn memory_index(machine: &ScriptMachine, address: INT) -> Result<usize, Box<EvalAltResult>> {
+    match usize::try_from(address) {
+        Ok(index) if index < machine.ram.len() => Ok(index),
+        _ => Err(format!("Address 0x{:X} is out of memory", address).into()),
+    }
+}
+
+fn pixel(framebuffer: &Framebuffer, x: INT, y: INT) -> Result<(usize, usize), Box<EvalAltResult>> {
+    match (usize::try_from(x), usize::try_from(y)) {
+        (Ok(x), Ok(y)) if framebuffer.index(x, y).is_some() => Ok((x, y)),
+        _ => Err(format!("Pixel ({}, {}) is off the display", x, y).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::platform::Platform;
+
+    /// I = 0x300, V0 += 1, save V0 at I, loop
+    const PROGRAM: [u8; 8] = [0xA3, 0x00, 0x70, 0x01, 0xF0, 0x55, 0x12, 0x00];
+
+    fn machine(source: &str) -> CHIP8 {
+        let mut chip8 = CHIP8::headless(Platform::Chip8);
+        chip8.load_program(&PROGRAM).unwrap();
+        chip8.set_instructions_per_frame(4);
+        chip8.set_script_hooks(ScriptHooks::compile(source).unwrap());
+        chip8
+    }
+
+    #[test]
+    fn hooks_run_on_their_events() {
+        let mut chip8 = machine("
+            on_frame(|| poke(0x310, peek(0x310) + 1));
+            on_exec(0x204, || set_v(1, v(1) + 1));
+            on_write(0x300, |address, value| set_v(2, value));
+            on_write(|address, value| set_v(3, address - 0x300));
+            on_key(|key, pressed| if pressed { set_v(4, key) } else { set_v(4, 0xFF) });
+        ");
+        chip8.step_frame().unwrap();
+        chip8.step_frame().unwrap();
+        let registers = chip8.registers();
+        assert_eq!(chip8.ram[0x310], 2);
+        assert_eq!((registers.v[1], registers.v[2], registers.v[3]), (2, 2, 0));
+
+        let mut keys = [false; 16];
+        keys[0xA] = true;
+        chip8.set_keys(keys);
+        assert_eq!(chip8.registers().v[4], 0xA);
+        chip8.set_keys([false; 16]);
+        assert_eq!(chip8.registers().v[4], 0xFF);
+    }
+
+    #[test]
+    fn changes_made_by_scripts_are_written_back() {
+        let mut chip8 = machine("
+            set_i(0x302);
+            set_dt(9);
+            poke(0x320, 0x42);
+            set_pixel(3, 4, true);
+            press(7);
+        ");
+        let registers = chip8.registers();
+        assert_eq!((registers.i, registers.dt, chip8.ram[0x320]), (0x302, 9, 0x42));
+        assert!(chip8.framebuffer().get_pixel(3, 4));
+        assert!(chip8.keys()[7]);
+
+        // Memory and pixels are lent, not copied: the program still runs on them
+        assert_eq!(chip8.ram.len(), 4096);
+        chip8.step_frame().unwrap();
+        assert_eq!(chip8.ram[0x300], 1);
+    }
+
+    #[test]
+    fn failing_hooks_are_detached() {
+        let mut chip8 = machine("on_frame(|| { set_v(5, v(5) + 1); if v(5) == 2 { poke(0x10000, 1) } });");
+        for _ in 0..4 {
+            chip8.step_frame().unwrap();
+        }
+        assert_eq!(chip8.registers().v[5], 2);
+
+        // Endless loops are stopped
+        let mut chip8 = machine("on_frame(|| { set_v(6, 1); loop {} });");
+        chip8.step_frame().unwrap();
+        assert_eq!(chip8.registers().v[6], 1);
+        assert_eq!(chip8.ram.len(), 4096);
+    }
+
+    #[test]
+    fn scripts_that_do_not_compile_are_errors() {
+        assert!(ScriptHooks::compile("on_frame(|| ").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use std::cell::RefCell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use rhai::{Dynamic, Engine, EvalAltResult, INT};
+
+use crate::chip8::chip8::CHIP8;
+use crate::chip8::fault::MachineFault;
+use crate::chip8::script::{self, ScriptMachine};
+
+/// Loads the ROM at a path into a machine without window nor sound, set up as it would run
+pub type RomLoader = Box<dyn Fn(&str) -> Result<CHIP8, String>>;
+
+/// The machine a test script drives
+struct Runner {
+    loader: RomLoader,
+    chip8: Option<CHIP8>,
+    /// Directory of the script, which ROM paths are relative to
+    dir: PathBuf,
+}
+
+impl Runner {
+    /// Runs the machine with `run`, given back the memory and registers the script changed
+    fn run(&mut self, machine: &RefCell<ScriptMachine>, run: impl FnOnce(&mut CHIP8) -> Result<(), MachineFault>) -> Result<(), Box<EvalAltResult>> {
+        let chip8 = self.chip8.as_mut().ok_or("No ROM loaded, call load(path) first")?;
+        chip8.return_from_script(&mut machine.borrow_mut());
+        let result = run(chip8);
+        chip8.lend_to_script(&mut machine.borrow_mut());
+        result.map_err(|fault| fault.to_string().into())
+    }
+}
+
+/// Runs a Rhai test script: it loads a ROM, presses keys, runs frames and asserts on the screen,
+/// the registers or memory, with the functions of script hooks and:
+///
+/// ```rhai
+/// load("game.ch8");
+/// frames(60);
+/// press(5); frames(2); release(5);
+/// steps(100);
+/// assert(pixel(10, 4), "the player is drawn");
+/// assert_eq(peek(0x2F0), 3);
+/// assert_eq(v(3), 9, "V3 holds the score");
+/// ```
+///
+/// Fails on the first failed assertion, fault or script error.
+pub fn run_test(path: &str, loader: RomLoader) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+
+    let machine = Rc::new(RefCell::new(ScriptMachine::default()));
+    let runner = Rc::new(RefCell::new(Runner {
+        loader: loader,
+        chip8: None,
+        dir: Path::new(path).parent().map(Path::to_path_buf).unwrap_or_default(),
+    }));
+
+    let mut engine = Engine::new();
+    script::register_machine_api(&mut engine, &machine);
+    register_runner_api(&mut engine, &runner, &machine);
+    register_assertions(&mut engine);
+
+    engine.run(&source).map_err(|e| e.to_string())
+}
+
+/// Functions loading and running ROMs: `load(path)`, `frames(n)` and `steps(n)`
+fn register_runner_api(engine: &mut Engine, runner: &Rc<RefCell<Runner>>, machine: &Rc<RefCell<ScriptMachine>>) {
+    let (r, m) = (runner.clone(), machine.clone());
+    engine.register_fn("load", move |path: &str| -> Result<(), Box<EvalAltResult>> {
+        let mut runner = r.borrow_mut();
+        let path = runner.dir.join(path);
+        let mut chip8 = (runner.loader)(&path.to_string_lossy())?;
+        chip8.lend_to_script(&mut m.borrow_mut());
+        runner.chip8 = Some(chip8);
+        Ok(())
+    });
+
+    let (r, m) = (runner.clone(), machine.clone());
+    engine.register_fn("frames", move |count: INT| -> Result<(), Box<EvalAltResult>> {
+        r.borrow_mut().run(&m, |chip8| (0..count).try_for_each(|_| chip8.step_frame()))
+    });
+
+    let (r, m) = (runner.clone(), machine.clone());
+    engine.register_fn("steps", move |count: INT| -> Result<(), Box<EvalAltResult>> {
+        r.borrow_mut().run(&m, |chip8| (0..count).try_for_each(|_| chip8.step()))
+    });
+}
+
+/// `assert(condition)`, `assert(condition, message)`, `assert_eq(actual, expected)` and
+/// `assert_eq(actual, expected, message)`
+fn register_assertions(engine: &mut Engine) {
+    engine.register_fn("assert", |condition: bool| assert(condition, "assertion failed"));
+    engine.register_fn("assert", |condition: bool, message: &str| assert(condition, message));
+    engine.register_fn("assert_eq", |actual: Dynamic, expected: Dynamic| assert_eq(actual, expected, ""));
+    engine.register_fn("assert_eq", |actual: Dynamic, expected: Dynamic, message: &str| assert_eq(actual, expected, message));
+}
+
+fn assert(condition: bool, message: &str) -> Result<(), Box<EvalAltResult>> {
+    match condition {
+        true => Ok(()),
+        false => Err(message.into()),
+    }
+}
+
+fn assert_eq(actual: Dynamic, expected: Dynamic, message: &str) -> Result<(), Box<EvalAltResult>> {
+    if actual.type_name() == expected.type_name() && actual.to_string() == expected.to_string() {
+        return Ok(());
+    }
+    let message = match message {
+        "" => format!("expected {:?}, got {:?}", expected, actual),
+        _ => format!("{}: expected {:?}, got {:?}", message, expected, actual),
+    };
+    Err(message.into())
+}
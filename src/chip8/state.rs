@@ -0,0 +1,101 @@
+/// Magic bytes at the start of save states
+pub const STATE_MAGIC: &[u8; 4] = b"RC8S";
+
+/// Version of the layout of save states, bumped when it changes
+pub const STATE_VERSION: u8 = 5;
+
+/// Writes the fields of a save state, little endian
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut writer = StateWriter { bytes: Vec::new() };
+        writer.bytes(STATE_MAGIC);
+        writer.u8(STATE_VERSION);
+        writer
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads the fields of a save state written by `StateWriter`
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    /// Checks the magic bytes and the version of the state
+    pub fn new(bytes: &'a [u8]) -> Result<Self, String> {
+        let mut reader = StateReader { bytes: bytes };
+        if reader.bytes(STATE_MAGIC.len())? != STATE_MAGIC {
+            return Err(String::from("Not a save state"));
+        }
+        match reader.u8()? {
+            STATE_VERSION => Ok(reader),
+            version => Err(format!("Unsupported save state version {}", version)),
+        }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < len {
+            return Err(String::from("Save state is truncated"));
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Fails unless every byte was read
+    pub fn finish(self) -> Result<(), String> {
+        match self.bytes.is_empty() {
+            true => Ok(()),
+            false => Err(String::from("Save state is too long")),
+        }
+    }
+}
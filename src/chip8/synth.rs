@@ -1,28 +1,41 @@
 use rodio::{Decoder, OutputStream, Sink, OutputStreamHandle};
-use rodio::source::{SineWave, Source, Amplify};
+use rodio::source::{Source, Amplify};
 use std::{thread, time};
+use std::f32::consts::PI;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::chip8::frontend::AudioSink;
+
+/// Sample rate of the synth
+const SYNTH_SAMPLE_RATE: u32 = 44100;
+
+/// Bits of the sample pattern, played in a loop
+const PATTERN_BITS: f32 = 128.0;
 
 pub struct Synth {
     sink: Sink,
     stream: OutputStream,
     stream_handle: OutputStreamHandle,
-    //source: Amplify<SineWave>,
+    /// Shared with the source the sink plays, to change the pattern while it plays
+    tone: Arc<Mutex<Tone>>,
     pub is_playing: bool,
 }
 
 impl Synth {
-    pub fn new() -> Self {
+    /// Returns a synth beeping at `frequency` Hz, `volume` being the amplitude from 0 to 1
+    pub fn new(frequency: f32, volume: f32) -> Self {
         let (_stream, stream_handle) = OutputStream::try_default().unwrap();
         let sink = Sink::try_new(&stream_handle).unwrap();
-        let source = SineWave::new(440.0).amplify(0.20);
-        sink.append(source);
+        let tone = Arc::new(Mutex::new(Tone::new(frequency, volume, SYNTH_SAMPLE_RATE)));
+        sink.append(ToneSource { tone: Arc::clone(&tone) });
         //sink.sleep_until_end();
         sink.pause();
         Synth { 
             sink: sink,
             stream: _stream,
-            //source: source,
             stream_handle: stream_handle,
+            tone: tone,
             is_playing: false,
         }
     }
@@ -36,4 +49,147 @@ impl Synth {
         self.is_playing = false;
         self.sink.pause();
     }
-}
\ No newline at end of file
+}
+
+/// The 1-bit sample pattern of XO-CHIP, loaded by F002, and its pitch, set by Fx3A
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SamplePattern {
+    /// 128 bits, most significant bit of the first byte first
+    pub bits: [u8; 16],
+    pub pitch: u8,
+}
+
+impl SamplePattern {
+    /// Bits played per second: 4000 at pitch 64, an octave higher every 48 steps
+    pub fn rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.bits[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+}
+
+/// Samples of the beep: a sine wave, or the sample pattern of XO-CHIP when one is loaded
+pub struct Tone {
+    /// Phase of the sine wave, in radians
+    phase: f32,
+    /// Phase advanced by each sample
+    step: f32,
+    volume: f32,
+    sample_rate: u32,
+    pattern: Option<SamplePattern>,
+    /// Bit of the pattern being played, and how far into it
+    position: f32,
+}
+
+impl Tone {
+    pub fn new(frequency: f32, volume: f32, sample_rate: u32) -> Self {
+        Tone {
+            phase: 0.0,
+            step: 2.0 * PI * frequency / sample_rate as f32,
+            volume: volume.clamp(0.0, 1.0),
+            sample_rate: sample_rate,
+            pattern: None,
+            position: 0.0,
+        }
+    }
+
+    /// Plays `pattern` instead of the sine wave, or the sine wave again
+    pub fn set_pattern(&mut self, pattern: Option<SamplePattern>) {
+        self.pattern = pattern;
+    }
+
+    /// Next sample, silent unless `beeping`
+    pub fn sample(&mut self, beeping: bool) -> i16 {
+        if ! beeping {
+            return 0;
+        }
+        let amplitude = self.volume * i16::MAX as f32;
+        match self.pattern {
+            Some(pattern) => {
+                let lit = pattern.bit(self.position as usize);
+                self.position = (self.position + pattern.rate() / self.sample_rate as f32) % PATTERN_BITS;
+                if lit { amplitude as i16 } else { -amplitude as i16 }
+            }
+            None => {
+                self.phase = (self.phase + self.step) % (2.0 * PI);
+                (self.phase.sin() * amplitude) as i16
+            }
+        }
+    }
+}
+
+/// Plays a tone shared with the synth, endlessly. The sink pauses it between beeps.
+struct ToneSource {
+    tone: Arc<Mutex<Tone>>,
+}
+
+impl Iterator for ToneSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        Some(self.tone.lock().unwrap().sample(true))
+    }
+}
+
+impl Source for ToneSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SYNTH_SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl AudioSink for Synth {
+    fn frame(&mut self, beeping: bool) {
+        if beeping && ! self.is_playing {
+            self.play();
+        }
+        if ! beeping && self.is_playing {
+            self.pause();
+        }
+    }
+
+    fn set_pattern(&mut self, pattern: Option<SamplePattern>) {
+        self.tone.lock().unwrap().set_pattern(pattern);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_play_their_bits_at_their_pitch() {
+        // Bits alternate every byte: 8 lit bits then 8 dark ones
+        let mut bits = [0; 16];
+        for byte in bits.iter_mut().step_by(2) {
+            *byte = 0xFF;
+        }
+        let pattern = SamplePattern { bits: bits, pitch: 64 };
+        assert_eq!(pattern.rate(), 4000.0);
+        assert_eq!(SamplePattern { bits: bits, pitch: 112 }.rate(), 8000.0);
+
+        // At 8000 samples per second, each bit lasts two samples
+        let mut tone = Tone::new(440.0, 1.0, 8000);
+        tone.set_pattern(Some(pattern));
+        let samples: Vec<i16> = (0..32).map(|_| tone.sample(true)).collect();
+        assert!(samples[..16].iter().all(|sample| *sample == i16::MAX));
+        assert!(samples[16..].iter().all(|sample| *sample == -i16::MAX));
+        assert_eq!(tone.sample(false), 0);
+
+        tone.set_pattern(None);
+        assert!(tone.sample(true) < i16::MAX);
+    }
+}
@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+use std::io::{self, Stderr, Write};
+use std::time::{Duration, Instant};
+
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::event::{KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags};
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen, SetTitle};
+use crossterm::{execute, queue};
+
+use crate::chip8::database::Palette;
+use crate::chip8::debugger::DebugView;
+use crate::chip8::disasm;
+use crate::chip8::framebuffer::Framebuffer;
+use crate::chip8::frontend::{InputEvent, InputSource, VideoSink};
+
+/// How long a key stays held after the terminal last reported it, when the terminal can't
+/// report releases
+const KEY_HOLD: Duration = Duration::from_millis(200);
+
+/// Instructions shown in the register panel, and how many of them come before PC
+const DISASM_ROWS: usize = 5;
+const DISASM_ROWS_BEFORE_PC: usize = 2;
+
+/// Characters the display is drawn with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum TerminalGlyphs {
+    /// Half blocks, 1x2 pixels per character
+    HalfBlocks,
+    /// Braille patterns, 2x4 pixels per character. Needs a font with braille
+    Braille,
+}
+
+/// A character of the display, drawn in the colors of the planes given as foreground and
+/// background
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Cell {
+    glyph: char,
+    foreground: u8,
+    background: u8,
+}
+
+impl Cell {
+    fn new(glyph: char, foreground: u8, background: u8) -> Self {
+        Cell { glyph: glyph, foreground: foreground, background: background }
+    }
+}
+
+const BLANK: Cell = Cell { glyph: ' ', foreground: 1, background: 0 };
+
+/// Draws the display in the terminal, with the register panel alongside when debugging.
+/// Only the lines that changed since the last frame are redrawn.
+///
+/// Draws on stderr, so the messages and instruction logs printed on stdout can be redirected.
+pub struct TerminalVideo {
+    terminal: Stderr,
+    glyphs: TerminalGlyphs,
+    /// Colors of the pixels, by the bits of their planes
+    colors: [Color; 4],
+    title: String,
+
+    /// Rows of the display and of the panel
+    screen: Vec<Vec<Cell>>,
+    panel: Vec<String>,
+    /// Lines on the terminal, to redraw only what changed
+    drawn: Vec<(Vec<Cell>, String)>,
+}
+
+impl TerminalVideo {
+    pub fn new(glyphs: TerminalGlyphs) -> io::Result<Self> {
+        let mut terminal = io::stderr();
+        execute!(terminal, EnterAlternateScreen, Hide, Clear(ClearType::All))?;
+
+        Ok(TerminalVideo {
+            terminal: terminal,
+            glyphs: glyphs,
+            colors: Palette::default().colors().map(|[r, g, b]| Color::Rgb { r: r, g: g, b: b }),
+            title: String::new(),
+            screen: Vec::new(),
+            panel: Vec::new(),
+            drawn: Vec::new(),
+        })
+    }
+
+    /// Forgets what is on the terminal, so the next frame redraws every line
+    fn invalidate(&mut self) {
+        self.drawn.clear();
+        let _ = queue!(self.terminal, Clear(ClearType::All));
+    }
+
+    fn draw(&mut self) -> io::Result<()> {
+        let width = self.screen.first().map_or(0, Vec::len);
+        let rows = self.screen.len().max(self.panel.len());
+
+        let mut lines: Vec<(Vec<Cell>, String)> = (0..rows)
+            .map(|row| {
+                let screen = self.screen.get(row).cloned().unwrap_or_else(|| vec![BLANK; width]);
+                (screen, self.panel.get(row).cloned().unwrap_or_default())
+            })
+            .collect();
+        lines.push((Vec::new(), self.title.clone()));
+
+        for (row, line) in lines.iter().enumerate() {
+            if self.drawn.get(row) == Some(line) {
+                continue;
+            }
+
+            let (screen, panel) = line;
+            queue!(self.terminal, MoveTo(0, row as u16))?;
+            // Colors are only sent when they change along the row
+            let mut colors = None;
+            for cell in screen {
+                if colors != Some((cell.foreground, cell.background)) {
+                    queue!(self.terminal,
+                        SetForegroundColor(self.colors[cell.foreground as usize]),
+                        SetBackgroundColor(self.colors[cell.background as usize]))?;
+                    colors = Some((cell.foreground, cell.background));
+                }
+                queue!(self.terminal, Print(cell.glyph))?;
+            }
+            queue!(self.terminal,
+                ResetColor,
+                Print("  "),
+                Print(panel),
+                Clear(ClearType::UntilNewLine))?;
+        }
+        self.drawn = lines;
+        self.terminal.flush()
+    }
+}
+
+impl Drop for TerminalVideo {
+    fn drop(&mut self) {
+        let _ = execute!(self.terminal, ResetColor, Show, LeaveAlternateScreen);
+    }
+}
+
+/// Rows of characters showing the display, two pixels per character vertically. Pixels of
+/// different colors are drawn as an upper half block on the color of the lower pixel.
+fn half_blocks(framebuffer: &Framebuffer) -> Vec<Vec<Cell>> {
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    let planes = |x: usize, y: usize| if y < height { framebuffer.planes()[y * width + x] } else { 0 };
+
+    (0..height).step_by(2)
+        .map(|y| (0..width)
+            .map(|x| match (planes(x, y), planes(x, y + 1)) {
+                (0, 0) => BLANK,
+                (top, 0) => Cell::new('▀', top, 0),
+                (0, bottom) => Cell::new('▄', bottom, 0),
+                (top, bottom) if top == bottom => Cell::new('█', top, 0),
+                (top, bottom) => Cell::new('▀', top, bottom),
+            })
+            .collect())
+        .collect()
+}
+
+/// Rows of characters showing the display, 2x4 pixels per braille character. A character has
+/// one color: the one of the planes of its dots together.
+fn braille(framebuffer: &Framebuffer) -> Vec<Vec<Cell>> {
+    // Bit of each dot of a braille pattern, by column and row
+    const DOTS: [[u32; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    let planes = |x: usize, y: usize| if x < width && y < height { framebuffer.planes()[y * width + x] } else { 0 };
+
+    (0..height).step_by(4)
+        .map(|y| (0..width).step_by(2)
+            .map(|x| {
+                let mut bits = 0;
+                let mut color = 0;
+                for (col, dots) in DOTS.iter().enumerate() {
+                    for (row, dot) in dots.iter().enumerate() {
+                        let planes = planes(x + col, y + row);
+                        if planes != 0 {
+                            bits |= dot;
+                            color |= planes;
+                        }
+                    }
+                }
+                Cell::new(char::from_u32(0x2800 + bits).unwrap_or(' '), color.max(1), 0)
+            })
+            .collect())
+        .collect()
+}
+
+/// Registers, instructions around PC, stack, keypad and fault, one line per row
+fn panel(view: &DebugView) -> Vec<String> {
+    let mut lines = Vec::new();
+    lines.push(format!("PC {:04X}  I {:04X}  SP {:X}", view.pc, view.i, view.sp));
+    lines.push(format!("DT {:02X}  ST {:02X}  cycle {}", view.dt, view.st, view.cycle));
+    lines.push(String::new());
+
+    for (row, registers) in view.v.chunks(4).enumerate() {
+        let registers: Vec<String> = registers.iter().enumerate()
+            .map(|(col, value)| format!("V{:X} {:02X}", row * 4 + col, value))
+            .collect();
+        lines.push(registers.join("  "));
+    }
+    lines.push(String::new());
+
+    let len = view.ram.len();
+    for row in 0..DISASM_ROWS {
+        let address = (view.pc as usize + len * 2 + row * 2 - DISASM_ROWS_BEFORE_PC * 2) % len;
+        let word = (view.ram[address] as u16) << 8 | view.ram[(address + 1) % len] as u16;
+        let current = if address == view.pc as usize { '>' } else { ' ' };
+        let breakpoint = if view.breakpoints.contains(&(address as u16)) { '*' } else { ' ' };
+        lines.push(format!("{}{}{:04X} {:04X} {}", current, breakpoint, address, word, disasm::disassemble(word)));
+    }
+    lines.push(String::new());
+
+    let stack: Vec<String> = view.stack[..view.sp.min(view.stack.len())].iter().map(|address| format!("{:04X}", address)).collect();
+    lines.push(format!("Stack {}", stack.join(" ")));
+    let keys: Vec<String> = (0..16).filter(|key| view.keys[*key]).map(|key| format!("{:X}", key)).collect();
+    lines.push(format!("Keys  {}", keys.join(" ")));
+
+    if let Some(fault) = view.fault {
+        lines.push(fault.to_string());
+    }
+    lines
+}
+
+impl VideoSink for TerminalVideo {
+    fn render(&mut self, framebuffer: &Framebuffer) {
+        let screen = match self.glyphs {
+            TerminalGlyphs::HalfBlocks => half_blocks(framebuffer),
+            TerminalGlyphs::Braille => braille(framebuffer),
+        };
+        // A change of resolution moves the panel and leaves lines behind
+        if screen.len() != self.screen.len() {
+            self.invalidate();
+        }
+        self.screen = screen;
+        self.draw().expect("Could not draw on the terminal");
+    }
+
+    fn render_debug(&mut self, view: &DebugView) {
+        self.panel = panel(view);
+        self.draw().expect("Could not draw on the terminal");
+    }
+
+    /// The next frame draws the cleared framebuffer
+    fn clear(&mut self) {}
+
+    /// Also redraws the whole terminal, wiping the messages printed over it
+    fn set_title(&mut self, title: &str) {
+        self.title = String::from(title);
+        let _ = queue!(self.terminal, SetTitle(title));
+        self.invalidate();
+    }
+
+    fn set_palette(&mut self, palette: Palette) {
+        self.colors = palette.colors().map(|[r, g, b]| Color::Rgb { r: r, g: g, b: b });
+        self.invalidate();
+    }
+}
+
+/// Reads the keyboard of the terminal.
+///
+/// Keys are named as SDL names them, so the keymap applies as with the SDL window. Most terminals
+/// only report key presses: a key is then held for `KEY_HOLD` after its last press or repeat,
+/// and TAB toggles fast forward. Terminals implementing the kitty keyboard protocol report
+/// releases too.
+pub struct TerminalInput {
+    /// True when the terminal reports key releases
+    releases: bool,
+    /// Keys held, with when the terminal last reported them
+    held: HashMap<String, Instant>,
+    fast_forward: bool,
+}
+
+impl TerminalInput {
+    pub fn new() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+
+        let releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if releases {
+            execute!(io::stderr(), PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+        }
+
+        Ok(TerminalInput {
+            releases: releases,
+            held: HashMap::new(),
+            fast_forward: false,
+        })
+    }
+
+    fn key(&mut self, key: KeyEvent, events: &mut Vec<InputEvent>) {
+        let name = match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                events.push(InputEvent::Quit);
+                return;
+            }
+            KeyCode::Tab => {
+                let enabled = match key.kind {
+                    KeyEventKind::Press if self.releases => true,
+                    KeyEventKind::Press => ! self.fast_forward,
+                    KeyEventKind::Release => false,
+                    KeyEventKind::Repeat => return,
+                };
+                self.fast_forward = enabled;
+                events.push(InputEvent::FastForward(enabled));
+                return;
+            }
+            KeyCode::Enter => String::from("Return"),
+            KeyCode::Up => String::from("Up"),
+            KeyCode::Down => String::from("Down"),
+            KeyCode::Left => String::from("Left"),
+            KeyCode::Right => String::from("Right"),
+            KeyCode::Char(c) if c.is_ascii_alphanumeric() => c.to_ascii_uppercase().to_string(),
+            code if key.kind == KeyEventKind::Press => {
+                let hotkey = match code {
+                    KeyCode::Esc => InputEvent::Quit,
+                    KeyCode::F(5) => InputEvent::Reset,
+                    KeyCode::F(6) => InputEvent::Step,
+                    KeyCode::F(7) => InputEvent::SlowDown,
+                    KeyCode::F(8) => InputEvent::SpeedUp,
+                    KeyCode::F(9) => InputEvent::NormalSpeed,
+                    KeyCode::Char('+') | KeyCode::Char('=') => InputEvent::MoreInstructions,
+                    KeyCode::Char('-') => InputEvent::FewerInstructions,
+                    KeyCode::Char(' ') => InputEvent::TogglePause,
+                    _ => return,
+                };
+                events.push(hotkey);
+                return;
+            }
+            _ => return,
+        };
+
+        match key.kind {
+            KeyEventKind::Press | KeyEventKind::Repeat => {
+                if self.held.insert(name.clone(), Instant::now()).is_none() {
+                    events.push(InputEvent::Key { name: name, pressed: true });
+                }
+            }
+            KeyEventKind::Release => {
+                if self.held.remove(&name).is_some() {
+                    events.push(InputEvent::Key { name: name, pressed: false });
+                }
+            }
+        }
+    }
+}
+
+impl InputSource for TerminalInput {
+    fn poll(&mut self) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+        while event::poll(Duration::ZERO).unwrap_or(false) {
+            match event::read() {
+                Ok(Event::Key(key)) => self.key(key, &mut events),
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+
+        if ! self.releases {
+            let released: Vec<String> = self.held.iter()
+                .filter(|(_, last)| last.elapsed() >= KEY_HOLD)
+                .map(|(name, _)| name.clone())
+                .collect();
+            for name in released {
+                self.held.remove(&name);
+                events.push(InputEvent::Key { name: name, pressed: false });
+            }
+        }
+        events
+    }
+}
+
+impl Drop for TerminalInput {
+    fn drop(&mut self) {
+        if self.releases {
+            let _ = execute!(io::stderr(), PopKeyboardEnhancementFlags);
+        }
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lights the corners of the display
+    fn corners(hires: bool) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.set_hires(hires);
+        let (right, bottom) = (framebuffer.width() - 1, framebuffer.height() - 1);
+        for (x, y) in [(0, 0), (right, 0), (0, bottom), (right, bottom)] {
+            framebuffer.set_pixel(x, y, true);
+        }
+        framebuffer
+    }
+
+    fn size(rows: &[Vec<Cell>]) -> (usize, usize) {
+        (rows[0].len(), rows.len())
+    }
+
+    fn text(row: &[Cell]) -> String {
+        row.iter().map(|cell| cell.glyph).collect()
+    }
+
+    #[test]
+    fn half_blocks_draw_both_resolutions() {
+        for (hires, width, height) in [(false, 64, 16), (true, 128, 32)] {
+            let rows = half_blocks(&corners(hires));
+            assert_eq!(size(&rows), (width, height));
+            assert!(text(&rows[0]).starts_with('▀') && text(&rows[0]).ends_with('▀'));
+            assert!(text(&rows[height - 1]).starts_with('▄') && text(&rows[height - 1]).ends_with('▄'));
+        }
+    }
+
+    #[test]
+    fn braille_draws_both_resolutions() {
+        for (hires, width, height) in [(false, 32, 8), (true, 64, 16)] {
+            let rows = braille(&corners(hires));
+            assert_eq!(size(&rows), (width, height));
+            assert!(text(&rows[0]).starts_with('⠁') && text(&rows[0]).ends_with('⠈'));
+            assert!(text(&rows[height - 1]).starts_with('⡀') && text(&rows[height - 1]).ends_with('⢀'));
+        }
+    }
+
+    #[test]
+    fn planes_are_drawn_in_their_colors() {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.set_pixel(0, 0, true);
+        framebuffer.select_planes(2);
+        framebuffer.set_pixel(0, 1, true);
+        framebuffer.set_pixel(1, 0, true);
+        framebuffer.set_pixel(1, 1, true);
+
+        let rows = half_blocks(&framebuffer);
+        assert_eq!(rows[0][0], Cell::new('▀', 1, 2));
+        assert_eq!(rows[0][1], Cell::new('█', 2, 0));
+
+        let rows = braille(&framebuffer);
+        assert_eq!(rows[0][0].foreground, 3);
+        assert_eq!(rows[0][1], Cell::new('\u{2800}', 1, 0));
+    }
+}
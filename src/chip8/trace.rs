@@ -0,0 +1,320 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+
+use crate::chip8::disasm;
+use crate::chip8::disasm::OpcodeClass;
+
+/// Magic bytes at the start of a binary trace, followed by the format version
+pub const BINARY_MAGIC: &[u8; 4] = b"RC8T";
+pub const BINARY_VERSION: u8 = 1;
+
+/// Size of a record in a binary trace
+pub const BINARY_RECORD_SIZE: usize = 34;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum TraceFormat {
+    /// One line per instruction:
+    /// `<cycle> PC:<pc> OP:<opcode> V:<V0..VF> I:<i> SP:<sp> DT:<dt> ST:<st> <mnemonic>`
+    Text,
+    /// `RC8T`, a version byte, then 34 byte little endian records:
+    /// cycle u64, pc u16, opcode u16, V0..VF, i u16, sp u8, dt u8, st u8, padding u8
+    Binary,
+}
+
+/// State of the machine before an instruction is executed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    pub v: [u8; 16],
+    pub i: u16,
+    pub sp: u8,
+    pub dt: u8,
+    pub st: u8,
+}
+
+impl TraceEntry {
+    pub fn to_text(&self) -> String {
+        let v: String = self.v.iter().map(|r| format!("{:02X}", r)).collect();
+        format!("{} PC:{:04X} OP:{:04X} V:{} I:{:04X} SP:{:X} DT:{:02X} ST:{:02X} {}",
+            self.cycle, self.pc, self.opcode, v, self.i, self.sp, self.dt, self.st, disasm::disassemble(self.opcode))
+    }
+
+    pub fn to_bytes(&self) -> [u8; BINARY_RECORD_SIZE] {
+        let mut bytes = [0; BINARY_RECORD_SIZE];
+        bytes[0..8].copy_from_slice(&self.cycle.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.pc.to_le_bytes());
+        bytes[10..12].copy_from_slice(&self.opcode.to_le_bytes());
+        bytes[12..28].copy_from_slice(&self.v);
+        bytes[28..30].copy_from_slice(&self.i.to_le_bytes());
+        bytes[30] = self.sp;
+        bytes[31] = self.dt;
+        bytes[32] = self.st;
+        bytes
+    }
+
+    /// Parses a line of a text trace. The mnemonic is ignored.
+    pub fn from_text(line: &str) -> Result<Self, String> {
+        let mut fields = line.split_whitespace();
+        let cycle = fields.next().ok_or("empty line")?;
+        let mut entry = TraceEntry {
+            cycle: cycle.parse().map_err(|_| format!("invalid cycle {}", cycle))?,
+            pc: 0, opcode: 0, v: [0; 16], i: 0, sp: 0, dt: 0, st: 0,
+        };
+
+        let hex = |name: &str, value: &str| {
+            u16::from_str_radix(value, 16).map_err(|_| format!("invalid {} {}", name, value))
+        };
+
+        let mut found = 0;
+        for field in fields {
+            let Some((name, value)) = field.split_once(':') else {
+                continue;
+            };
+            match name {
+                "PC" => entry.pc = hex(name, value)?,
+                "OP" => entry.opcode = hex(name, value)?,
+                "I" => entry.i = hex(name, value)?,
+                "SP" => entry.sp = hex(name, value)? as u8,
+                "DT" => entry.dt = hex(name, value)? as u8,
+                "ST" => entry.st = hex(name, value)? as u8,
+                "V" if value.len() == 32 => {
+                    for (index, register) in entry.v.iter_mut().enumerate() {
+                        *register = hex(name, &value[index * 2..index * 2 + 2])? as u8;
+                    }
+                }
+                "V" => return Err(format!("expected 16 registers, got {}", value)),
+                _ => continue,
+            }
+            found += 1;
+        }
+
+        if found < 7 {
+            return Err(String::from("expected PC, OP, V, I, SP, DT and ST"));
+        }
+        Ok(entry)
+    }
+
+    pub fn from_bytes(bytes: &[u8; BINARY_RECORD_SIZE]) -> Self {
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let mut cycle = [0; 8];
+        cycle.copy_from_slice(&bytes[0..8]);
+        let mut v = [0; 16];
+        v.copy_from_slice(&bytes[12..28]);
+
+        TraceEntry {
+            cycle: u64::from_le_bytes(cycle),
+            pc: u16_at(8),
+            opcode: u16_at(10),
+            v: v,
+            i: u16_at(28),
+            sp: bytes[30],
+            dt: bytes[31],
+            st: bytes[32],
+        }
+    }
+}
+
+/// Reads a trace in the text or binary format
+pub fn read(path: &str) -> Result<Vec<TraceEntry>, String> {
+    let data = fs::read(path).map_err(|e| format!("Could not read trace {}: {}", path, e))?;
+
+    if data.starts_with(BINARY_MAGIC) {
+        if data.get(BINARY_MAGIC.len()) != Some(&BINARY_VERSION) {
+            return Err(format!("Unsupported binary trace version in {}", path));
+        }
+        let records = &data[BINARY_MAGIC.len() + 1..];
+        if records.len() % BINARY_RECORD_SIZE != 0 {
+            return Err(format!("Truncated binary trace {}", path));
+        }
+        return Ok(records.chunks_exact(BINARY_RECORD_SIZE)
+            .map(|record| TraceEntry::from_bytes(record.try_into().unwrap()))
+            .collect());
+    }
+
+    let text = String::from_utf8(data).map_err(|_| format!("{} is not a trace", path))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| ! line.trim().is_empty())
+        .map(|(number, line)| TraceEntry::from_text(line)
+            .map_err(|e| format!("{}:{}: {}", path, number + 1, e)))
+        .collect()
+}
+
+/// Inclusive range of addresses, written in hex as `200-3FF`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AddressRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl AddressRange {
+    pub fn contains(&self, address: u16) -> bool {
+        self.start <= address && address <= self.end
+    }
+}
+
+impl FromStr for AddressRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |value: &str| {
+            let value = value.trim().trim_start_matches("0x").trim_start_matches("0X");
+            u16::from_str_radix(value, 16).map_err(|e| format!("invalid address {}: {}", value, e))
+        };
+
+        let (start, end) = s.split_once('-').ok_or(format!("expected START-END, got {}", s))?;
+        let range = AddressRange { start: parse(start)?, end: parse(end)? };
+        if range.start > range.end {
+            return Err(format!("{} starts after it ends", s));
+        }
+        Ok(range)
+    }
+}
+
+/// Which instructions end up in the trace. Empty filters let everything through.
+#[derive(Clone, Debug, Default)]
+pub struct TraceFilter {
+    pub ranges: Vec<AddressRange>,
+    pub classes: Vec<OpcodeClass>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, entry: &TraceEntry) -> bool {
+        (self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(entry.pc)))
+            && (self.classes.is_empty() || self.classes.contains(&disasm::classify(entry.opcode)))
+    }
+}
+
+/// Writes the executed instructions to a file, or to stdout when debugging
+pub struct Tracer {
+    writer: BufWriter<Box<dyn Write>>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    stdout: bool,
+}
+
+impl Tracer {
+    pub fn create(path: &str, format: TraceFormat, filter: TraceFilter) -> io::Result<Self> {
+        let mut writer = BufWriter::new(Box::new(File::create(path)?) as Box<dyn Write>);
+        if format == TraceFormat::Binary {
+            writer.write_all(BINARY_MAGIC)?;
+            writer.write_all(&[BINARY_VERSION])?;
+        }
+
+        Ok(Tracer {
+            writer: writer,
+            format: format,
+            filter: filter,
+            stdout: false,
+        })
+    }
+
+    /// Logs every instruction on stdout in the text format
+    pub fn stdout() -> Self {
+        Tracer {
+            // Unbuffered, so the log stays in order with the other messages on stdout
+            writer: BufWriter::with_capacity(0, Box::new(io::stdout())),
+            format: TraceFormat::Text,
+            filter: TraceFilter::default(),
+            stdout: true,
+        }
+    }
+
+    pub fn is_stdout(&self) -> bool {
+        self.stdout
+    }
+
+    pub fn record(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        if ! self.filter.matches(entry) {
+            return Ok(());
+        }
+
+        match self.format {
+            TraceFormat::Text => writeln!(self.writer, "{}", entry.to_text()),
+            TraceFormat::Binary => self.writer.write_all(&entry.to_bytes()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(cycle: u64, pc: u16, opcode: u16) -> TraceEntry {
+        let mut v = [0; 16];
+        for (index, register) in v.iter_mut().enumerate() {
+            *register = (index * 17) as u8;
+        }
+        TraceEntry { cycle: cycle, pc: pc, opcode: opcode, v: v, i: 0x1234, sp: 3, dt: 0x3C, st: 0xFF }
+    }
+
+    /// Traces `entries` to a file in `format` through `filter`, then reads it back
+    fn round_trip(entries: &[TraceEntry], format: TraceFormat, filter: TraceFilter) -> Vec<TraceEntry> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace");
+        let path = path.to_str().unwrap();
+        {
+            let mut tracer = Tracer::create(path, format, filter).unwrap();
+            for entry in entries {
+                tracer.record(entry).unwrap();
+            }
+        }
+        read(path).unwrap()
+    }
+
+    #[test]
+    fn traces_round_trip_in_both_formats() {
+        let entries = [entry(0, 0x200, 0x6A2F), entry(1, 0x202, 0xD015), entry(u64::MAX, 0xFFFE, 0xF000)];
+        assert_eq!(round_trip(&entries, TraceFormat::Text, TraceFilter::default()), entries);
+        assert_eq!(round_trip(&entries, TraceFormat::Binary, TraceFilter::default()), entries);
+
+        assert_eq!(entries[0].to_text(),
+            "0 PC:0200 OP:6A2F V:00112233445566778899AABBCCDDEEFF I:1234 SP:3 DT:3C ST:FF LD VA, 0x2F");
+        assert_eq!(TraceEntry::from_bytes(&entries[1].to_bytes()), entries[1]);
+    }
+
+    #[test]
+    fn bad_traces_are_errors() {
+        assert!(TraceEntry::from_text("12 PC:0200 OP:6A2F").is_err());
+        assert!(TraceEntry::from_text("x PC:0200").is_err());
+        assert!(TraceEntry::from_text("0 PC:0200 OP:6A2F V:00 I:0 SP:0 DT:0 ST:0").is_err());
+        assert!(TraceEntry::from_text("0 PC:02G0 OP:6A2F V:00112233445566778899AABBCCDDEEFF I:0 SP:0 DT:0 ST:0").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace");
+        let path = path.to_str().unwrap();
+        fs::write(path, [&BINARY_MAGIC[..], &[BINARY_VERSION, 0, 0]].concat()).unwrap();
+        assert_eq!(read(path).unwrap_err(), format!("Truncated binary trace {}", path));
+        fs::write(path, [&BINARY_MAGIC[..], &[BINARY_VERSION + 1]].concat()).unwrap();
+        assert_eq!(read(path).unwrap_err(), format!("Unsupported binary trace version in {}", path));
+        fs::write(path, "0 PC:0200\n").unwrap();
+        assert!(read(path).unwrap_err().starts_with(&format!("{}:1: ", path)));
+    }
+
+    #[test]
+    fn filters_keep_matching_addresses_and_classes() {
+        let entries = [entry(0, 0x200, 0x6001), entry(1, 0x202, 0xD015), entry(2, 0x300, 0x6002), entry(3, 0x400, 0x1400)];
+
+        let ranges = vec!["200-2FF".parse().unwrap(), "0x400-0x400".parse().unwrap()];
+        let filter = TraceFilter { ranges: ranges, classes: Vec::new() };
+        let cycles: Vec<u64> = round_trip(&entries, TraceFormat::Binary, filter).iter().map(|entry| entry.cycle).collect();
+        assert_eq!(cycles, [0, 1, 3]);
+
+        let filter = TraceFilter { ranges: Vec::new(), classes: vec![OpcodeClass::Alu, OpcodeClass::Flow] };
+        let cycles: Vec<u64> = round_trip(&entries, TraceFormat::Text, filter).iter().map(|entry| entry.cycle).collect();
+        assert_eq!(cycles, [0, 2, 3]);
+
+        // Both filters must match
+        let filter = TraceFilter { ranges: vec!["200-2FF".parse().unwrap()], classes: vec![OpcodeClass::Alu] };
+        assert!(filter.matches(&entries[0]) && ! filter.matches(&entries[1]) && ! filter.matches(&entries[2]));
+
+        assert!("300-200".parse::<AddressRange>().is_err());
+        assert!("300".parse::<AddressRange>().is_err());
+        assert!("200-XYZ".parse::<AddressRange>().is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use std::fmt::Write;
+
+use crate::chip8::chip8::CHIP8;
+use crate::chip8::disasm;
+use crate::chip8::fault::MachineFault;
+use crate::chip8::trace::TraceEntry;
+
+/// Result of running a machine along a reference trace
+pub enum Outcome {
+    /// Every compared instruction matches
+    Match { compared: usize },
+    /// The state of the machine differs from the reference
+    Diverged { expected: TraceEntry, actual: TraceEntry, previous: Option<TraceEntry> },
+    /// The machine faulted before reaching the cycle of `expected`
+    Faulted { fault: MachineFault, expected: TraceEntry },
+}
+
+/// Runs `chip8` and compares its state with each entry of `reference`, up to `cycles` cycles.
+/// Entries are matched by cycle, so the reference can be a filtered trace.
+pub fn compare(chip8: &mut CHIP8, reference: &[TraceEntry], cycles: Option<u64>) -> Result<Outcome, String> {
+    let mut previous = None;
+    let mut compared = 0;
+
+    for expected in reference.iter().take_while(|entry| cycles.is_none_or(|cycles| entry.cycle < cycles)) {
+        if expected.cycle < chip8.instruction_count() {
+            return Err(format!("Cycles in the reference trace go backwards at cycle {}", expected.cycle));
+        }
+
+        while chip8.instruction_count() < expected.cycle {
+            if let Err(fault) = chip8.step() {
+                return Ok(Outcome::Faulted { fault: fault, expected: *expected });
+            }
+        }
+
+        let actual = chip8.trace_entry();
+        if actual != *expected {
+            return Ok(Outcome::Diverged { expected: *expected, actual: actual, previous: previous });
+        }
+
+        previous = Some(actual);
+        compared += 1;
+    }
+
+    Ok(Outcome::Match { compared: compared })
+}
+
+/// Prints the outcome, see `report`
+pub fn print_report(outcome: &Outcome, ram: &[u8]) {
+    print!("{}", report(outcome, ram));
+}
+
+/// Describes the outcome. On divergence, registers are shown side by side with the differences
+/// marked, followed by the memory around PC and I.
+pub fn report(outcome: &Outcome, ram: &[u8]) -> String {
+    let mut report = String::new();
+    match outcome {
+        Outcome::Match { compared } => writeln!(report, "No divergence in {} instructions.", compared).unwrap(),
+        Outcome::Faulted { fault, expected } => {
+            writeln!(report, "{} before cycle {}", fault, expected.cycle).unwrap();
+            writeln!(report, "Expected: {}", expected.to_text()).unwrap();
+        }
+        Outcome::Diverged { expected, actual, previous } => {
+            writeln!(report, "First divergence at cycle {}", expected.cycle).unwrap();
+            match previous {
+                Some(previous) => writeln!(report, "Last matching instruction: {}", previous.to_text()).unwrap(),
+                None => writeln!(report, "No instruction matched.").unwrap(),
+            }
+            writeln!(report).unwrap();
+
+            writeln!(report, "     {:<16} {:<16}", "Expected", "Actual").unwrap();
+            let mut row = |name: &str, expected: String, actual: String| {
+                let marker = if expected != actual { "<-" } else { "" };
+                writeln!(report, "{:<4} {:<16} {:<16} {}", name, expected, actual, marker).unwrap();
+            };
+            row("PC", format!("0x{:04X}", expected.pc), format!("0x{:04X}", actual.pc));
+            row("OP", format!("{:04X} {}", expected.opcode, disasm::disassemble(expected.opcode)),
+                format!("{:04X} {}", actual.opcode, disasm::disassemble(actual.opcode)));
+            for index in 0..16 {
+                row(&format!("V{:X}", index), format!("0x{:02X}", expected.v[index]), format!("0x{:02X}", actual.v[index]));
+            }
+            row("I", format!("0x{:04X}", expected.i), format!("0x{:04X}", actual.i));
+            row("SP", format!("{}", expected.sp), format!("{}", actual.sp));
+            row("DT", format!("0x{:02X}", expected.dt), format!("0x{:02X}", actual.dt));
+            row("ST", format!("0x{:02X}", expected.st), format!("0x{:02X}", actual.st));
+            writeln!(report).unwrap();
+
+            writeln!(report, "Memory at PC:").unwrap();
+            write_memory(&mut report, ram, actual.pc);
+            writeln!(report, "Memory at I:").unwrap();
+            write_memory(&mut report, ram, actual.i);
+        }
+    }
+    report
+}
+
+/// Writes 32 bytes of memory from the 16 byte row holding `address`
+fn write_memory(report: &mut String, ram: &[u8], address: u16) {
+    let start = (address as usize & !0xF) % ram.len();
+    for row in 0..2 {
+        let row_address = (start + row * 16) % ram.len();
+        let bytes: Vec<String> = (0..16).map(|offset| format!("{:02X}", ram[(row_address + offset) % ram.len()])).collect();
+        writeln!(report, "  {:04X}: {}", row_address, bytes.join(" ")).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::platform::Platform;
+
+    /// V0 = 1, V1 = 2, then jump to itself
+    const PROGRAM: [u8; 6] = [0x60, 0x01, 0x61, 0x02, 0x12, 0x04];
+
+    fn machine() -> CHIP8 {
+        let mut chip8 = CHIP8::headless(Platform::Chip8);
+        chip8.load_program(&PROGRAM).unwrap();
+        chip8
+    }
+
+    /// The trace of the program, up to `cycles`
+    fn trace(cycles: usize) -> Vec<TraceEntry> {
+        let mut chip8 = machine();
+        (0..cycles).map(|_| {
+            let entry = chip8.trace_entry();
+            chip8.step().unwrap();
+            entry
+        }).collect()
+    }
+
+    #[test]
+    fn matching_traces_compare_up_to_the_cycle_limit() {
+        let reference = trace(10);
+        assert!(matches!(compare(&mut machine(), &reference, None), Ok(Outcome::Match { compared: 10 })));
+        assert!(matches!(compare(&mut machine(), &reference, Some(4)), Ok(Outcome::Match { compared: 4 })));
+
+        // Filtered traces skip cycles
+        let filtered: Vec<TraceEntry> = reference.iter().copied().filter(|entry| entry.cycle % 3 == 0).collect();
+        assert!(matches!(compare(&mut machine(), &filtered, None), Ok(Outcome::Match { compared: 4 })));
+
+        let backwards = vec![reference[2], reference[1]];
+        assert!(compare(&mut machine(), &backwards, None).is_err());
+    }
+
+    #[test]
+    fn divergences_report_the_fields_that_differ() {
+        let mut reference = trace(5);
+        reference[2].v[1] = 3;
+        reference[2].i = 0x300;
+
+        let outcome = compare(&mut machine(), &reference, None).unwrap();
+        let Outcome::Diverged { expected, actual, previous } = &outcome else {
+            panic!("expected a divergence");
+        };
+        assert_eq!((expected.cycle, actual.v[1], previous.unwrap().cycle), (2, 2, 1));
+
+        let chip8 = machine();
+        let report = report(&outcome, &chip8.ram);
+        assert!(report.starts_with("First divergence at cycle 2\nLast matching instruction: 1 PC:0202"));
+        assert!(report.contains("\nV0   0x01             0x01             \n"));
+        assert!(report.contains("\nV1   0x03             0x02             <-\n"));
+        assert!(report.contains("\nI    0x0300           0x0000           <-\n"));
+        assert!(report.contains("Memory at PC:\n  0200: 60 01 61 02 12 04 00"));
+    }
+
+    #[test]
+    fn faults_before_the_expected_cycle_are_reported() {
+        let mut chip8 = CHIP8::headless(Platform::Chip8);
+        chip8.load_program(&[0xFF, 0xFF]).unwrap();
+        let mut reference = trace(1);
+        reference[0].cycle = 5;
+
+        let outcome = compare(&mut chip8, &reference, None).unwrap();
+        assert!(matches!(outcome, Outcome::Faulted { expected, .. } if expected.cycle == 5));
+        assert!(report(&outcome, &chip8.ram).contains(" before cycle 5\nExpected: 5 PC:0200"));
+    }
+}
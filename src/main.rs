@@ -1,95 +1,825 @@
-use std::fs::File;
-use std::io::BufReader;
-use std::io::Read;
 use std::env;
-use clap::{Parser, CommandFactory};
+use clap::{Args, Parser, Subcommand};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::rc::Rc;
+use std::time::Instant;
+
+use chip8::cartridge;
+use chip8::cheats::{self, CheatEngine};
+use chip8::config::{Config, ConfigLayer, Settings, MAX_SCALE};
+use chip8::coverage::{Coverage, CoverageFormat};
+use chip8::database::{self, Database, RomInfo};
+use chip8::disasm::{self, OpcodeClass};
+use chip8::fault::{FaultPolicy, MachineFault};
+use chip8::frontend::{AudioSink, Backend, InputSource, NullAudio, NullInput, NullVideo, VideoSink};
+use chip8::gdb::GdbStub;
+use chip8::headless::{self, KeyScript, MachineState, ScreenFormat};
+use chip8::octo;
+use chip8::platform::Platform;
+use chip8::profiler::Profiler;
+use chip8::recording::{RecordingAudio, RecordingVideo};
+use chip8::rom;
+use chip8::screen::Screen;
+use chip8::script::ScriptHooks;
+use chip8::script_runner;
+use chip8::synth::Synth;
+use chip8::terminal::{TerminalGlyphs, TerminalInput, TerminalVideo};
+use chip8::trace;
+use chip8::trace::{AddressRange, TraceFilter, TraceFormat, Tracer};
+use chip8::trace_diff;
+
+use chip8::chip8::{CHIP8, WINDOW_TITLE};
+use rchip8::chip8;
 
-use chip8::chip8::MAX_MEM;
-use chip8::chip8::PROGRAM_ADDRESS;
+fn main() -> Result<(), String> {
 
-use crate::chip8::chip8::CHIP8;
-mod chip8;
 
-fn main() -> Result<(), String> {
-    
-    
     let args = Cli::parse();
-    
-    if args.scale < 0 || args.scale > 5 {
-        panic!("Scale must be between 1 and 5");
+    let config = load_config(&args);
+
+    match &args.command {
+        Command::Run(run_args) => run(&args, &config, run_args),
+        Command::Headless(headless_args) => headless(&args, &config, headless_args),
+        Command::Disasm { rom } => disassemble(rom),
+        Command::Asm { source, output } => assemble(source, output.as_deref()),
+        Command::Trace { rom, output, cycles, options } => trace(&args, &config, rom, output, *cycles, options),
+        Command::TraceDiff { rom, trace, cycles } => trace_diff(&args, &config, rom, trace, *cycles),
+        Command::Bench { rom, cycles } => bench(&args, &config, rom, *cycles),
+        Command::Info { rom } => info(&args, rom),
+        Command::Test { rom, expect, cycles } => test(&args, &config, rom, expect, *cycles),
+        Command::Script { scripts } => run_scripts(&args, &config, scripts),
     }
+}
 
-    let program = match args.rom.as_str() {
+type Frontend = (Box<dyn VideoSink>, Box<dyn InputSource>, Box<dyn AudioSink>);
 
-        "" => match args.demo {
-            true => get_demo_program(),
-            
-            false => {
-                let mut cmd = Cli::command();
-                cmd.error(clap::error::ErrorKind::MissingRequiredArgument, "").exit();
+/// Sets up the backend showing the machine, reading the keypad and playing the beep
+fn frontend(run_args: &RunArgs, settings: &Settings) -> Result<Frontend, String> {
+    let synth = || -> Box<dyn AudioSink> {
+        if settings.audio.enabled {
+            Box::new(Synth::new(settings.audio.frequency, settings.audio.volume))
+        } else {
+            Box::new(NullAudio)
+        }
+    };
+
+    match run_args.backend {
+        Backend::Sdl => {
+            let screen = Screen::new(settings.debug.level > 1, settings.scale, settings.vsync, WINDOW_TITLE);
+            let input = screen.input()?;
+            Ok((Box::new(screen), Box::new(input), synth()))
+        }
+        Backend::Terminal => {
+            let input = TerminalInput::new().map_err(|e| format!("Could not read the terminal: {}", e))?;
+            let video = TerminalVideo::new(run_args.glyphs).map_err(|e| format!("Could not draw on the terminal: {}", e))?;
+            Ok((Box::new(video), Box::new(input), synth()))
+        }
+        Backend::Null => Ok((Box::new(NullVideo), Box::new(NullInput), Box::new(NullAudio))),
+        Backend::Recording => {
+            let path = run_args.record.as_deref().ok_or("The recording backend needs --record")?;
+            if run_args.frames.is_none() {
+                return Err(String::from("The recording backend needs --frames, as it has no input to stop it"));
             }
+            let video = RecordingVideo::create(path, settings.fps, settings.scale as usize)?;
+            let audio = match &run_args.record_audio {
+                Some(path) if settings.audio.enabled => {
+                    Box::new(RecordingAudio::create(path, settings.fps, settings.audio.frequency, settings.audio.volume)?) as Box<dyn AudioSink>
+                }
+                _ => Box::new(NullAudio),
+            };
+            Ok((Box::new(video), Box::new(NullInput), audio))
         }
-        
-        _ => read_from_disk(&args.rom)
+    }
+}
+
+/// Runs a ROM in a window, in the terminal or recorded to files, with sound
+fn run(args: &Cli, config: &Config, run_args: &RunArgs) -> Result<(), String> {
+    if run_args.print_config && run_args.rom.is_none() && ! run_args.demo {
+        print!("{}", resolve_settings(args, Some(run_args), config, None, &RomInfo::default()).to_toml());
+        return Ok(());
+    }
+
+    let (program, cartridge_info) = match &run_args.rom {
+        Some(path) => load_rom(path),
+        None => (get_demo_program(), None),
     };
-    
-    let mut chip8 = CHIP8::new(args.debug, args.scale);
-    
-    
-    chip8.load_program(program, program.len());
 
-    if args.paused {
+    let info = rom_info(args, &program, cartridge_info);
+    let rom = run_args.rom.as_deref().map(|path| (path, program.as_slice()));
+    let settings = resolve_settings(args, Some(run_args), config, rom, &info);
+
+    if run_args.print_config {
+        print!("{}", settings.to_toml());
+        return Ok(());
+    }
+
+    let (video, input, audio) = frontend(run_args, &settings)?;
+    let mut chip8 = CHIP8::new(video, input, audio, settings.debug.level, settings.platform);
+    configure(&mut chip8, &settings, &program);
+    chip8.set_frame_limit(run_args.frames);
+
+    if let Some(path) = &run_args.trace {
+        set_tracer(&mut chip8, path, &run_args.trace_options);
+    }
+
+    if run_args.profile.is_some() || run_args.profile_folded.is_some() {
+        chip8.set_profiler(Profiler::new());
+    }
+
+    if run_args.coverage.is_some() {
+        let program_address = chip8::chip8::PROGRAM_ADDRESS;
+        let memory_size = settings.platform.memory_size();
+        chip8.set_coverage(Coverage::new(memory_size, program_address..program_address + program.len()));
+    }
+
+    if let Some(port) = run_args.gdb {
+        match GdbStub::listen(port) {
+            Ok(gdb) => chip8.set_gdb_stub(gdb),
+            Err(e) => {
+                eprintln!("Could not listen for gdb on port {}: {}", port, e);
+                exit(1);
+            }
+        }
+    }
+
+    load_cheats(&mut chip8, run_args, &program);
+
+    if let Some(path) = &run_args.script {
+        load_script(&mut chip8, path);
+    }
+
+    chip8.bind_action_keys(&info.keys);
+    chip8.bind_keys(&settings.keys);
+    chip8.set_palette(settings.palette());
+    if ! info.title.is_empty() {
+        chip8.set_window_title(&info.title);
+    }
+
+    if settings.debug.paused {
         chip8.pause();
     }
-    
-    chip8.run(args.fps)
+
+    let result = chip8.run(settings.fps, settings.fast_forward);
+    if let Some(profiler) = chip8.profiler() {
+        write_profile(run_args, profiler, &chip8.ram);
+    }
+    if let (Some(coverage), Some(path)) = (chip8.coverage(), &run_args.coverage) {
+        let report = match run_args.coverage_format {
+            CoverageFormat::Annotated => coverage.annotated(&chip8.ram),
+            CoverageFormat::Lcov => coverage.lcov(run_args.rom.as_deref().unwrap_or("demo")),
+        };
+        match fs::write(path, report) {
+            Ok(()) => println!("Coverage written to {}", path),
+            Err(e) => eprintln!("Could not write coverage {}: {}", path, e),
+        }
+    }
+    result
+}
+
+/// Runs a ROM without window nor sound, pressing keys as the key script says, then writes the
+/// screen and the state of the machine
+fn headless(args: &Cli, config: &Config, headless_args: &HeadlessArgs) -> Result<(), String> {
+    let script = headless_args.keys.as_deref().map(KeyScript::load).transpose()?;
+
+    let mut chip8 = headless_machine(args, config, &headless_args.rom);
+    if let Some(path) = &headless_args.script {
+        load_script(&mut chip8, path);
+    }
+    let result = run_headless(&mut chip8, headless_args.cycles, script.as_ref(), |_| false);
+
+    headless::write_screen(chip8.framebuffer(), headless_args.screen, headless_args.screen_file.as_deref())?;
+    if headless_args.json {
+        println!("{}", MachineState::of(&chip8, result.as_ref().err()).to_json());
+    }
+    result.map_err(|fault| fault.to_string())
+}
+
+/// Prints the disassembly of a ROM, one word per line from the program address
+fn disassemble(path: &str) -> Result<(), String> {
+    let (program, _) = load_rom(path);
+
+    for (index, chunk) in program.chunks(2).enumerate() {
+        let address = chip8::chip8::PROGRAM_ADDRESS + index * 2;
+        match chunk {
+            [high, low] => {
+                let word = (*high as u16) << 8 | *low as u16;
+                println!("{:04X}  {:04X}  {}", address, word, disasm::disassemble(word));
+            }
+            [byte] => println!("{:04X}  {:02X}    DB 0x{:02X}", address, byte, byte),
+            _ => unreachable!(),
+        }
+    }
+    Ok(())
+}
+
+/// Assembles Octo source into a ROM, written next to the source unless `output` is given
+fn assemble(source: &str, output: Option<&str>) -> Result<(), String> {
+    let text = fs::read_to_string(source).map_err(|e| format!("Could not read {}: {}", source, e))?;
+    let program = octo::assemble(&text).map_err(|e| format!("{}: {}", source, e))?;
+
+    let output = output.map(PathBuf::from).unwrap_or_else(|| Path::new(source).with_extension("ch8"));
+    fs::write(&output, &program).map_err(|e| format!("Could not write {}: {}", output.display(), e))?;
+    println!("Assembled {} bytes to {}", program.len(), output.display());
+    Ok(())
+}
+
+/// Runs a ROM headless and writes every executed instruction to a trace file
+fn trace(args: &Cli, config: &Config, path: &str, output: &str, cycles: u64, options: &TraceOptions) -> Result<(), String> {
+    let mut chip8 = headless_machine(args, config, path);
+    set_tracer(&mut chip8, output, options);
+
+    let result = run_headless(&mut chip8, cycles, None, |_| false);
+    println!("Traced {} instructions to {}", chip8.instruction_count(), output);
+    result.map_err(|fault| fault.to_string())
+}
+
+/// Runs a ROM headless as fast as possible and reports the speed of the interpreter
+fn bench(args: &Cli, config: &Config, path: &str, cycles: u64) -> Result<(), String> {
+    let mut chip8 = headless_machine(args, config, path);
+
+    let start = Instant::now();
+    let result = run_headless(&mut chip8, cycles, None, |_| false);
+    let elapsed = start.elapsed();
+
+    let executed = chip8.instruction_count();
+    println!("Executed {} instructions in {:.3} s", executed, elapsed.as_secs_f64());
+    println!("{:.0} instructions per second, {:.1} ns per instruction",
+        executed as f64 / elapsed.as_secs_f64(), elapsed.as_nanos() as f64 / executed.max(1) as f64);
+    result.map_err(|fault| fault.to_string())
+}
+
+/// Prints what is known about a ROM
+fn info(args: &Cli, path: &str) -> Result<(), String> {
+    let (program, cartridge_info) = load_rom(path);
+    let is_cartridge = cartridge_info.is_some();
+    let info = match (cartridge_info, args.machine.no_db) {
+        (Some(info), _) => info,
+        (None, true) => RomInfo::default(),
+        (None, false) => Database::load().lookup(&program).cloned().unwrap_or_default(),
+    };
+
+    println!("File: {}", path);
+    println!("Size: {} bytes", program.len());
+    println!("SHA-1: {}", database::sha1_hex(&program));
+    if is_cartridge {
+        println!("Octo cartridge");
+    }
+    if ! info.title.is_empty() {
+        println!("Title: {}", info.title);
+    }
+    match (info.platform, rom::guess_platform(&program)) {
+        (Some(platform), _) => println!("Platform: {}", platform),
+        (None, Some(platform)) => println!("Platform: {} (guessed from the instructions)", platform),
+        (None, None) => println!("Platform: unknown, runs as CHIP-8 with the default quirks"),
+    }
+    if let Some(tickrate) = info.tickrate {
+        println!("Instructions per frame: {}", tickrate);
+    }
+    Ok(())
+}
+
+/// Runs a test ROM until it settles in a jump to itself, then compares the SHA-1 hash of the
+/// screen with `expect`. Exits with status 1 on failure.
+fn test(args: &Cli, config: &Config, path: &str, expect: &str, cycles: u64) -> Result<(), String> {
+    let mut chip8 = headless_machine(args, config, path);
+    let settled = |chip8: &CHIP8| {
+        let entry = chip8.trace_entry();
+        entry.opcode == 0x1000 | entry.pc
+    };
+
+    if let Err(fault) = run_headless(&mut chip8, cycles, None, settled) {
+        println!("FAIL: {}", fault);
+        exit(1);
+    }
+    if ! settled(&chip8) {
+        println!("The ROM did not settle in {} instructions, comparing the screen as it is.", cycles);
+    }
+
+    let hash = chip8.framebuffer().sha1_hex();
+    if ! expect.eq_ignore_ascii_case(&hash) {
+        println!("FAIL: screen hash {}, expected {}", hash, expect);
+        exit(1);
+    }
+    println!("PASS: screen hash {}", hash);
+    Ok(())
+}
+
+/// Runs each test script, see `script_runner`. Exits with status 1 if any fails.
+fn run_scripts(args: &Cli, config: &Config, scripts: &[String]) -> Result<(), String> {
+    // Loaders are kept by the scripts, so they share a copy of the arguments
+    let shared = Rc::new((args.clone(), config.clone()));
+    let mut failed = 0;
+    for path in scripts {
+        let shared = shared.clone();
+        let loader = Box::new(move |rom: &str| open_headless_machine(&shared.0, &shared.1, rom));
+
+        match script_runner::run_test(path, loader) {
+            Ok(()) => println!("PASS: {}", path),
+            Err(e) => {
+                println!("FAIL: {}: {}", path, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        println!("{} of {} scripts failed", failed, scripts.len());
+        exit(1);
+    }
+    Ok(())
+}
+
+/// Runs `chip8` for `cycles` instructions, or until `done` returns true. Keys are pressed at the
+/// start of the frames given in `script`.
+fn run_headless(chip8: &mut CHIP8, cycles: u64, script: Option<&KeyScript>, done: impl Fn(&CHIP8) -> bool) -> Result<(), MachineFault> {
+    let end = chip8.instruction_count() + cycles;
+    let mut frame = None;
+
+    while chip8.instruction_count() < end && ! done(chip8) {
+        if frame != Some(chip8.frame_count()) {
+            frame = Some(chip8.frame_count());
+            if let Some(keys) = script.and_then(|script| script.keys_at(chip8.frame_count())) {
+                chip8.set_keys(keys);
+            }
+        }
+        chip8.step()?;
+    }
+    Ok(())
+}
+
+/// Loads a ROM in a machine without window nor sound, set up as it would run. Exits on errors.
+fn headless_machine(args: &Cli, config: &Config, path: &str) -> CHIP8 {
+    match open_headless_machine(args, config, path) {
+        Ok(chip8) => chip8,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+fn open_headless_machine(args: &Cli, config: &Config, path: &str) -> Result<CHIP8, String> {
+    let (program, cartridge_info) = read_rom(path)?;
+    let info = rom_info(args, &program, cartridge_info);
+    let settings = resolve_settings(args, None, config, Some((path, &program)), &info);
+
+    let mut chip8 = CHIP8::headless(settings.platform);
+    configure(&mut chip8, &settings, &program);
+    Ok(chip8)
+}
+
+/// Attaches the Rhai script at `path` to the machine, see `script`. Exits on errors.
+fn load_script(chip8: &mut CHIP8, path: &str) {
+    match ScriptHooks::load(path) {
+        Ok(hooks) => chip8.set_script_hooks(hooks),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+/// Writes every executed instruction to `path`. Exits on errors.
+fn set_tracer(chip8: &mut CHIP8, path: &str, options: &TraceOptions) {
+    let filter = TraceFilter {
+        ranges: options.trace_range.clone(),
+        classes: options.trace_class.clone(),
+    };
+    match Tracer::create(path, options.trace_format, filter) {
+        Ok(tracer) => chip8.set_tracer(tracer),
+        Err(e) => {
+            eprintln!("Could not create trace file {}: {}", path, e);
+            exit(1);
+        }
+    }
+}
+
+/// Loads the cheats of the ROM, from the file given on the command line or the default cheat file,
+/// and starts the cheat console. Exits on errors.
+fn load_cheats(chip8: &mut CHIP8, args: &RunArgs, program: &[u8]) {
+    let Some(path) = args.cheats.clone().map(PathBuf::from).or_else(|| CheatEngine::default_path(program)) else {
+        return;
+    };
+
+    let mut engine = CheatEngine::new();
+    if let Err(e) = engine.load(&path) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+
+    if ! engine.cheats().is_empty() {
+        println!("Loaded {} cheats from {}", engine.cheats().len(), path.display());
+    }
+    if args.cheat_console {
+        println!("Cheat console ready, type help for the list of commands.");
+        chip8.set_cheat_console(cheats::console());
+    }
+    if ! engine.cheats().is_empty() || args.cheat_console {
+        chip8.set_cheats(engine);
+    }
+}
+
+/// Writes the profiler report and folded stacks to the files given on the command line
+fn write_profile(args: &RunArgs, profiler: &Profiler, ram: &[u8]) {
+    let outputs = [
+        (&args.profile, profiler.report(ram)),
+        (&args.profile_folded, profiler.folded_stacks()),
+    ];
+
+    for (path, content) in outputs {
+        if let Some(path) = path {
+            match fs::write(path, content) {
+                Ok(()) => println!("Profile written to {}", path),
+                Err(e) => eprintln!("Could not write profile {}: {}", path, e),
+            }
+        }
+    }
+}
+
+/// Reads a ROM or an Octo cartridge. Exits on errors.
+fn load_rom(path: &str) -> (Vec<u8>, Option<RomInfo>) {
+    match read_rom(path) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+fn read_rom(path: &str) -> Result<(Vec<u8>, Option<RomInfo>), String> {
+    let loaded = match cartridge::is_cartridge(path) {
+        true => cartridge::load(path).map(|cartridge| (cartridge.program, Some(cartridge.info))),
+        false => rom::read(path).map(|program| (program, None)),
+    };
+    loaded.map_err(|e| e.to_string())
+}
+
+/// Returns the settings of the cartridge, or the ones found in the ROM database. Without a
+/// platform, the platform is guessed from the instructions of the ROM.
+fn rom_info(args: &Cli, program: &[u8], cartridge_info: Option<RomInfo>) -> RomInfo {
+    let mut info = match (cartridge_info, args.machine.no_db) {
+        (Some(info), _) => info,
+        (None, true) => RomInfo::default(),
+        (None, false) => {
+            let info = Database::load().lookup(program).cloned().unwrap_or_default();
+            if ! info.title.is_empty() {
+                println!("Found {} in the ROM database.", info.title);
+            }
+            info
+        }
+    };
+    info.platform = info.platform.or_else(|| rom::guess_platform(program));
+    info
+}
+
+/// Loads the config file given on the command line, or the one in the config directory.
+/// Exits on errors.
+fn load_config(args: &Cli) -> Config {
+    let loaded = match (&args.machine.config, Config::default_path()) {
+        (Some(path), _) => Config::load(Path::new(path), true),
+        (None, Some(path)) => Config::load(&path, false),
+        (None, None) => Ok(Config::default()),
+    };
+
+    match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+/// Settings given on the command line
+fn cli_layer(args: &Cli, run_args: Option<&RunArgs>) -> ConfigLayer {
+    let mut layer = ConfigLayer {
+        ipf: args.machine.ipf,
+        platform: args.machine.platform,
+        on_fault: args.machine.on_fault,
+        ..ConfigLayer::default()
+    };
+
+    if let Some(run_args) = run_args {
+        layer.fps = run_args.fps;
+        layer.fast_forward = run_args.fast_forward;
+        layer.scale = run_args.scale;
+        layer.vsync = run_args.vsync.then_some(true);
+        layer.debug.level = run_args.debug;
+        layer.debug.paused = run_args.paused.then_some(true);
+    }
+    layer
+}
+
+/// Resolves the settings of a ROM from the config file, the cartridge or the ROM database and the
+/// command line. Exits on errors.
+fn resolve_settings(args: &Cli, run_args: Option<&RunArgs>, config: &Config, rom: Option<(&str, &[u8])>, info: &RomInfo) -> Settings {
+    match config.settings(rom, info, &cli_layer(args, run_args)) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+/// Loads the program and sets up the machine.
+fn configure(chip8: &mut CHIP8, settings: &Settings, program: &[u8]) {
+    if let Err(e) = chip8.load_program(program) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+
+    chip8.set_quirks(settings.quirks);
+    chip8.set_fault_policy(settings.on_fault);
+    chip8.set_instructions_per_frame(settings.ipf);
+}
+
+/// Runs a ROM headless along a reference trace and reports the first divergence
+fn trace_diff(args: &Cli, config: &Config, path: &str, trace_path: &str, cycles: Option<u64>) -> Result<(), String> {
+    let mut chip8 = headless_machine(args, config, path);
+    let reference = trace::read(trace_path)?;
+
+    let outcome = trace_diff::compare(&mut chip8, &reference, cycles)?;
+    trace_diff::print_report(&outcome, &chip8.ram);
+
+    if let trace_diff::Outcome::Match { .. } = outcome {
+        Ok(())
+    } else {
+        exit(1);
+    }
 }
 
 
 
 
 /// Commandline parser
-#[derive(Parser)]
+#[derive(Clone, Parser)]
 #[command(about = "Dedraks' CHIP8 emulator.")]
 struct Cli {
-    #[arg(short, long, default_value_t = String::from(""))]
+    #[command(subcommand)]
+    command: Command,
+
+    #[command(flatten)]
+    machine: MachineArgs,
+}
+
+/// Options of every command that runs a ROM
+#[derive(Args, Clone)]
+struct MachineArgs {
+    /// Instructions executed per frame [default: from the ROM database, or 1]
+    #[arg(long, global = true)]
+    ipf: Option<u32>,
+
+    /// Target platform. Decides quirks and how large a ROM can be [default: from the ROM database, or chip8]
+    #[arg(long, value_enum, global = true)]
+    platform: Option<Platform>,
+
+    /// What to do on stack overflows, out of bounds memory accesses and illegal opcodes
+    #[arg(long, value_enum, global = true)]
+    on_fault: Option<FaultPolicy>,
+
+    /// Don't look up the ROM in the ROM database
+    #[arg(long, default_value_t = false, global = true)]
+    no_db: bool,
+
+    /// Config file [default: config.toml in the config directory]
+    #[arg(long, value_name = "FILE", global = true)]
+    config: Option<String>,
+}
+
+#[derive(Clone, Subcommand)]
+enum Command {
+    /// Run a ROM in a window
+    Run(Box<RunArgs>),
+
+    /// Run a ROM without window nor sound, then print the screen and the state of the machine
+    Headless(HeadlessArgs),
+
+    /// Print the disassembly of a ROM
+    Disasm {
+        rom: String,
+    },
+
+    /// Assemble Octo source into a ROM
+    Asm {
+        source: String,
+
+        /// ROM to write [default: the source with the .ch8 extension]
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+
+    /// Run a ROM headless and write every executed instruction to a trace file
+    Trace {
+        rom: String,
+
+        /// Trace file to write
+        #[arg(short, long)]
+        output: String,
+
+        /// Instructions to execute
+        #[arg(long, default_value_t = 1_000_000)]
+        cycles: u64,
+
+        #[command(flatten)]
+        options: TraceOptions,
+    },
+
+    /// Run a ROM headless and compare its execution with a trace from another emulator
+    TraceDiff {
+        /// ROM to run
+        rom: String,
+
+        /// Reference trace, in the text or binary trace format
+        trace: String,
+
+        /// Only compare the first N cycles
+        #[arg(long)]
+        cycles: Option<u64>,
+    },
+
+    /// Measure how fast the interpreter runs a ROM
+    Bench {
+        rom: String,
+
+        /// Instructions to execute
+        #[arg(long, default_value_t = 10_000_000)]
+        cycles: u64,
+    },
+
+    /// Print the size, hash, title and platform of a ROM
+    Info {
+        rom: String,
+    },
+
+    /// Run a test ROM and check the screen it ends on
+    Test {
+        rom: String,
+
+        /// Expected SHA-1 hash of the screen, as printed when it doesn't match
+        #[arg(long, value_name = "HASH")]
+        expect: String,
+
+        /// Instructions to execute at most before checking the screen
+        #[arg(long, default_value_t = 10_000_000)]
+        cycles: u64,
+    },
+
+    /// Run Rhai test scripts driving ROMs headless
+    Script {
+        #[arg(required = true)]
+        scripts: Vec<String>,
+    },
+}
+
+#[derive(Args, Clone)]
+struct HeadlessArgs {
+    /// ROM file or Octo cartridge to run
     rom: String,
 
-    #[arg(short, long, default_value_t = false)]
+    /// Instructions to execute
+    #[arg(long, default_value_t = 1_000_000)]
+    cycles: u64,
+
+    /// Key script: lines of a frame number followed by the hex keys held from that frame on
+    #[arg(long, value_name = "SCRIPT")]
+    keys: Option<String>,
+
+    /// How to write the final screen
+    #[arg(long, value_enum, default_value_t = ScreenFormat::Ascii)]
+    screen: ScreenFormat,
+
+    /// Write the screen to FILE instead of stdout. Required for PNG
+    #[arg(long, value_name = "FILE")]
+    screen_file: Option<String>,
+
+    /// Print the state of the machine as JSON, after the screen
+    #[arg(long, default_value_t = false)]
+    json: bool,
+
+    /// Rhai script hooking frame ends, instructions, memory writes and key events
+    #[arg(long, value_name = "FILE")]
+    script: Option<String>,
+}
+
+#[derive(Args, Clone)]
+struct RunArgs {
+    /// ROM file or Octo cartridge to run
+    #[arg(required_unless_present_any = ["demo", "print_config"])]
+    rom: Option<String>,
+
+    /// Run the demo program
+    #[arg(short, long, default_value_t = false, conflicts_with = "rom")]
     demo: bool,
 
-    #[arg(short, long, default_value_t = 60)]
-    fps: u32,
+    /// Frames per second [default: 60]
+    #[arg(short, long)]
+    fps: Option<u32>,
+
+    /// Speed factor while TAB is held. 0 runs uncapped [default: 0]
+    #[arg(long)]
+    fast_forward: Option<u32>,
 
-    #[arg(long, default_value_t = 0)]
-    debug: u32,
+    /// Debug level [default: 0]
+    #[arg(long)]
+    debug: Option<u32>,
 
-    #[arg(short, long, default_value_t = 1)]
-    scale: i32,
+    /// Display scale, from 1 to 5 [default: 1]
+    #[arg(short, long, value_parser = clap::value_parser!(i32).range(1..=MAX_SCALE as i64))]
+    scale: Option<i32>,
 
+    /// Start paused
     #[arg(short, long, default_value_t = false)]
     paused: bool,
+
+    /// Where to show the display and read the keypad from
+    #[arg(long, value_enum, default_value_t = Backend::Sdl)]
+    backend: Backend,
+
+    /// Characters drawing the display with the terminal backend
+    #[arg(long, value_enum, default_value_t = TerminalGlyphs::HalfBlocks)]
+    glyphs: TerminalGlyphs,
+
+    /// Animated GIF the recording backend writes the display to
+    #[arg(long, value_name = "FILE")]
+    record: Option<String>,
+
+    /// WAV file the recording backend writes the beep to
+    #[arg(long, value_name = "FILE")]
+    record_audio: Option<String>,
+
+    /// Quit after N frames, counting the frames spent paused
+    #[arg(long, value_name = "N")]
+    frames: Option<u64>,
+
+    /// Lock presentation to the display refresh rate
+    #[arg(long, default_value_t = false)]
+    vsync: bool,
+
+    /// Write a report of where cycles went to FILE at exit
+    #[arg(long, value_name = "FILE")]
+    profile: Option<String>,
+
+    /// Write the call stacks of the executed instructions to FILE at exit, in the folded format of flame graphs
+    #[arg(long, value_name = "FILE")]
+    profile_folded: Option<String>,
+
+    /// Write which bytes of the ROM were executed, read and written to FILE at exit
+    #[arg(long, value_name = "FILE")]
+    coverage: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = CoverageFormat::Annotated)]
+    coverage_format: CoverageFormat,
+
+    /// Listen for gdb on this TCP port of localhost
+    #[arg(long, value_name = "PORT")]
+    gdb: Option<u16>,
+
+    /// Write every executed instruction to FILE
+    #[arg(long, value_name = "FILE")]
+    trace: Option<String>,
+
+    #[command(flatten)]
+    trace_options: TraceOptions,
+
+    /// Cheat file of the ROM [default: cheats/<sha1 of the ROM>.cht in the config directory]
+    #[arg(long, value_name = "FILE")]
+    cheats: Option<String>,
+
+    /// Read RAM search, cheat and watch commands on stdin while the ROM runs
+    #[arg(long, default_value_t = false)]
+    cheat_console: bool,
+
+    /// Rhai script hooking frame ends, instructions, memory writes and key events
+    #[arg(long, value_name = "FILE")]
+    script: Option<String>,
+
+    /// Print the settings resolved from the config file, the ROM database and the command line, then exit
+    #[arg(long, default_value_t = false)]
+    print_config: bool,
 }
 
-fn read_from_disk(filename: &str) -> [u8; MAX_MEM - PROGRAM_ADDRESS] {
-    
-    let mut program = [0; MAX_MEM - PROGRAM_ADDRESS];
+/// What goes in a trace
+#[derive(Args, Clone)]
+struct TraceOptions {
+    #[arg(long, value_enum, default_value_t = TraceFormat::Text)]
+    trace_format: TraceFormat,
 
-        let mut i = 0usize;
-        let my_buf = BufReader::new(File::open(filename).unwrap());
-        for byte_or_error in my_buf.bytes() {
-            let byte = byte_or_error.unwrap();
-            program[i] = byte;
-            i+=1;
-        }
+    /// Only trace instructions in these hex address ranges, e.g. 200-2FF
+    #[arg(long, value_delimiter = ',')]
+    trace_range: Vec<AddressRange>,
 
-        program
+    /// Only trace these classes of instructions
+    #[arg(long, value_enum, value_delimiter = ',')]
+    trace_class: Vec<OpcodeClass>,
 }
 
-fn get_demo_program() -> [u8; MAX_MEM - PROGRAM_ADDRESS] {
+fn get_demo_program() -> Vec<u8> {
 
-    let mut program = [0u8; MAX_MEM - PROGRAM_ADDRESS];
+    let mut program = vec![0u8; 0x30];
 
     // Play sound for 255 cycles
     program[0x0000] = 0x6E; 
@@ -0,0 +1,171 @@
+//! Programs that crashed the interpreter, or that the fuzz targets in `fuzz/` found it running
+//! differently from the reference interpreter, reduced to the instructions at fault.
+
+use rchip8::chip8::chip8::{CHIP8, FONT_ADDRESS};
+use rchip8::chip8::fault::{FaultPolicy, MachineFault};
+use rchip8::chip8::platform::Platform;
+
+fn machine(program: &[u16], fault_policy: FaultPolicy) -> CHIP8 {
+    let bytes: Vec<u8> = program.iter().flat_map(|word| word.to_be_bytes()).collect();
+    let mut chip8 = CHIP8::headless(Platform::Chip8);
+    chip8.load_program(&bytes).unwrap();
+    chip8.set_fault_policy(fault_policy);
+    chip8.set_instructions_per_frame(1000);
+    chip8
+}
+
+/// Runs the whole program, one instruction per word
+fn run(program: &[u16]) -> CHIP8 {
+    let mut chip8 = machine(program, FaultPolicy::Halt);
+    for _ in program {
+        chip8.step().unwrap();
+    }
+    chip8
+}
+
+#[test]
+fn call_past_the_stack_faults() {
+    let mut chip8 = machine(&[0x2200], FaultPolicy::Halt);
+    for _ in 0..16 {
+        chip8.step().unwrap();
+    }
+    assert_eq!(chip8.step(), Err(MachineFault::StackOverflow { address: 0x200 }));
+
+    let mut chip8 = machine(&[0x2200], FaultPolicy::Wrap);
+    for _ in 0..100 {
+        chip8.step().unwrap();
+    }
+}
+
+#[test]
+fn return_with_an_empty_stack_faults() {
+    let mut chip8 = machine(&[0x00EE], FaultPolicy::Halt);
+    assert_eq!(chip8.step(), Err(MachineFault::StackUnderflow { address: 0x200 }));
+    assert_eq!(chip8.registers().pc, 0x200);
+}
+
+#[test]
+fn only_00e0_and_00ee_are_valid_0nnn_instructions() {
+    let mut chip8 = machine(&[0x01E0], FaultPolicy::Halt);
+    assert_eq!(chip8.step(), Err(MachineFault::IllegalOpcode { address: 0x200, opcode: 0x01E0 }));
+
+    let mut chip8 = machine(&[0x0FEE], FaultPolicy::Halt);
+    assert_eq!(chip8.step(), Err(MachineFault::IllegalOpcode { address: 0x200, opcode: 0x0FEE }));
+}
+
+#[test]
+fn add_byte_leaves_vf_unchanged() {
+    let chip8 = run(&[0x6F05, 0x60FF, 0x7002]);
+    assert_eq!(chip8.registers().v[0x0], 0x01);
+    assert_eq!(chip8.registers().v[0xF], 0x05);
+}
+
+#[test]
+fn add_clears_vf_without_carry() {
+    let chip8 = run(&[0x6F01, 0x6001, 0x6102, 0x8014]);
+    assert_eq!(chip8.registers().v[0x0], 0x03);
+    assert_eq!(chip8.registers().v[0xF], 0);
+}
+
+#[test]
+fn subn_sets_vf_when_there_is_no_borrow() {
+    let chip8 = run(&[0x6005, 0x6105, 0x8017]);
+    assert_eq!(chip8.registers().v[0x0], 0);
+    assert_eq!(chip8.registers().v[0xF], 1);
+
+    let chip8 = run(&[0x6006, 0x6105, 0x8017]);
+    assert_eq!(chip8.registers().v[0x0], 0xFF);
+    assert_eq!(chip8.registers().v[0xF], 0);
+}
+
+#[test]
+fn flags_are_set_after_results_into_vf() {
+    // 8FF4: 0x80 + 0x80 carries
+    let chip8 = run(&[0x6F80, 0x8FF4]);
+    assert_eq!(chip8.registers().v[0xF], 1);
+
+    // 8FF6: 0x03 shifted right drops a 1
+    let chip8 = run(&[0x6F03, 0x8FF6]);
+    assert_eq!(chip8.registers().v[0xF], 1);
+
+    // 8FFE: 0x40 shifted left drops a 0
+    let chip8 = run(&[0x6F40, 0x8FFE]);
+    assert_eq!(chip8.registers().v[0xF], 0);
+
+    // 8F07: 0x00 - 0x01 borrows
+    let chip8 = run(&[0x6F01, 0x8F07]);
+    assert_eq!(chip8.registers().v[0xF], 0);
+}
+
+#[test]
+fn skip_if_key_reads_the_key_from_vx() {
+    let mut chip8 = machine(&[0x6005, 0xE09E], FaultPolicy::Halt);
+    let mut keys = [false; 16];
+    keys[5] = true;
+    chip8.set_keys(keys);
+    chip8.step().unwrap();
+    chip8.step().unwrap();
+    assert_eq!(chip8.registers().pc, 0x206);
+}
+
+#[test]
+fn add_to_i_keeps_16_bits_and_leaves_vf_unchanged() {
+    let chip8 = run(&[0x6F05, 0x6001, 0xAFFF, 0xF01E]);
+    assert_eq!((chip8.registers().i, chip8.registers().v[0xF]), (0x1000, 0x05));
+
+    // Fx55 and Fx65 leave I past the end of memory when they wrap around
+    let mut chip8 = machine(&[0x60FF, 0xF01E], FaultPolicy::Wrap);
+    chip8.step().unwrap();
+    let mut registers = chip8.registers();
+    registers.i = 0xFFFF;
+    chip8.set_registers(&registers);
+    chip8.step().unwrap();
+    assert_eq!(chip8.registers().i, 0x0FE);
+}
+
+#[test]
+fn instructions_past_the_end_of_memory_fault() {
+    // Jump to the last byte of memory
+    let mut chip8 = machine(&[0x1FFF], FaultPolicy::Halt);
+    chip8.step().unwrap();
+    assert_eq!(chip8.step(), Err(MachineFault::MemoryOutOfBounds { address: 0xFFF, access: 0x1000 }));
+    assert_eq!(chip8.registers().pc, 0xFFF);
+
+    // The word wraps around to the first byte of memory
+    let mut chip8 = machine(&[0x1FFF], FaultPolicy::Wrap);
+    chip8.step().unwrap();
+    chip8.step().unwrap();
+    assert_eq!(chip8.registers().pc, 0x001);
+
+    // A word ending memory leaves pc past it
+    let mut chip8 = machine(&[0x1FFE], FaultPolicy::Halt);
+    chip8.ram[0xFFE..].copy_from_slice(&[0x60, 0x01]);
+    chip8.step().unwrap();
+    chip8.step().unwrap();
+    assert_eq!(chip8.registers().pc, 0x1000);
+    assert_eq!(chip8.step(), Err(MachineFault::MemoryOutOfBounds { address: 0x1000, access: 0x1000 }));
+}
+
+#[test]
+fn font_digits_are_the_low_nibble_of_vx() {
+    let chip8 = run(&[0x601A, 0xF029]);
+    assert_eq!(chip8.registers().i as usize, FONT_ADDRESS + 0xA * 5);
+}
+
+#[test]
+fn memory_instructions_past_the_end_of_memory_fault() {
+    let mut chip8 = machine(&[0xAFFF, 0xF233], FaultPolicy::Halt);
+    chip8.step().unwrap();
+    assert_eq!(chip8.step(), Err(MachineFault::MemoryOutOfBounds { address: 0x202, access: 0x1001 }));
+
+    let mut chip8 = machine(&[0xAFFE, 0xF355], FaultPolicy::Halt);
+    chip8.step().unwrap();
+    assert_eq!(chip8.step(), Err(MachineFault::MemoryOutOfBounds { address: 0x202, access: 0x1001 }));
+
+    // Wrapping around writes the last bytes at the start of memory
+    let mut chip8 = machine(&[0x60FF, 0xAFFF, 0xF033, 0xF055], FaultPolicy::Wrap);
+    for _ in 0..4 {
+        chip8.step().unwrap();
+    }
+    assert_eq!(&chip8.ram[0..2], &[5, 5]);
+}
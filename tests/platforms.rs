@@ -0,0 +1,170 @@
+//! Instructions of SUPER-CHIP and XO-CHIP, which CHIP-8 ROMs cannot use.
+
+use rchip8::chip8::chip8::{BIG_FONT_ADDRESS, CHIP8};
+use rchip8::chip8::fault::MachineFault;
+use rchip8::chip8::platform::Platform;
+
+fn machine(program: &[u16], platform: Platform) -> CHIP8 {
+    let bytes: Vec<u8> = program.iter().flat_map(|word| word.to_be_bytes()).collect();
+    let mut chip8 = CHIP8::headless(platform);
+    chip8.load_program(&bytes).unwrap();
+    chip8.set_instructions_per_frame(1000);
+    chip8
+}
+
+/// Runs `steps` instructions
+fn run(program: &[u16], platform: Platform, steps: usize) -> CHIP8 {
+    let mut chip8 = machine(program, platform);
+    for _ in 0..steps {
+        chip8.step().unwrap();
+    }
+    chip8
+}
+
+#[test]
+fn superchip_instructions_are_illegal_on_chip8() {
+    for opcode in [0x00C1, 0x00FB, 0x00FC, 0x00FD, 0x00FE, 0x00FF, 0xF030, 0xF075, 0xF085] {
+        let mut chip8 = machine(&[opcode], Platform::Chip8);
+        assert_eq!(chip8.step(), Err(MachineFault::IllegalOpcode { address: 0x200, opcode: opcode }));
+    }
+}
+
+#[test]
+fn superchip_switches_resolution() {
+    for platform in [Platform::Schip, Platform::XoChip] {
+        let chip8 = run(&[0x00FF], platform, 1);
+        assert!(chip8.framebuffer().is_hires());
+
+        let chip8 = run(&[0x00FF, 0x00FE], platform, 2);
+        assert!(! chip8.framebuffer().is_hires());
+    }
+}
+
+#[test]
+fn superchip_draws_large_sprites_and_scrolls() {
+    // hires, I = large font 0, draw 16x16 from there at (0, 0), scroll down 2 and right 4
+    let chip8 = run(&[0x00FF, 0x6000, 0xF030, 0xD000, 0x00C2, 0x00FB], Platform::Schip, 6);
+    assert_eq!(chip8.registers().i as usize, BIG_FONT_ADDRESS);
+    let framebuffer = chip8.framebuffer();
+    // Its first row is two 0xFF bytes
+    assert!(framebuffer.get_pixel(4, 2) && framebuffer.get_pixel(19, 2));
+    assert!(! framebuffer.get_pixel(4, 1) && ! framebuffer.get_pixel(3, 2) && ! framebuffer.get_pixel(20, 2));
+
+    // The large sprite is 16x16: drawing it again over itself collides
+    let chip8 = run(&[0x00FF, 0xF030, 0xD000, 0xD000], Platform::Schip, 4);
+    assert_eq!(chip8.registers().v[0xF], 1);
+}
+
+#[test]
+fn superchip_exit_stays_on_itself() {
+    let chip8 = run(&[0x00FD], Platform::Schip, 10);
+    assert_eq!(chip8.registers().pc, 0x200);
+}
+
+#[test]
+fn flag_registers_are_saved_in_states() {
+    let mut chip8 = run(&[0x6007, 0x6109, 0xF175, 0x6000, 0x6100, 0xF185], Platform::Schip, 3);
+    let state = chip8.save_state();
+    chip8.step().unwrap();
+    chip8.step().unwrap();
+    chip8.step().unwrap();
+    assert_eq!(chip8.registers().v[..2], [0x07, 0x09]);
+
+    let mut other = machine(&[0x00FF], Platform::Schip);
+    other.step().unwrap();
+    other.load_state(&state).unwrap();
+    for _ in 0..3 {
+        other.step().unwrap();
+    }
+    assert_eq!(other.registers().v[..2], [0x07, 0x09]);
+    assert!(! other.framebuffer().is_hires());
+}
+
+#[test]
+fn hires_displays_are_saved_in_states() {
+    // A row of 4 pixels from the font, drawn from the bottom right corner and clipped
+    let chip8 = run(&[0x00FF, 0xA05A, 0x607F, 0x613F, 0xD011], Platform::Schip, 5);
+    let state = chip8.save_state();
+
+    let mut other = machine(&[0x00E0], Platform::Schip);
+    other.load_state(&state).unwrap();
+    assert!(other.framebuffer().is_hires());
+    assert_eq!(other.framebuffer().pixels(), chip8.framebuffer().pixels());
+    assert!(other.framebuffer().get_pixel(127, 63) && ! other.framebuffer().get_pixel(0, 63));
+}
+
+#[test]
+fn xo_chip_instructions_are_illegal_on_superchip() {
+    for opcode in [0x00D1, 0x5012, 0x5013, 0xF000, 0xF101, 0xF002, 0xF03A] {
+        let mut chip8 = machine(&[opcode], Platform::Schip);
+        assert_eq!(chip8.step(), Err(MachineFault::IllegalOpcode { address: 0x200, opcode: opcode }));
+    }
+}
+
+#[test]
+fn xo_chip_loads_long_addresses_and_skips_over_them() {
+    let chip8 = run(&[0xF000, 0x1234], Platform::XoChip, 1);
+    assert_eq!((chip8.registers().i, chip8.registers().pc), (0x1234, 0x204));
+
+    // SE V0, 0 skips all of F000 1234
+    let chip8 = run(&[0x3000, 0xF000, 0x1234, 0x6101], Platform::XoChip, 2);
+    assert_eq!((chip8.registers().i, chip8.registers().v[1]), (0, 1));
+}
+
+#[test]
+fn xo_chip_saves_register_ranges_in_either_order() {
+    // V1 = 1, V2 = 2, save V1-V2 at 0x300, load them reversed into V2-V1
+    let chip8 = run(&[0x6101, 0x6202, 0xA300, 0x5122, 0x5213], Platform::XoChip, 5);
+    assert_eq!(chip8.ram[0x300..0x302], [1, 2]);
+    assert_eq!((chip8.registers().v[1], chip8.registers().v[2], chip8.registers().i), (2, 1, 0x300));
+}
+
+#[test]
+fn xo_chip_draws_on_the_selected_planes() {
+    // Both planes, a 1-byte sprite of the font per plane, then clear plane 1 only
+    let chip8 = run(&[0xF301, 0xA050, 0xD001, 0xF101, 0x00E0], Platform::XoChip, 3);
+    // 0x60 on plane 1, 0xB0 on plane 2
+    assert_eq!(chip8.framebuffer().planes()[..4], [0x2, 0x1, 0x3, 0x2]);
+
+    let chip8 = run(&[0xF301, 0xA050, 0xD001, 0xF101, 0x00E0], Platform::XoChip, 5);
+    assert_eq!(chip8.framebuffer().planes()[..4], [0x2, 0x0, 0x2, 0x2]);
+    assert!(chip8.framebuffer().get_pixel(0, 0) && ! chip8.framebuffer().get_pixel(1, 0));
+}
+
+#[test]
+fn xo_chip_scrolls_up() {
+    // 0x60 drawn at (5, 5)
+    let chip8 = run(&[0xA050, 0x6005, 0xD001, 0x00D5], Platform::XoChip, 4);
+    assert!(chip8.framebuffer().get_pixel(6, 0) && ! chip8.framebuffer().get_pixel(6, 5));
+}
+
+#[test]
+fn xo_chip_adds_to_i_over_16_bits() {
+    // I = 0x1FFF, V0 = 1, VF = 5: I + V0 keeps bit 12 and leaves VF alone
+    let chip8 = run(&[0xF000, 0x1FFF, 0x6001, 0x6F05, 0xF01E], Platform::XoChip, 4);
+    assert_eq!((chip8.registers().i, chip8.registers().v[0xF]), (0x2000, 5));
+
+    let chip8 = run(&[0xF000, 0xFFFF, 0x6001, 0xF01E], Platform::XoChip, 3);
+    assert_eq!(chip8.registers().i, 0x0000);
+
+    // As on CHIP-8 and SUPER-CHIP, where I past memory faults when used
+    let chip8 = run(&[0xAFFF, 0x6001, 0xF01E], Platform::Schip, 3);
+    assert_eq!((chip8.registers().i, chip8.registers().v[0xF]), (0x1000, 0));
+}
+
+#[test]
+fn xo_chip_sample_patterns_are_saved_in_states() {
+    // Pattern from the font at 0x50, then pitch 0x70
+    let mut chip8 = run(&[0xA050, 0xF002, 0x6070, 0xF03A], Platform::XoChip, 2);
+    let pattern = chip8.sample_pattern().unwrap();
+    assert_eq!((pattern.bits[0], pattern.bits[1], pattern.pitch), (0x60, 0xB0, 64));
+    chip8.step().unwrap();
+    chip8.step().unwrap();
+    assert_eq!(chip8.sample_pattern().unwrap().pitch, 0x70);
+    let state = chip8.save_state();
+
+    let mut other = machine(&[0x00E0], Platform::XoChip);
+    assert_eq!(other.sample_pattern(), None);
+    other.load_state(&state).unwrap();
+    assert_eq!(other.sample_pattern(), chip8.sample_pattern());
+}
@@ -0,0 +1,43 @@
+//! The test scripts of tests/scripts, run by the script runner and by `rchip8 script`.
+
+use std::fs;
+use std::process::Command;
+
+use rchip8::chip8::chip8::CHIP8;
+use rchip8::chip8::platform::Platform;
+use rchip8::chip8::script_runner::{self, RomLoader};
+
+const PASSING: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/scripts/ibm_logo.rhai");
+const FAILING: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/scripts/ibm_logo_not_drawn.rhai");
+
+fn loader() -> RomLoader {
+    Box::new(|path| {
+        let program = fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        let mut chip8 = CHIP8::headless(Platform::Chip8);
+        chip8.load_program(&program).map_err(|e| e.to_string())?;
+        Ok(chip8)
+    })
+}
+
+#[test]
+fn scripts_pass_when_their_assertions_hold() {
+    script_runner::run_test(PASSING, loader()).unwrap();
+}
+
+#[test]
+fn failed_assertions_fail_scripts() {
+    let error = script_runner::run_test(FAILING, loader()).unwrap_err();
+    assert!(error.contains("the logo is drawn: expected \"d4598c296d5884a621d3fb2bc9461a308710fcfa\", got "), "{}", error);
+}
+
+#[test]
+fn script_command_exits_with_status_1_when_a_script_fails() {
+    let rchip8 = env!("CARGO_BIN_EXE_rchip8");
+    let output = Command::new(rchip8).args(["script", PASSING]).output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("PASS: "));
+
+    let output = Command::new(rchip8).args(["script", PASSING, FAILING]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 of 2 scripts failed"));
+}